@@ -0,0 +1,277 @@
+/*
+@author: xiao cai niao
+@datetime: 2020/08/20
+*/
+
+use std::net::{TcpListener, TcpStream};
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+use std::error::Error;
+use std::thread;
+use std::time::Duration;
+use serde::Serialize;
+use mymha::ha::procotol::{MyProtocol, ReponseErr, DownNodeCheck, DownNodeCheckStatus, ChangeMasterInfo,
+                          RecoveryInfo, SyncBinlogInfo, BinlogValue, RowsSql, CommandSql, AgentRegister, AgentHeartbeat,
+                          VipInfo, VipState, MonitorRequest};
+use crate::model::Fleet;
+
+///
+/// 注册及心跳中上报的client版本
+const VERSION: &str = "sim-0.1";
+
+///
+/// 接收到的server端请求包
+struct Request {
+    type_code: MyProtocol,
+    value: Vec<u8>,
+}
+
+///
+/// 为每个模拟节点启动client端监听
+pub fn start(fleet: Arc<Mutex<Fleet>>) -> Result<(), Box<dyn Error>> {
+    let listens: Vec<(String, u16)> = {
+        let f = fleet.lock().unwrap();
+        f.nodes.iter().map(|n| (n.ip.clone(), n.agent_port)).collect()
+    };
+    for (ip, port) in listens {
+        let listener = TcpListener::bind(format!("{}:{}", ip, port))?;
+        let f = fleet.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(s) => {
+                        let ff = f.clone();
+                        thread::spawn(move || {
+                            if let Err(e) = handle(s, port, ff) {
+                                println!("agent {}: {}", port, e.to_string());
+                            }
+                        });
+                    }
+                    Err(e) => {
+                        println!("agent {} accept error: {}", port, e.to_string());
+                    }
+                }
+            }
+        });
+    }
+    Ok(())
+}
+
+fn handle(mut conn: TcpStream, port: u16, fleet: Arc<Mutex<Fleet>>) -> Result<(), Box<dyn Error>> {
+    conn.set_read_timeout(Some(Duration::from_secs(10)))?;
+    {
+        //client被kill之后直接断开连接， 与真实client进程不存在的表现一致
+        let f = fleet.lock().unwrap();
+        if let Some(idx) = f.find_by_agent_port(port) {
+            if !f.nodes[idx].agent_alive {
+                return Ok(());
+            }
+        }
+    }
+    let request = read_request(&mut conn)?;
+    let (code, value) = match dispatch(&request, port, &fleet) {
+        Ok(v) => v,
+        Err(e) => {
+            let err = ReponseErr{ err: e.to_string() };
+            (MyProtocol::Error.get_code(), serde_json::to_vec(&err)?)
+        }
+    };
+    let mut buf = vec![code];
+    buf.extend(mymha::readvalue::write_u64(value.len() as u64));
+    buf.extend(value);
+    conn.write_all(&buf)?;
+    conn.flush()?;
+    Ok(())
+}
+
+fn read_request(conn: &mut TcpStream) -> Result<Request, Box<dyn Error>> {
+    let mut header = vec![0u8; 9];
+    conn.read_exact(&mut header)?;
+    let payload = mymha::readvalue::read_u64(&header[1..]);
+    let mut value = vec![0u8; payload as usize];
+    conn.read_exact(&mut value)?;
+    Ok(Request{ type_code: MyProtocol::new(&header[0]), value })
+}
+
+fn reply<T: Serialize>(code: MyProtocol, value: &T) -> Result<(u8, Vec<u8>), Box<dyn Error>> {
+    Ok((code.get_code(), serde_json::to_vec(value)?))
+}
+
+fn ok() -> Result<(u8, Vec<u8>), Box<dyn Error>> {
+    Ok((MyProtocol::Ok.get_code(), vec![]))
+}
+
+///
+/// 按照协议类型模拟client端的处理逻辑
+fn dispatch(request: &Request, port: u16, fleet: &Arc<Mutex<Fleet>>) -> Result<(u8, Vec<u8>), Box<dyn Error>> {
+    let mut f = fleet.lock().unwrap();
+    let idx = match f.find_by_agent_port(port) {
+        Some(idx) => idx,
+        None => return Err(format!("no node listen on port {}", port).into())
+    };
+    let db_alive = f.nodes[idx].db_alive;
+    match request.type_code {
+        MyProtocol::MysqlCheck => {
+            reply(MyProtocol::MysqlCheck, &f.nodes[idx].state())
+        }
+        MyProtocol::Ping => ok(),
+        MyProtocol::DownNodeCheck => {
+            let check: DownNodeCheck = serde_json::from_slice(&request.value)?;
+            let mut status = DownNodeCheckStatus::new();
+            status.host = check.host.clone();
+            if let Ok(target) = f.find(&check.host) {
+                status.client_status = f.nodes[target].agent_alive;
+                status.db_status = f.nodes[target].db_alive;
+            }
+            reply(MyProtocol::DownNodeCheck, &status)
+        }
+        //vip由client所在机器管理， 与mysql是否存活无关
+        MyProtocol::VipAcquire => {
+            let info: VipInfo = serde_json::from_slice(&request.value)?;
+            f.nodes[idx].vips.retain(|v| v != &info.vip);
+            f.nodes[idx].vips.push(info.vip.clone());
+            println!("{} acquire vip {}/{} on {}", f.nodes[idx].host(), info.vip, info.netmask, info.interface);
+            ok()
+        }
+        MyProtocol::VipRelease => {
+            let info: VipInfo = serde_json::from_slice(&request.value)?;
+            f.nodes[idx].vips.retain(|v| v != &info.vip);
+            println!("{} release vip {}", f.nodes[idx].host(), info.vip);
+            ok()
+        }
+        MyProtocol::VipStatus => {
+            let info: VipInfo = serde_json::from_slice(&request.value)?;
+            let bound = f.nodes[idx].vips.contains(&info.vip);
+            reply(MyProtocol::VipStatus, &VipState{ vip: info.vip, bound })
+        }
+        _ if !db_alive => {
+            let err = format!("mysql instance {} is down", f.nodes[idx].dbport);
+            Err(err.into())
+        }
+        MyProtocol::GetMonitor => {
+            let req: MonitorRequest = serde_json::from_slice(&request.value)?;
+            reply(MyProtocol::GetMonitor, &f.nodes[idx].monitor_status(&req.variables))
+        }
+        MyProtocol::ReplicationStatus => {
+            reply(MyProtocol::ReplicationStatus, &f.nodes[idx].replication_state())
+        }
+        MyProtocol::GetRecoveryInfo => {
+            reply(MyProtocol::GetRecoveryInfo, &f.nodes[idx].recovery_info())
+        }
+        MyProtocol::SetMaster => {
+            f.nodes[idx].promote();
+            println!("{} promoted to master", f.nodes[idx].host());
+            ok()
+        }
+        MyProtocol::ChangeMaster => {
+            let info: ChangeMasterInfo = serde_json::from_slice(&request.value)?;
+            let master = f.find_by_db(&info.master_host, info.master_port)
+                .ok_or_else(|| format!("unknown master {}:{}", info.master_host, info.master_port))?;
+            let master_port = f.nodes[master].dbport;
+            f.nodes[idx].replicate_from(master_port);
+            println!("{} change master to {}:{}", f.nodes[idx].host(), info.master_host, info.master_port);
+            ok()
+        }
+        MyProtocol::RecoveryCluster => {
+            let info: RecoveryInfo = serde_json::from_slice(&request.value)?;
+            let master = f.find_by_db(&info.masterhost, info.masterport)
+                .ok_or_else(|| format!("unknown master {}:{}", info.masterhost, info.masterport))?;
+            let rows = f.rollback_diverged(idx, master);
+            let master_port = f.nodes[master].dbport;
+            f.nodes[idx].replicate_from(master_port);
+            println!("{} recovered from {}:{}, rollback {} statements", f.nodes[idx].host(),
+                     info.masterhost, info.masterport, rows.sqls.iter().map(|s| s.cur_sql.len()).sum::<usize>());
+            reply(MyProtocol::RecoveryValue, &rows)
+        }
+        MyProtocol::PullBinlog => {
+            let _info: SyncBinlogInfo = serde_json::from_slice(&request.value)?;
+            reply(MyProtocol::PullBinlog, &BinlogValue{ value: vec![] })
+        }
+        MyProtocol::PushBinlog => {
+            let _value: BinlogValue = serde_json::from_slice(&request.value)?;
+            let rows = RowsSql{ sqls: vec![], error: "".to_string(), etype: "append".to_string() };
+            reply(MyProtocol::RecoveryValue, &rows)
+        }
+        MyProtocol::SetVariables => {
+            f.nodes[idx].read_only = true;
+            ok()
+        }
+        MyProtocol::RecoveryVariables => {
+            f.nodes[idx].read_only = false;
+            ok()
+        }
+        MyProtocol::Command => {
+            let sqls: CommandSql = serde_json::from_slice(&request.value)?;
+            println!("{} execute {} statements", f.nodes[idx].host(), sqls.sqls.len());
+            ok()
+        }
+        _ => {
+            let err = format!("unsupported type code: {:?}", request.type_code);
+            Err(err.into())
+        }
+    }
+}
+
+///
+/// 模拟client主动注册并定时推送心跳， client被kill时停止推送
+pub fn push(fleet: Arc<Mutex<Fleet>>, server: String, token: String) {
+    let ports: Vec<u16> = fleet.lock().unwrap().nodes.iter().map(|n| n.agent_port).collect();
+    for port in ports {
+        let f = fleet.clone();
+        let server = server.clone();
+        let token = token.clone();
+        thread::spawn(move || {
+            loop {
+                if let Err(e) = push_loop(&f, port, &server, &token) {
+                    println!("agent {} push to {}: {}", port, &server, e.to_string());
+                }
+                thread::sleep(Duration::from_secs(2));
+            }
+        });
+    }
+}
+
+fn push_loop(fleet: &Arc<Mutex<Fleet>>, port: u16, server: &String, token: &String) -> Result<(), Box<dyn Error>> {
+    let mut conn = TcpStream::connect(server)?;
+    conn.set_read_timeout(Some(Duration::from_secs(10)))?;
+    let register = {
+        let f = fleet.lock().unwrap();
+        let idx = f.find_by_agent_port(port).ok_or("node not found")?;
+        let node = &f.nodes[idx];
+        AgentRegister{
+            host: node.host(),
+            dbport: node.dbport,
+            cluster_name: f.cluster_name.clone(),
+            version: VERSION.to_string(),
+            token: token.clone()
+        }
+    };
+    send_packet(&mut conn, MyProtocol::Register, &register)?;
+    loop {
+        let heartbeat = {
+            let f = fleet.lock().unwrap();
+            let idx = f.find_by_agent_port(port).ok_or("node not found")?;
+            let node = &f.nodes[idx];
+            if !node.agent_alive {
+                return Ok(());
+            }
+            AgentHeartbeat{ host: node.host(), version: VERSION.to_string(), state: node.state(), token: token.clone() }
+        };
+        send_packet(&mut conn, MyProtocol::Heartbeat, &heartbeat)?;
+        thread::sleep(Duration::from_secs(2));
+    }
+}
+
+fn send_packet<T: Serialize>(conn: &mut TcpStream, code: MyProtocol, value: &T) -> Result<(), Box<dyn Error>> {
+    let value = serde_json::to_vec(value)?;
+    let mut buf = vec![code.get_code()];
+    buf.extend(mymha::readvalue::write_u64(value.len() as u64));
+    buf.extend(value);
+    conn.write_all(&buf)?;
+    let response = read_request(conn)?;
+    if let MyProtocol::Error = response.type_code {
+        let err: ReponseErr = serde_json::from_slice(&response.value)?;
+        return Err(err.err.into());
+    }
+    Ok(())
+}
@@ -0,0 +1,206 @@
+/*
+@author: xiao cai niao
+@datetime: 2020/08/20
+*/
+
+//! 模拟mysqlMP-client及mysql实例， 用于在单机上对server进行宕机切换、恢复等场景测试
+//!
+//! 启动后每个节点监听一个client端口， 可以通过/import接口把`127.0.0.1:<client端口>`加入server管理，
+//! 之后通过控制端口、标准输入或者脚本文件发送命令模拟故障
+
+mod model;
+mod agent;
+
+use std::sync::{Arc, Mutex};
+use std::{thread, time};
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::error::Error;
+use std::fs::File;
+use structopt::StructOpt;
+use crate::model::Fleet;
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "agent_sim", about = "mysqlMP client simulator")]
+pub struct Opt {
+    #[structopt(long = "cluster", default_value = "sim", help="集群名称")]
+    pub cluster: String,
+
+    #[structopt(long = "nodes", default_value = "3", help="节点数量， 第一个节点为master")]
+    pub nodes: usize,
+
+    #[structopt(long = "listen", default_value = "127.0.0.1", help="client监听地址")]
+    pub listen: String,
+
+    #[structopt(long = "port", default_value = "9011", help="第一个节点的client端口， 之后依次递增")]
+    pub port: u16,
+
+    #[structopt(long = "dbport", default_value = "3306", help="第一个节点的mysql端口， 之后依次递增")]
+    pub dbport: usize,
+
+    #[structopt(long = "control", default_value = "9010", help="控制命令监听端口")]
+    pub control: u16,
+
+    #[structopt(long = "server", help="server的agentport地址， 设置后各节点主动注册并推送心跳")]
+    pub server: Option<String>,
+
+    #[structopt(long = "token", default_value = "", help="与server的agent_token一致")]
+    pub token: String,
+
+    #[structopt(long = "script", help="启动后按顺序执行的命令文件")]
+    pub script: Option<String>,
+}
+
+const HELP: &str = "commands:
+  status                    show all nodes
+  kill-db <node>            stop mysql instance
+  start-db <node>           start mysql instance
+  kill-agent <node>         stop client (no response)
+  start-agent <node>        start client
+  lag <node> <seconds>      inject replication lag, 0 to clear
+  stop-slave <node>         stop io/sql thread
+  start-slave <node>        start io/sql thread
+  tps <node> <trx>          set write transactions per second
+  sleep <seconds>           wait (script only)
+<node> is the node index, client host (ip:port) or mysql port";
+
+pub fn main() {
+    let args = Opt::from_args();
+    let fleet = Fleet::new(&args.cluster, &args.listen, args.nodes, args.port, args.dbport);
+    for node in &fleet.nodes {
+        println!("import: {{\"host\": \"{}\", \"rtype\": \"db\", \"dbport\": {}, \"cluster_name\": \"{}\"}}",
+                 node.host(), node.dbport, &args.cluster);
+    }
+    let fleet = Arc::new(Mutex::new(fleet));
+
+    if let Err(e) = agent::start(fleet.clone()) {
+        println!("start agent listener failed: {}", e.to_string());
+        std::process::exit(1);
+    }
+
+    if let Some(server) = &args.server {
+        agent::push(fleet.clone(), server.clone(), args.token.clone());
+    }
+
+    let f = fleet.clone();
+    thread::spawn(move || {
+        loop {
+            f.lock().unwrap().tick();
+            thread::sleep(time::Duration::from_secs(1));
+        }
+    });
+
+    let f = fleet.clone();
+    let control = format!("{}:{}", &args.listen, args.control);
+    thread::spawn(move || {
+        if let Err(e) = control_listener(&control, f) {
+            println!("control listener error: {}", e.to_string());
+        }
+    });
+
+    if let Some(script) = &args.script {
+        if let Err(e) = run_script(script, &fleet) {
+            println!("script error: {}", e.to_string());
+        }
+    }
+
+    let stdin = std::io::stdin();
+    for line in stdin.lock().lines() {
+        match line {
+            Ok(l) => println!("{}", execute(&l, &fleet)),
+            Err(_) => break
+        }
+    }
+    //标准输入关闭时保持运行， 通过控制端口继续操作
+    loop {
+        thread::sleep(time::Duration::from_secs(3600));
+    }
+}
+
+fn run_script(path: &String, fleet: &Arc<Mutex<Fleet>>) -> Result<(), Box<dyn Error>> {
+    let reader = BufReader::new(File::open(path)?);
+    for line in reader.lines() {
+        let line = line?;
+        println!("> {}", line.trim());
+        println!("{}", execute(&line, fleet));
+    }
+    Ok(())
+}
+
+fn control_listener(addr: &String, fleet: Arc<Mutex<Fleet>>) -> Result<(), Box<dyn Error>> {
+    let listener = TcpListener::bind(addr)?;
+    for stream in listener.incoming() {
+        let mut stream = stream?;
+        let reader = BufReader::new(stream.try_clone()?);
+        for line in reader.lines() {
+            let line = line?;
+            let out = execute(&line, &fleet);
+            stream.write_all(format!("{}\n", out).as_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+///
+/// 执行一条控制命令并返回结果
+fn execute(line: &str, fleet: &Arc<Mutex<Fleet>>) -> String {
+    let args: Vec<&str> = line.split_whitespace().collect();
+    if args.len() == 0 || args[0].starts_with('#') {
+        return "".to_string();
+    }
+    if args[0] == "sleep" {
+        let secs = args.get(1).and_then(|s| s.parse::<u64>().ok()).unwrap_or(1);
+        thread::sleep(time::Duration::from_secs(secs));
+        return "OK".to_string();
+    }
+    match command(&args, &mut fleet.lock().unwrap()) {
+        Ok(v) => v,
+        Err(e) => format!("ERROR: {}", e.to_string())
+    }
+}
+
+fn command(args: &Vec<&str>, fleet: &mut Fleet) -> Result<String, Box<dyn Error>> {
+    match args[0] {
+        "help" => return Ok(HELP.to_string()),
+        "status" => return Ok(status(fleet)),
+        _ => {}
+    }
+    let name = args.get(1).ok_or("missing node argument")?;
+    let idx = fleet.find(name)?;
+    let node = &mut fleet.nodes[idx];
+    match args[0] {
+        "kill-db" => node.db_alive = false,
+        "start-db" => node.db_alive = true,
+        "kill-agent" => node.agent_alive = false,
+        "start-agent" => node.agent_alive = true,
+        "lag" => {
+            node.injected_lag = args.get(2).ok_or("missing seconds argument")?.parse()?;
+        }
+        "stop-slave" => {
+            node.io_thread = false;
+            node.sql_thread = false;
+        }
+        "start-slave" => {
+            node.io_thread = true;
+            node.sql_thread = true;
+        }
+        "tps" => {
+            node.write_tps = args.get(2).ok_or("missing trx argument")?.parse()?;
+        }
+        _ => {
+            let err = format!("unknown command: {}\n{}", args[0], HELP);
+            return Err(err.into());
+        }
+    }
+    Ok("OK".to_string())
+}
+
+fn status(fleet: &Fleet) -> String {
+    let mut out = vec![format!("cluster: {}", fleet.cluster_name)];
+    for (idx, n) in fleet.nodes.iter().enumerate() {
+        out.push(format!("[{}] {} dbport={} role={} master={:?} db={} agent={} io={} sql={} ro={} behind={} gtid={} vip={:?}",
+                         idx, n.host(), n.dbport, n.role, n.master, n.db_alive, n.agent_alive,
+                         n.io_thread, n.sql_thread, n.read_only, n.seconds_behind, n.gtid_string(), n.vips));
+    }
+    out.join("\n")
+}
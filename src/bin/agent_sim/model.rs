@@ -0,0 +1,323 @@
+/*
+@author: xiao cai niao
+@datetime: 2020/08/20
+*/
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use mymha::ha::procotol::{MysqlState, MysqlMonitorStatus, MonitorVariable, ReplicationState, GetRecoveryInfo, RowsSql, TractionValue};
+use rand::{thread_rng, Rng};
+
+///
+/// 每个事务在binlog中占用的平均字节数
+const TRX_EVENT_SIZE: usize = 256;
+
+///
+/// 模拟的单个mysql实例及其client状态
+#[derive(Debug, Clone)]
+pub struct SimNode {
+    pub ip: String,
+    pub agent_port: u16,
+    pub dbport: usize,
+    pub server_id: usize,
+    pub uuid: String,
+    pub role: String,               //master、slave
+    pub master: Option<usize>,      //master节点的dbport
+    pub db_alive: bool,             //mysql实例是否存活
+    pub agent_alive: bool,          //client是否存活
+    pub io_thread: bool,
+    pub sql_thread: bool,
+    pub read_only: bool,
+    pub injected_lag: usize,        //人为注入的延迟秒数
+    pub seconds_behind: usize,
+    pub write_tps: usize,           //作为master时每秒写入的事务数
+    pub gtid: BTreeMap<String, u64>,
+    pub binlog_file: String,
+    pub binlog_pos: usize,
+    pub master_log_file: String,
+    pub read_master_log_pos: usize,
+    pub exec_master_log_pos: usize,
+    pub counters: MysqlMonitorStatus,
+    pub vips: Vec<String>,          //绑定在本机的vip
+}
+
+impl SimNode {
+    pub fn new(ip: &String, agent_port: u16, dbport: usize, server_id: usize) -> SimNode {
+        SimNode{
+            ip: ip.clone(),
+            agent_port,
+            dbport,
+            server_id,
+            uuid: format!("3e11fa47-71ca-11e1-9e33-c80aa9{:06}", server_id),
+            role: "slave".to_string(),
+            master: None,
+            db_alive: true,
+            agent_alive: true,
+            io_thread: true,
+            sql_thread: true,
+            read_only: true,
+            injected_lag: 0,
+            seconds_behind: 0,
+            write_tps: 50,
+            gtid: BTreeMap::new(),
+            binlog_file: "mysql-bin.000001".to_string(),
+            binlog_pos: 4,
+            master_log_file: "".to_string(),
+            read_master_log_pos: 0,
+            exec_master_log_pos: 0,
+            counters: MysqlMonitorStatus::default(),
+            vips: vec![],
+        }
+    }
+
+    ///
+    /// server端使用的节点key， 格式为ip:client端口
+    pub fn host(&self) -> String {
+        format!("{}:{}", self.ip, self.agent_port)
+    }
+
+    pub fn gtid_string(&self) -> String {
+        let mut sets = vec![];
+        for (uuid, trx) in &self.gtid {
+            if *trx > 0 {
+                sets.push(format!("{}:1-{}", uuid, trx));
+            }
+        }
+        sets.join(",")
+    }
+
+    ///
+    /// 生成回复server状态检查的数据
+    pub fn state(&self) -> MysqlState {
+        let mut state = MysqlState::new();
+        state.online = self.db_alive;
+        state.role = self.role.clone();
+        state.read_only = self.read_only;
+        state.version = "5.7.30-sim".to_string();
+        state.server_id = self.server_id;
+        state.executed_gtid_set = self.gtid_string();
+        state.innodb_flush_log_at_trx_commit = 1;
+        state.sync_binlog = 1;
+        state.event_scheduler = "OFF".to_string();
+        state.innodb_buffer_pool_size = 134217728;
+        if self.role == "slave".to_string() {
+            state.master = match self.master {
+                Some(port) => format!("{}:{}", self.ip, port),
+                None => "".to_string()
+            };
+            state.sql_thread = self.sql_thread;
+            state.io_thread = self.io_thread;
+            state.seconds_behind = self.seconds_behind;
+            state.master_log_file = self.master_log_file.clone();
+            state.read_master_log_pos = self.read_master_log_pos;
+            state.exec_master_log_pos = self.exec_master_log_pos;
+            if !self.io_thread {
+                state.last_io_error = "Slave I/O thread stopped by simulator".to_string();
+            }
+            if !self.sql_thread {
+                state.last_sql_error = "Slave SQL thread stopped by simulator".to_string();
+            }
+        }
+        state
+    }
+
+    pub fn replication_state(&self) -> ReplicationState {
+        ReplicationState{
+            log_name: self.master_log_file.clone(),
+            read_log_pos: self.read_master_log_pos,
+            exec_log_pos: self.exec_master_log_pos
+        }
+    }
+
+    pub fn recovery_info(&self) -> GetRecoveryInfo {
+        GetRecoveryInfo{
+            binlog: self.binlog_file.clone(),
+            position: self.binlog_pos,
+            gtid: self.gtid_string()
+        }
+    }
+
+    ///
+    /// 只返回请求的变量， 没有模拟的变量为0， 请求为空时返回所有模拟的变量
+    pub fn monitor_status(&self, variables: &Vec<MonitorVariable>) -> MysqlMonitorStatus {
+        let mut status = self.counters.clone();
+        if variables.len() > 0 {
+            status.values = variables.iter().map(|v| (v.name.clone(), self.counters.get(&v.name))).collect();
+        }
+        status.time = mymha::timestamp();
+        status
+    }
+
+    ///
+    /// 提升为master， 对应client端SetMaster协议
+    pub fn promote(&mut self) {
+        self.role = "master".to_string();
+        self.master = None;
+        self.read_only = false;
+        self.io_thread = false;
+        self.sql_thread = false;
+        self.seconds_behind = 0;
+        self.injected_lag = 0;
+        self.master_log_file = "".to_string();
+        self.read_master_log_pos = 0;
+        self.exec_master_log_pos = 0;
+    }
+
+    ///
+    /// 指向新master， 对应client端ChangeMaster协议
+    pub fn replicate_from(&mut self, master_port: usize) {
+        self.role = "slave".to_string();
+        self.master = Some(master_port);
+        self.read_only = true;
+        self.io_thread = true;
+        self.sql_thread = true;
+    }
+
+    fn write(&mut self, trx: u64) {
+        let own = self.gtid.entry(self.uuid.clone()).or_insert(0);
+        *own += trx;
+        self.binlog_pos += trx as usize * TRX_EVENT_SIZE;
+        self.bump_counters(trx as usize, true);
+    }
+
+    fn bump_counters(&mut self, trx: usize, master: bool) {
+        let mut rng = thread_rng();
+        let c = &mut self.counters.values;
+        let mut add = |name: &str, v: usize| *c.entry(name.to_string()).or_insert(0) += v as u64;
+        let selects = rng.gen_range(trx * 2 + 10, trx * 4 + 20);
+        add("com_select", selects);
+        if master {
+            add("com_insert", trx / 2);
+            add("com_update", trx - trx / 2);
+            add("com_delete", rng.gen_range(0, trx / 10 + 1));
+        }
+        add("questions", selects + trx);
+        add("innodb_buffer_pool_read_requests", selects * 10);
+        add("innodb_buffer_pool_reads", rng.gen_range(0, 5));
+        add("handler_read_key", selects * 3);
+        add("handler_read_next", selects * 5);
+        add("handler_read_rnd_next", rng.gen_range(0, selects + 1));
+        add("created_tmp_tables", rng.gen_range(0, 3));
+        add("bytes_received", (selects + trx) * 120);
+        add("bytes_sent", selects * 900);
+        if rng.gen_range(0, 100) < 3 {
+            add("slow_queries", 1);
+        }
+        c.insert("threads_connected".to_string(), rng.gen_range(10, 40));
+        c.insert("threads_running".to_string(), rng.gen_range(1, 8));
+    }
+}
+
+///
+/// 模拟的整个集群
+#[derive(Debug)]
+pub struct Fleet {
+    pub cluster_name: String,
+    pub nodes: Vec<SimNode>,
+}
+
+impl Fleet {
+    ///
+    /// 初始化一主多从的集群， 第一个节点为master
+    pub fn new(cluster_name: &String, ip: &String, nodes: usize, agent_port: u16, dbport: usize) -> Fleet {
+        let mut list = vec![];
+        for i in 0..nodes {
+            let mut node = SimNode::new(ip, agent_port + i as u16, dbport + i, i + 1);
+            if i == 0 {
+                node.promote();
+            } else {
+                node.replicate_from(dbport);
+            }
+            list.push(node);
+        }
+        Fleet{ cluster_name: cluster_name.clone(), nodes: list }
+    }
+
+    ///
+    /// 通过节点序号、host或者dbport查找节点
+    pub fn find(&self, name: &str) -> Result<usize, Box<dyn Error>> {
+        if let Ok(idx) = name.parse::<usize>() {
+            if idx < self.nodes.len() {
+                return Ok(idx);
+            }
+        }
+        for (idx, node) in self.nodes.iter().enumerate() {
+            if node.host() == name || node.dbport.to_string() == name {
+                return Ok(idx);
+            }
+        }
+        let err = format!("no such node: {}", name);
+        Err(err.into())
+    }
+
+    pub fn find_by_agent_port(&self, port: u16) -> Option<usize> {
+        self.nodes.iter().position(|n| n.agent_port == port)
+    }
+
+    pub fn find_by_db(&self, ip: &str, dbport: usize) -> Option<usize> {
+        self.nodes.iter().position(|n| n.ip == ip && n.dbport == dbport)
+    }
+
+    ///
+    /// 每秒推进一次模拟状态: master写入事务, slave按照io/sql线程状态及注入延迟同步
+    pub fn tick(&mut self) {
+        for node in &mut self.nodes {
+            if node.db_alive && node.role == "master".to_string() {
+                let tps = node.write_tps as u64;
+                node.write(tps);
+            }
+        }
+
+        let snapshot = self.nodes.clone();
+        for node in &mut self.nodes {
+            if !node.db_alive || node.role != "slave".to_string() {
+                continue;
+            }
+            node.bump_counters(node.write_tps, false);
+            let master = match node.master.and_then(|p| snapshot.iter().find(|n| n.dbport == p)) {
+                Some(m) => m,
+                None => continue
+            };
+            if node.io_thread && master.db_alive {
+                node.master_log_file = master.binlog_file.clone();
+                node.read_master_log_pos = master.binlog_pos;
+            }
+            if !node.sql_thread {
+                continue;
+            }
+            let behind_trx = (node.injected_lag * master.write_tps) as u64;
+            for (uuid, trx) in &master.gtid {
+                let target = if uuid == &master.uuid { trx.saturating_sub(behind_trx) } else { *trx };
+                let cur = node.gtid.entry(uuid.clone()).or_insert(0);
+                if *cur < target {
+                    *cur = target;
+                }
+            }
+            node.exec_master_log_pos = node.read_master_log_pos.saturating_sub(behind_trx as usize * TRX_EVENT_SIZE);
+            node.seconds_behind = if node.io_thread { node.injected_lag } else { 0 };
+        }
+    }
+
+    ///
+    /// 旧master宕机恢复时回滚新master上不存在的事务， 并返回回滚数据
+    pub fn rollback_diverged(&mut self, idx: usize, master_idx: usize) -> RowsSql {
+        let master_gtid = self.nodes[master_idx].gtid.clone();
+        let node = &mut self.nodes[idx];
+        let own_uuid = node.uuid.clone();
+        let own = node.gtid.get(&own_uuid).cloned().unwrap_or(0);
+        let applied = master_gtid.get(&own_uuid).cloned().unwrap_or(0);
+        let mut rows = RowsSql{ sqls: vec![], error: "".to_string(), etype: "rollback".to_string() };
+        if own > applied {
+            let mut value = TractionValue{ cur_sql: vec![], rollback_sql: vec![] };
+            //只生成最后100个事务的sql， 避免回滚数据过大
+            let start = std::cmp::max(applied + 1, own.saturating_sub(99));
+            for trx in start..=own {
+                value.cur_sql.push(format!("INSERT INTO sim.t1(id, gtid) VALUES ({}, '{}:{}')", trx, own_uuid, trx));
+                value.rollback_sql.push(format!("DELETE FROM sim.t1 WHERE id = {}", trx));
+            }
+            rows.sqls.push(value);
+            node.gtid.insert(own_uuid, applied);
+        }
+        rows
+    }
+}
@@ -0,0 +1,422 @@
+/*
+@author: xiao cai niao
+@datetime: 2020/09/17
+*/
+
+//! server配置文件
+//!
+//! 启动时通过--config指定toml格式的配置文件， 未指定的配置使用默认值， 命令行参数优先于配置文件，
+//! 未知的section或key视为错误
+//!
+//! interval、retention、exporter中的配置可以在运行中重新加载， 收到SIGHUP或调用/reloadconfig时生效，
+//! 其余配置修改后需要重启， 重新加载时只记录日志并保持原值
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use signal_hook::SIGHUP;
+use std::{thread, time};
+use serde::{Serialize, Deserialize};
+use serde_json::{Map, Value};
+use crate::Opt;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSection {
+    pub listen: String,
+    pub port: usize,
+    pub agentport: Option<usize>,       //不配置则不启用
+    #[serde(skip_serializing)]
+    pub agent_token: String,            //client注册及推送心跳时携带的token， 启用agentport时必须配置， /getserverconfig不返回
+    pub dnsport: Option<usize>,         //不配置则不启用
+    pub dnsdomain: String,
+}
+
+impl Default for ServerSection {
+    fn default() -> ServerSection {
+        ServerSection{
+            listen: String::from("127.0.0.1"),
+            port: 8099,
+            agentport: None,
+            agent_token: String::new(),
+            dnsport: None,
+            dnsdomain: String::from("db.local")
+        }
+    }
+}
+
+///
+/// web服务的https配置
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsSection {
+    pub enable: bool,
+    pub cert: String,           //证书链文件， pem格式
+    pub key: String,            //私钥文件， pem格式
+}
+
+impl Default for TlsSection {
+    fn default() -> TlsSection {
+        TlsSection{ enable: false, cert: String::from("cert.pem"), key: String::from("key.pem") }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageSection {
+    pub data_dir: String,       //rocksdb数据目录
+    pub backup_dir: String,     //元数据备份目录
+}
+
+impl Default for StorageSection {
+    fn default() -> StorageSection {
+        StorageSection{ data_dir: String::from("rocksdb"), backup_dir: String::from("backup") }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogSection {
+    pub path: String,
+    pub level: String,          //error、warn、info、debug、trace
+}
+
+impl Default for LogSection {
+    fn default() -> LogSection {
+        LogSection{ path: String::from("log/requests.log"), level: String::from("info") }
+    }
+}
+
+///
+/// 各后台线程的执行间隔， 可热加载
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct IntervalSection {
+    pub health_check_ms: u64,   //节点状态检查
+    pub route_ms: u64,          //路由信息检查
+    pub monitor_secs: u64,      //监控数据采集
+    pub purge_hours: u64,       //过期数据清理
+}
+
+impl Default for IntervalSection {
+    fn default() -> IntervalSection {
+        IntervalSection{ health_check_ms: 1000, route_ms: 1000, monitor_secs: 10, purge_hours: 24 }
+    }
+}
+
+///
+/// 数据保留时长， 可热加载
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionSection {
+    pub rollback_days: u64,         //切换产生的回滚sql
+    pub alert_history_days: u64,    //报警通知发送记录、已结束的静默及审计记录
+    pub state_event_days: u64,      //节点状态变化记录
+    pub route_history_days: u64,    //路由变化历史， 每个集群最后一条始终保留
+}
+
+impl Default for RetentionSection {
+    fn default() -> RetentionSection {
+        RetentionSection{ rollback_days: 7, alert_history_days: 30, state_event_days: 30, route_history_days: 30 }
+    }
+}
+
+///
+/// 路由导出， 输出文件只能写入output_dir， reload命令只能在配置文件中配置， 可热加载
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExporterSection {
+    pub output_dir: String,
+    pub reload: BTreeMap<String, String>,   //名称到命令， 导出配置中按名称选择
+}
+
+impl Default for ExporterSection {
+    fn default() -> ExporterSection {
+        ExporterSection{ output_dir: String::from("export"), reload: BTreeMap::new() }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub server: ServerSection,
+    pub tls: TlsSection,
+    pub storage: StorageSection,
+    pub log: LogSection,
+    pub interval: IntervalSection,
+    pub retention: RetentionSection,
+    pub exporter: ExporterSection,
+}
+
+impl ServerConfig {
+    fn check(&self) -> Result<(), Box<dyn Error>> {
+        let i = &self.interval;
+        if i.health_check_ms == 0 || i.route_ms == 0 || i.monitor_secs == 0 || i.purge_hours == 0 {
+            return Err("all intervals must be greater than 0".into());
+        }
+        let r = &self.retention;
+        if r.rollback_days == 0 || r.alert_history_days == 0 || r.state_event_days == 0 || r.route_history_days == 0 {
+            return Err("all retention days must be greater than 0".into());
+        }
+        if self.storage.data_dir.len() == 0 || self.storage.backup_dir.len() == 0 || self.exporter.output_dir.len() == 0 {
+            return Err("data_dir, backup_dir and output_dir can not be empty".into());
+        }
+        if self.server.agentport.is_some() && self.server.agent_token.len() == 0 {
+            return Err("agent_token is required when agentport is enabled".into());
+        }
+        self.log.level.parse::<log::LevelFilter>()
+            .map_err(|_| format!("invalid log level: {}", &self.log.level))?;
+        Ok(())
+    }
+
+    ///
+    /// 命令行参数覆盖配置文件
+    fn apply_args(&mut self, args: &Opt) -> Result<(), Box<dyn Error>> {
+        if let Some(port) = &args.port {
+            self.server.port = port.parse()?;
+        }
+        if let Some(listen) = &args.listen {
+            self.server.listen = listen.clone();
+        }
+        if let Some(port) = &args.agentport {
+            self.server.agentport = Some(port.parse()?);
+        }
+        if let Some(token) = &args.agenttoken {
+            self.server.agent_token = token.clone();
+        }
+        if let Some(port) = &args.dnsport {
+            self.server.dnsport = Some(port.parse()?);
+        }
+        if let Some(domain) = &args.dnsdomain {
+            self.server.dnsdomain = domain.clone();
+        }
+        Ok(())
+    }
+}
+
+///
+/// 重新加载的结果， 内容为section.key
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReloadResult {
+    pub reloaded: Vec<String>,
+    pub restart_required: Vec<String>,
+}
+
+struct ConfigState {
+    args: Opt,                  //重新加载时命令行参数仍然优先
+    config: Arc<ServerConfig>,
+}
+
+static STATE: RwLock<Option<ConfigState>> = RwLock::new(None);
+
+///
+/// 可热加载的section
+const RELOADABLE: [&str; 3] = ["interval", "retention", "exporter"];
+
+///
+/// 启动时加载配置， 必须在打开rocksdb之前执行
+pub fn init(args: &Opt) -> Result<(), Box<dyn Error>> {
+    let config = load(args)?;
+    let mut state = STATE.write().map_err(|_| "config lock poisoned")?;
+    *state = Some(ConfigState{ args: args.clone(), config: Arc::new(config) });
+    Ok(())
+}
+
+///
+/// 当前生效的配置， 未初始化时为默认配置
+pub fn get() -> Arc<ServerConfig> {
+    match STATE.read() {
+        Ok(state) => match &*state {
+            Some(s) => s.config.clone(),
+            None => Arc::new(ServerConfig::default())
+        }
+        Err(_) => Arc::new(ServerConfig::default())
+    }
+}
+
+fn parse(content: &str) -> Result<ServerConfig, Box<dyn Error>> {
+    let config: ServerConfig = toml::from_str(content)?;
+    Ok(config)
+}
+
+fn load(args: &Opt) -> Result<ServerConfig, Box<dyn Error>> {
+    let mut config = match &args.config {
+        Some(p) => {
+            let content = fs::read_to_string(p).map_err(|e| format!("read config file {} failed: {}", p, e.to_string()))?;
+            parse(&content).map_err(|e| format!("invalid config file {}: {}", p, e.to_string()))?
+        }
+        None => ServerConfig::default()
+    };
+    config.apply_args(args)?;
+    config.check()?;
+    Ok(config)
+}
+
+///
+/// 重新读取配置文件， 只有可热加载的section会生效
+pub fn reload() -> Result<ReloadResult, Box<dyn Error>> {
+    let mut guard = STATE.write().map_err(|_| "config lock poisoned")?;
+    let state = guard.as_mut().ok_or("config is not initialized")?;
+    let new = load(&state.args)?;
+
+    let old_value = serde_json::to_value(&*state.config)?;
+    let new_value = serde_json::to_value(&new)?;
+    let mut result = ReloadResult{ reloaded: vec![], restart_required: vec![] };
+    let mut merged = (*state.config).clone();
+    for (section, key) in changed_keys(&old_value, &new_value) {
+        let name = format!("{}.{}", &section, &key);
+        if RELOADABLE.contains(&section.as_str()) {
+            result.reloaded.push(name);
+        } else {
+            result.restart_required.push(name);
+        }
+    }
+    merged.interval = new.interval;
+    merged.retention = new.retention;
+    merged.exporter = new.exporter;
+    state.config = Arc::new(merged);
+
+    if result.reloaded.len() > 0 {
+        info!("config reloaded: {}", result.reloaded.join(", "));
+    }
+    if result.restart_required.len() > 0 {
+        info!("config changed but requires restart: {}", result.restart_required.join(", "));
+    }
+    Ok(result)
+}
+
+fn changed_keys(old: &Value, new: &Value) -> Vec<(String, String)> {
+    let mut keys = vec![];
+    let empty = Map::new();
+    if let (Some(old), Some(new)) = (old.as_object(), new.as_object()) {
+        for (section, new_section) in new {
+            let old_section = old.get(section).and_then(|v| v.as_object()).unwrap_or(&empty);
+            if let Some(new_section) = new_section.as_object() {
+                for (key, v) in new_section {
+                    if old_section.get(key) != Some(v) {
+                        keys.push((section.clone(), key.clone()));
+                    }
+                }
+            }
+        }
+    }
+    keys
+}
+
+///
+/// 监听SIGHUP并重新加载配置， 信号处理中只设置标记， 由该线程执行重新加载
+pub fn watcher() {
+    let signal = Arc::new(AtomicBool::new(false));
+    if let Err(e) = signal_hook::flag::register(SIGHUP, signal.clone()) {
+        info!("register SIGHUP failed: {}", e.to_string());
+    }
+    info!("config reload thread start success");
+    loop {
+        if signal.swap(false, Ordering::SeqCst) {
+            info!("received SIGHUP, reload config");
+            if let Err(e) = reload() {
+                info!("reload config failed: {}", e.to_string());
+            }
+        }
+        thread::sleep(time::Duration::from_secs(1));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_empty_uses_default() {
+        assert_eq!(parse("").unwrap(), ServerConfig::default());
+    }
+
+    #[test]
+    fn parse_sections() {
+        let content = r#"
+            # comment
+            [server]
+            listen = "0.0.0.0"   # trailing comment
+            port = 8099
+            agentport = 8098
+            dnsdomain = "db.\"local\""
+
+            [retention]
+            rollback_days = 14
+            route_history_days = 7
+        "#;
+        let config = parse(content).unwrap();
+        assert_eq!(config.server.listen, "0.0.0.0");
+        assert_eq!(config.server.agentport, Some(8098));
+        assert_eq!(config.server.dnsport, None);
+        assert_eq!(config.server.dnsdomain, "db.\"local\"");
+        assert_eq!(config.retention.rollback_days, 14);
+        assert_eq!(config.retention.route_history_days, 7);
+        assert_eq!(config.retention.state_event_days, 30);
+        assert_eq!(config.interval, IntervalSection::default());
+    }
+
+    #[test]
+    fn agent_token_not_serialized() {
+        let config = parse("[server]\nagentport = 8098\nagent_token = \"secret\"").unwrap();
+        assert_eq!(config.server.agent_token, "secret");
+        let value = serde_json::to_string(&config).unwrap();
+        assert!(!value.contains("secret"));
+        assert!(!value.contains("agent_token"));
+    }
+
+    #[test]
+    fn parse_exporter_reload() {
+        let content = r#"
+            [exporter]
+            output_dir = "/etc/haproxy/conf.d"
+
+            [exporter.reload]
+            haproxy = "systemctl reload haproxy"
+        "#;
+        let config = parse(content).unwrap();
+        assert_eq!(config.exporter.output_dir, "/etc/haproxy/conf.d");
+        assert_eq!(config.exporter.reload.get("haproxy").map(|s| s.as_str()), Some("systemctl reload haproxy"));
+    }
+
+    #[test]
+    fn parse_inline_table_section() {
+        let config = parse("tls = { enable = true, cert = \"a.pem\" }").unwrap();
+        assert!(config.tls.enable);
+        assert_eq!(config.tls.cert, "a.pem");
+        assert_eq!(config.tls.key, "key.pem");
+    }
+
+    #[test]
+    fn parse_rejects_unknown_and_invalid() {
+        assert!(parse("[server]\nunknown = 1").is_err());
+        assert!(parse("[unknown]\na = 1").is_err());
+        assert!(parse("[server]\nport = \"8099\"").is_err());
+        assert!(parse("[server]\nport = 1\nport = 2").is_err());
+        assert!(parse("[server\nport = 1").is_err());
+    }
+
+    #[test]
+    fn check_rejects_zero() {
+        let mut config = ServerConfig::default();
+        assert!(config.check().is_ok());
+        config.retention.state_event_days = 0;
+        assert!(config.check().is_err());
+        let mut config = ServerConfig::default();
+        config.log.level = "verbose".to_string();
+        assert!(config.check().is_err());
+    }
+
+    #[test]
+    fn changed_keys_by_section() {
+        let old = ServerConfig::default();
+        let mut new = old.clone();
+        new.interval.route_ms = 500;
+        new.server.port = 8100;
+        let mut keys = changed_keys(&serde_json::to_value(&old).unwrap(), &serde_json::to_value(&new).unwrap());
+        keys.sort();
+        assert_eq!(keys, vec![("interval".to_string(), "route_ms".to_string()), ("server".to_string(), "port".to_string())]);
+    }
+}
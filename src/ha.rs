@@ -9,12 +9,27 @@ use crate::ha::procotol::{MyProtocol, MysqlState, ReponseErr};
 use crate::storage::opdb::HostInfoValue;
 use std::error::Error;
 use std::net::{TcpStream, SocketAddr, IpAddr, Ipv4Addr};
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use crate::ha::metrics::ServerMetrics;
 
 pub mod procotol;
 pub mod nodes_manager;
 pub mod route_manager;
 pub mod sys_manager;
+pub mod heartbeat;
+pub mod mysql_probe;
+pub mod state_history;
+pub mod route_notify;
+pub mod proxy;
+pub mod mysql_conn;
+pub mod proxysql;
+pub mod dns;
+pub mod exporter;
+pub mod vip;
+pub mod metrics;
+pub mod alert;
+pub mod alert_channel;
+pub mod silence;
 use actix_web::web;
 use std::sync::{mpsc};
 
@@ -37,19 +52,21 @@ struct NodesInfo {
 }
 
 impl NodesInfo {
-    fn set_offline(&mut self, db: &web::Data<DbInfo>, sender: &mpsc::Sender<DownNodeInfo>) -> Result<(), Box<dyn Error>> {
+    fn set_offline(&mut self, db: &DbInfo, sender: &mpsc::Sender<DownNodeInfo>) -> Result<(), Box<dyn Error>> {
         if self.value.online{
             self.value.online = false;
             self.update_value(db)?;
+            state_history::record_online(db, &self.value, false);
             self.send_down_info(sender, false);
         }
         Ok(())
     }
 
-    fn set_online(&mut self, db: &web::Data<DbInfo>, sender: &mpsc::Sender<DownNodeInfo>) -> Result<(), Box<dyn Error>> {
+    fn set_online(&mut self, db: &DbInfo, sender: &mpsc::Sender<DownNodeInfo>) -> Result<(), Box<dyn Error>> {
         if !self.value.online {
             self.value.online = true;
             self.update_value(db)?;
+            state_history::record_online(db, &self.value, true);
             self.send_down_info(sender, true);
         }
         Ok(())
@@ -64,7 +81,7 @@ impl NodesInfo {
         }
     }
 
-    fn update_value(&mut self, db: &web::Data<DbInfo>) -> Result<(), Box<dyn Error>> {
+    fn update_value(&mut self, db: &DbInfo) -> Result<(), Box<dyn Error>> {
         let value = serde_json::to_string(&self.value)?;
         let a = KeyValue{key: (&self.key).parse()?, value: (&value).parse()? };
         db.put(&a, &self.cf_name)?;
@@ -74,11 +91,16 @@ impl NodesInfo {
     ///
     /// 修改当前节点状态数据
     ///
-    fn update_nodes_state(&mut self, db: &web::Data<DbInfo>, nodes_state: &MysqlState) -> Result<(), Box<dyn Error>> {
+    fn update_nodes_state(&mut self, db: &DbInfo, nodes_state: &MysqlState) -> Result<(), Box<dyn Error>> {
         if self.value.online {
 //            let value = serde_json::to_string(nodes_state)?;
 //            let a = KeyValue{key: (&self.key).parse()?, value };
 //            db.put(&a, &CfNameTypeCode::NodesState.get())?;
+            let last = db.get(&self.key, &CfNameTypeCode::NodesState.get())?;
+            if last.value.len() > 0 {
+                let last_state: MysqlState = serde_json::from_str(&last.value)?;
+                state_history::record_state(db, &self.value, &last_state, nodes_state)?;
+            }
             nodes_state.save(db, &self.key)?;
         }
         Ok(())
@@ -94,17 +116,19 @@ struct AllNodes {
 }
 
 impl AllNodes {
-    fn new(db: &web::Data<DbInfo>) -> AllNodes {
+    fn new(db: &DbInfo) -> AllNodes {
         let nodes_info = get_nodes_info(db).unwrap();
         AllNodes{
             info: nodes_info
         }
     }
 
-    fn check_node_state(&mut self, db: &web::Data<DbInfo>, sender: &mpsc::Sender<DownNodeInfo>) {
+    fn check_node_state(&mut self, db: &DbInfo, sender: &mpsc::Sender<DownNodeInfo>, metrics: &web::Data<ServerMetrics>) {
         for nodes in &mut self.info {
             //if !nodes.value.maintain {
-            let state = get_node_state_from_host(&nodes.key);
+            let check_start = Instant::now();
+            let state = get_node_state(db, &nodes.key);
+            metrics.observe_health_check(&nodes.key, check_start.elapsed());
             match state {
                 Ok(v) => {
                     //info!("{:?}", &v);
@@ -138,18 +162,18 @@ impl AllNodes {
 ///
 /// 负责所有节点状态检查及高可用管理操作
 ///
-/// 单循环获取每个节点状态信息，每次循环之间sleep 1秒
+/// 单循环获取每个节点状态信息，每次循环之间sleep interval.health_check_ms， 默认1秒
 ///
 /// 没60次循环之后重新从db中获取所有节点的host信息
 ///
 ///
-pub fn ha_manager(db: web::Data<DbInfo>,  sender: mpsc::Sender<DownNodeInfo>) {
+pub fn ha_manager(db: web::Data<DbInfo>,  sender: mpsc::Sender<DownNodeInfo>, metrics: web::Data<ServerMetrics>) {
     info!("ha manager thread start success");
     let mut start_time = crate::timestamp();
     let mut nodes_info = AllNodes::new(&db);
     //info!("node list: {:?}",nodes_info);
     'all: loop {
-        nodes_info.check_node_state(&db, &sender);
+        nodes_info.check_node_state(&db, &sender, &metrics);
 
         if crate::timestamp() - start_time >= 10000 {
             //每10秒获取一次rocksdb中节点信息
@@ -158,8 +182,8 @@ pub fn ha_manager(db: web::Data<DbInfo>,  sender: mpsc::Sender<DownNodeInfo>) {
             start_time = crate::timestamp();
         }
 
-        let ten_secs = time::Duration::from_secs(1);
-        thread::sleep(ten_secs);
+        let interval = crate::config::get().interval.health_check_ms;
+        thread::sleep(time::Duration::from_millis(interval));
     }
 }
 
@@ -167,7 +191,7 @@ pub fn ha_manager(db: web::Data<DbInfo>,  sender: mpsc::Sender<DownNodeInfo>) {
 ///
 /// 从rocksdb中获取最新的节点信息
 ///
-fn get_nodes_info(db: &web::Data<DbInfo>) -> Result<Vec<NodesInfo>, Box<dyn Error>> {
+fn get_nodes_info(db: &DbInfo) -> Result<Vec<NodesInfo>, Box<dyn Error>> {
     let mut nodes_info: Vec<NodesInfo> = vec![];
     let cf_name = CfNameTypeCode::HaNodesInfo.get();
     let all_nodes_info = db.iterator(&cf_name, &String::from(""))?;
@@ -179,6 +203,18 @@ fn get_nodes_info(db: &web::Data<DbInfo>) -> Result<Vec<NodesInfo>, Box<dyn Erro
     Ok(nodes_info)
 }
 
+///
+/// 心跳未超时的节点使用心跳数据， 从未推送或心跳超时时连接节点获取
+///
+fn get_node_state(db: &DbInfo, host_info: &String) -> Result<MysqlState, Box<dyn Error>> {
+    if let Some(heartbeat) = db.get_heartbeat(host_info)? {
+        if let Ok(state) = heartbeat.check() {
+            return Ok(state);
+        }
+    }
+    get_node_state_from_host(host_info)
+}
+
 ///
 /// 连接节点并接收返回数据，并序列化对应的结构
 /// 返回正确的数据只有两种类型
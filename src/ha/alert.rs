@@ -0,0 +1,658 @@
+/*
+@author: xiao cai niao
+@datetime: 2020/09/16
+*/
+
+//! 报警规则
+//!
+//! 报警线程每秒检查一次， 每条规则按自己的interval评估， 每个(规则, 目标)的状态保存在AlertState中:
+//!     条件成立后为pending， 持续for_secs后变为firing并发送通知， repeat_secs大于0时按间隔重复发送
+//!     条件不再成立或目标被删除时删除状态， 已经firing的发送恢复通知
+//!
+//! 切换相关的报警由nodes_manager写入AlertEvent， 报警线程读取后按规则发送并删除， 没有恢复通知
+//!
+//! 匹配到静默时不发送通知， 静默结束后仍在firing且未通知过的报警立即发送
+//!
+//! 通知由Notifier异步发送， 发送记录先保存为pending， 发送完成后更新结果
+//!
+//! 规则类型:
+//!     node_down           节点离线， 不是只有client宕机
+//!     agent_down          只有client宕机， mysql正常
+//!     lag                 slave延迟大于threshold秒
+//!     repl_stopped        slave的sql或io线程停止
+//!     metric              最新一次监控数据中metric字段与threshold比较， op为> >= < <= == !=
+//!     failover_started    master宕机开始切换
+//!     failover_finished   切换成功
+//!     failover_failed     切换失败
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::{thread, time};
+use actix_web::web;
+use serde::{Serialize, Deserialize};
+use crate::storage::rocks::{DbInfo, PrefixTypeCode, CfNameTypeCode, RowValue};
+use crate::storage::repo::Repo;
+use crate::storage::opdb::HostInfoValue;
+use crate::ha::procotol::{MysqlState, MysqlMonitorStatus};
+use crate::ha::nodes_manager::CheckState;
+use crate::ha::alert_channel::{AlertChannel, Notifier, Finished};
+use crate::ha::silence::{self, Silence};
+
+pub const FAILOVER_STARTED: &str = "failover_started";
+pub const FAILOVER_FINISHED: &str = "failover_finished";
+pub const FAILOVER_FAILED: &str = "failover_failed";
+pub const VIP_UNVERIFIED: &str = "vip_unverified";
+
+const RULE_KINDS: [&str; 9] = ["node_down", "agent_down", "lag", "repl_stopped", "metric",
+    FAILOVER_STARTED, FAILOVER_FINISHED, FAILOVER_FAILED, VIP_UNVERIFIED];
+
+///
+/// 报警规则
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AlertRule {
+    pub name: String,
+    pub kind: String,
+    #[serde(default)]
+    pub clusters: Vec<String>,      //生效的集群， 为空时所有集群
+    #[serde(default)]
+    pub metric: String,             //metric类型使用的监控字段， 如threads_running
+    #[serde(default)]
+    pub op: String,                 //metric类型的比较方式， 为空时为>
+    #[serde(default)]
+    pub threshold: u64,             //lag为秒， metric为监控值
+    #[serde(default)]
+    pub for_secs: u64,              //条件持续多久后报警， 0为立即报警
+    #[serde(default = "default_interval")]
+    pub interval: u64,              //评估间隔秒数
+    #[serde(default)]
+    pub repeat_secs: u64,           //firing期间重复通知的间隔， 0为只通知一次
+    #[serde(default)]
+    pub severity: String,           //报警级别， 原样带到通知中
+    pub channels: Vec<String>,      //通知渠道名称
+    pub enable: bool,
+}
+
+fn default_interval() -> u64 {
+    10
+}
+
+impl AlertRule {
+    pub fn save(&self, db: &DbInfo) -> Result<(), Box<dyn Error>> {
+        db.prefix_put(&PrefixTypeCode::AlertRule, &self.name, &self)?;
+        Ok(())
+    }
+
+    pub fn check(&self) -> Result<(), Box<dyn Error>> {
+        if self.name.len() == 0 {
+            return Err("name can not be empty".into());
+        }
+        if !RULE_KINDS.contains(&self.kind.as_str()) {
+            return Err(format!("invalid rule kind: {}, must be one of {}", &self.kind, RULE_KINDS.join(", ")).into());
+        }
+        if self.kind == "metric" {
+            //采集的变量可按集群或节点配置， 这里只检查不为空， 节点未采集该变量时不评估
+            if self.metric.len() == 0 {
+                return Err("metric can not be empty".into());
+            }
+            compare(&self.op, 0, 0)?;
+        }
+        if self.interval == 0 {
+            return Err("interval must be greater than 0".into());
+        }
+        Ok(())
+    }
+
+    fn match_cluster(&self, cluster_name: &String) -> bool {
+        self.clusters.len() == 0 || self.clusters.contains(cluster_name)
+    }
+
+    fn is_event(&self) -> bool {
+        self.kind.starts_with("failover_") || self.kind == VIP_UNVERIFIED
+    }
+}
+
+fn compare(op: &str, value: u64, threshold: u64) -> Result<bool, Box<dyn Error>> {
+    match op {
+        "" | ">" => Ok(value > threshold),
+        ">=" => Ok(value >= threshold),
+        "<" => Ok(value < threshold),
+        "<=" => Ok(value <= threshold),
+        "==" => Ok(value == threshold),
+        "!=" => Ok(value != threshold),
+        _ => Err(format!("invalid op: {}", op).into())
+    }
+}
+
+///
+/// 规则对某个目标的报警状态
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AlertState {
+    pub rule: String,
+    pub kind: String,
+    pub severity: String,
+    pub cluster_name: String,
+    pub target: String,             //节点host
+    pub status: String,             //pending、firing
+    pub value: u64,
+    pub message: String,
+    pub start_time: i64,            //条件开始成立的时间
+    pub last_notify_time: i64,      //0为还未通知
+    #[serde(default)]
+    pub silence: String,            //当前匹配的静默id
+}
+
+impl AlertState {
+    fn key(rule: &String, target: &String) -> String {
+        format!("{}:{}", rule, target)
+    }
+
+    fn save(&self, db: &DbInfo) -> Result<(), Box<dyn Error>> {
+        db.prefix_put(&PrefixTypeCode::AlertState, &AlertState::key(&self.rule, &self.target), &self)
+    }
+
+    fn delete(&self, db: &DbInfo) -> Result<(), Box<dyn Error>> {
+        let key = format!("{}:{}", PrefixTypeCode::AlertState.prefix(), AlertState::key(&self.rule, &self.target));
+        db.delete(&key, &CfNameTypeCode::SystemData.get())
+    }
+
+    fn notice(&self, status: &str) -> AlertNotice {
+        AlertNotice{
+            rule: self.rule.clone(),
+            kind: self.kind.clone(),
+            severity: self.severity.clone(),
+            status: status.to_string(),
+            cluster_name: self.cluster_name.clone(),
+            target: self.target.clone(),
+            value: self.value,
+            message: self.message.clone(),
+            start_time: self.start_time,
+            time: crate::timestamp()
+        }
+    }
+}
+
+///
+/// 切换过程中产生的事件， 由报警线程消费
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AlertEvent {
+    pub kind: String,
+    pub cluster_name: String,
+    pub host: String,               //宕机的master
+    pub message: String,
+    pub time: i64,
+}
+
+///
+/// 发送到通知渠道的内容， webhook直接POST该结构
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AlertNotice {
+    pub rule: String,
+    pub kind: String,
+    pub severity: String,
+    pub status: String,             //firing、resolved
+    pub cluster_name: String,
+    pub target: String,
+    pub value: u64,
+    pub message: String,
+    pub start_time: i64,
+    pub time: i64,
+}
+
+impl AlertNotice {
+    pub fn title(&self) -> String {
+        format!("[{}][{}] {} {} {}", self.status.to_uppercase(), &self.severity, &self.rule, &self.cluster_name, &self.target)
+    }
+
+    pub fn text(&self) -> String {
+        format!("{}\n集群: {}\n节点: {}\n信息: {}\n开始时间: {}\n通知时间: {}",
+                self.title(), &self.cluster_name, &self.target, &self.message,
+                format_time(self.start_time), format_time(self.time))
+    }
+}
+
+///
+/// 通知发送记录
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AlertHistory {
+    pub notice: AlertNotice,
+    pub results: Vec<ChannelResult>,
+    #[serde(default)]
+    pub silence: String,            //被静默时为静默id， 此时没有发送
+}
+
+impl AlertHistory {
+    fn key(&self) -> String {
+        format!("{:013}_{}_{}", self.notice.time, &self.notice.rule, &self.notice.target)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ChannelResult {
+    pub channel: String,
+    pub success: bool,
+    pub error: String,
+    #[serde(default)]
+    pub pending: bool,              //已放入发送队列， 还没有结果
+}
+
+impl ChannelResult {
+    pub fn pending(channel: &String) -> ChannelResult {
+        ChannelResult{ channel: channel.clone(), success: false, error: "".to_string(), pending: true }
+    }
+
+    pub fn success(channel: &String) -> ChannelResult {
+        ChannelResult{ channel: channel.clone(), success: true, error: "".to_string(), pending: false }
+    }
+
+    pub fn failed(channel: &String, error: String) -> ChannelResult {
+        ChannelResult{ channel: channel.clone(), success: false, error, pending: false }
+    }
+}
+
+///
+/// 毫秒时间戳格式化为UTC时间
+fn format_time(ms: i64) -> String {
+    let secs = ms.div_euclid(1000);
+    let days = secs.div_euclid(86400);
+    let rem = secs.rem_euclid(86400);
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC", year, month, day, rem / 3600, rem % 3600 / 60, rem % 60)
+}
+
+impl DbInfo {
+    ///
+    /// 获取所有报警规则
+    pub fn get_alert_rule(&self) -> Result<Vec<RowValue<AlertRule>>, Box<dyn Error>> {
+        Repo::<AlertRule>::new(self).all()
+    }
+
+    ///
+    /// 删除报警规则及其状态
+    pub fn delete_alert_rule(&self, name: &String) -> Result<(), Box<dyn Error>> {
+        Repo::<AlertRule>::new(self).delete(name)?;
+        for state in self.get_alert_state()? {
+            if &state.rule == name {
+                state.delete(self)?;
+            }
+        }
+        Ok(())
+    }
+
+    ///
+    /// 获取所有pending及firing的报警
+    pub fn get_alert_state(&self) -> Result<Vec<AlertState>, Box<dyn Error>> {
+        let prefix = format!("{}:", PrefixTypeCode::AlertState.prefix());
+        let mut states = vec![];
+        for kv in self.prefix_iterator(&prefix, &CfNameTypeCode::SystemData.get())? {
+            if !kv.key.starts_with(&prefix) || kv.value.len() == 0 {continue;}
+            let value: AlertState = serde_json::from_str(&kv.value)?;
+            states.push(value);
+        }
+        Ok(states)
+    }
+
+    ///
+    /// 写入切换事件
+    pub fn push_alert_event(&self, event: &AlertEvent) -> Result<(), Box<dyn Error>> {
+        self.prefix_put(&PrefixTypeCode::AlertEvent, &format!("{:013}_{}", event.time, &event.host), event)
+    }
+
+    ///
+    /// 获取时间范围内的通知记录
+    pub fn get_alert_history(&self, start: i64, stop: i64) -> Result<Vec<AlertHistory>, Box<dyn Error>> {
+        let prefix = format!("{}:", PrefixTypeCode::AlertHistory.prefix());
+        let start_key = format!("{}{:013}", &prefix, start);
+        let stop_key = format!("{}{:013}", &prefix, stop);
+        let mut history = vec![];
+        for kv in self.storage.range(&CfNameTypeCode::SystemData.get(), &start_key, &stop_key)? {
+            if kv.value.len() == 0 {continue;}
+            let value: AlertHistory = serde_json::from_str(&kv.value)?;
+            history.push(value);
+        }
+        Ok(history)
+    }
+
+    ///
+    /// 删除过期的通知记录
+    pub fn expired_alert_history(&self, days: u64) -> Result<(), Box<dyn Error>> {
+        let prefix = format!("{}:", PrefixTypeCode::AlertHistory.prefix());
+        let expire = crate::timestamp() - days as i64 * 24 * 3600 * 1000;
+        self.storage.delete_range(&CfNameTypeCode::SystemData.get(), &prefix, &format!("{}{:013}", &prefix, expire))
+    }
+}
+
+///
+/// 记录切换及vip漂移事件， 失败只记录日志， 不影响切换流程
+pub fn push_event(db: &DbInfo, kind: &str, cluster_name: &String, host: &String, message: String) {
+    let event = AlertEvent{
+        kind: kind.to_string(),
+        cluster_name: cluster_name.clone(),
+        host: host.clone(),
+        message,
+        time: crate::timestamp()
+    };
+    if let Err(e) = db.push_alert_event(&event) {
+        info!("save alert event {:?} failed: {}", &event, e.to_string());
+    }
+}
+
+///
+/// 报警线程
+pub fn manager(db: web::Data<DbInfo>) {
+    info!("alert thread start success");
+    let mut last_eval: HashMap<String, i64> = HashMap::new();
+    let mut notifier = Notifier::new();
+    loop {
+        if let Err(e) = check_alert(&db, &mut last_eval, &mut notifier) {
+            info!("check alert failed: {}", e.to_string());
+        }
+        thread::sleep(time::Duration::from_secs(1));
+    }
+}
+
+fn check_alert(db: &DbInfo, last_eval: &mut HashMap<String, i64>, notifier: &mut Notifier) -> Result<(), Box<dyn Error>> {
+    for finished in notifier.finished() {
+        update_history(db, finished)?;
+    }
+    let rules: Vec<AlertRule> = db.get_alert_rule()?.into_iter().map(|r| r.value).collect();
+    let channels: Vec<AlertChannel> = db.get_alert_channel()?.into_iter().map(|r| r.value).collect();
+    notifier.set_channels(channels);
+    let silences = db.get_silences(false)?;
+    process_events(db, &rules, notifier, &silences)?;
+
+    let states = db.get_alert_state()?;
+    //规则被关闭或删除后直接清除状态， 不发送恢复通知
+    for state in &states {
+        if !rules.iter().any(|r| r.name == state.rule && r.enable) {
+            state.delete(db)?;
+        }
+    }
+    let now = crate::timestamp();
+    for rule in &rules {
+        if !rule.enable || rule.is_event() {continue;}
+        if let Some(t) = last_eval.get(&rule.name) {
+            if now - t < rule.interval as i64 * 1000 {continue;}
+        }
+        last_eval.insert(rule.name.clone(), now);
+        let rule_states: Vec<&AlertState> = states.iter().filter(|s| s.rule == rule.name).collect();
+        if let Err(e) = evaluate_rule(db, rule, &rule_states, notifier, &silences) {
+            info!("evaluate alert rule {} failed: {}", &rule.name, e.to_string());
+        }
+    }
+    Ok(())
+}
+
+///
+/// 规则在某个目标上的评估结果
+struct Check {
+    cluster_name: String,
+    target: String,
+    active: bool,
+    value: u64,
+    message: String,
+}
+
+fn evaluate_rule(db: &DbInfo, rule: &AlertRule, states: &Vec<&AlertState>, notifier: &mut Notifier, silences: &Vec<Silence>) -> Result<(), Box<dyn Error>> {
+    let checks = evaluate(db, rule)?;
+    let now = crate::timestamp();
+    for check in &checks {
+        let old = states.iter().find(|s| s.target == check.target);
+        if !check.active {
+            if let Some(state) = old {
+                resolve(db, rule, state, notifier, silences)?;
+            }
+            continue;
+        }
+        let mut state = match old {
+            Some(s) => (*s).clone(),
+            None => AlertState{
+                rule: rule.name.clone(),
+                kind: rule.kind.clone(),
+                severity: rule.severity.clone(),
+                cluster_name: check.cluster_name.clone(),
+                target: check.target.clone(),
+                status: "pending".to_string(),
+                value: 0,
+                message: "".to_string(),
+                start_time: now,
+                last_notify_time: 0,
+                silence: "".to_string()
+            }
+        };
+        state.value = check.value;
+        state.message = check.message.clone();
+        state.silence = silence::find(silences, &state.cluster_name, &state.target, &rule.kind, now)
+            .map(|s| s.id.clone()).unwrap_or_default();
+        if state.status == "pending" && now - state.start_time >= rule.for_secs as i64 * 1000 {
+            state.status = "firing".to_string();
+        }
+        if state.status == "firing" && state.silence.len() == 0 {
+            let repeat = rule.repeat_secs > 0 && now - state.last_notify_time >= rule.repeat_secs as i64 * 1000;
+            if state.last_notify_time == 0 || repeat {
+                state.last_notify_time = now;
+                notify(db, rule, &state.notice("firing"), notifier)?;
+            }
+        }
+        state.save(db)?;
+    }
+    //目标已不存在， 如节点被删除或监控已关闭
+    for state in states {
+        if !checks.iter().any(|c| c.target == state.target) {
+            resolve(db, rule, state, notifier, silences)?;
+        }
+    }
+    Ok(())
+}
+
+fn resolve(db: &DbInfo, rule: &AlertRule, state: &AlertState, notifier: &mut Notifier, silences: &Vec<Silence>) -> Result<(), Box<dyn Error>> {
+    let silenced = silence::find(silences, &state.cluster_name, &state.target, &rule.kind, crate::timestamp()).is_some();
+    if state.status == "firing" && state.last_notify_time > 0 && !silenced {
+        notify(db, rule, &state.notice("resolved"), notifier)?;
+    }
+    state.delete(db)
+}
+
+///
+/// 评估规则， 返回所有目标的结果
+fn evaluate(db: &DbInfo, rule: &AlertRule) -> Result<Vec<Check>, Box<dyn Error>> {
+    let nodes: Vec<HostInfoValue> = Repo::<HostInfoValue>::new(db).all()?.into_iter()
+        .map(|r| r.value)
+        .filter(|n| rule.match_cluster(&n.cluster_name))
+        .collect();
+    let mut checks = vec![];
+    for node in &nodes {
+        //维护模式的节点不报警
+        if node.maintain {continue;}
+        let mut check = Check{ cluster_name: node.cluster_name.clone(), target: node.host.clone(), active: false, value: 0, message: "".to_string() };
+        match rule.kind.as_str() {
+            "node_down" | "agent_down" => {
+                //切换线程检查完成前没有CheckState， 视为节点宕机
+                let agent_only = match Repo::<CheckState>::new(db).get(&node.host)? {
+                    Some(c) => c.client_down && !c.db_down,
+                    None => false
+                };
+                let down = !node.online && (agent_only == (rule.kind == "agent_down"));
+                check.active = down;
+                if down {
+                    check.message = match agent_only {
+                        true => format!("client of {} is down, mysql is running", &node.host),
+                        false => format!("node {} is offline", &node.host)
+                    };
+                }
+            }
+            "lag" | "repl_stopped" => {
+                if !node.online {continue;}
+                let state = match Repo::<MysqlState>::new(db).get(&node.host)? {
+                    Some(s) => s,
+                    None => continue
+                };
+                if state.role != "slave" {continue;}
+                if rule.kind == "lag" {
+                    check.value = state.seconds_behind as u64;
+                    check.active = check.value > rule.threshold;
+                    check.message = format!("seconds behind master is {}, threshold {}", check.value, rule.threshold);
+                } else {
+                    check.active = !state.sql_thread || !state.io_thread;
+                    check.message = format!("sql_thread: {}, io_thread: {}, last_sql_error: {}, last_io_error: {}",
+                                            state.sql_thread, state.io_thread, &state.last_sql_error, &state.last_io_error);
+                }
+            }
+            "metric" => {
+                let result = db.prefix_get(&PrefixTypeCode::NodeMonitorLast, &node.host)?;
+                if result.value.len() == 0 {continue;}
+                let value: MysqlMonitorStatus = serde_json::from_str(&result.value)?;
+                //超过5个监控周期没有新数据时不再评估
+                let stale = crate::config::get().interval.monitor_secs as i64 * 5000;
+                if crate::timestamp() - value.time > stale {continue;}
+                let v = match value.values.get(&rule.metric) {
+                    Some(v) => *v,
+                    None => continue
+                };
+                let op = if rule.op.len() == 0 { ">" } else { rule.op.as_str() };
+                check.value = v;
+                check.active = compare(op, v, rule.threshold)?;
+                check.message = format!("{} is {}, threshold {} {}", &rule.metric, v, op, rule.threshold);
+            }
+            _ => continue
+        }
+        checks.push(check);
+    }
+    Ok(checks)
+}
+
+///
+/// 发送切换事件， 发送后删除
+fn process_events(db: &DbInfo, rules: &Vec<AlertRule>, notifier: &mut Notifier, silences: &Vec<Silence>) -> Result<(), Box<dyn Error>> {
+    let prefix = format!("{}:", PrefixTypeCode::AlertEvent.prefix());
+    let cf_name = CfNameTypeCode::SystemData.get();
+    for kv in db.prefix_iterator(&prefix, &cf_name)? {
+        if !kv.key.starts_with(&prefix) {continue;}
+        if kv.value.len() > 0 {
+            let event: AlertEvent = serde_json::from_str(&kv.value)?;
+            for rule in rules {
+                if !rule.enable || rule.kind != event.kind || !rule.match_cluster(&event.cluster_name) {continue;}
+                let notice = AlertNotice{
+                    rule: rule.name.clone(),
+                    kind: rule.kind.clone(),
+                    severity: rule.severity.clone(),
+                    status: "firing".to_string(),
+                    cluster_name: event.cluster_name.clone(),
+                    target: event.host.clone(),
+                    value: 0,
+                    message: event.message.clone(),
+                    start_time: event.time,
+                    time: crate::timestamp()
+                };
+                match silence::find(silences, &event.cluster_name, &event.host, &rule.kind, notice.time) {
+                    Some(s) => {
+                        info!("alert {} is silenced by {}", notice.title(), &s.id);
+                        save_history(db, &AlertHistory{ notice, results: vec![], silence: s.id.clone() })?;
+                    }
+                    None => {
+                        notify(db, rule, &notice, notifier)?;
+                    }
+                }
+            }
+        }
+        db.delete(&kv.key, &cf_name)?;
+    }
+    Ok(())
+}
+
+///
+/// 放入规则所有渠道的发送队列并记录， 发送结果由update_history更新
+fn notify(db: &DbInfo, rule: &AlertRule, notice: &AlertNotice, notifier: &mut Notifier) -> Result<(), Box<dyn Error>> {
+    info!("alert {}", notice.title());
+    let mut history = AlertHistory{ notice: notice.clone(), results: vec![], silence: "".to_string() };
+    history.results = notifier.send(&history.key(), notice, &rule.channels);
+    save_history(db, &history)
+}
+
+fn save_history(db: &DbInfo, history: &AlertHistory) -> Result<(), Box<dyn Error>> {
+    db.prefix_put(&PrefixTypeCode::AlertHistory, &history.key(), history)
+}
+
+///
+/// 用发送结果替换发送记录中对应渠道的pending结果， 记录已被清理时忽略
+fn update_history(db: &DbInfo, finished: Finished) -> Result<(), Box<dyn Error>> {
+    let (key, result) = finished;
+    let kv = db.prefix_get(&PrefixTypeCode::AlertHistory, &key)?;
+    if kv.value.len() == 0 {
+        return Ok(());
+    }
+    let mut history: AlertHistory = serde_json::from_str(&kv.value)?;
+    if let Some(r) = history.results.iter_mut().find(|r| r.channel == result.channel && r.pending) {
+        *r = result;
+        save_history(db, &history)?;
+    }
+    Ok(())
+}
+
+///
+/// 发送测试通知
+pub fn test_channel(channel: &AlertChannel) -> Result<(), Box<dyn Error>> {
+    let now = crate::timestamp();
+    let notice = AlertNotice{
+        rule: "test".to_string(),
+        kind: "test".to_string(),
+        severity: "info".to_string(),
+        status: "firing".to_string(),
+        cluster_name: "".to_string(),
+        target: "".to_string(),
+        value: 0,
+        message: format!("test notification of channel {}", &channel.name),
+        start_time: now,
+        time: now
+    };
+    channel.send(&notice)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn notice(time: i64) -> AlertNotice {
+        AlertNotice{ rule: "r1".to_string(), kind: "lag".to_string(), severity: "warning".to_string(),
+            status: "firing".to_string(), cluster_name: "c1".to_string(), target: "127.0.0.1:9011".to_string(),
+            value: 100, message: "lag".to_string(), start_time: time, time }
+    }
+
+    #[test]
+    fn update_pending_history() {
+        let db = DbInfo::memory();
+        let c1 = "c1".to_string();
+        let c2 = "c2".to_string();
+        let history = AlertHistory{ notice: notice(1000), results: vec![ChannelResult::pending(&c1), ChannelResult::pending(&c2)],
+            silence: "".to_string() };
+        save_history(&db, &history).unwrap();
+        update_history(&db, (history.key(), ChannelResult::failed(&c2, "timeout".to_string()))).unwrap();
+        //已被清理的记录忽略
+        update_history(&db, ("0000000000000_r1_x".to_string(), ChannelResult::success(&c1))).unwrap();
+
+        let all = db.get_alert_history(0, 2000).unwrap();
+        assert_eq!(all.len(), 1);
+        assert_eq!(all[0].results, vec![ChannelResult::pending(&c1), ChannelResult::failed(&c2, "timeout".to_string())]);
+    }
+
+    #[test]
+    fn compare_op() {
+        assert!(compare(">", 2, 1).unwrap());
+        assert!(!compare("<=", 2, 1).unwrap());
+        assert!(compare("!=", 2, 1).unwrap());
+        assert!(compare("=>", 2, 1).is_err());
+    }
+
+    #[test]
+    fn format_utc_time() {
+        assert_eq!(format_time(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(format_time(1600000000000), "2020-09-13 12:26:40 UTC");
+    }
+}
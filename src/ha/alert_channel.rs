@@ -0,0 +1,595 @@
+/*
+@author: xiao cai niao
+@datetime: 2020/09/16
+*/
+
+//! 报警通知渠道
+//!
+//! webhook     POST通知的json到url
+//! dingtalk    钉钉机器人， 配置secret时按加签方式在url后追加timestamp及sign
+//! wecom       企业微信机器人
+//! email       SMTP发送， smtp_security为none、ssl(465端口直接TLS)或starttls
+//!
+//! http及smtp都直接使用TcpStream， https通过openssl建立连接， 每次发送都是短连接
+//!
+//! 报警线程通过Notifier发送， 每个渠道一个发送线程及有界队列， 一个渠道无响应不影响其他渠道及报警评估，
+//! 发送结果由报警线程取回后更新到发送记录中， 渠道空闲一段时间后发送线程退出
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, Sender, Receiver, SyncSender, TrySendError, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+use openssl::ssl::{SslConnector, SslMethod, SslStream};
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use serde::{Serialize, Deserialize};
+use serde_json::json;
+use crate::storage::rocks::{DbInfo, PrefixTypeCode, RowValue};
+use crate::storage::repo::Repo;
+use crate::ha::alert::{AlertNotice, ChannelResult};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+///
+/// 每个渠道最多排队的通知数， 超过时直接记录为发送失败
+const QUEUE_SIZE: usize = 100;
+
+///
+/// 渠道发送线程空闲超过该时间退出， 有新通知时重新启动
+const WORKER_IDLE: Duration = Duration::from_secs(300);
+
+///
+/// 报警通知渠道配置
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AlertChannel {
+    pub name: String,
+    pub kind: String,               //webhook、dingtalk、wecom、email
+    #[serde(default)]
+    pub url: String,                //webhook、dingtalk、wecom的地址
+    #[serde(default)]
+    pub secret: String,             //钉钉加签密钥， 为空时不加签
+    #[serde(default)]
+    pub smtp_host: String,
+    #[serde(default)]
+    pub smtp_port: u16,
+    #[serde(default)]
+    pub smtp_security: String,      //none、ssl、starttls， 为空时为none
+    #[serde(default)]
+    pub username: String,           //smtp认证用户， 为空时不认证
+    #[serde(default)]
+    pub password: String,
+    #[serde(default)]
+    pub from: String,
+    #[serde(default)]
+    pub to: Vec<String>,
+    pub enable: bool,
+}
+
+impl AlertChannel {
+    pub fn save(&self, db: &DbInfo) -> Result<(), Box<dyn Error>> {
+        db.prefix_put(&PrefixTypeCode::AlertChannel, &self.name, &self)?;
+        Ok(())
+    }
+
+    ///
+    /// 查询时不返回password及secret， 保存时为空则沿用已保存的值， 需要清除时删除渠道后重新添加
+    pub fn keep_secret(&mut self, old: &AlertChannel) {
+        if self.password.len() == 0 {
+            self.password = old.password.clone();
+        }
+        if self.secret.len() == 0 {
+            self.secret = old.secret.clone();
+        }
+    }
+
+    pub fn check(&self) -> Result<(), Box<dyn Error>> {
+        if self.name.len() == 0 {
+            return Err("name can not be empty".into());
+        }
+        match self.kind.as_str() {
+            "webhook" | "dingtalk" | "wecom" => {
+                Url::parse(&self.url)?;
+            }
+            "email" => {
+                if self.smtp_host.len() == 0 || self.smtp_port == 0 || self.from.len() == 0 || self.to.len() == 0 {
+                    return Err("smtp_host, smtp_port, from and to can not be empty".into());
+                }
+                match self.smtp_security.as_str() {
+                    "" | "none" | "ssl" | "starttls" => {}
+                    s => return Err(format!("invalid smtp_security: {}", s).into())
+                }
+            }
+            k => return Err(format!("invalid channel kind: {}", k).into())
+        }
+        Ok(())
+    }
+
+    ///
+    /// 发送一条通知
+    pub fn send(&self, notice: &AlertNotice) -> Result<(), Box<dyn Error>> {
+        match self.kind.as_str() {
+            "webhook" => {
+                http_post(&self.url, &serde_json::to_string(notice)?)
+            }
+            "dingtalk" => {
+                let body = json!({"msgtype": "text", "text": {"content": notice.text()}});
+                http_post(&self.dingtalk_url()?, &body.to_string())
+            }
+            "wecom" => {
+                let body = json!({"msgtype": "text", "text": {"content": notice.text()}});
+                http_post(&self.url, &body.to_string())
+            }
+            "email" => {
+                self.send_mail(&notice.title(), &notice.text())
+            }
+            k => Err(format!("invalid channel kind: {}", k).into())
+        }
+    }
+
+    ///
+    /// 钉钉加签: base64(hmac_sha256(secret, "{timestamp}\n{secret}"))
+    fn dingtalk_url(&self) -> Result<String, Box<dyn Error>> {
+        if self.secret.len() == 0 {
+            return Ok(self.url.clone());
+        }
+        let timestamp = crate::timestamp();
+        let key = PKey::hmac(self.secret.as_bytes())?;
+        let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+        signer.update(format!("{}\n{}", timestamp, &self.secret).as_bytes())?;
+        let sign = base64::encode(&signer.sign_to_vec()?);
+        let sep = if self.url.contains('?') { "&" } else { "?" };
+        Ok(format!("{}{}timestamp={}&sign={}", &self.url, sep, timestamp, url_encode(&sign)))
+    }
+
+    fn send_mail(&self, subject: &str, text: &str) -> Result<(), Box<dyn Error>> {
+        let tcp = connect(&self.smtp_host, self.smtp_port)?;
+        let mut conn = match self.smtp_security.as_str() {
+            "ssl" => Conn::Tls(tls_connect(&self.smtp_host, tcp)?),
+            _ => Conn::Plain(tcp)
+        };
+        smtp_reply(&mut conn, 220)?;
+        smtp_cmd(&mut conn, "EHLO mymha", 250)?;
+        if self.smtp_security == "starttls" {
+            smtp_cmd(&mut conn, "STARTTLS", 220)?;
+            conn = match conn {
+                Conn::Plain(tcp) => Conn::Tls(tls_connect(&self.smtp_host, tcp)?),
+                tls => tls
+            };
+            smtp_cmd(&mut conn, "EHLO mymha", 250)?;
+        }
+        if self.username.len() > 0 {
+            smtp_cmd(&mut conn, "AUTH LOGIN", 334)?;
+            smtp_cmd(&mut conn, &base64::encode(&self.username), 334)?;
+            smtp_cmd(&mut conn, &base64::encode(&self.password), 235)?;
+        }
+        smtp_cmd(&mut conn, &format!("MAIL FROM:<{}>", &self.from), 250)?;
+        for to in &self.to {
+            smtp_cmd(&mut conn, &format!("RCPT TO:<{}>", to), 250)?;
+        }
+        smtp_cmd(&mut conn, "DATA", 354)?;
+        let message = format!("From: {}\r\nTo: {}\r\nSubject: =?UTF-8?B?{}?=\r\nMIME-Version: 1.0\r\n\
+            Content-Type: text/plain; charset=UTF-8\r\nContent-Transfer-Encoding: base64\r\n\r\n{}\r\n.",
+            &self.from, self.to.join(", "), base64::encode(subject), wrap_base64(&base64::encode(text)));
+        smtp_cmd(&mut conn, &message, 250)?;
+        let _ = smtp_cmd(&mut conn, "QUIT", 221);
+        Ok(())
+    }
+}
+
+impl DbInfo {
+    ///
+    /// 获取所有报警通知渠道
+    pub fn get_alert_channel(&self) -> Result<Vec<RowValue<AlertChannel>>, Box<dyn Error>> {
+        Repo::<AlertChannel>::new(self).all()
+    }
+}
+
+///
+/// 一条待发送的通知， history为发送记录的key
+struct Job {
+    history: String,
+    channel: AlertChannel,
+    notice: AlertNotice,
+}
+
+///
+/// 发送完成的结果， (发送记录的key, 渠道结果)
+pub type Finished = (String, ChannelResult);
+
+///
+/// 报警通知的发送队列， 由报警线程持有
+pub struct Notifier {
+    channels: Vec<AlertChannel>,
+    workers: HashMap<String, SyncSender<Job>>,
+    tx: Sender<Finished>,
+    rx: Receiver<Finished>,
+}
+
+impl Notifier {
+    pub fn new() -> Notifier {
+        let (tx, rx) = mpsc::channel();
+        Notifier{ channels: vec![], workers: HashMap::new(), tx, rx }
+    }
+
+    ///
+    /// 更新渠道配置， 之后放入队列的通知使用新配置发送
+    pub fn set_channels(&mut self, channels: Vec<AlertChannel>) {
+        self.channels = channels;
+    }
+
+    ///
+    /// 放入各渠道的发送队列， 返回每个渠道的初始结果， 成功放入队列的为pending
+    pub fn send(&mut self, history: &String, notice: &AlertNotice, names: &Vec<String>) -> Vec<ChannelResult> {
+        let mut results = vec![];
+        for name in names {
+            let channel = match self.channels.iter().find(|c| &c.name == name) {
+                Some(c) => c.clone(),
+                None => {
+                    results.push(ChannelResult::failed(name, "channel not found".to_string()));
+                    continue;
+                }
+            };
+            if !channel.enable {continue;}
+            let job = Job{ history: history.clone(), channel, notice: notice.clone() };
+            match self.push(name, job) {
+                Ok(()) => results.push(ChannelResult::pending(name)),
+                Err(e) => {
+                    info!("send alert {} to channel {} failed: {}", &notice.rule, name, &e);
+                    results.push(ChannelResult::failed(name, e));
+                }
+            }
+        }
+        results
+    }
+
+    fn push(&mut self, name: &String, job: Job) -> Result<(), String> {
+        let job = match self.workers.get(name) {
+            Some(worker) => match worker.try_send(job) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Full(_)) => return Err("send queue is full".to_string()),
+                //发送线程已经空闲退出
+                Err(TrySendError::Disconnected(job)) => job
+            }
+            None => job
+        };
+        let worker = self.spawn(name);
+        let state = worker.try_send(job).map_err(|_| "send queue is unavailable".to_string());
+        self.workers.insert(name.clone(), worker);
+        state
+    }
+
+    fn spawn(&self, name: &String) -> SyncSender<Job> {
+        let (job_tx, job_rx) = mpsc::sync_channel::<Job>(QUEUE_SIZE);
+        let tx = self.tx.clone();
+        let name = name.clone();
+        thread::spawn(move || {
+            loop {
+                let job = match job_rx.recv_timeout(WORKER_IDLE) {
+                    Ok(job) => job,
+                    Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => break
+                };
+                let result = match job.channel.send(&job.notice) {
+                    Ok(()) => ChannelResult::success(&name),
+                    Err(e) => {
+                        info!("send alert {} to channel {} failed: {}", &job.notice.rule, &name, e.to_string());
+                        ChannelResult::failed(&name, e.to_string())
+                    }
+                };
+                if tx.send((job.history, result)).is_err() {
+                    break;
+                }
+            }
+        });
+        job_tx
+    }
+
+    ///
+    /// 取回已经发送完成的结果
+    pub fn finished(&self) -> Vec<Finished> {
+        self.rx.try_iter().collect()
+    }
+}
+
+enum Conn {
+    Plain(TcpStream),
+    Tls(SslStream<TcpStream>),
+}
+
+impl Read for Conn {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Conn::Plain(s) => s.read(buf),
+            Conn::Tls(s) => s.read(buf)
+        }
+    }
+}
+
+impl Write for Conn {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Conn::Plain(s) => s.write(buf),
+            Conn::Tls(s) => s.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Conn::Plain(s) => s.flush(),
+            Conn::Tls(s) => s.flush()
+        }
+    }
+}
+
+fn connect(host: &str, port: u16) -> Result<TcpStream, Box<dyn Error>> {
+    let addr = (host, port).to_socket_addrs()?.next().ok_or(format!("can not resolve {}", host))?;
+    let tcp = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+    tcp.set_read_timeout(Some(TIMEOUT))?;
+    tcp.set_write_timeout(Some(TIMEOUT))?;
+    Ok(tcp)
+}
+
+fn tls_connect(host: &str, tcp: TcpStream) -> Result<SslStream<TcpStream>, Box<dyn Error>> {
+    let connector = SslConnector::builder(SslMethod::tls())?.build();
+    connector.connect(host, tcp).map_err(|e| format!("tls handshake with {} failed: {}", host, e).into())
+}
+
+///
+/// http(s)地址
+struct Url {
+    tls: bool,
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl Url {
+    fn parse(url: &str) -> Result<Url, Box<dyn Error>> {
+        let (tls, rest) = if url.starts_with("https://") {
+            (true, &url[8..])
+        } else if url.starts_with("http://") {
+            (false, &url[7..])
+        } else {
+            return Err(format!("invalid url: {}", url).into());
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/")
+        };
+        let (host, port) = match authority.rfind(':') {
+            Some(i) => (&authority[..i], authority[i + 1..].parse::<u16>().map_err(|_| format!("invalid url: {}", url))?),
+            None => (authority, if tls { 443 } else { 80 })
+        };
+        if host.len() == 0 {
+            return Err(format!("invalid url: {}", url).into());
+        }
+        Ok(Url{ tls, host: host.to_string(), port, path: path.to_string() })
+    }
+}
+
+///
+/// POST json， 返回2xx之外的状态码视为失败
+fn http_post(url: &str, body: &str) -> Result<(), Box<dyn Error>> {
+    let url = Url::parse(url)?;
+    let tcp = connect(&url.host, url.port)?;
+    let mut conn = if url.tls { Conn::Tls(tls_connect(&url.host, tcp)?) } else { Conn::Plain(tcp) };
+    let request = format!("POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json; charset=utf-8\r\n\
+        Content-Length: {}\r\nConnection: close\r\n\r\n{}", &url.path, &url.host, body.len(), body);
+    conn.write_all(request.as_bytes())?;
+    conn.flush()?;
+    let status = read_line(&mut conn)?;
+    let code = status.split_whitespace().nth(1).and_then(|c| c.parse::<u16>().ok())
+        .ok_or(format!("invalid http response: {}", &status))?;
+    if code < 200 || code >= 300 {
+        return Err(format!("http status {}", code).into());
+    }
+    Ok(())
+}
+
+fn read_line<R: Read>(conn: &mut R) -> Result<String, Box<dyn Error>> {
+    let mut line = vec![];
+    let mut buf = [0u8; 1];
+    loop {
+        if conn.read(&mut buf)? == 0 {
+            break;
+        }
+        if buf[0] == b'\n' {
+            break;
+        }
+        line.push(buf[0]);
+    }
+    if line.len() == 0 {
+        return Err("connection closed".into());
+    }
+    Ok(String::from_utf8_lossy(&line).trim_end().to_string())
+}
+
+///
+/// 读取smtp响应， 多行响应以"250-"开头， 最后一行为"250 "
+fn smtp_reply<R: Read>(conn: &mut R, code: u16) -> Result<(), Box<dyn Error>> {
+    loop {
+        let line = read_line(conn)?;
+        //按字节比较， 返回中可能有多字节字符
+        let ok = line.get(..3).and_then(|c| c.parse::<u16>().ok()).map(|c| c == code || (code == 250 && c == 251)).unwrap_or(false);
+        if !ok {
+            return Err(format!("smtp error: {}", line).into());
+        }
+        if line.len() == 3 || line.as_bytes().get(3) == Some(&b' ') {
+            return Ok(());
+        }
+    }
+}
+
+fn smtp_cmd<S: Read + Write>(conn: &mut S, cmd: &str, code: u16) -> Result<(), Box<dyn Error>> {
+    conn.write_all(format!("{}\r\n", cmd).as_bytes())?;
+    conn.flush()?;
+    smtp_reply(conn, code)
+}
+
+fn wrap_base64(s: &str) -> String {
+    let lines: Vec<&str> = s.as_bytes().chunks(76).map(|c| std::str::from_utf8(c).unwrap_or("")).collect();
+    lines.join("\r\n")
+}
+
+fn url_encode(s: &str) -> String {
+    let mut out = String::new();
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => out.push(b as char),
+            _ => out.push_str(&format!("%{:02X}", b))
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::net::TcpListener;
+
+    ///
+    /// 读取预设的响应， 记录写入的内容
+    struct MockConn {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl MockConn {
+        fn new(input: &str) -> MockConn {
+            MockConn{ input: Cursor::new(input.as_bytes().to_vec()), output: vec![] }
+        }
+    }
+
+    impl Read for MockConn {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for MockConn {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn webhook(name: &str, url: &str) -> AlertChannel {
+        AlertChannel{ name: name.to_string(), kind: "webhook".to_string(), url: url.to_string(), secret: "".to_string(),
+            smtp_host: "".to_string(), smtp_port: 0, smtp_security: "".to_string(), username: "".to_string(),
+            password: "".to_string(), from: "".to_string(), to: vec![], enable: true }
+    }
+
+    fn notice() -> AlertNotice {
+        AlertNotice{ rule: "r1".to_string(), kind: "lag".to_string(), severity: "warning".to_string(),
+            status: "firing".to_string(), cluster_name: "c1".to_string(), target: "127.0.0.1:9011".to_string(),
+            value: 100, message: "lag".to_string(), start_time: 0, time: 0 }
+    }
+
+    #[test]
+    fn parse_url() {
+        let url = Url::parse("https://oapi.dingtalk.com/robot/send?access_token=xxx").unwrap();
+        assert!(url.tls);
+        assert_eq!(url.host, "oapi.dingtalk.com");
+        assert_eq!(url.port, 443);
+        assert_eq!(url.path, "/robot/send?access_token=xxx");
+
+        let url = Url::parse("http://127.0.0.1:8080").unwrap();
+        assert!(!url.tls);
+        assert_eq!(url.host, "127.0.0.1");
+        assert_eq!(url.port, 8080);
+        assert_eq!(url.path, "/");
+
+        assert!(Url::parse("ftp://127.0.0.1/").is_err());
+        assert!(Url::parse("http://:80/").is_err());
+        assert!(Url::parse("http://127.0.0.1:port/").is_err());
+    }
+
+    #[test]
+    fn read_lines() {
+        let mut conn = MockConn::new("HTTP/1.1 200 OK\r\nServer: test\n");
+        assert_eq!(read_line(&mut conn).unwrap(), "HTTP/1.1 200 OK");
+        assert_eq!(read_line(&mut conn).unwrap(), "Server: test");
+        assert!(read_line(&mut conn).is_err());
+    }
+
+    #[test]
+    fn smtp_replies() {
+        let mut conn = MockConn::new("250-smtp.example.com\r\n250-AUTH LOGIN\r\n250 OK\r\n");
+        smtp_reply(&mut conn, 250).unwrap();
+        assert!(read_line(&mut conn).is_err());
+
+        let mut conn = MockConn::new("251 forwarded\r\n");
+        smtp_reply(&mut conn, 250).unwrap();
+
+        let mut conn = MockConn::new("550 mailbox unavailable\r\n");
+        assert!(smtp_reply(&mut conn, 250).is_err());
+
+        //第4或第3个字节为多字节字符时不能panic
+        let mut conn = MockConn::new("250你好\r\n250 OK\r\n");
+        smtp_reply(&mut conn, 250).unwrap();
+        let mut conn = MockConn::new("25你 OK\r\n");
+        assert!(smtp_reply(&mut conn, 250).is_err());
+
+        let mut conn = MockConn::new("334 VXNlcm5hbWU6\r\n");
+        smtp_cmd(&mut conn, "AUTH LOGIN", 334).unwrap();
+        assert_eq!(conn.output, b"AUTH LOGIN\r\n".to_vec());
+    }
+
+    #[test]
+    fn encode() {
+        assert_eq!(url_encode("a+b/c=="), "a%2Bb%2Fc%3D%3D");
+        let s = "a".repeat(100);
+        assert_eq!(wrap_base64(&s), format!("{}\r\n{}", "a".repeat(76), "a".repeat(24)));
+    }
+
+    #[test]
+    fn keep_secret_when_empty() {
+        let mut old = webhook("c1", "http://127.0.0.1/");
+        old.secret = "SEC1".to_string();
+        old.password = "pass".to_string();
+        let mut new = webhook("c1", "http://127.0.0.1/");
+        new.keep_secret(&old);
+        assert_eq!(new.secret, "SEC1");
+        assert_eq!(new.password, "pass");
+        new.secret = "SEC2".to_string();
+        new.keep_secret(&old);
+        assert_eq!(new.secret, "SEC2");
+    }
+
+    #[test]
+    fn notifier_sends_in_background() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut buf = [0u8; 4096];
+                let _ = stream.read(&mut buf);
+                let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+            }
+        });
+        let mut disabled = webhook("disabled", "http://127.0.0.1:1/");
+        disabled.enable = false;
+        let mut notifier = Notifier::new();
+        notifier.set_channels(vec![webhook("ok", &format!("http://{}/", addr)), disabled]);
+        let names = vec!["ok".to_string(), "missing".to_string(), "disabled".to_string()];
+        let results = notifier.send(&"h1".to_string(), &notice(), &names);
+        assert_eq!(results, vec![ChannelResult::pending(&"ok".to_string()),
+                                 ChannelResult::failed(&"missing".to_string(), "channel not found".to_string())]);
+
+        let mut finished = vec![];
+        for _ in 0..100 {
+            finished.extend(notifier.finished());
+            if finished.len() > 0 {break;}
+            thread::sleep(Duration::from_millis(50));
+        }
+        assert_eq!(finished, vec![("h1".to_string(), ChannelResult::success(&"ok".to_string()))]);
+    }
+}
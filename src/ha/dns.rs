@@ -0,0 +1,420 @@
+/*
+@author: xiao cai niao
+@datetime: 2020/09/08
+*/
+
+//! 内置DNS， 只解析集群路由
+//!
+//! write.<cluster>.<domain> 返回当前master的A记录，
+//! read.<cluster>.<domain> 返回读权重大于0的slave， 没有可用slave时返回master
+//!
+//! 每次查询直接读取RouteInfo， TTL固定为5秒， 切换后应用重新解析即可连接到新节点
+
+use std::net::{UdpSocket, TcpListener, TcpStream, Ipv4Addr};
+use std::io::{Read, Write};
+use std::error::Error;
+use std::thread;
+use std::time::Duration;
+use actix_web::web;
+use crate::storage::rocks::DbInfo;
+use crate::ha::route_manager::RouteInfo;
+
+const DNS_TTL: u32 = 5;
+const UDP_MAX_SIZE: usize = 512;
+const TCP_MAX_SIZE: usize = 65535;
+
+const TYPE_A: u16 = 1;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+
+const RCODE_NOERROR: u8 = 0;
+const RCODE_FORMERR: u8 = 1;
+const RCODE_SERVFAIL: u8 = 2;
+const RCODE_NXDOMAIN: u8 = 3;
+const RCODE_NOTIMP: u8 = 4;
+const RCODE_REFUSED: u8 = 5;
+
+///
+/// 解析出的查询
+struct Question {
+    id: u16,
+    rd: bool,
+    name: String,
+    qtype: u16,
+    qclass: u16,
+    raw: Vec<u8>,           //question段原始数据， 返回时原样带回
+}
+
+///
+/// 启动udp及tcp监听， udp在当前线程处理
+pub fn listener(db: web::Data<DbInfo>, listen_info: String, domain: String) {
+    let domain = domain.trim_matches('.').to_lowercase();
+    let tcp_db = db.clone();
+    let tcp_listen = listen_info.clone();
+    let tcp_domain = domain.clone();
+    thread::spawn(move || {
+        if let Err(e) = tcp_listener(tcp_db, &tcp_listen, tcp_domain) {
+            info!("dns tcp listener {} failed: {}", &tcp_listen, e.to_string());
+        }
+    });
+    if let Err(e) = udp_listener(db, &listen_info, domain) {
+        info!("dns udp listener {} failed: {}", &listen_info, e.to_string());
+    }
+}
+
+fn udp_listener(db: web::Data<DbInfo>, listen_info: &String, domain: String) -> Result<(), Box<dyn Error>> {
+    let socket = UdpSocket::bind(listen_info)?;
+    info!("dns udp listener {} start success, domain: {}", listen_info, &domain);
+    let mut buf = [0u8; 512];
+    loop {
+        let (size, src) = match socket.recv_from(&mut buf) {
+            Ok(v) => v,
+            Err(e) => {
+                info!("dns udp recv failed: {}", e.to_string());
+                continue;
+            }
+        };
+        if let Some(response) = handle(&db, &buf[..size], &domain, UDP_MAX_SIZE) {
+            if let Err(e) = socket.send_to(&response, src) {
+                info!("dns udp send to {} failed: {}", src, e.to_string());
+            }
+        }
+    }
+}
+
+fn tcp_listener(db: web::Data<DbInfo>, listen_info: &String, domain: String) -> Result<(), Box<dyn Error>> {
+    let listener = TcpListener::bind(listen_info)?;
+    info!("dns tcp listener {} start success", listen_info);
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let db = db.clone();
+                let domain = domain.clone();
+                thread::spawn(move || {
+                    if let Err(e) = tcp_handle(&db, stream, &domain) {
+                        info!("dns tcp handle failed: {}", e.to_string());
+                    }
+                });
+            }
+            Err(e) => {
+                info!("dns tcp accept failed: {}", e.to_string());
+            }
+        }
+    }
+    Ok(())
+}
+
+///
+/// tcp每个消息前带2字节长度， 同一连接可以有多次查询
+fn tcp_handle(db: &DbInfo, mut stream: TcpStream, domain: &String) -> Result<(), Box<dyn Error>> {
+    stream.set_read_timeout(Some(Duration::new(10, 0)))?;
+    loop {
+        let mut len = [0u8; 2];
+        if stream.read_exact(&mut len).is_err() {
+            return Ok(());
+        }
+        let mut buf = vec![0u8; u16::from_be_bytes(len) as usize];
+        stream.read_exact(&mut buf)?;
+        let response = match handle(db, &buf, domain, TCP_MAX_SIZE) {
+            Some(v) => v,
+            None => return Ok(())
+        };
+        let mut packet = (response.len() as u16).to_be_bytes().to_vec();
+        packet.extend(response);
+        stream.write_all(&packet)?;
+    }
+}
+
+///
+/// 处理一个查询， 无法解析头部时不返回
+///
+/// 返回超过max_size时截断并设置TC， udp客户端会改用tcp重试
+fn handle(db: &DbInfo, buf: &[u8], domain: &String, max_size: usize) -> Option<Vec<u8>> {
+    if buf.len() < 12 {
+        return None;
+    }
+    let id = u16::from_be_bytes([buf[0], buf[1]]);
+    //只处理查询， 忽略响应包
+    if buf[2] & 0x80 != 0 {
+        return None;
+    }
+    let opcode = (buf[2] >> 3) & 0x0f;
+    let qdcount = u16::from_be_bytes([buf[4], buf[5]]);
+    let question = match parse_question(buf) {
+        Some(q) if qdcount == 1 => q,
+        _ => {
+            let q = Question{ id, rd: buf[2] & 0x01 != 0, name: "".to_string(), qtype: 0, qclass: 0, raw: vec![] };
+            return Some(build_response(&q, RCODE_FORMERR, &vec![]));
+        }
+    };
+    if opcode != 0 {
+        return Some(build_response(&question, RCODE_NOTIMP, &vec![]));
+    }
+    let (rcode, ips) = resolve(db, &question, domain);
+    let response = build_response(&question, rcode, &ips);
+    if response.len() > max_size {
+        return Some(truncate(&response, question.raw.len()));
+    }
+    Some(response)
+}
+
+fn parse_question(buf: &[u8]) -> Option<Question> {
+    let mut labels = vec![];
+    let mut pos = 12;
+    loop {
+        let len = *buf.get(pos)? as usize;
+        pos += 1;
+        if len == 0 {
+            break;
+        }
+        //查询中不应出现压缩指针
+        if len & 0xc0 != 0 {
+            return None;
+        }
+        let label = buf.get(pos..pos + len)?;
+        labels.push(String::from_utf8_lossy(label).to_lowercase());
+        pos += len;
+    }
+    let tail = buf.get(pos..pos + 4)?;
+    Some(Question{
+        id: u16::from_be_bytes([buf[0], buf[1]]),
+        rd: buf[2] & 0x01 != 0,
+        name: labels.join("."),
+        qtype: u16::from_be_bytes([tail[0], tail[1]]),
+        qclass: u16::from_be_bytes([tail[2], tail[3]]),
+        raw: buf[12..pos + 4].to_vec()
+    })
+}
+
+///
+/// 按照名称查询路由， 返回rcode及ip列表
+fn resolve(db: &DbInfo, question: &Question, domain: &String) -> (u8, Vec<Ipv4Addr>) {
+    let suffix = format!(".{}", domain);
+    if !question.name.ends_with(&suffix) {
+        return (RCODE_REFUSED, vec![]);
+    }
+    let name = &question.name[..question.name.len() - suffix.len()];
+    let (role, cluster_name) = match name.find('.') {
+        Some(i) => (&name[..i], name[i + 1..].to_string()),
+        None => return (RCODE_NXDOMAIN, vec![])
+    };
+    if role != "write" && role != "read" {
+        return (RCODE_NXDOMAIN, vec![]);
+    }
+    let route = match get_route(db, &cluster_name) {
+        Ok(Some(r)) => r,
+        Ok(None) => return (RCODE_NXDOMAIN, vec![]),
+        Err(e) => {
+            info!("dns get route for cluster {} failed: {}", &cluster_name, e.to_string());
+            return (RCODE_SERVFAIL, vec![]);
+        }
+    };
+    if question.qclass != CLASS_IN || (question.qtype != TYPE_A && question.qtype != TYPE_ANY) {
+        return (RCODE_NOERROR, vec![]);
+    }
+    let mut hosts = vec![];
+    if role == "read" {
+        hosts = route.read.iter().filter(|r| r.weight > 0).map(|r| r.host.clone()).collect();
+    }
+    if hosts.len() == 0 {
+        hosts.push(route.write.host.clone());
+    }
+    let mut ips = vec![];
+    for host in hosts {
+        if let Ok(ip) = host.parse::<Ipv4Addr>() {
+            if !ips.contains(&ip) {
+                ips.push(ip);
+            }
+        }
+    }
+    (RCODE_NOERROR, ips)
+}
+
+///
+/// 集群名不区分大小写， 路由中没有master时视为不存在
+fn get_route(db: &DbInfo, cluster_name: &String) -> Result<Option<RouteInfo>, Box<dyn Error>> {
+    for row in db.get_route_all()? {
+        if row.value.cluster_name.to_lowercase() == *cluster_name && row.value.write.host.len() > 0 {
+            return Ok(Some(row.value));
+        }
+    }
+    Ok(None)
+}
+
+fn build_response(question: &Question, rcode: u8, ips: &Vec<Ipv4Addr>) -> Vec<u8> {
+    let mut buf = vec![];
+    buf.extend(&question.id.to_be_bytes());
+    //QR=1 AA=1 保留RD， RA=0
+    let mut flags: u16 = 0x8400 | rcode as u16;
+    if question.rd {
+        flags |= 0x0100;
+    }
+    buf.extend(&flags.to_be_bytes());
+    let qdcount: u16 = if question.raw.len() > 0 { 1 } else { 0 };
+    buf.extend(&qdcount.to_be_bytes());
+    buf.extend(&(ips.len() as u16).to_be_bytes());
+    buf.extend(&[0u8, 0, 0, 0]);
+    buf.extend(&question.raw);
+    for ip in ips {
+        //名称使用指向question的压缩指针
+        buf.extend(&[0xc0u8, 0x0c]);
+        buf.extend(&TYPE_A.to_be_bytes());
+        buf.extend(&CLASS_IN.to_be_bytes());
+        buf.extend(&DNS_TTL.to_be_bytes());
+        buf.extend(&4u16.to_be_bytes());
+        buf.extend(&ip.octets());
+    }
+    buf
+}
+
+///
+/// 截断为只包含头部及question并设置TC， qlen为question段长度
+///
+/// label中可以包含0x00， 不能按0查找名称结尾
+fn truncate(response: &Vec<u8>, qlen: usize) -> Vec<u8> {
+    let mut buf = response[..12].to_vec();
+    buf.extend(&response[12..12 + qlen]);
+    buf[2] |= 0x02;
+    buf[6] = 0;
+    buf[7] = 0;
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ha::route_manager::MysqlHostInfo;
+    use crate::storage::rocks::PrefixTypeCode;
+
+    fn domain() -> String {
+        "db.local".to_string()
+    }
+
+    ///
+    /// 构造一个查询， flags为第3、4字节
+    fn query(labels: &[&[u8]], qtype: u16, flags: [u8; 2], qdcount: u16) -> Vec<u8> {
+        let mut buf = vec![0x12u8, 0x34, flags[0], flags[1]];
+        buf.extend(&qdcount.to_be_bytes());
+        buf.extend(&[0u8, 0, 0, 0, 0, 0]);
+        for label in labels {
+            buf.push(label.len() as u8);
+            buf.extend(*label);
+        }
+        buf.push(0);
+        buf.extend(&qtype.to_be_bytes());
+        buf.extend(&CLASS_IN.to_be_bytes());
+        buf
+    }
+
+    fn name_query(name: &str) -> Vec<u8> {
+        let labels: Vec<&[u8]> = name.split('.').map(|l| l.as_bytes()).collect();
+        query(&labels, TYPE_A, [0x01, 0x00], 1)
+    }
+
+    fn rcode(response: &Vec<u8>) -> u8 {
+        response[3] & 0x0f
+    }
+
+    fn ancount(response: &Vec<u8>) -> u16 {
+        u16::from_be_bytes([response[6], response[7]])
+    }
+
+    fn host(ip: &str) -> MysqlHostInfo {
+        MysqlHostInfo{ host: ip.to_string(), port: 3306, weight: 100 }
+    }
+
+    fn db_with_route(readers: usize) -> DbInfo {
+        let db = DbInfo::memory();
+        let mut route = RouteInfo::new("c1".to_string());
+        route.write = host("10.0.0.1");
+        for i in 0..readers {
+            route.read.push(host(&format!("10.0.1.{}", i + 1)));
+        }
+        db.prefix_put(&PrefixTypeCode::RouteInfo, &route.cluster_name, &route).unwrap();
+        db
+    }
+
+    #[test]
+    fn formerr_for_bad_question() {
+        let db = DbInfo::memory();
+        //头部不完整时不返回
+        assert!(handle(&db, &[0u8; 11], &domain(), UDP_MAX_SIZE).is_none());
+        let mut buf = name_query("write.c1.db.local");
+        buf.truncate(buf.len() - 2);
+        let response = handle(&db, &buf, &domain(), UDP_MAX_SIZE).unwrap();
+        assert_eq!(&response[..2], &[0x12, 0x34]);
+        assert_eq!(rcode(&response), RCODE_FORMERR);
+        assert_eq!(response.len(), 12);
+        let buf = query(&[b"write", b"c1", b"db", b"local"], TYPE_A, [0x01, 0x00], 2);
+        assert_eq!(rcode(&handle(&db, &buf, &domain(), UDP_MAX_SIZE).unwrap()), RCODE_FORMERR);
+        //压缩指针
+        let mut buf = name_query("write.c1.db.local");
+        buf[12] = 0xc0;
+        assert_eq!(rcode(&handle(&db, &buf, &domain(), UDP_MAX_SIZE).unwrap()), RCODE_FORMERR);
+        //响应包不处理
+        let buf = query(&[b"write"], TYPE_A, [0x81, 0x00], 1);
+        assert!(handle(&db, &buf, &domain(), UDP_MAX_SIZE).is_none());
+    }
+
+    #[test]
+    fn refused_notimp_nxdomain() {
+        let db = db_with_route(0);
+        let response = handle(&db, &name_query("write.c1.example.com"), &domain(), UDP_MAX_SIZE).unwrap();
+        assert_eq!(rcode(&response), RCODE_REFUSED);
+        //opcode为2(STATUS)
+        let buf = query(&[b"write", b"c1", b"db", b"local"], TYPE_A, [0x11, 0x00], 1);
+        assert_eq!(rcode(&handle(&db, &buf, &domain(), UDP_MAX_SIZE).unwrap()), RCODE_NOTIMP);
+        assert_eq!(rcode(&handle(&db, &name_query("write.c2.db.local"), &domain(), UDP_MAX_SIZE).unwrap()), RCODE_NXDOMAIN);
+        assert_eq!(rcode(&handle(&db, &name_query("other.c1.db.local"), &domain(), UDP_MAX_SIZE).unwrap()), RCODE_NXDOMAIN);
+    }
+
+    #[test]
+    fn a_answers() {
+        let db = db_with_route(0);
+        let buf = name_query("WRITE.C1.db.local");
+        let response = handle(&db, &buf, &domain(), UDP_MAX_SIZE).unwrap();
+        //QR AA RD
+        assert_eq!(&response[2..4], &[0x85, 0x00]);
+        assert_eq!(ancount(&response), 1);
+        //question原样带回
+        assert_eq!(&response[12..buf.len()], &buf[12..]);
+        let answer = &response[buf.len()..];
+        assert_eq!(answer, &[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 5, 0, 4, 10, 0, 0, 1]);
+
+        //没有slave时read返回master
+        let response = handle(&db, &name_query("read.c1.db.local"), &domain(), UDP_MAX_SIZE).unwrap();
+        assert_eq!(ancount(&response), 1);
+        assert_eq!(&response[response.len() - 4..], &[10, 0, 0, 1]);
+        let db = db_with_route(2);
+        let response = handle(&db, &name_query("read.c1.db.local"), &domain(), UDP_MAX_SIZE).unwrap();
+        assert_eq!(ancount(&response), 2);
+        //其他类型返回空结果
+        let buf = query(&[b"write", b"c1", b"db", b"local"], 28, [0x01, 0x00], 1);
+        let response = handle(&db, &buf, &domain(), UDP_MAX_SIZE).unwrap();
+        assert_eq!((rcode(&response), ancount(&response)), (RCODE_NOERROR, 0));
+    }
+
+    #[test]
+    fn truncate_large_udp_response() {
+        let db = db_with_route(40);
+        let buf = name_query("read.c1.db.local");
+        let response = handle(&db, &buf, &domain(), TCP_MAX_SIZE).unwrap();
+        assert_eq!(ancount(&response), 40);
+        assert!(response.len() > UDP_MAX_SIZE);
+        let response = handle(&db, &buf, &domain(), UDP_MAX_SIZE).unwrap();
+        assert_eq!(response[2] & 0x02, 0x02);
+        assert_eq!(ancount(&response), 0);
+        assert_eq!(&response[12..], &buf[12..]);
+    }
+
+    #[test]
+    fn truncate_keeps_label_with_zero_byte() {
+        let buf = query(&[b"a\0b", b"db", b"local"], TYPE_A, [0x01, 0x00], 1);
+        let question = parse_question(&buf).unwrap();
+        let ips: Vec<Ipv4Addr> = (0..40).map(|i| Ipv4Addr::new(10, 0, 1, i)).collect();
+        let response = truncate(&build_response(&question, RCODE_NOERROR, &ips), question.raw.len());
+        assert_eq!(response.len(), buf.len());
+        assert_eq!(&response[12..], &buf[12..]);
+        assert_eq!(u16::from_be_bytes([response[4], response[5]]), 1);
+    }
+}
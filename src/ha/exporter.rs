@@ -0,0 +1,351 @@
+/*
+@author: xiao cai niao
+@datetime: 2020/09/09
+*/
+
+//! 按照模板导出集群路由， 用于生成HAProxy等外部代理的配置
+//!
+//! 每个导出配置有一个模板， 对每个集群渲染一次后拼接写入target文件，
+//! 内容变化时先写入临时文件再rename， 之后执行reload命令
+//!
+//! 输出目录及reload命令只能在server配置文件的[exporter]中配置， 接口只能选择目录中的文件名及reload命令的名称，
+//! 写入前会解析真实路径， 不在输出目录中时拒绝写入
+//!
+//! 模板支持:
+//!     {{cluster_name}} {{generation}}
+//!     {{#write}}...{{/write}}  master， 段内可使用{{host}} {{port}} {{weight}} {{index}}
+//!     {{#read}}...{{/read}}    每个读权重大于0的slave重复一次， 没有可用slave时为master
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::Command;
+use std::{thread, time};
+use actix_web::web;
+use serde::{Serialize, Deserialize};
+use crate::storage::rocks::{DbInfo, PrefixTypeCode, RowValue};
+use crate::storage::repo::Repo;
+use crate::ha::route_manager::{RouteInfo, MysqlHostInfo};
+
+const RETRY_INTERVAL: i64 = 10000;
+
+///
+/// 路由导出配置
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ExporterSetting {
+    pub name: String,
+    pub clusters: Vec<String>,      //需要导出的集群， 为空时导出所有集群
+    #[serde(default)]
+    pub header: String,             //文件头， 只输出一次
+    pub template: String,           //每个集群的模板
+    pub target: String,             //输出文件名， 写入配置的output_dir中
+    #[serde(default)]
+    pub reload: String,             //文件变化后执行的reload命令名称， 为空则不执行
+    pub enable: bool,
+}
+
+impl ExporterSetting {
+    pub fn save(&self, db: &DbInfo) -> Result<(), Box<dyn Error>> {
+        db.prefix_put(&PrefixTypeCode::ExporterSeting, &self.name, &self)?;
+        Ok(())
+    }
+
+    pub fn check(&self) -> Result<(), Box<dyn Error>> {
+        if self.name.len() == 0 {
+            return Err("name can not be empty".into());
+        }
+        output_path(&self.target)?;
+        if self.reload.len() > 0 {
+            reload_cmd(&self.reload)?;
+        }
+        Ok(())
+    }
+}
+
+///
+/// target只能是文件名， 不能包含路径
+fn check_target(target: &str) -> Result<(), Box<dyn Error>> {
+    if target.len() == 0 || target.starts_with('.') || target.contains('/') || target.contains('\\') || target.contains('\0') {
+        return Err(format!("invalid target: {}, must be a file name in output_dir", target).into());
+    }
+    Ok(())
+}
+
+///
+/// 输出文件的完整路径， 已存在的文件解析符号链接后也必须在output_dir中
+pub fn output_path(target: &str) -> Result<PathBuf, Box<dyn Error>> {
+    check_target(target)?;
+    let output_dir = crate::config::get().exporter.output_dir.clone();
+    fs::create_dir_all(&output_dir)?;
+    let dir = fs::canonicalize(&output_dir)?;
+    let path = dir.join(target);
+    let real = match fs::symlink_metadata(&path) {
+        Ok(_) => fs::canonicalize(&path)?,
+        Err(_) => path.clone()
+    };
+    if real.parent() != Some(dir.as_path()) {
+        return Err(format!("target {} is outside of {}", target, dir.display()).into());
+    }
+    Ok(path)
+}
+
+///
+/// 按名称获取配置文件中的reload命令
+fn reload_cmd(name: &String) -> Result<String, Box<dyn Error>> {
+    match crate::config::get().exporter.reload.get(name) {
+        Some(cmd) => Ok(cmd.clone()),
+        None => Err(format!("reload command {} is not configured in [exporter.reload]", name).into())
+    }
+}
+
+///
+/// 导出状态
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExporterStatus {
+    pub name: String,
+    pub generations: HashMap<String, u64>,  //最后一次写入文件时各集群的路由generation
+    pub last_render_time: i64,              //最后一次内容变化写入文件的时间
+    pub last_reload_time: i64,              //最后一次reload成功的时间
+    pub reload_pending: bool,               //文件已更新但reload还未成功
+    pub last_error: String,                 //最后一次渲染、写入或reload的错误， 成功后清空
+    pub last_error_time: i64,
+}
+
+impl ExporterStatus {
+    fn new(name: &String) -> ExporterStatus {
+        ExporterStatus{
+            name: name.clone(),
+            generations: HashMap::new(),
+            last_render_time: 0,
+            last_reload_time: 0,
+            reload_pending: false,
+            last_error: "".to_string(),
+            last_error_time: 0
+        }
+    }
+
+    fn save(&self, db: &DbInfo) -> Result<(), Box<dyn Error>> {
+        db.prefix_put(&PrefixTypeCode::ExporterStatus, &self.name, &self)?;
+        Ok(())
+    }
+
+    fn failed(&mut self, err: String) {
+        self.last_error = err;
+        self.last_error_time = crate::timestamp();
+    }
+}
+
+impl DbInfo {
+    ///
+    /// 获取所有路由导出配置
+    pub fn get_exporter_setting(&self) -> Result<Vec<RowValue<ExporterSetting>>, Box<dyn Error>> {
+        Repo::<ExporterSetting>::new(self).all()
+    }
+
+    ///
+    /// 获取导出状态， 未执行过时返回初始状态
+    pub fn get_exporter_status(&self, name: &String) -> Result<ExporterStatus, Box<dyn Error>> {
+        let result = self.prefix_get(&PrefixTypeCode::ExporterStatus, name)?;
+        if result.value.len() > 0 {
+            let value: ExporterStatus = serde_json::from_str(&result.value)?;
+            return Ok(value);
+        }
+        Ok(ExporterStatus::new(name))
+    }
+}
+
+///
+/// 路由导出线程， 每秒渲染一次， 内容变化时写入文件并reload
+pub fn manager(db: web::Data<DbInfo>) {
+    info!("route exporter thread start success");
+    loop {
+        match db.get_exporter_setting() {
+            Ok(settings) => {
+                for s in &settings {
+                    if !s.value.enable {continue;}
+                    if let Err(e) = check_export(&db, &s.value) {
+                        info!("route exporter {} failed: {}", &s.key, e.to_string());
+                    }
+                }
+            }
+            Err(e) => {
+                info!("get exporter setting failed: {}", e.to_string());
+            }
+        }
+        thread::sleep(time::Duration::from_secs(1));
+    }
+}
+
+fn check_export(db: &DbInfo, setting: &ExporterSetting) -> Result<(), Box<dyn Error>> {
+    let mut status = db.get_exporter_status(&setting.name)?;
+    //失败后每10秒重试一次
+    if status.last_error.len() > 0 && crate::timestamp() - status.last_error_time < RETRY_INTERVAL {
+        return Ok(());
+    }
+    let routes = get_routes(db, setting)?;
+    let content = match render(setting, &routes) {
+        Ok(v) => v,
+        Err(e) => {
+            status.failed(format!("render failed: {}", e.to_string()));
+            return status.save(db);
+        }
+    };
+    let path = match output_path(&setting.target) {
+        Ok(p) => p,
+        Err(e) => {
+            status.failed(e.to_string());
+            return status.save(db);
+        }
+    };
+    let old = fs::read_to_string(&path).unwrap_or("".to_string());
+    if old != content {
+        if let Err(e) = write_file(&path, &content) {
+            info!("route exporter {} write {} failed: {}", &setting.name, path.display(), e.to_string());
+            status.failed(format!("write {} failed: {}", path.display(), e.to_string()));
+            return status.save(db);
+        }
+        info!("route exporter {} write {} success", &setting.name, path.display());
+        status.generations = routes.iter().map(|r| (r.cluster_name.clone(), r.generation)).collect();
+        status.last_render_time = crate::timestamp();
+        status.reload_pending = setting.reload.len() > 0;
+        status.last_error = "".to_string();
+    } else if !status.reload_pending {
+        return Ok(());
+    }
+    if status.reload_pending {
+        match reload(&setting.reload) {
+            Ok(()) => {
+                info!("route exporter {} reload success", &setting.name);
+                status.reload_pending = false;
+                status.last_reload_time = crate::timestamp();
+                status.last_error = "".to_string();
+            }
+            Err(e) => {
+                info!("route exporter {} reload failed: {}", &setting.name, e.to_string());
+                status.failed(format!("reload failed: {}", e.to_string()));
+            }
+        }
+    }
+    status.save(db)
+}
+
+///
+/// 获取需要导出的集群路由， 按集群名排序保证输出稳定
+pub fn get_routes(db: &DbInfo, setting: &ExporterSetting) -> Result<Vec<RouteInfo>, Box<dyn Error>> {
+    let mut routes: Vec<RouteInfo> = db.get_route_all()?.into_iter()
+        .map(|r| r.value)
+        .filter(|r| r.write.host.len() > 0)
+        .filter(|r| setting.clusters.len() == 0 || setting.clusters.contains(&r.cluster_name))
+        .collect();
+    routes.sort_by(|a, b| a.cluster_name.cmp(&b.cluster_name));
+    Ok(routes)
+}
+
+///
+/// 渲染所有集群
+pub fn render(setting: &ExporterSetting, routes: &Vec<RouteInfo>) -> Result<String, Box<dyn Error>> {
+    let mut content = setting.header.clone();
+    for route in routes {
+        content.push_str(&render_route(&setting.template, route)?);
+    }
+    Ok(content)
+}
+
+fn render_route(template: &String, route: &RouteInfo) -> Result<String, Box<dyn Error>> {
+    let write = vec![route.write.clone()];
+    let mut read: Vec<MysqlHostInfo> = route.read.iter().filter(|r| r.weight > 0).cloned().collect();
+    if read.len() == 0 {
+        read = write.clone();
+    }
+    let mut content = render_section(template, "write", &write)?;
+    content = render_section(&content, "read", &read)?;
+    Ok(content.replace("{{cluster_name}}", &route.cluster_name)
+        .replace("{{generation}}", &route.generation.to_string()))
+}
+
+///
+/// 将{{#name}}...{{/name}}段按节点重复渲染
+fn render_section(template: &String, name: &str, hosts: &Vec<MysqlHostInfo>) -> Result<String, Box<dyn Error>> {
+    let start_tag = format!("{{{{#{}}}}}", name);
+    let end_tag = format!("{{{{/{}}}}}", name);
+    let mut content = String::new();
+    let mut rest = template.as_str();
+    while let Some(start) = rest.find(&start_tag) {
+        let body_start = start + start_tag.len();
+        let end = match rest[body_start..].find(&end_tag) {
+            Some(i) => body_start + i,
+            None => return Err(format!("template section {} is not closed", start_tag).into())
+        };
+        content.push_str(&rest[..start]);
+        let body = &rest[body_start..end];
+        for (index, host) in hosts.iter().enumerate() {
+            content.push_str(&body.replace("{{host}}", &host.host)
+                .replace("{{port}}", &host.port.to_string())
+                .replace("{{weight}}", &host.weight.to_string())
+                .replace("{{index}}", &index.to_string()));
+        }
+        rest = &rest[end + end_tag.len()..];
+    }
+    content.push_str(rest);
+    Ok(content)
+}
+
+///
+/// 写入临时文件后rename， 保证读取方不会读到写了一半的文件，
+/// 临时文件使用create_new创建， 不会跟随已存在的符号链接
+fn write_file(path: &PathBuf, content: &String) -> Result<(), Box<dyn Error>> {
+    let mut tmp = path.clone().into_os_string();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    let _ = fs::remove_file(&tmp);
+    {
+        let mut file = fs::OpenOptions::new().write(true).create_new(true).open(&tmp)?;
+        file.write_all(content.as_bytes())?;
+        file.sync_all()?;
+    }
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+fn reload(name: &String) -> Result<(), Box<dyn Error>> {
+    let cmd = reload_cmd(name)?;
+    let output = Command::new("sh").arg("-c").arg(&cmd).output()?;
+    if !output.status.success() {
+        let err = format!("{}, {}", output.status, String::from_utf8_lossy(&output.stderr).trim());
+        return Err(err.into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn host(host: &str, weight: usize) -> MysqlHostInfo {
+        MysqlHostInfo{ host: host.to_string(), port: 3306, weight }
+    }
+
+    #[test]
+    fn target_must_be_file_name() {
+        for t in &["", ".", "..", "../a.cfg", "/etc/passwd", "a/b.cfg", "a\\b", ".hidden"] {
+            assert!(check_target(t).is_err(), "{}", t);
+        }
+        assert!(check_target("mysql.cfg").is_ok());
+    }
+
+    #[test]
+    fn render_sections() {
+        let mut route = RouteInfo::new("test".to_string());
+        route.write = host("10.0.0.1", 100);
+        route.read = vec![host("10.0.0.2", 100), host("10.0.0.3", 0)];
+        route.generation = 3;
+        let t = "{{cluster_name}}:{{generation}} w={{#write}}{{host}}{{/write}} r={{#read}}{{index}}:{{host}}:{{weight}},{{/read}}".to_string();
+        assert_eq!(render_route(&t, &route).unwrap(), "test:3 w=10.0.0.1 r=0:10.0.0.2:100,");
+
+        route.read = vec![];
+        assert_eq!(render_route(&t, &route).unwrap(), "test:3 w=10.0.0.1 r=0:10.0.0.1:100,");
+        assert!(render_route(&"{{#read}}".to_string(), &route).is_err());
+    }
+}
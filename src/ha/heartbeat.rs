@@ -0,0 +1,266 @@
+/*
+@author: xiao cai niao
+@datetime: 2020/08/24
+*/
+
+//! client主动注册及心跳推送
+//!
+//! client启动后连接server的agentport发送Register包， 新节点进入待审批队列，
+//! 审批通过后写入Ha_nodes_info由ha_manager管理
+//!
+//! 之后client定时推送Heartbeat包， ha_manager优先使用未超时的心跳数据判断节点状态，
+//! 心跳超时后恢复轮询
+//!
+//! Register及Heartbeat都需要携带与配置中agent_token一致的token， 未审批节点的心跳直接拒绝
+
+use std::net::{TcpListener, TcpStream};
+use std::error::Error;
+use std::thread;
+use std::time::Duration;
+use actix_web::web;
+use serde::{Serialize, Deserialize};
+use crate::storage::rocks::{DbInfo, PrefixTypeCode, CfNameTypeCode};
+use crate::ha::procotol::{MyProtocol, MysqlState, AgentRegister, AgentHeartbeat, ReponseErr, Null};
+
+///
+/// 心跳超时时间(毫秒)， 超过该时间未收到心跳认为节点离线
+pub const HEARTBEAT_TIMEOUT: i64 = 10000;
+
+///
+/// 待审批的注册节点
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct PendingNode {
+    pub host: String,
+    pub dbport: usize,
+    pub cluster_name: String,
+    pub version: String,
+    pub time: i64,              //注册时间
+}
+
+impl PendingNode {
+    fn new(info: &AgentRegister) -> PendingNode {
+        PendingNode{
+            host: info.host.clone(),
+            dbport: info.dbport.clone(),
+            cluster_name: info.cluster_name.clone(),
+            version: info.version.clone(),
+            time: crate::timestamp()
+        }
+    }
+
+    fn save(&self, db: &DbInfo) -> Result<(), Box<dyn Error>> {
+        db.prefix_put(&PrefixTypeCode::PendingNode, &self.host, &self)?;
+        Ok(())
+    }
+}
+
+///
+/// 最后一次收到的心跳数据
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct HeartbeatValue {
+    pub host: String,
+    pub version: String,
+    pub state: MysqlState,
+    pub time: i64,              //server接收时间
+}
+
+impl HeartbeatValue {
+    fn new(info: &AgentHeartbeat) -> HeartbeatValue {
+        HeartbeatValue{
+            host: info.host.clone(),
+            version: info.version.clone(),
+            state: info.state.clone(),
+            time: crate::timestamp()
+        }
+    }
+
+    fn save(&self, db: &DbInfo) -> Result<(), Box<dyn Error>> {
+        db.prefix_put(&PrefixTypeCode::AgentHeartbeat, &self.host, &self)?;
+        Ok(())
+    }
+
+    ///
+    /// 心跳未超时返回推送的状态， 超时返回错误
+    pub fn check(&self) -> Result<MysqlState, Box<dyn Error>> {
+        if crate::timestamp() - self.time > HEARTBEAT_TIMEOUT {
+            let err = format!("{} heartbeat timeout", &self.host);
+            return Err(err.into());
+        }
+        Ok(self.state.clone())
+    }
+}
+
+impl DbInfo {
+    ///
+    /// 获取节点最后一次心跳， 从未推送过心跳的节点返回None
+    pub fn get_heartbeat(&self, host: &String) -> Result<Option<HeartbeatValue>, Box<dyn Error>> {
+        let result = self.prefix_get(&PrefixTypeCode::AgentHeartbeat, host)?;
+        if result.value.len() > 0 {
+            let value: HeartbeatValue = serde_json::from_str(&result.value)?;
+            return Ok(Some(value));
+        }
+        Ok(None)
+    }
+
+    ///
+    /// 获取所有待审批的节点
+    pub fn get_pending_nodes(&self) -> Result<Vec<PendingNode>, Box<dyn Error>> {
+        let prefix = PrefixTypeCode::PendingNode.prefix();
+        let mut nodes = vec![];
+        let result = self.prefix_iterator(&prefix, &CfNameTypeCode::SystemData.get())?;
+        for row in result{
+            if !row.key.starts_with(&prefix){continue;}
+            if row.value.len() == 0 {continue;}
+            let value: PendingNode = serde_json::from_str(&row.value)?;
+            nodes.push(value);
+        }
+        Ok(nodes)
+    }
+
+    pub fn get_pending_node(&self, host: &String) -> Result<PendingNode, Box<dyn Error>> {
+        let result = self.prefix_get(&PrefixTypeCode::PendingNode, host)?;
+        if result.value.len() > 0 {
+            let value: PendingNode = serde_json::from_str(&result.value)?;
+            return Ok(value);
+        }
+        let err = format!("no pending node: {}", host);
+        Err(err.into())
+    }
+
+    pub fn delete_pending_node(&self, host: &String) -> Result<(), Box<dyn Error>> {
+        let key = format!("{}:{}", PrefixTypeCode::PendingNode.prefix(), host);
+        self.delete(&key, &CfNameTypeCode::SystemData.get())
+    }
+}
+
+///
+/// 监听client主动连接， 每个连接一个线程
+pub fn listener(db: web::Data<DbInfo>, listen_info: String) {
+    let listener = match TcpListener::bind(&listen_info) {
+        Ok(l) => l,
+        Err(e) => {
+            info!("agent listener bind {} failed: {}", &listen_info, e.to_string());
+            return;
+        }
+    };
+    info!("agent listener start success on {}", &listen_info);
+    for stream in listener.incoming() {
+        match stream {
+            Ok(conn) => {
+                let db = db.clone();
+                thread::spawn(move || {
+                    if let Err(e) = handle_conn(conn, &db) {
+                        info!("agent connection closed: {}", e.to_string());
+                    }
+                });
+            }
+            Err(e) => {
+                info!("agent listener accept failed: {}", e.to_string());
+            }
+        }
+    }
+}
+
+///
+/// client可以在一个连接上持续推送心跳， 超过3倍心跳超时时间没有数据断开连接
+fn handle_conn(mut conn: TcpStream, db: &DbInfo) -> Result<(), Box<dyn Error>> {
+    conn.set_read_timeout(Some(Duration::from_millis(HEARTBEAT_TIMEOUT as u64 * 3)))?;
+    conn.set_write_timeout(Some(Duration::new(10,10)))?;
+    loop {
+        let packet = MyProtocol::read_packet(&mut conn)?;
+        let token = crate::config::get().server.agent_token.clone();
+        let state = match packet.type_code {
+            MyProtocol::Register => {
+                let value: AgentRegister = serde_json::from_slice(&packet.value)?;
+                register(db, &value, &token)
+            }
+            MyProtocol::Heartbeat => {
+                let value: AgentHeartbeat = serde_json::from_slice(&packet.value)?;
+                heartbeat(db, &value, &token)
+            }
+            _ => {
+                let err = format!("invalid type code: {:?}", &packet.type_code);
+                Err(err.into())
+            }
+        };
+        match state {
+            Ok(()) => MyProtocol::Ok.response(&conn, &Null::new())?,
+            Err(e) => MyProtocol::Error.response(&conn, &ReponseErr{ err: e.to_string() })?
+        }
+    }
+}
+
+///
+/// 按字节比较token， 比较时间与内容无关， expected为配置中的agent_token
+fn check_token(token: &String, expected: &String) -> Result<(), Box<dyn Error>> {
+    let a = token.as_bytes();
+    let b = expected.as_bytes();
+    let diff = a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y));
+    if expected.len() == 0 || a.len() != b.len() || diff != 0 {
+        return Err("invalid agent token".into());
+    }
+    Ok(())
+}
+
+fn is_approved(db: &DbInfo, host: &String) -> Result<bool, Box<dyn Error>> {
+    let result = db.get(host, &CfNameTypeCode::HaNodesInfo.get())?;
+    Ok(result.value.len() > 0)
+}
+
+///
+/// 已经在管理中的节点直接返回成功， 否则放入待审批队列
+fn register(db: &DbInfo, info: &AgentRegister, token: &String) -> Result<(), Box<dyn Error>> {
+    check_token(&info.token, token)?;
+    if is_approved(db, &info.host)? {
+        return Ok(());
+    }
+    info!("agent {} register for cluster {}, waiting for approval", &info.host, &info.cluster_name);
+    PendingNode::new(info).save(db)
+}
+
+///
+/// 只接受已审批节点的心跳
+fn heartbeat(db: &DbInfo, info: &AgentHeartbeat, token: &String) -> Result<(), Box<dyn Error>> {
+    check_token(&info.token, token)?;
+    if !is_approved(db, &info.host)? {
+        let err = format!("{} is not approved", &info.host);
+        return Err(err.into());
+    }
+    HeartbeatValue::new(info).save(db)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::rocks::KeyValue;
+
+    fn heartbeat_info(host: &str, token: &str) -> AgentHeartbeat {
+        AgentHeartbeat{ host: host.to_string(), version: "test".to_string(), state: MysqlState::new(), token: token.to_string() }
+    }
+
+    #[test]
+    fn heartbeat_requires_token_and_approval() {
+        let token = "secret".to_string();
+        let db = DbInfo::memory();
+        let host = "127.0.0.1:9011".to_string();
+        assert!(heartbeat(&db, &heartbeat_info(&host, "secret"), &token).is_err());
+        assert!(db.get_heartbeat(&host).unwrap().is_none());
+
+        db.put(&KeyValue{ key: host.clone(), value: "{}".to_string() }, &CfNameTypeCode::HaNodesInfo.get()).unwrap();
+        assert!(heartbeat(&db, &heartbeat_info(&host, ""), &token).is_err());
+        assert!(heartbeat(&db, &heartbeat_info(&host, "secreT"), &token).is_err());
+        //未配置token时拒绝所有请求
+        assert!(heartbeat(&db, &heartbeat_info(&host, ""), &"".to_string()).is_err());
+        assert!(db.get_heartbeat(&host).unwrap().is_none());
+        heartbeat(&db, &heartbeat_info(&host, "secret"), &token).unwrap();
+        assert!(db.get_heartbeat(&host).unwrap().is_some());
+    }
+
+    #[test]
+    fn stale_heartbeat_is_rejected() {
+        let mut value = HeartbeatValue::new(&heartbeat_info("127.0.0.1:9011", ""));
+        assert!(value.check().is_ok());
+        value.time -= HEARTBEAT_TIMEOUT + 1;
+        assert!(value.check().is_err());
+    }
+}
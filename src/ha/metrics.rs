@@ -0,0 +1,311 @@
+/*
+@author: xiao cai niao
+@datetime: 2020/09/18
+*/
+
+//! Prometheus指标
+//!
+//! /metrics返回text格式， 节点状态、监控数据、切换日志及路由在请求时从rocksdb读取，
+//! 切换次数及耗时由保留的切换日志计算， 健康检查耗时只存在于运行过程中， 由检查线程写入ServerMetrics， server重启后从0开始
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::sync::Mutex;
+use std::time::Duration;
+use crate::storage::rocks::{DbInfo, PrefixTypeCode};
+use crate::storage::repo::Repo;
+use crate::storage::opdb::{HostInfoValue, HaChangeLog};
+use crate::ha::procotol::{MysqlState, MysqlMonitorStatus};
+use crate::ha::route_manager::RouteInfo;
+use crate::ha::sys_manager::MonitorSetting;
+
+const HEALTH_CHECK_BUCKETS: [f64; 10] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+const SWITCH_BUCKETS: [f64; 9] = [1.0, 2.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0];
+
+struct Histogram {
+    buckets: &'static [f64],
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(buckets: &'static [f64]) -> Histogram {
+        Histogram{ buckets, counts: vec![0; buckets.len()], sum: 0.0, count: 0 }
+    }
+
+    fn observe(&mut self, value: f64) {
+        for (i, b) in self.buckets.iter().enumerate() {
+            if value <= *b {
+                self.counts[i] += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn write(&self, out: &mut MetricsText, name: &str, labels: &[(&str, &str)]) {
+        for (i, b) in self.buckets.iter().enumerate() {
+            let le = b.to_string();
+            let mut l = labels.to_vec();
+            l.push(("le", &le));
+            out.sample(&format!("{}_bucket", name), &l, self.counts[i] as f64);
+        }
+        let mut l = labels.to_vec();
+        l.push(("le", "+Inf"));
+        out.sample(&format!("{}_bucket", name), &l, self.count as f64);
+        out.sample(&format!("{}_sum", name), labels, self.sum);
+        out.sample(&format!("{}_count", name), labels, self.count as f64);
+    }
+}
+
+struct MetricsInner {
+    health_check: Histogram,
+    health_check_last: BTreeMap<String, f64>,
+}
+
+///
+/// 运行过程中产生的指标， 通过web::Data在线程间共享
+pub struct ServerMetrics {
+    inner: Mutex<MetricsInner>,
+}
+
+fn result_label(success: bool) -> &'static str {
+    if success { "success" } else { "failed" }
+}
+
+impl ServerMetrics {
+    pub fn new() -> ServerMetrics {
+        ServerMetrics{ inner: Mutex::new(MetricsInner{
+            health_check: Histogram::new(&HEALTH_CHECK_BUCKETS),
+            health_check_last: BTreeMap::new()
+        }) }
+    }
+
+    ///
+    /// 记录一次节点状态检查耗时
+    pub fn observe_health_check(&self, host: &String, d: Duration) {
+        if let Ok(mut inner) = self.inner.lock() {
+            inner.health_check.observe(d.as_secs_f64());
+            inner.health_check_last.insert(host.clone(), d.as_secs_f64());
+        }
+    }
+
+    fn write(&self, out: &mut MetricsText) -> Result<(), Box<dyn Error>> {
+        let inner = self.inner.lock().map_err(|_| "metrics lock poisoned")?;
+        out.family("mymha_health_check_duration_seconds", "histogram", "Duration of a single node state check");
+        inner.health_check.write(out, "mymha_health_check_duration_seconds", &[]);
+        out.family("mymha_health_check_last_duration_seconds", "gauge", "Duration of the last state check per node");
+        for (host, v) in &inner.health_check_last {
+            out.sample("mymha_health_check_last_duration_seconds", &[("host", host)], *v);
+        }
+        Ok(())
+    }
+}
+
+///
+/// text格式输出
+struct MetricsText {
+    out: String,
+}
+
+impl MetricsText {
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        self.out.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, kind));
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: f64) {
+        self.out.push_str(name);
+        if labels.len() > 0 {
+            let l: Vec<String> = labels.iter().map(|(k, v)| format!("{}=\"{}\"", k, escape(v))).collect();
+            self.out.push_str(&format!("{{{}}}", l.join(",")));
+        }
+        self.out.push_str(&format!(" {}\n", value));
+    }
+}
+
+fn escape(v: &str) -> String {
+    v.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn bool_value(v: bool) -> f64 {
+    if v { 1.0 } else { 0.0 }
+}
+
+///
+/// 生成/metrics的返回内容
+pub fn render(db: &DbInfo, metrics: &ServerMetrics) -> Result<String, Box<dyn Error>> {
+    let mut out = MetricsText{ out: String::new() };
+    write_nodes(db, &mut out)?;
+    write_monitor(db, &mut out)?;
+    write_change_log(db, &mut out)?;
+    write_routes(db, &mut out)?;
+    metrics.write(&mut out)?;
+    write_storage(db, &mut out)?;
+    Ok(out.out)
+}
+
+fn write_nodes(db: &DbInfo, out: &mut MetricsText) -> Result<(), Box<dyn Error>> {
+    let nodes: Vec<HostInfoValue> = Repo::<HostInfoValue>::new(db).all()?.into_iter().map(|r| r.value).collect();
+    let states = Repo::<MysqlState>::new(db);
+    let mut rows = vec![];
+    for node in &nodes {
+        rows.push((node, states.get(&node.host)?.unwrap_or(MysqlState::new())));
+    }
+
+    let gauges: [(&str, &str, fn(&HostInfoValue, &MysqlState) -> f64); 7] = [
+        ("mymha_node_online", "Whether the node client is online", |n, _| bool_value(n.online)),
+        ("mymha_node_maintain", "Whether the node is in maintenance mode", |n, _| bool_value(n.maintain)),
+        ("mymha_mysql_up", "Whether mysql is online", |_, s| bool_value(s.online)),
+        ("mymha_mysql_read_only", "Value of read_only", |_, s| bool_value(s.read_only)),
+        ("mymha_mysql_seconds_behind_master", "Seconds_Behind_Master of slave", |_, s| s.seconds_behind as f64),
+        ("mymha_mysql_slave_sql_running", "Whether the slave sql thread is running", |_, s| bool_value(s.sql_thread)),
+        ("mymha_mysql_slave_io_running", "Whether the slave io thread is running", |_, s| bool_value(s.io_thread)),
+    ];
+    for (name, help, f) in gauges.iter() {
+        out.family(name, "gauge", help);
+        for (node, state) in &rows {
+            out.sample(name, &[("cluster", &node.cluster_name), ("host", &node.host)], f(node, state));
+        }
+    }
+    out.family("mymha_node_role", "gauge", "Replication role of the node, value is always 1");
+    for (node, state) in &rows {
+        if state.role.len() == 0 {continue;}
+        out.sample("mymha_node_role", &[("cluster", &node.cluster_name), ("host", &node.host), ("role", &state.role)], 1.0);
+    }
+    Ok(())
+}
+
+///
+/// 已开启监控节点的最新一次监控数据
+fn write_monitor(db: &DbInfo, out: &mut MetricsText) -> Result<(), Box<dyn Error>> {
+    let clusters: BTreeMap<String, String> = Repo::<HostInfoValue>::new(db).all()?.into_iter()
+        .map(|r| (r.value.host, r.value.cluster_name)).collect();
+    let mut rows = vec![];
+    for setting in Repo::<MonitorSetting>::new(db).all()? {
+        if !setting.value.monitor {continue;}
+        let host = setting.value.host;
+        let result = db.prefix_get(&PrefixTypeCode::NodeMonitorLast, &host)?;
+        if result.value.len() == 0 {continue;}
+        let value: MysqlMonitorStatus = serde_json::from_str(&result.value)?;
+        let cluster = clusters.get(&host).cloned().unwrap_or_default();
+        rows.push((cluster, host, value));
+    }
+
+    out.family("mymha_mysql_status", "gauge", "Latest monitored status variable, counters are per second rates");
+    for (cluster, host, value) in &rows {
+        for (variable, v) in &value.values {
+            out.sample("mymha_mysql_status", &[("cluster", cluster), ("host", host), ("variable", variable)], *v as f64);
+        }
+    }
+    out.family("mymha_mysql_status_time_seconds", "gauge", "Time of the latest monitored status");
+    for (cluster, host, value) in &rows {
+        out.sample("mymha_mysql_status_time_seconds", &[("cluster", cluster), ("host", host)], value.time as f64 / 1000.0);
+    }
+    Ok(())
+}
+
+///
+/// 保留的切换日志条数及耗时， kind为failover、switchover， 旧版本日志没有记录时间不参与耗时统计
+fn write_change_log(db: &DbInfo, out: &mut MetricsText) -> Result<(), Box<dyn Error>> {
+    let mut counts: BTreeMap<(String, String, &str), u64> = BTreeMap::new();
+    let mut durations: BTreeMap<(String, String, &str), Histogram> = BTreeMap::new();
+    for row in Repo::<HaChangeLog>::new(db).all()? {
+        let log = row.value;
+        let key = (log.cluster_name.clone(), log.kind.clone(), result_label(log.switch_status));
+        if let Some(d) = log.duration_secs() {
+            durations.entry(key.clone()).or_insert(Histogram::new(&SWITCH_BUCKETS)).observe(d);
+        }
+        *counts.entry(key).or_insert(0) += 1;
+    }
+    out.family("mymha_ha_change_log_records", "gauge", "Number of retained switch logs");
+    for ((cluster, kind, result), v) in &counts {
+        out.sample("mymha_ha_change_log_records", &[("cluster", cluster), ("kind", kind), ("result", result)], *v as f64);
+    }
+    out.family("mymha_ha_change_duration_seconds", "histogram", "Duration of retained switch logs");
+    for ((cluster, kind, result), h) in &durations {
+        h.write(out, "mymha_ha_change_duration_seconds", &[("cluster", cluster), ("kind", kind), ("result", result)]);
+    }
+    Ok(())
+}
+
+fn write_routes(db: &DbInfo, out: &mut MetricsText) -> Result<(), Box<dyn Error>> {
+    let routes: Vec<RouteInfo> = Repo::<RouteInfo>::new(db).all()?.into_iter().map(|r| r.value).collect();
+    out.family("mymha_route_readers", "gauge", "Number of nodes in the read route");
+    for r in &routes {
+        out.sample("mymha_route_readers", &[("cluster", &r.cluster_name)], r.read.len() as f64);
+    }
+    out.family("mymha_route_writer_available", "gauge", "Whether the write route has a node");
+    for r in &routes {
+        out.sample("mymha_route_writer_available", &[("cluster", &r.cluster_name)], bool_value(r.write.host.len() > 0));
+    }
+    out.family("mymha_route_degraded", "gauge", "Whether the read route is degraded by read policy");
+    for r in &routes {
+        out.sample("mymha_route_degraded", &[("cluster", &r.cluster_name)], bool_value(r.degraded));
+    }
+    out.family("mymha_route_generation", "counter", "Number of route changes");
+    for r in &routes {
+        out.sample("mymha_route_generation", &[("cluster", &r.cluster_name)], r.generation as f64);
+    }
+    Ok(())
+}
+
+fn write_storage(db: &DbInfo, out: &mut MetricsText) -> Result<(), Box<dyn Error>> {
+    let stats = db.storage.stats()?;
+    out.family("mymha_rocksdb_property", "gauge", "RocksDB column family property");
+    for (cf, property, v) in &stats.properties {
+        out.sample("mymha_rocksdb_property", &[("cf", cf), ("property", property)], *v as f64);
+    }
+    out.family("mymha_rocksdb_ticker_total", "counter", "RocksDB statistics ticker");
+    for (ticker, v) in &stats.tickers {
+        out.sample("mymha_rocksdb_ticker_total", &[("ticker", ticker)], *v as f64);
+    }
+    out.family("mymha_rocksdb_histogram", "summary", "RocksDB statistics histogram");
+    for (name, quantiles, count, sum) in &stats.histograms {
+        for (q, v) in quantiles {
+            out.sample("mymha_rocksdb_histogram", &[("name", name), ("quantile", &q.to_string())], *v);
+        }
+        out.sample("mymha_rocksdb_histogram_sum", &[("name", name)], *sum as f64);
+        out.sample("mymha_rocksdb_histogram_count", &[("name", name)], *count as f64);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::opdb::HA_LOG_SWITCHOVER;
+
+    #[test]
+    fn change_log_by_kind() {
+        let db = DbInfo::memory();
+        let mut failover = HaChangeLog::new();
+        failover.key = "127.0.0.1:9011".to_string();
+        failover.cluster_name = "c1".to_string();
+        failover.switch_status = true;
+        failover.start_time -= 3000;
+        failover.save(&db).unwrap();
+        let mut switchover = HaChangeLog::new();
+        switchover.kind = HA_LOG_SWITCHOVER.to_string();
+        switchover.key = "127.0.0.1:9012".to_string();
+        switchover.cluster_name = "c1".to_string();
+        switchover.save(&db).unwrap();
+        //旧版本日志只计数
+        let mut legacy = HaChangeLog::new();
+        legacy.key = "127.0.0.1:9013".to_string();
+        legacy.cluster_name = "c1".to_string();
+        legacy.start_time = 0;
+        legacy.save(&db).unwrap();
+
+        let mut out = MetricsText{ out: String::new() };
+        write_change_log(&db, &mut out).unwrap();
+        let text = out.out;
+        assert!(text.contains("mymha_ha_change_log_records{cluster=\"c1\",kind=\"failover\",result=\"success\"} 1\n"));
+        assert!(text.contains("mymha_ha_change_log_records{cluster=\"c1\",kind=\"failover\",result=\"failed\"} 1\n"));
+        assert!(text.contains("mymha_ha_change_log_records{cluster=\"c1\",kind=\"switchover\",result=\"failed\"} 1\n"));
+        assert!(text.contains("mymha_ha_change_duration_seconds_count{cluster=\"c1\",kind=\"failover\",result=\"success\"} 1\n"));
+        assert!(text.contains("mymha_ha_change_duration_seconds_bucket{cluster=\"c1\",kind=\"failover\",result=\"success\",le=\"2\"} 0\n"));
+        assert!(text.contains("mymha_ha_change_duration_seconds_bucket{cluster=\"c1\",kind=\"failover\",result=\"success\",le=\"5\"} 1\n"));
+        assert!(!text.contains("mymha_ha_change_duration_seconds_count{cluster=\"c1\",kind=\"failover\",result=\"failed\"}"));
+    }
+}
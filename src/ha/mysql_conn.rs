@@ -0,0 +1,168 @@
+/*
+@author: xiao cai niao
+@datetime: 2020/09/07
+*/
+
+//! server端使用的简单mysql连接
+//!
+//! 只实现握手认证及执行不关心返回结果的语句， 用于直连检查及ProxySQL管理接口
+
+use std::net::{TcpStream, SocketAddr, Shutdown};
+use std::io::{Read, Write};
+use std::error::Error;
+use std::time::Duration;
+use mysql_common::constants::CapabilityFlags;
+use mysql_common::packets::{parse_handshake_packet, parse_auth_switch_request, parse_err_packet, AuthPlugin, HandshakeResponse};
+
+pub struct MysqlConn {
+    conn: TcpStream,
+    seq: u8,
+    capabilities: CapabilityFlags,
+    responded: bool,
+}
+
+impl MysqlConn {
+    ///
+    /// 连接超时2秒， 读写超时5秒
+    pub fn connect(addr: &String, user: &String, password: &String) -> Result<MysqlConn, Box<dyn Error>> {
+        let mut my = MysqlConn::open(addr)?;
+        my.handshake(user, password)?;
+        Ok(my)
+    }
+
+    ///
+    /// 只建立tcp连接， 之后调用handshake认证
+    pub fn open(addr: &String) -> Result<MysqlConn, Box<dyn Error>> {
+        let addrs: SocketAddr = addr.parse()?;
+        let conn = TcpStream::connect_timeout(&addrs, Duration::new(2,0))?;
+        conn.set_read_timeout(Some(Duration::new(5,0)))?;
+        conn.set_write_timeout(Some(Duration::new(5,0)))?;
+        Ok(MysqlConn{ conn, seq: 0, capabilities: CapabilityFlags::empty(), responded: false })
+    }
+
+    ///
+    /// 是否收到过mysql返回的数据包， 包括握手阶段的错误包
+    pub fn responded(&self) -> bool {
+        self.responded
+    }
+
+    ///
+    /// 执行一条语句， 返回结果集时读取并丢弃
+    pub fn execute(&mut self, sql: &str) -> Result<(), Box<dyn Error>> {
+        self.seq = 0;
+        let mut buf = vec![0x03u8];
+        buf.extend(sql.as_bytes());
+        self.write_packet(&buf)?;
+        let payload = self.read_packet()?;
+        match payload.get(0) {
+            Some(0x00) => return Ok(()),
+            Some(0xff) => return Err(self.err_packet(&payload)),
+            _ => {}
+        }
+        //结果集: 列定义 EOF 数据行 EOF
+        let mut eof = 0;
+        while eof < 2 {
+            let payload = self.read_packet()?;
+            match payload.get(0) {
+                Some(0xff) => return Err(self.err_packet(&payload)),
+                Some(0xfe) if payload.len() < 9 => eof += 1,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    pub fn handshake(&mut self, user: &String, password: &String) -> Result<(), Box<dyn Error>> {
+        let payload = self.read_packet()?;
+        if payload.len() > 0 && payload[0] == 0xff {
+            return Err(self.err_packet(&payload));
+        }
+        let handshake = parse_handshake_packet(&payload)?;
+        let nonce = handshake.nonce();
+        let plugin = match handshake.auth_plugin() {
+            Some(p) => p.clone().into_owned(),
+            None => AuthPlugin::MysqlNativePassword
+        };
+        let version = handshake.server_version_parsed().unwrap_or((5, 7, 0));
+        self.capabilities = handshake.capabilities() & (CapabilityFlags::CLIENT_PROTOCOL_41
+            | CapabilityFlags::CLIENT_SECURE_CONNECTION
+            | CapabilityFlags::CLIENT_LONG_PASSWORD
+            | CapabilityFlags::CLIENT_TRANSACTIONS
+            | CapabilityFlags::CLIENT_PLUGIN_AUTH);
+        let scramble = plugin.gen_data(Some(password), &nonce);
+        let response = HandshakeResponse::new(&scramble, version, Some(user), None,
+                                              plugin, self.capabilities);
+        self.write_packet(response.as_ref())?;
+        self.auth_result(password)
+    }
+
+    ///
+    /// 处理认证返回， 支持切换认证插件及caching_sha2_password快速认证
+    fn auth_result(&mut self, password: &String) -> Result<(), Box<dyn Error>> {
+        loop {
+            let payload = self.read_packet()?;
+            match payload.get(0) {
+                Some(0x00) => return Ok(()),
+                Some(0xff) => return Err(self.err_packet(&payload)),
+                Some(0xfe) => {
+                    let switch = parse_auth_switch_request(&payload)?;
+                    let mut nonce = switch.plugin_data().to_vec();
+                    if nonce.len() > 20 {
+                        nonce.truncate(20);
+                    }
+                    let data = switch.auth_plugin().gen_data(Some(password), &nonce)
+                        .unwrap_or(vec![]);
+                    self.write_packet(&data)?;
+                }
+                Some(0x01) => {
+                    //0x03快速认证成功， 之后还会返回OK包
+                    if payload.get(1) != Some(&0x03) {
+                        let err = "caching_sha2_password full authentication requires ssl, please use mysql_native_password";
+                        return Err(err.into());
+                    }
+                }
+                _ => {
+                    return Err("invalid auth response packet".into());
+                }
+            }
+        }
+    }
+
+    fn read_packet(&mut self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut header = vec![0u8; 4];
+        self.conn.read_exact(&mut header)?;
+        let payload = crate::readvalue::read_u24(&header[..3]);
+        self.seq = header[3].wrapping_add(1);
+        let mut buf = vec![0u8; payload as usize];
+        self.conn.read_exact(&mut buf)?;
+        self.responded = true;
+        Ok(buf)
+    }
+
+    fn write_packet(&mut self, payload: &[u8]) -> Result<(), Box<dyn Error>> {
+        let mut buf = crate::readvalue::write_u24(payload.len() as u32);
+        buf.push(self.seq);
+        buf.extend(payload);
+        self.conn.write_all(&buf)?;
+        self.conn.flush()?;
+        self.seq = self.seq.wrapping_add(1);
+        Ok(())
+    }
+
+    fn err_packet(&self, payload: &[u8]) -> Box<dyn Error> {
+        match parse_err_packet(payload, self.capabilities | CapabilityFlags::CLIENT_PROTOCOL_41) {
+            Ok(e) => e.to_string().into(),
+            Err(e) => e.into()
+        }
+    }
+}
+
+impl Drop for MysqlConn {
+    ///
+    /// 发送COM_QUIT， 不关心返回
+    fn drop(&mut self) {
+        self.seq = 0;
+        let _ = self.write_packet(&[0x01u8]);
+        let _ = self.conn.shutdown(Shutdown::Both);
+    }
+}
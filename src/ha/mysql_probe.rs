@@ -0,0 +1,167 @@
+/*
+@author: xiao cai niao
+@datetime: 2020/08/26
+*/
+
+//! server直连mysql做二次检查
+//!
+//! client无响应时仅凭client复检结果无法区分是client挂起还是mysql宕机，
+//! 开启后在写入CheckState之前由server使用监控账号直接握手并执行SELECT 1
+
+use std::error::Error;
+use serde::{Serialize, Deserialize};
+use crate::storage::rocks::{DbInfo, PrefixTypeCode};
+use crate::ha::mysql_conn::MysqlConn;
+
+///
+/// 直连检查配置， 全局一份
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ProbeSetting {
+    pub enable: bool,
+    pub user: String,
+    pub password: String,
+}
+
+impl ProbeSetting {
+    pub fn new() -> ProbeSetting {
+        ProbeSetting{
+            enable: false,
+            user: "".to_string(),
+            password: "".to_string()
+        }
+    }
+
+    ///
+    /// 查询时不返回password， 保存时为空则沿用已保存的值
+    pub fn keep_secret(&mut self, old: &ProbeSetting) {
+        if self.password.len() == 0 {
+            self.password = old.password.clone();
+        }
+    }
+
+    pub fn save(&self, db: &DbInfo) -> Result<(), Box<dyn Error>> {
+        db.prefix_put(&PrefixTypeCode::MysqlProbeSeting, &"probe".to_string(), &self)?;
+        Ok(())
+    }
+}
+
+impl DbInfo {
+    ///
+    /// 获取直连检查配置， 未配置时默认关闭
+    pub fn get_probe_setting(&self) -> Result<ProbeSetting, Box<dyn Error>> {
+        let result = self.prefix_get(&PrefixTypeCode::MysqlProbeSeting, &"probe".to_string())?;
+        if result.value.len() > 0 {
+            let value: ProbeSetting = serde_json::from_str(&result.value)?;
+            return Ok(value);
+        }
+        Ok(ProbeSetting::new())
+    }
+}
+
+///
+/// 直连检查结果
+///
+/// 只要收到mysql返回的数据包(包括认证失败等错误包)就说明mysqld还在运行， 与连接失败区分开
+#[derive(Debug, PartialEq)]
+pub enum ProbeState {
+    Disabled,           //未开启
+    Up,                 //握手及SELECT 1成功
+    Responded(String),  //mysql有返回但认证或执行失败
+    Unreachable(String),//连接失败或超时， 没有收到任何数据包
+}
+
+///
+/// 对节点mysql做一次直连检查， host为节点key(ip:client端口)
+pub fn probe(db: &DbInfo, host: &String, dbport: usize) -> ProbeState {
+    let setting = match db.get_probe_setting() {
+        Ok(v) => v,
+        Err(e) => {
+            info!("get probe setting failed: {}", e.to_string());
+            return ProbeState::Disabled;
+        }
+    };
+    if !setting.enable {
+        return ProbeState::Disabled;
+    }
+    let ip = host.split(":").collect::<Vec<&str>>()[0];
+    let addr = format!("{}:{}", ip, dbport);
+    probe_addr(&addr, &setting)
+}
+
+fn probe_addr(addr: &String, setting: &ProbeSetting) -> ProbeState {
+    let mut conn = match MysqlConn::open(addr) {
+        Ok(c) => c,
+        Err(e) => {
+            info!("probe mysql {} failed: {}", addr, e.to_string());
+            return ProbeState::Unreachable(e.to_string());
+        }
+    };
+    let state = conn.handshake(&setting.user, &setting.password)
+        .and_then(|_| conn.execute("SELECT 1"));
+    match state {
+        Ok(()) => ProbeState::Up,
+        Err(e) => {
+            info!("probe mysql {} failed: {}", addr, e.to_string());
+            if conn.responded() {
+                ProbeState::Responded(e.to_string())
+            } else {
+                ProbeState::Unreachable(e.to_string())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn setting() -> ProbeSetting {
+        ProbeSetting{ enable: true, user: "monitor".to_string(), password: "xxx".to_string() }
+    }
+
+    #[test]
+    fn unreachable_when_connect_failed() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        match probe_addr(&addr, &setting()) {
+            ProbeState::Unreachable(_) => {}
+            v => panic!("unexpected probe state: {:?}", v)
+        }
+    }
+
+    #[test]
+    fn responded_when_server_returns_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            //握手阶段直接返回错误包， 如Too many connections
+            let mut payload = vec![0xffu8, 0x10, 0x04];
+            payload.extend(b"#08004Too many connections");
+            let mut buf = crate::readvalue::write_u24(payload.len() as u32);
+            buf.push(0);
+            buf.extend(payload);
+            conn.write_all(&buf).unwrap();
+        });
+        match probe_addr(&addr, &setting()) {
+            ProbeState::Responded(e) => assert!(e.contains("Too many connections"), "{}", e),
+            v => panic!("unexpected probe state: {:?}", v)
+        }
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn keep_password_when_empty() {
+        let old = setting();
+        let mut new = ProbeSetting{ enable: true, user: "monitor".to_string(), password: "".to_string() };
+        new.keep_secret(&old);
+        assert_eq!(new.password, "xxx");
+        new.password = "yyy".to_string();
+        new.keep_secret(&old);
+        assert_eq!(new.password, "yyy");
+    }
+}
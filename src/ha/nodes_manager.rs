@@ -14,7 +14,10 @@ use crate::ha::procotol::{DownNodeCheckStatus, MyProtocol, ReplicationState, Dow
 use std::{thread, time};
 use std::time::Duration;
 use serde::{Serialize, Deserialize};
-use crate::storage::opdb::HaChangeLog;
+use crate::storage::opdb::{HaChangeLog, HA_LOG_SWITCHOVER};
+use crate::ha::mysql_probe::{self, ProbeState};
+use crate::ha::vip;
+use crate::ha::alert;
 
 
 ///
@@ -78,14 +81,14 @@ impl DifferenceSql{
         })
     }
 
-    fn alter_cluster(&mut self, db: &web::Data<DbInfo>) -> Result<(), Box<dyn Error>> {
+    fn alter_cluster(&mut self, db: &DbInfo) -> Result<(), Box<dyn Error>> {
         let result = db.get(&self.host, &CfNameTypeCode::HaNodesInfo.get())?;
         let info: HostInfoValue = serde_json::from_str(&result.value).unwrap();
         self.cluster = info.cluster_name;
         Ok(())
     }
 
-    pub fn save(&mut self, db: &web::Data<DbInfo>) -> Result<(), Box<dyn Error>> {
+    pub fn save(&mut self, db: &DbInfo) -> Result<(), Box<dyn Error>> {
         if self.etype == "append".to_string(){return Ok(())}
         self.alter_cluster(db)?;
         return self.save_key(db);
@@ -93,7 +96,7 @@ impl DifferenceSql{
 
     ///
     /// 执行sql或确认之后进行修改，同时对整次过程产生的数据进行判断是否已全部处理
-    pub fn alter(&mut self, db: &web::Data<DbInfo>, number: &u64) -> Result<(), Box<dyn Error>>{
+    pub fn alter(&mut self, db: &DbInfo, number: &u64) -> Result<(), Box<dyn Error>>{
         if self.status == 1{return Ok(());};
         let mut total_c = 0 as usize;
         for r in &mut self.sqls{
@@ -113,7 +116,7 @@ impl DifferenceSql{
         return self.save_key(db);
     }
 
-    fn save_key(&self, db: &web::Data<DbInfo>) -> Result<(), Box<dyn Error>>{
+    fn save_key(&self, db: &DbInfo) -> Result<(), Box<dyn Error>>{
         let key = format!("{}:{}_{}", &self.cluster, &self.host, &self.time);
         db.prefix_put(&PrefixTypeCode::RollBackSql, &key, &self)?;
         Ok(())
@@ -132,10 +135,12 @@ pub struct CheckState {
     pub db_down: bool,
     pub client_down: bool,
     pub role: String,
+    #[serde(default)]
+    pub probe: String,          //server直连检查结果， up、responded、down， 未开启为空
 }
 impl CheckState {
     fn new(all_nodes: usize) -> CheckState{
-        CheckState{ db_offline: 0, client_offline: 0, all_nodes, db_down: false, client_down: false, role: "".to_string() }
+        CheckState{ db_offline: 0, client_offline: 0, all_nodes, db_down: false, client_down: false, role: "".to_string(), probe: "".to_string() }
     }
     fn check(&mut self, state: &DownNodeCheckStatus) {
         if state.host.len() > 0 {
@@ -155,20 +160,20 @@ impl CheckState {
         }
     }
 
-    fn update_db(&self, db: &web::Data<DbInfo>, key: &String) -> Result<(), Box<dyn Error>> {
+    fn update_db(&self, db: &DbInfo, key: &String) -> Result<(), Box<dyn Error>> {
         let value = serde_json::to_string(&self)?;
         let a = KeyValue{key: key.clone(), value: (&value).parse()? };
         db.put(&a, &CfNameTypeCode::CheckState.get())?;
         Ok(())
     }
 
-    fn delete_from_db(&self, db: &web::Data<DbInfo>, key: &String) {
+    fn delete_from_db(&self, db: &DbInfo, key: &String) {
         if let Err(e) = db.delete(key, &CfNameTypeCode::CheckState.get()){
             info!("{:?}",e.to_string());
         };
     }
 
-    fn is_slave(&self, db: &web::Data<DbInfo>, key: &String) -> Result<bool, Box<dyn Error>> {
+    fn is_slave(&self, db: &DbInfo, key: &String) -> Result<bool, Box<dyn Error>> {
         let result = db.get(key, &CfNameTypeCode::NodesState.get())?;
         let value: MysqlState = serde_json::from_str(&result.value)?;
         info!("{:?}", &value);
@@ -178,7 +183,7 @@ impl CheckState {
         return Ok(true);
     }
 
-    fn is_client_down(&self, db: &web::Data<DbInfo>, key: &String) -> Result<bool, Box<dyn Error>> {
+    fn is_client_down(&self, db: &DbInfo, key: &String) -> Result<bool, Box<dyn Error>> {
         let result = db.get(key, &CfNameTypeCode::CheckState.get())?;
         let value: CheckState = serde_json::from_str(&result.value)?;
         if value.db_down {
@@ -202,12 +207,26 @@ pub fn manager(db: web::Data<DbInfo>,  rec: mpsc::Receiver<DownNodeInfo>){
             //let nodes = crate::ha::get_nodes_info(&db);
             let down_node = procotol::DownNodeCheck::new(r.host, r.dbport);
             let mut elc = ElectionMaster::new(r.cluster_name.clone(), down_node);
-            if let Err(e) = elc.election(&db){
-                if let Err(er) = elc.ha_log.save(&db){
-                    info!("{}", er.to_string());
-                };
-                info!("{}", e.to_string());
-            };
+            match elc.election(&db) {
+                Ok(()) => {
+                    //宕机的是slave或只有client宕机时不进行切换
+                    if elc.ha_log.switch_status {
+                        let new_master = elc.slave_nodes.iter().find(|s| s.new_master).map(|s| s.host.clone()).unwrap_or_default();
+                        alert::push_event(&db, alert::FAILOVER_FINISHED, &elc.cluster_name, &elc.down_node_info.host,
+                                          format!("master {} is down, switch to {} success", &elc.down_node_info.host, new_master));
+                    }
+                }
+                Err(e) => {
+                    if elc.check_state.role == "master" {
+                        alert::push_event(&db, alert::FAILOVER_FAILED, &elc.cluster_name, &elc.down_node_info.host,
+                                          format!("master {} is down, switch failed: {}", &elc.down_node_info.host, e.to_string()));
+                    }
+                    if let Err(er) = elc.ha_log.save(&db){
+                        info!("{}", er.to_string());
+                    };
+                    info!("{}", e.to_string());
+                }
+            }
         }else {
             info!("host: {} is running...", &r.host);
             let state = CheckState::new(0);
@@ -261,7 +280,7 @@ impl RecoveryDownNode {
         }
     }
 
-    fn recovery(&mut self, db: &web::Data<DbInfo>) -> Result<(), Box<dyn Error>> {
+    fn recovery(&mut self, db: &DbInfo) -> Result<(), Box<dyn Error>> {
         self.get_recovery_info(db)?;
         if !self.ha_log.switch_status{
             info!("when the machine({}) was shut down during the year, the switchover failed, and the recovery operation could not be performed",&self.host);
@@ -284,24 +303,15 @@ impl RecoveryDownNode {
         Ok(())
     }
 
-    fn get_recovery_info(&mut self, db: &web::Data<DbInfo>) -> Result<(), Box<dyn Error>> {
-        let result = db.prefix_iterator(&self.host, &CfNameTypeCode::HaChangeLog.get())?;
-        let mut tmp = vec![];
-        for row in result {
-            if row.key.starts_with(&self.host){
-                tmp.push(row);
-            }
-        }
-        if tmp.len() > 0 {
-            tmp.sort_by(|a, b| b.key.cmp(&a.key));
-            let value: HaChangeLog = serde_json::from_str(&tmp[0].value)?;
-            self.ha_log_key = tmp[0].key.clone();
+    fn get_recovery_info(&mut self, db: &DbInfo) -> Result<(), Box<dyn Error>> {
+        if let Some((key, value)) = db.get_last_failover_log(&self.host)? {
+            self.ha_log_key = key;
             self.ha_log = value;
         }
         Ok(())
     }
 
-    fn update_state(&mut self, db: &web::Data<DbInfo>) -> Result<(), Box<dyn Error>> {
+    fn update_state(&mut self, db: &DbInfo) -> Result<(), Box<dyn Error>> {
         self.ha_log.recovery_status = true;
         self.ha_log.update(db, self.ha_log_key.clone())?;
         Ok(())
@@ -333,7 +343,8 @@ impl ElectionMaster {
                 all_nodes: 0,
                 db_down: false,
                 client_down: false,
-                role: "".to_string()
+                role: "".to_string(),
+                probe: "".to_string()
             },
             slave_nodes: vec![],
             ha_log: HaChangeLog::new()
@@ -344,9 +355,10 @@ impl ElectionMaster {
         return el;
     }
 
-    fn election(&mut self, db: &web::Data<DbInfo>) -> Result<(), Box<dyn Error>> {
+    fn election(&mut self, db: &DbInfo) -> Result<(), Box<dyn Error>> {
         let result = db.iterator(&CfNameTypeCode::HaNodesInfo.get(),&String::from(""))?;
         self.check_downnode_status(&result)?;
+        self.check_probe(db);
         self.check_state.update_db(db, &self.down_node_info.host)?;
         info!("{:?}", self.check_state);
         let check_master = self.is_master(db);
@@ -356,6 +368,8 @@ impl ElectionMaster {
                     info!("host: {} is slave, exece change route info...",&self.down_node_info.host);
                     return Ok(())
                 }
+                alert::push_event(db, alert::FAILOVER_STARTED, &self.cluster_name, &self.down_node_info.host,
+                                  format!("master {} is down, start switch", &self.down_node_info.host));
             }
             Err(e) => {
                 return Err(e.into())
@@ -370,7 +384,7 @@ impl ElectionMaster {
         Ok(())
     }
 
-    fn save_ha_log(&mut self, db: &web::Data<DbInfo>) -> Result<(), Box<dyn Error>>{
+    fn save_ha_log(&mut self, db: &DbInfo) -> Result<(), Box<dyn Error>>{
         self.ha_log.recovery_status = false;
         self.ha_log.switch_status = true;
         self.ha_log.save(db)?;
@@ -433,12 +447,44 @@ impl ElectionMaster {
         Ok(())
     }
 
+    ///
+    /// 使用server直连检查结果修正复检状态
+    ///
+    /// 直连成功或mysql有返回(如认证失败)说明mysqld还在运行， 只是client故障不做切换
+    /// 只有连接失败且没有节点参与复检时以直连结果为准
+    fn check_probe(&mut self, db: &DbInfo) {
+        let state = mysql_probe::probe(db, &self.down_node_info.host, self.down_node_info.dbport);
+        self.apply_probe(state);
+    }
+
+    fn apply_probe(&mut self, state: ProbeState) {
+        match state {
+            ProbeState::Up => {
+                info!("host {} mysql is alive by direct probe", &self.down_node_info.host);
+                self.check_state.probe = "up".to_string();
+                self.check_state.db_down = false;
+            }
+            ProbeState::Responded(e) => {
+                info!("host {} mysql responded to direct probe: {}", &self.down_node_info.host, e);
+                self.check_state.probe = "responded".to_string();
+                self.check_state.db_down = false;
+            }
+            ProbeState::Unreachable(_) => {
+                self.check_state.probe = "down".to_string();
+                if self.check_state.all_nodes == 0 {
+                    self.check_state.db_down = true;
+                }
+            }
+            ProbeState::Disabled => {}
+        }
+    }
+
     ///
     /// 获取可以做选举的slave节点信息
     ///
     /// 宕机、维护状态、slave线程非正常状态的节点不能做为候选
     /// 且不会对这些状态的节点进行切换操作
-    fn get_slave_nodes(&mut self, db: &web::Data<DbInfo>, result: &Vec<KeyValue>) -> Result<(), Box<dyn Error>> {
+    fn get_slave_nodes(&mut self, db: &DbInfo, result: &Vec<KeyValue>) -> Result<(), Box<dyn Error>> {
         for nodes in result{
             let state: HostInfoValue = serde_json::from_str(&nodes.value)?;
             if nodes.key != self.down_node_info.host{
@@ -470,7 +516,7 @@ impl ElectionMaster {
     ///
     /// 执行切换操作
     /// 
-    fn change(&mut self, db: &web::Data<DbInfo>) -> Result<(), Box<dyn Error>> {
+    fn change(&mut self, db: &DbInfo) -> Result<(), Box<dyn Error>> {
         if self.check_state.db_down {
             // mysql实例宕机
             let change_master_info = self.elc_new_master()?;
@@ -486,6 +532,7 @@ impl ElectionMaster {
                 self.reacquire_recovery_info()?;
                 self.execute_switch_master(db, &change_master_info)?;
             }
+            self.move_vip(db);
 
             if let Err(e) = self.save_ha_log(db){
                 info!("{:?}", e.to_string());
@@ -535,7 +582,7 @@ impl ElectionMaster {
         Ok(())
     }
 
-    fn execute_switch_master(&mut self, db: &web::Data<DbInfo>, change_info: &ChangeMasterInfo) -> Result<(), Box<dyn Error>> {
+    fn execute_switch_master(&mut self, db: &DbInfo, change_info: &ChangeMasterInfo) -> Result<(), Box<dyn Error>> {
         for slave in &self.slave_nodes{
             if slave.new_master {
                 info!("send to new master:{}....",&slave.host);
@@ -556,6 +603,15 @@ impl ElectionMaster {
         Ok(())
     }
 
+    ///
+    /// 切换完成后将vip漂移到新master
+    fn move_vip(&mut self, db: &DbInfo) {
+        let new_master = self.slave_nodes.iter().find(|s| s.new_master).map(|s| s.host.clone());
+        if let Some(new_master) = new_master {
+            self.ha_log.vip = vip::move_vip(db, vip::VIP_FAILOVER, &self.cluster_name, &self.down_node_info.host, &new_master);
+        }
+    }
+
     ///
     /// 通过read_binlog信息选举新master
     /// 
@@ -583,7 +639,7 @@ impl ElectionMaster {
         return Ok(cm);
     }
 
-    fn is_master(&mut self, db: &web::Data<DbInfo>) -> Result<bool, Box<dyn Error>> {
+    fn is_master(&mut self, db: &DbInfo) -> Result<bool, Box<dyn Error>> {
         if let Ok(r) = db.get(&self.down_node_info.host, &CfNameTypeCode::NodesState.get()){
             let state: MysqlState = serde_json::from_str(&r.value)?;
             if state.role == "master".to_string() {
@@ -621,7 +677,7 @@ pub struct SlaveInfo {
     pub new_master: bool,
 }
 impl SlaveInfo {
-    fn new(host: String, dbport: usize, db: &web::Data<DbInfo>) -> Result<SlaveInfo, Box<dyn Error>> {
+    fn new(host: String, dbport: usize, db: &DbInfo) -> Result<SlaveInfo, Box<dyn Error>> {
         let node_info = db.get(&host, &CfNameTypeCode::NodesState.get())?;
         let node_info: MysqlState = serde_json::from_str(&node_info.value)?;
         Ok(SlaveInfo {
@@ -671,7 +727,28 @@ impl SwitchForNodes {
         }
     }
 
-    pub fn switch(&mut self, db: &web::Data<DbInfo>) -> Result<(), Box<dyn Error>>{
+    ///
+    /// 执行切换并写入一条switchover类型的切换日志， 记录结果及耗时， 不需要宕机恢复
+    pub fn switch(&mut self, db: &DbInfo) -> Result<(), Box<dyn Error>>{
+        let mut ha_log = HaChangeLog::new();
+        ha_log.kind = HA_LOG_SWITCHOVER.to_string();
+        let result = self.exec_switch(db, &mut ha_log);
+        //节点不存在时不记录
+        if self.cluster_name.len() > 0 {
+            //未获取到旧master时以目标节点作为key
+            ha_log.key = if self.old_master_info.host.len() > 0 { self.old_master_info.host.clone() } else { self.host.clone() };
+            ha_log.cluster_name = self.cluster_name.clone();
+            ha_log.old_master_info = DownNodeCheck::new(self.old_master_info.host.clone(), self.old_master_info.dbport);
+            ha_log.switch_status = result.is_ok();
+            ha_log.recovery_status = true;
+            if let Err(e) = ha_log.save(db) {
+                info!("save switchover log failed: {}", e.to_string());
+            }
+        }
+        result
+    }
+
+    fn exec_switch(&mut self, db: &DbInfo, ha_log: &mut HaChangeLog) -> Result<(), Box<dyn Error>>{
         info!("start.....");
         let cf_name = CfNameTypeCode::HaNodesInfo.get();
         let node_info = db.get(&self.host, &cf_name)?;
@@ -687,6 +764,8 @@ impl SwitchForNodes {
             self.rollback_switch()?;
             return Err(e);
         };
+        //切换已经完成， vip漂移失败只记录在VipLog中并报警， 不影响切换结果
+        ha_log.vip = vip::move_vip(db, vip::VIP_SWITCHOVER, &self.cluster_name, &self.old_master_info.host, &self.host);
         info!("Ok");
         Ok(())
     }
@@ -694,7 +773,7 @@ impl SwitchForNodes {
     ///
     /// 检查节点状态是否能提升为master
     ///
-    fn check_host_status(&self, db: &web::Data<DbInfo>) -> Result<(), Box<dyn Error>> {
+    fn check_host_status(&self, db: &DbInfo) -> Result<(), Box<dyn Error>> {
         let result = db.get(&self.host, &CfNameTypeCode::HaNodesInfo.get())?;
         let node_state: HostInfoValue = serde_json::from_str(&result.value)?;
         if !node_state.online {
@@ -711,7 +790,7 @@ impl SwitchForNodes {
         Ok(())
     }
 
-    fn get_all_nodes_for_cluster_name(&mut self, db: &web::Data<DbInfo>, cf_name: &String) -> Result<(), Box<dyn Error>> {
+    fn get_all_nodes_for_cluster_name(&mut self, db: &DbInfo, cf_name: &String) -> Result<(), Box<dyn Error>> {
         let result = db.iterator(cf_name,&String::from(""))?;
         for row in result {
             let value: HostInfoValue = serde_json::from_str(&row.value)?;
@@ -816,7 +895,7 @@ impl SwitchForNodes {
 /// 检查节点是否为维护模式
 ///
 /// 只要集群中有存在维护模式的将无法执行切换操作
-fn check_mainatain(db: &web::Data<DbInfo>, key: &String) -> Result<(), Box<dyn Error>> {
+fn check_mainatain(db: &DbInfo, key: &String) -> Result<(), Box<dyn Error>> {
     let result = db.get(key, &CfNameTypeCode::HaNodesInfo.get())?;
     let node_state: HostInfoValue = serde_json::from_str(&result.value)?;
     if node_state.maintain {
@@ -829,3 +908,41 @@ fn check_mainatain(db: &web::Data<DbInfo>, key: &String) -> Result<(), Box<dyn E
 
 
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn election() -> ElectionMaster {
+        ElectionMaster::new("c1".to_string(), DownNodeCheck::new("127.0.0.1:9011".to_string(), 3306))
+    }
+
+    #[test]
+    fn probe_responded_keeps_master() {
+        let mut elc = election();
+        elc.apply_probe(ProbeState::Responded("Access denied for user 'monitor'".to_string()));
+        assert!(!elc.check_state.db_down);
+        assert_eq!(elc.check_state.probe, "responded");
+
+        //其他节点复检认为宕机， mysql有返回时仍不切换
+        let mut elc = election();
+        elc.check_state.all_nodes = 2;
+        elc.check_state.db_down = true;
+        elc.apply_probe(ProbeState::Responded("Too many connections".to_string()));
+        assert!(!elc.check_state.db_down);
+    }
+
+    #[test]
+    fn probe_unreachable_marks_down_without_peers() {
+        let mut elc = election();
+        elc.apply_probe(ProbeState::Unreachable("connection refused".to_string()));
+        assert!(elc.check_state.db_down);
+        assert_eq!(elc.check_state.probe, "down");
+
+        //有节点参与复检时以复检结果为准
+        let mut elc = election();
+        elc.check_state.all_nodes = 2;
+        elc.apply_probe(ProbeState::Unreachable("connection refused".to_string()));
+        assert!(!elc.check_state.db_down);
+    }
+}
@@ -6,6 +6,7 @@ use serde::Serialize;
 use serde::Deserialize;
 use std::net::TcpStream;
 use std::error::Error;
+use std::collections::BTreeMap;
 use std::io::{Read, Write};
 use crate::storage::opdb::HostInfoValue;
 use crate::ha::nodes_manager::SlaveInfo;
@@ -29,6 +30,11 @@ pub enum  MyProtocol {
     SetVariables,
     RecoveryVariables,
     Command,            //执行追加sql
+    Register,           //client主动注册
+    Heartbeat,          //client主动推送心跳及状态
+    VipAcquire,         //绑定vip并发送免费arp
+    VipRelease,         //释放vip
+    VipStatus,          //查询vip是否绑定在本机
     Ok,
     Error,
     UnKnow
@@ -73,6 +79,16 @@ impl MyProtocol {
             return MyProtocol::Ping;
         }else if code == &0x05 {
             return MyProtocol::Command;
+        }else if code == &0xf1 {
+            return MyProtocol::Register;
+        }else if code == &0xf0 {
+            return MyProtocol::Heartbeat;
+        }else if code == &0xef {
+            return MyProtocol::VipAcquire;
+        }else if code == &0xee {
+            return MyProtocol::VipRelease;
+        }else if code == &0xed {
+            return MyProtocol::VipStatus;
         }
         else {
             return MyProtocol::UnKnow;
@@ -100,18 +116,24 @@ impl MyProtocol {
             MyProtocol::RecoveryVariables => 0x03,
             MyProtocol::Ping => 0x01,
             MyProtocol::Command => 0x05,
+            MyProtocol::Register => 0xf1,
+            MyProtocol::Heartbeat => 0xf0,
+            MyProtocol::VipAcquire => 0xef,
+            MyProtocol::VipRelease => 0xee,
+            MyProtocol::VipStatus => 0xed,
             MyProtocol::UnKnow => 0xff
         }
     }
 
     ///
-    /// 从mysql节点获取监控信息
-    pub fn get_monitor(&self, host: &String) -> Result<MysqlMonitorStatus, Box<dyn Error>>{
-        let packet_value = Null::new();
+    /// 从mysql节点获取监控信息， 变量名统一转为小写
+    pub fn get_monitor(&self, host: &String, variables: &Vec<MonitorVariable>) -> Result<MysqlMonitorStatus, Box<dyn Error>>{
+        let packet_value = MonitorRequest{ default: 0, variables: variables.clone() };
         let packet = self.socket_io(host, &packet_value)?;
         match packet.type_code {
             MyProtocol::GetMonitor => {
-                let value: MysqlMonitorStatus = serde_json::from_slice(&packet.value)?;
+                let mut value: MysqlMonitorStatus = serde_json::from_slice(&packet.value)?;
+                value.values = value.values.into_iter().map(|(k, v)| (k.to_lowercase(), v)).collect();
                 return Ok(value);
             }
             MyProtocol::Error => {
@@ -225,6 +247,26 @@ impl MyProtocol {
         return Ok(v);
     }
 
+    ///
+    /// 查询vip在节点上的绑定状态
+    pub fn vip_status(&self, host: &String, info: &VipInfo) -> Result<VipState, Box<dyn Error>> {
+        let packet = self.socket_io(host, info)?;
+        match packet.type_code {
+            MyProtocol::VipStatus => {
+                let value: VipState = serde_json::from_slice(&packet.value)?;
+                return Ok(value);
+            }
+            MyProtocol::Error => {
+                let err: ReponseErr = serde_json::from_slice(&packet.value)?;
+                return Err(err.err.into());
+            }
+            _ => {
+                let a = format!("return invalid type code:{:?}", &packet.type_code);
+                return Err(a.into());
+            }
+        }
+    }
+
     ///
     /// 发送无数据内容的协议包
     /// 协议类型为自己
@@ -278,6 +320,18 @@ impl MyProtocol {
         return Ok(packet);
     }
 
+    ///
+    /// 读取client主动发送的数据包， 用于注册及心跳等client推送的协议
+    pub fn read_packet(conn: &mut TcpStream) -> Result<RecPacket, Box<dyn Error>> {
+        MyProtocol::UnKnow.rec_packet(conn)
+    }
+
+    ///
+    /// 回复client主动发送的数据包， 协议类型为自己
+    pub fn response<T: Serialize>(&self, conn: &TcpStream, value: &T) -> Result<(), Box<dyn Error>> {
+        self.send_value_packet(conn, value)
+    }
+
     fn send_value_packet<T: Serialize>(&self, mut tcp: &TcpStream, value: &T) -> Result<(), Box<dyn Error>> {
         let value = serde_json::to_string(value)?;
         let mut buf = self.header(value.len() as u64);
@@ -583,36 +637,77 @@ impl HostInfoValueGetAllState {
 
 ///
 /// mysql运行状态监控值
-#[derive(Deserialize, Serialize, Debug, Clone)]
+///
+/// key为小写的变量名， 保存时计数器为两次采集之间的每秒速率， gauge为采集时的值，
+/// 序列化后与旧版本的固定字段格式相同， 旧版本client返回的数据及已保存的数据可以直接读取
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
 pub struct MysqlMonitorStatus{
-    pub com_insert: usize,
-    pub com_update: usize,
-    pub com_delete: usize,
-    pub com_select: usize,
-    pub questions: usize,
-    pub innodb_row_lock_current_waits: usize,
-    pub innodb_row_lock_time: usize,
-    pub created_tmp_disk_tables: usize,
-    pub created_tmp_tables: usize,
-    pub innodb_buffer_pool_reads: usize,
-    pub innodb_buffer_pool_read_requests: usize,
-    pub handler_read_first: usize,
-    pub handler_read_key: usize,
-    pub handler_read_next: usize,
-    pub handler_read_prev: usize,
-    pub handler_read_rnd: usize,
-    pub handler_read_rnd_next: usize,
-    pub innodb_os_log_pending_fsyncs: usize,
-    pub innodb_os_log_pending_writes: usize,
-    pub innodb_log_waits: usize,
-    pub threads_connected: usize,
-    pub threads_running: usize,
-    pub bytes_sent: usize,
-    pub bytes_received: usize,
-    pub slow_queries: usize,
+    #[serde(flatten)]
+    pub values: BTreeMap<String, u64>,
     pub time: i64
 }
 
+impl MysqlMonitorStatus {
+    ///
+    /// 获取变量值， 不存在时为0
+    pub fn get(&self, name: &str) -> u64 {
+        self.values.get(name).cloned().unwrap_or(0)
+    }
+}
+
+///
+/// 需要采集的监控变量
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct MonitorVariable {
+    pub name: String,           //变量名， 保存时转为小写
+    pub kind: String,           //counter、gauge
+    #[serde(default = "default_monitor_source")]
+    pub source: String,         //status: SHOW GLOBAL STATUS， variables: SHOW GLOBAL VARIABLES， innodb_metrics: information_schema.INNODB_METRICS的count
+}
+
+fn default_monitor_source() -> String {
+    MONITOR_SOURCES[0].to_string()
+}
+
+pub const MONITOR_KINDS: [&str; 2] = ["counter", "gauge"];
+pub const MONITOR_SOURCES: [&str; 3] = ["status", "variables", "innodb_metrics"];
+
+impl MonitorVariable {
+    pub fn new(name: &str, kind: &str) -> MonitorVariable {
+        MonitorVariable{ name: name.to_string(), kind: kind.to_string(), source: default_monitor_source() }
+    }
+
+    pub fn is_counter(&self) -> bool {
+        self.kind == "counter"
+    }
+}
+
+///
+/// 默认采集的变量， 未配置集群或节点监控变量时使用
+pub fn default_monitor_variables() -> Vec<MonitorVariable> {
+    let counters = ["com_insert", "com_update", "com_delete", "com_select", "questions", "innodb_row_lock_time",
+        "created_tmp_disk_tables", "created_tmp_tables", "innodb_buffer_pool_reads", "innodb_buffer_pool_read_requests",
+        "handler_read_first", "handler_read_key", "handler_read_next", "handler_read_prev", "handler_read_rnd",
+        "handler_read_rnd_next", "bytes_sent", "bytes_received", "slow_queries"];
+    let gauges = ["innodb_row_lock_current_waits", "innodb_os_log_pending_fsyncs", "innodb_os_log_pending_writes",
+        "innodb_log_waits", "threads_connected", "threads_running"];
+    let mut variables: Vec<MonitorVariable> = counters.iter().map(|n| MonitorVariable::new(n, "counter")).collect();
+    variables.extend(gauges.iter().map(|n| MonitorVariable::new(n, "gauge")));
+    variables
+}
+
+///
+/// 获取监控数据的请求， client按source采集variables中的变量， 返回变量名到整数值的map及采集时间
+///
+/// default用于兼容旧版本client的空包， 旧版本client忽略variables返回固定的变量
+#[derive(Deserialize, Serialize, Debug)]
+pub struct MonitorRequest {
+    #[serde(default)]
+    pub default: usize,
+    #[serde(default)]
+    pub variables: Vec<MonitorVariable>,
+}
+
 ///
 /// 用于追加sql， 发送于客户端执行
 #[derive(Deserialize, Serialize)]
@@ -620,3 +715,43 @@ pub struct CommandSql{
     pub sqls: Vec<String>
 }
 
+
+///
+/// client主动注册的信息
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct AgentRegister {
+    pub host: String,           //client地址， 127.0.0.1:9011
+    pub dbport: usize,
+    pub cluster_name: String,
+    pub version: String,        //client版本
+    #[serde(default)]
+    pub token: String,          //与server配置的agent_token一致才接受
+}
+
+///
+/// client定时推送的心跳及mysql状态
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct AgentHeartbeat {
+    pub host: String,
+    pub version: String,
+    pub state: MysqlState,
+    #[serde(default)]
+    pub token: String,
+}
+
+///
+/// vip绑定、释放及查询时发送给client的信息
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct VipInfo {
+    pub vip: String,            //192.168.1.100
+    pub interface: String,      //绑定的网卡， 如eth0
+    pub netmask: u8,            //掩码位数， 如24
+}
+
+///
+/// client返回的vip绑定状态
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct VipState {
+    pub vip: String,
+    pub bound: bool,
+}
@@ -0,0 +1,438 @@
+/*
+@author: xiao cai niao
+@datetime: 2020/09/04
+*/
+
+//! 内置读写分离代理
+//!
+//! 每个开启代理的集群监听写端口及读端口， 写端口连接转发到RouteInfo.write，
+//! 读端口按照读权重分配到RouteInfo.read， 没有可用读节点时转发到master
+//!
+//! 路由变化后关闭连接到已不在路由中节点的连接， 应用重连后即连接到新节点
+//!
+//! accept之后在单独的线程中连接后端， 后端无响应时不影响其他连接的accept，
+//! 连接成功后在路由锁内检查后端是否仍在当前路由中再登记， 连接期间路由已变化的直接关闭
+
+use std::net::{TcpListener, TcpStream, Shutdown, SocketAddr, IpAddr};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::io::{self, ErrorKind};
+use std::error::Error;
+use std::{thread, time};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use actix_web::web;
+use serde::{Serialize, Deserialize};
+use rand::{thread_rng, Rng};
+use crate::storage::rocks::{DbInfo, PrefixTypeCode, RowValue};
+use crate::storage::repo::Repo;
+use crate::ha::route_manager::{RouteInfo, MysqlHostInfo};
+
+///
+/// 集群代理配置
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ProxySetting {
+    pub cluster_name: String,
+    #[serde(default)]
+    pub listen: String,         //监听地址， 为空时与web服务的listen相同
+    pub write_port: u16,
+    pub read_port: u16,
+    pub enable: bool,
+}
+
+impl ProxySetting {
+    ///
+    /// 检查监听地址及端口， 端口不能与web、agent、dns端口及其他集群的代理端口冲突
+    pub fn check(&self, db: &DbInfo) -> Result<(), Box<dyn Error>> {
+        if self.listen.len() > 0 {
+            self.listen.parse::<IpAddr>().map_err(|_| format!("invalid listen address: {}", &self.listen))?;
+        }
+        if self.write_port == 0 || self.read_port == 0 {
+            return Err("write_port and read_port can not be 0".into());
+        }
+        if self.write_port == self.read_port {
+            return Err("write_port and read_port must be different".into());
+        }
+        let config = crate::config::get();
+        let mut used = vec![("web port".to_string(), config.server.port)];
+        if let Some(port) = config.server.agentport {
+            used.push(("agentport".to_string(), port));
+        }
+        if let Some(port) = config.server.dnsport {
+            used.push(("dnsport".to_string(), port));
+        }
+        for row in db.get_proxy_setting()? {
+            if row.value.cluster_name == self.cluster_name {continue;}
+            let name = format!("proxy of cluster {}", &row.value.cluster_name);
+            used.push((name.clone(), row.value.write_port as usize));
+            used.push((name, row.value.read_port as usize));
+        }
+        for port in &[self.write_port, self.read_port] {
+            if let Some((name, _)) = used.iter().find(|(_, p)| *p == *port as usize) {
+                let err = format!("port {} is already used by {}", port, name);
+                return Err(err.into());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn save(&self, db: &DbInfo) -> Result<(), Box<dyn Error>> {
+        db.prefix_put(&PrefixTypeCode::ProxySeting, &self.cluster_name, &self)?;
+        Ok(())
+    }
+}
+
+impl DbInfo {
+    ///
+    /// 获取所有集群的代理配置
+    pub fn get_proxy_setting(&self) -> Result<Vec<RowValue<ProxySetting>>, Box<dyn Error>> {
+        Repo::<ProxySetting>::new(self).all()
+    }
+}
+
+///
+/// 一个代理连接， 保存两端的句柄用于路由变化时关闭
+struct ProxyConn {
+    id: u64,
+    write: bool,                //是否为写端口的连接
+    backend: String,            //ip:port
+    client: TcpStream,
+    server: TcpStream,
+}
+
+impl ProxyConn {
+    fn close(&self) {
+        let _ = self.client.shutdown(Shutdown::Both);
+        let _ = self.server.shutdown(Shutdown::Both);
+    }
+}
+
+///
+/// 单个集群的代理
+struct ClusterProxy {
+    setting: ProxySetting,
+    stop: Arc<AtomicBool>,
+    route: Arc<Mutex<Option<RouteInfo>>>,
+    conns: Arc<Mutex<Vec<ProxyConn>>>,
+    accepts: Vec<JoinHandle<()>>,       //accept线程， 关闭时等待退出以释放端口
+}
+
+impl ClusterProxy {
+    fn start(setting: &ProxySetting, listen: &String) -> Result<ClusterProxy, Box<dyn Error>> {
+        let listen = if setting.listen.len() > 0 { &setting.listen } else { listen };
+        let mut proxy = ClusterProxy{
+            setting: setting.clone(),
+            stop: Arc::new(AtomicBool::new(false)),
+            route: Arc::new(Mutex::new(None)),
+            conns: Arc::new(Mutex::new(vec![])),
+            accepts: vec![]
+        };
+        for (port, write) in &[(setting.write_port, true), (setting.read_port, false)] {
+            match proxy.listen(format!("{}:{}", listen, port), *write) {
+                Ok(h) => proxy.accepts.push(h),
+                Err(e) => {
+                    proxy.stop();
+                    return Err(e);
+                }
+            }
+        }
+        info!("proxy for cluster {} start success on {}, write port: {}, read port: {}",
+              &setting.cluster_name, listen, setting.write_port, setting.read_port);
+        Ok(proxy)
+    }
+
+    fn listen(&self, addr: String, write: bool) -> Result<JoinHandle<()>, Box<dyn Error>> {
+        let listener = TcpListener::bind(&addr)?;
+        //非阻塞accept， 以便关闭代理时退出线程
+        listener.set_nonblocking(true)?;
+        let stop = self.stop.clone();
+        let route = self.route.clone();
+        let conns = self.conns.clone();
+        let handle = thread::spawn(move || {
+            while !stop.load(Ordering::SeqCst) {
+                match listener.accept() {
+                    Ok((client, _)) => {
+                        let (route, conns, addr) = (route.clone(), conns.clone(), addr.clone());
+                        thread::spawn(move || {
+                            if let Err(e) = forward(client, write, &route, &conns) {
+                                info!("proxy {} forward failed: {}", &addr, e.to_string());
+                            }
+                        });
+                    }
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                        thread::sleep(Duration::from_millis(100));
+                    }
+                    Err(e) => {
+                        info!("proxy {} accept failed: {}", &addr, e.to_string());
+                    }
+                }
+            }
+            info!("proxy {} stopped", &addr);
+        });
+        Ok(handle)
+    }
+
+    ///
+    /// 更新路由， 关闭连接到已不在路由中节点的连接
+    fn update_route(&self, new_route: RouteInfo) {
+        let mut route = self.route.lock().unwrap();
+        if let Some(old) = route.as_ref() {
+            if old.generation == new_route.generation {
+                return;
+            }
+        }
+        for conn in self.conns.lock().unwrap().iter() {
+            if !backend_valid(&new_route, &conn.backend, conn.write) {
+                info!("proxy for cluster {} close connection to {}", &self.setting.cluster_name, &conn.backend);
+                conn.close();
+            }
+        }
+        *route = Some(new_route);
+    }
+
+    ///
+    /// 关闭所有连接并等待accept线程退出， 之后可以重新监听相同端口
+    fn stop(self) {
+        self.stop.store(true, Ordering::SeqCst);
+        for conn in self.conns.lock().unwrap().iter() {
+            conn.close();
+        }
+        for h in self.accepts {
+            let _ = h.join();
+        }
+    }
+}
+
+static CONN_ID: AtomicU64 = AtomicU64::new(0);
+
+fn host_addr(host: &MysqlHostInfo) -> String {
+    format!("{}:{}", host.host, host.port)
+}
+
+///
+/// 后端是否仍在路由中， 读连接在没有读节点时会连接到master， 所以master对读写连接都有效
+fn backend_valid(route: &RouteInfo, backend: &String, write: bool) -> bool {
+    if route.write.host.len() > 0 && &host_addr(&route.write) == backend {
+        return true;
+    }
+    !write && route.read.iter().any(|r| &host_addr(r) == backend)
+}
+
+///
+/// 在路由锁内检查后端并登记连接， 与update_route互斥， 后端已不在路由中时返回false
+fn register(route: &Arc<Mutex<Option<RouteInfo>>>, conns: &Arc<Mutex<Vec<ProxyConn>>>, conn: ProxyConn) -> bool {
+    let route = route.lock().unwrap();
+    match route.as_ref() {
+        Some(r) if backend_valid(r, &conn.backend, conn.write) => {
+            conns.lock().unwrap().push(conn);
+            true
+        }
+        _ => false
+    }
+}
+
+///
+/// 按照读权重随机选择读节点， 没有读节点时使用master
+fn choose_backend(route: &RouteInfo, write: bool) -> Option<String> {
+    if route.write.host.len() == 0 {
+        return None;
+    }
+    if write {
+        return Some(host_addr(&route.write));
+    }
+    let total: usize = route.read.iter().map(|r| r.weight).sum();
+    if total == 0 {
+        return Some(host_addr(&route.write));
+    }
+    let mut n = thread_rng().gen_range(0, total);
+    for r in &route.read {
+        if n < r.weight {
+            return Some(host_addr(r));
+        }
+        n -= r.weight;
+    }
+    Some(host_addr(&route.write))
+}
+
+fn forward(client: TcpStream, write: bool, route: &Arc<Mutex<Option<RouteInfo>>>,
+           conns: &Arc<Mutex<Vec<ProxyConn>>>) -> Result<(), Box<dyn Error>> {
+    client.set_nonblocking(false)?;
+    let backend = match route.lock().unwrap().as_ref() {
+        Some(r) => choose_backend(r, write),
+        None => None
+    };
+    let backend = backend.ok_or("no route info")?;
+    let addr: SocketAddr = backend.parse()?;
+    let server = TcpStream::connect_timeout(&addr, Duration::new(2, 0))?;
+    let id = CONN_ID.fetch_add(1, Ordering::SeqCst);
+    let conn = ProxyConn{
+        id,
+        write,
+        backend: backend.clone(),
+        client: client.try_clone()?,
+        server: server.try_clone()?
+    };
+    //连接期间发生切换时不再转发到旧节点
+    if !register(route, conns, conn) {
+        let _ = server.shutdown(Shutdown::Both);
+        let _ = client.shutdown(Shutdown::Both);
+        return Err(format!("route changed while connecting to {}", &backend).into());
+    }
+
+    let (client_r, server_w) = (client.try_clone()?, server.try_clone()?);
+    thread::spawn(move || {
+        copy(client_r, server_w);
+    });
+    let conns = conns.clone();
+    thread::spawn(move || {
+        copy(server, client);
+        conns.lock().unwrap().retain(|c| c.id != id);
+    });
+    Ok(())
+}
+
+///
+/// 单向复制数据， 任意一端关闭后关闭两端
+fn copy(mut from: TcpStream, mut to: TcpStream) {
+    let _ = io::copy(&mut from, &mut to);
+    let _ = from.shutdown(Shutdown::Both);
+    let _ = to.shutdown(Shutdown::Both);
+}
+
+///
+/// 代理管理线程， 每秒检查一次配置及路由变化
+pub fn manager(db: web::Data<DbInfo>, listen: String) {
+    info!("proxy manager thread start success");
+    let mut proxys: Vec<ClusterProxy> = vec![];
+    loop {
+        match db.get_proxy_setting() {
+            Ok(settings) => {
+                proxys = check_setting(&settings, proxys, &listen);
+            }
+            Err(e) => {
+                info!("get proxy setting failed: {}", e.to_string());
+            }
+        }
+        for proxy in &proxys {
+            if let Err(e) = load_route(&db, proxy) {
+                info!("proxy load route for cluster {} failed: {}", &proxy.setting.cluster_name, e.to_string());
+            }
+        }
+        thread::sleep(time::Duration::from_secs(1));
+    }
+}
+
+///
+/// 按照最新配置启动或关闭代理， 配置有修改的先关闭再启动
+fn check_setting(settings: &Vec<RowValue<ProxySetting>>, proxys: Vec<ClusterProxy>, listen: &String) -> Vec<ClusterProxy> {
+    let mut running = vec![];
+    for proxy in proxys {
+        if settings.iter().any(|s| s.value.enable && s.value == proxy.setting) {
+            running.push(proxy);
+        } else {
+            info!("stop proxy for cluster {}", &proxy.setting.cluster_name);
+            proxy.stop();
+        }
+    }
+    for s in settings {
+        if !s.value.enable {continue;}
+        if running.iter().any(|p| p.setting == s.value) {continue;}
+        match ClusterProxy::start(&s.value, listen) {
+            Ok(p) => running.push(p),
+            Err(e) => {
+                info!("start proxy for cluster {} failed: {}", &s.value.cluster_name, e.to_string());
+            }
+        }
+    }
+    running
+}
+
+fn load_route(db: &DbInfo, proxy: &ClusterProxy) -> Result<(), Box<dyn Error>> {
+    let kv = db.prefix_get(&PrefixTypeCode::RouteInfo, &proxy.setting.cluster_name)?;
+    if kv.value.len() > 0 {
+        let route: RouteInfo = serde_json::from_str(&kv.value)?;
+        proxy.update_route(route);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setting(cluster_name: &str, write_port: u16, read_port: u16) -> ProxySetting {
+        ProxySetting{ cluster_name: cluster_name.to_string(), listen: "".to_string(), write_port, read_port, enable: true }
+    }
+
+    #[test]
+    fn check_port_conflict() {
+        let db = DbInfo::memory();
+        setting("c1", 6033, 6034).check(&db).unwrap();
+        assert!(setting("c1", 6033, 6033).check(&db).is_err());
+        assert!(setting("c1", 6033, crate::config::get().server.port as u16).check(&db).is_err());
+        let mut s = setting("c1", 6033, 6034);
+        s.listen = "not an ip".to_string();
+        assert!(s.check(&db).is_err());
+
+        setting("c1", 6033, 6034).save(&db).unwrap();
+        setting("c1", 6033, 6035).check(&db).unwrap();
+        assert!(setting("c2", 6035, 6034).check(&db).is_err());
+        setting("c2", 6035, 6036).check(&db).unwrap();
+    }
+
+    #[test]
+    fn read_backend_by_weight() {
+        let mut route = RouteInfo::new("c1".to_string());
+        assert_eq!(choose_backend(&route, true), None);
+        route.write = MysqlHostInfo{ host: "10.0.0.1".to_string(), port: 3306, weight: 100 };
+        assert_eq!(choose_backend(&route, false), Some("10.0.0.1:3306".to_string()));
+        route.read.push(MysqlHostInfo{ host: "10.0.0.2".to_string(), port: 3306, weight: 0 });
+        route.read.push(MysqlHostInfo{ host: "10.0.0.3".to_string(), port: 3306, weight: 50 });
+        for _ in 0..20 {
+            assert_eq!(choose_backend(&route, false), Some("10.0.0.3:3306".to_string()));
+        }
+        assert_eq!(choose_backend(&route, true), Some("10.0.0.1:3306".to_string()));
+    }
+
+    fn free_port() -> u16 {
+        TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+    }
+
+    ///
+    /// 本地建立一对连接作为ProxyConn的两端
+    fn proxy_conn(backend: &str, write: bool) -> ProxyConn {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        ProxyConn{ id: CONN_ID.fetch_add(1, Ordering::SeqCst), write, backend: backend.to_string(), client, server }
+    }
+
+    #[test]
+    fn register_rejects_stale_backend() {
+        let mut r = RouteInfo::new("c1".to_string());
+        r.write = MysqlHostInfo{ host: "10.0.0.1".to_string(), port: 3306, weight: 100 };
+        r.read.push(MysqlHostInfo{ host: "10.0.0.2".to_string(), port: 3306, weight: 100 });
+        let route = Arc::new(Mutex::new(None));
+        let conns = Arc::new(Mutex::new(vec![]));
+        //还没有路由信息
+        assert!(!register(&route, &conns, proxy_conn("10.0.0.1:3306", true)));
+        *route.lock().unwrap() = Some(r);
+        assert!(register(&route, &conns, proxy_conn("10.0.0.1:3306", true)));
+        assert!(register(&route, &conns, proxy_conn("10.0.0.1:3306", false)));
+        assert!(register(&route, &conns, proxy_conn("10.0.0.2:3306", false)));
+        //写连接不能连到读节点， 已切换走的旧master不再登记
+        assert!(!register(&route, &conns, proxy_conn("10.0.0.2:3306", true)));
+        assert!(!register(&route, &conns, proxy_conn("10.0.0.3:3306", true)));
+        assert_eq!(conns.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn restart_rebinds_same_port() {
+        let listen = "127.0.0.1".to_string();
+        let s = setting("c1", free_port(), free_port());
+        let proxy = ClusterProxy::start(&s, &listen).unwrap();
+        proxy.stop();
+        let proxy = ClusterProxy::start(&s, &listen).unwrap();
+        proxy.stop();
+    }
+}
@@ -0,0 +1,281 @@
+/*
+@author: xiao cai niao
+@datetime: 2020/09/07
+*/
+
+//! 同步集群路由到ProxySQL
+//!
+//! 每个集群配置ProxySQL管理接口及写、读hostgroup， 路由generation变化或配置修改后
+//! 通过管理接口重写mysql_servers中对应hostgroup的数据并加载到runtime、保存到磁盘
+//!
+//! 同步失败按照1、2、4...秒重试， 最长60秒
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::{thread, time};
+use actix_web::web;
+use serde::{Serialize, Deserialize};
+use crate::storage::rocks::{DbInfo, PrefixTypeCode, RowValue};
+use crate::storage::repo::Repo;
+use crate::ha::route_manager::RouteInfo;
+use crate::ha::mysql_conn::MysqlConn;
+
+const MAX_RETRY_INTERVAL: i64 = 60000;
+
+///
+/// 集群ProxySQL同步配置
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ProxysqlSetting {
+    pub cluster_name: String,
+    pub admin_host: String,         //ProxySQL管理接口 ip:port
+    pub user: String,
+    pub password: String,
+    pub writer_hostgroup: u32,
+    pub reader_hostgroup: u32,
+    pub enable: bool,
+}
+
+impl ProxysqlSetting {
+    ///
+    /// 查询时不返回password， 保存时为空则沿用已保存的值
+    pub fn keep_secret(&mut self, old: &ProxysqlSetting) {
+        if self.password.len() == 0 {
+            self.password = old.password.clone();
+        }
+    }
+
+    pub fn save(&self, db: &DbInfo) -> Result<(), Box<dyn Error>> {
+        db.prefix_put(&PrefixTypeCode::ProxysqlSeting, &self.cluster_name, &self)?;
+        Ok(())
+    }
+}
+
+///
+/// 集群ProxySQL同步状态
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProxysqlStatus {
+    pub cluster_name: String,
+    pub generation: u64,            //最后一次同步成功的路由generation
+    pub last_sync_time: i64,        //最后一次同步成功的时间
+    pub last_error: String,         //最后一次同步失败的错误， 成功后清空
+    pub retries: u32,               //连续失败次数
+    pub next_retry: i64,            //下一次重试时间
+}
+
+impl ProxysqlStatus {
+    fn new(cluster_name: &String) -> ProxysqlStatus {
+        ProxysqlStatus{
+            cluster_name: cluster_name.clone(),
+            generation: 0,
+            last_sync_time: 0,
+            last_error: "".to_string(),
+            retries: 0,
+            next_retry: 0
+        }
+    }
+
+    fn save(&self, db: &DbInfo) -> Result<(), Box<dyn Error>> {
+        db.prefix_put(&PrefixTypeCode::ProxysqlStatus, &self.cluster_name, &self)?;
+        Ok(())
+    }
+
+    fn success(&mut self, generation: u64) {
+        self.generation = generation;
+        self.last_sync_time = crate::timestamp();
+        self.last_error = "".to_string();
+        self.retries = 0;
+        self.next_retry = 0;
+    }
+
+    fn failed(&mut self, err: String) {
+        self.last_error = err;
+        let interval = 1000i64 << self.retries.min(6);
+        self.retries += 1;
+        self.next_retry = crate::timestamp() + interval.min(MAX_RETRY_INTERVAL);
+    }
+}
+
+impl DbInfo {
+    ///
+    /// 获取所有集群的ProxySQL同步配置
+    pub fn get_proxysql_setting(&self) -> Result<Vec<RowValue<ProxysqlSetting>>, Box<dyn Error>> {
+        Repo::<ProxysqlSetting>::new(self).all()
+    }
+
+    ///
+    /// 获取集群ProxySQL同步状态， 未同步过时返回初始状态
+    pub fn get_proxysql_status(&self, cluster_name: &String) -> Result<ProxysqlStatus, Box<dyn Error>> {
+        let result = self.prefix_get(&PrefixTypeCode::ProxysqlStatus, cluster_name)?;
+        if result.value.len() > 0 {
+            let value: ProxysqlStatus = serde_json::from_str(&result.value)?;
+            return Ok(value);
+        }
+        Ok(ProxysqlStatus::new(cluster_name))
+    }
+}
+
+///
+/// ProxySQL同步线程， 每秒检查一次
+pub fn manager(db: web::Data<DbInfo>) {
+    info!("proxysql sync thread start success");
+    //已同步成功的配置， 配置修改后需要重新同步
+    let mut synced: HashMap<String, ProxysqlSetting> = HashMap::new();
+    loop {
+        match db.get_proxysql_setting() {
+            Ok(settings) => {
+                synced.retain(|k, _| settings.iter().any(|s| &s.key == k && s.value.enable));
+                for s in &settings {
+                    if !s.value.enable {continue;}
+                    if let Err(e) = check_sync(&db, &s.value, &mut synced) {
+                        info!("proxysql sync for cluster {} failed: {}", &s.key, e.to_string());
+                    }
+                }
+            }
+            Err(e) => {
+                info!("get proxysql setting failed: {}", e.to_string());
+            }
+        }
+        thread::sleep(time::Duration::from_secs(1));
+    }
+}
+
+fn check_sync(db: &DbInfo, setting: &ProxysqlSetting, synced: &mut HashMap<String, ProxysqlSetting>) -> Result<(), Box<dyn Error>> {
+    let kv = db.prefix_get(&PrefixTypeCode::RouteInfo, &setting.cluster_name)?;
+    if kv.value.len() == 0 {
+        return Ok(());
+    }
+    let route: RouteInfo = serde_json::from_str(&kv.value)?;
+    if route.write.host.len() == 0 {
+        return Ok(());
+    }
+    let mut status = db.get_proxysql_status(&setting.cluster_name)?;
+    let setting_changed = synced.get(&setting.cluster_name) != Some(setting);
+    if !need_sync(&status, route.generation, setting_changed, crate::timestamp()) {
+        return Ok(());
+    }
+    match sync(setting, &route) {
+        Ok(()) => {
+            info!("proxysql sync for cluster {} success, generation: {}", &setting.cluster_name, route.generation);
+            status.success(route.generation);
+            synced.insert(setting.cluster_name.clone(), setting.clone());
+        }
+        Err(e) => {
+            info!("proxysql sync for cluster {} failed: {}, retries: {}", &setting.cluster_name, e.to_string(), status.retries);
+            status.failed(e.to_string());
+            synced.remove(&setting.cluster_name);
+        }
+    }
+    status.save(db)
+}
+
+///
+/// 配置修改或路由generation变化时需要同步， 失败后等到重试时间
+fn need_sync(status: &ProxysqlStatus, generation: u64, setting_changed: bool, now: i64) -> bool {
+    if !setting_changed && status.generation == generation && status.last_error.len() == 0 {
+        return false;
+    }
+    !(status.last_error.len() > 0 && now < status.next_retry)
+}
+
+fn sync(setting: &ProxysqlSetting, route: &RouteInfo) -> Result<(), Box<dyn Error>> {
+    let mut conn = MysqlConn::connect(&setting.admin_host, &setting.user, &setting.password)?;
+    for sql in &sync_sqls(setting, route) {
+        conn.execute(sql)?;
+    }
+    Ok(())
+}
+
+///
+/// 重写写、读hostgroup， 没有可用读节点时master加入读组
+fn sync_sqls(setting: &ProxysqlSetting, route: &RouteInfo) -> Vec<String> {
+    let mut sqls = vec![];
+    sqls.push(format!("DELETE FROM mysql_servers WHERE hostgroup_id IN ({},{})",
+                      setting.writer_hostgroup, setting.reader_hostgroup));
+    sqls.push(insert_sql(setting.writer_hostgroup, &route.write.host, route.write.port, 1));
+    let readers: Vec<_> = route.read.iter().filter(|r| r.weight > 0).collect();
+    if readers.len() == 0 {
+        sqls.push(insert_sql(setting.reader_hostgroup, &route.write.host, route.write.port, 1));
+    }
+    for r in readers {
+        sqls.push(insert_sql(setting.reader_hostgroup, &r.host, r.port, r.weight));
+    }
+    sqls.push("LOAD MYSQL SERVERS TO RUNTIME".to_string());
+    sqls.push("SAVE MYSQL SERVERS TO DISK".to_string());
+    sqls
+}
+
+fn insert_sql(hostgroup: u32, host: &String, port: usize, weight: usize) -> String {
+    format!("INSERT INTO mysql_servers(hostgroup_id,hostname,port,weight) VALUES({},'{}',{},{})",
+            hostgroup, host.replace("'", ""), port, weight)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ha::route_manager::MysqlHostInfo;
+
+    fn setting() -> ProxysqlSetting {
+        ProxysqlSetting{ cluster_name: "c1".to_string(), admin_host: "127.0.0.1:6032".to_string(), user: "admin".to_string(),
+            password: "admin".to_string(), writer_hostgroup: 10, reader_hostgroup: 20, enable: true }
+    }
+
+    fn host(host: &str, weight: usize) -> MysqlHostInfo {
+        MysqlHostInfo{ host: host.to_string(), port: 3306, weight }
+    }
+
+    #[test]
+    fn sync_writer_and_weighted_readers() {
+        let mut route = RouteInfo::new("c1".to_string());
+        route.write = host("10.0.0.1", 100);
+        route.read = vec![host("10.0.0.2", 100), host("10.0.0.3", 0), host("10.0.0.4", 50)];
+        assert_eq!(sync_sqls(&setting(), &route), vec![
+            "DELETE FROM mysql_servers WHERE hostgroup_id IN (10,20)".to_string(),
+            "INSERT INTO mysql_servers(hostgroup_id,hostname,port,weight) VALUES(10,'10.0.0.1',3306,1)".to_string(),
+            "INSERT INTO mysql_servers(hostgroup_id,hostname,port,weight) VALUES(20,'10.0.0.2',3306,100)".to_string(),
+            "INSERT INTO mysql_servers(hostgroup_id,hostname,port,weight) VALUES(20,'10.0.0.4',3306,50)".to_string(),
+            "LOAD MYSQL SERVERS TO RUNTIME".to_string(),
+            "SAVE MYSQL SERVERS TO DISK".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn sync_master_as_reader_without_readers() {
+        let mut route = RouteInfo::new("c1".to_string());
+        route.write = host("10.0.0.1", 100);
+        route.read = vec![host("10.0.0.2", 0)];
+        let sqls = sync_sqls(&setting(), &route);
+        assert_eq!(sqls.len(), 5);
+        assert_eq!(sqls[2], "INSERT INTO mysql_servers(hostgroup_id,hostname,port,weight) VALUES(20,'10.0.0.1',3306,1)");
+        //host中的引号被去掉
+        assert_eq!(insert_sql(10, &"10.0.0.1'".to_string(), 3306, 1),
+                   "INSERT INTO mysql_servers(hostgroup_id,hostname,port,weight) VALUES(10,'10.0.0.1',3306,1)");
+    }
+
+    #[test]
+    fn sync_on_change_and_retry_after_backoff() {
+        let mut status = ProxysqlStatus::new(&"c1".to_string());
+        assert!(need_sync(&status, 1, false, 0));
+        status.success(1);
+        assert!(!need_sync(&status, 1, false, 0));
+        assert!(need_sync(&status, 2, false, 0));
+        assert!(need_sync(&status, 1, true, 0));
+
+        status.failed("connect failed".to_string());
+        status.failed("connect failed".to_string());
+        assert_eq!(status.retries, 2);
+        assert!(!need_sync(&status, 1, false, status.next_retry - 1));
+        assert!(need_sync(&status, 1, false, status.next_retry));
+        for _ in 0..10 {
+            status.failed("connect failed".to_string());
+        }
+        assert!(status.next_retry <= crate::timestamp() + MAX_RETRY_INTERVAL);
+    }
+
+    #[test]
+    fn keep_password_when_empty() {
+        let mut new = setting();
+        new.password = "".to_string();
+        new.keep_secret(&setting());
+        assert_eq!(new.password, "admin");
+    }
+}
@@ -5,35 +5,69 @@
 use actix_web::web;
 use crate::storage::rocks::{DbInfo, CfNameTypeCode, KeyValue, PrefixTypeCode};
 use std::{time, thread};
+use std::collections::HashMap;
 use crate::ha::procotol::{MysqlState};
 use std::error::Error;
 use crate::ha::nodes_manager::CheckState;
-use crate::storage::opdb::{HaChangeLog, HostInfoValue};
+use crate::storage::opdb::{HostInfoValue, SlaveBehindSetting, READ_POLICY_MASTER, READ_POLICY_LEAST_LAG};
 use serde::{Serialize, Deserialize};
+use crate::ha::route_notify::RouteNotify;
+
+///
+/// 监控数据超过该时间(毫秒)未更新时不参与权重计算
+const MONITOR_EXPIRED: i64 = 60000;
+
+///
+/// threads_running达到该值时负载系数为0.5
+const RUNNING_THREADS_FACTOR: f64 = 10.0;
+
+///
+/// 读权重系数分档数， 系数向上取整到1/4档， 避免延迟及负载的小幅波动引起路由变化
+const WEIGHT_STEPS: f64 = 4.0;
 
 ///
 /// 每个mysql实例ip及端口信息
-#[derive(Serialize, Deserialize, Debug)]
+///
+/// weight为读权重， 由基础权重、复制延迟及threads_running计算， 写节点固定为100
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct MysqlHostInfo {
     pub host: String,
-    pub port: usize
+    pub port: usize,
+    #[serde(default)]
+    pub weight: usize,
 }
 
 ///
 /// 集群路由信息
 ///
-#[derive(Serialize, Deserialize, Debug)]
+/// generation每次路由变化递增， update_time为最后一次变化时间，
+/// 最后使用的generation单独保存， 路由被删除后重新生成时继续递增， 不会与历史及客户端持有的ETag重复
+///
+/// 没有slave满足读路由条件时degraded为true， read按照read_policy处理
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct RouteInfo {
     pub cluster_name: String,
     pub write: MysqlHostInfo,
-    pub read: Vec<MysqlHostInfo>
+    pub read: Vec<MysqlHostInfo>,
+    #[serde(default)]
+    pub generation: u64,
+    #[serde(default)]
+    pub update_time: i64,
+    #[serde(default)]
+    pub read_policy: String,
+    #[serde(default)]
+    pub degraded: bool,
 }
 impl RouteInfo {
     pub fn new(cluster_name: String) -> RouteInfo {
         RouteInfo{
             cluster_name,
-            write: MysqlHostInfo { host: "".to_string(), port: 0 },
-            read: vec![]
+            write: MysqlHostInfo { host: "".to_string(), port: 0, weight: 0 },
+            read: vec![],
+            generation: 0,
+            update_time: 0,
+            read_policy: "".to_string(),
+            degraded: false
         }
     }
 
@@ -45,12 +79,12 @@ impl RouteInfo {
 
     fn set_master_info(&mut self, node: &NodeInfo) {
         let host = self.split_str(node.value.host.clone());
-        self.write = MysqlHostInfo{ host, port: node.value.dbport.clone() };
+        self.write = MysqlHostInfo{ host, port: node.value.dbport.clone(), weight: 100 };
     }
 
-    fn set_slave_info(&mut self, node: &NodeInfo) {
+    fn set_slave_info(&mut self, node: &NodeInfo, weight: usize) {
         let host = self.split_str(node.value.host.clone());
-        self.read.push(MysqlHostInfo{host, port: node.value.dbport.clone()});
+        self.read.push(MysqlHostInfo{host, port: node.value.dbport.clone(), weight});
     }
 
     ///
@@ -63,7 +97,7 @@ impl RouteInfo {
     /// 原因有二： 有可能切换失败， 有可能正在切换中
     ///
     /// client宕机将直接返回true
-    fn check_down_status(&mut self, key: &String, db: &web::Data<DbInfo>, role: String) -> Result<bool, Box<dyn Error>> {
+    fn check_down_status(&mut self, key: &String, db: &DbInfo, role: String) -> Result<bool, Box<dyn Error>> {
         let result = db.get(key, &CfNameTypeCode::CheckState.get())?;
         //info!("check_status: {}:{:?}", key, result);
         let value: CheckState = serde_json::from_str(&result.value)?;
@@ -78,20 +112,8 @@ impl RouteInfo {
         return Ok(true);
     }
 
-    fn check_recovery_status(&self, key: &String, db: &web::Data<DbInfo>) -> Result<(), Box<dyn Error>> {
-        let result = db.prefix_iterator(key, &CfNameTypeCode::HaChangeLog.get())?;
-        //info!("{:?}", result);
-        let mut tmp = vec![];
-        for row in result {
-            if row.key.starts_with(key){
-                tmp.push(row);
-            }
-        }
-
-        if tmp.len() > 0 {
-            tmp.sort_by(|a, b| b.key.cmp(&a.key));
-            let key = tmp[0].key.clone();
-            let value: HaChangeLog = serde_json::from_str(&tmp[0].value)?;
+    fn check_recovery_status(&self, key: &String, db: &DbInfo) -> Result<(), Box<dyn Error>> {
+        if let Some((key, value)) = db.get_last_failover_log(key)? {
             //info!("{:?}", value);
             //判断切换状态， 如果为成功则需再次判断是否已恢复，如果是已恢复状态表示是旧数据
             //因为正常切换恢复和切换之间至少得有时间差， 有可能在进行路由判断时正处在切换的时候
@@ -114,6 +136,57 @@ impl RouteInfo {
         return Err(err.into());
     }
 
+    ///
+    /// 与db中保存的路由对比， 有变化时设置generation并返回变化原因
+    fn change_reason(&mut self, db: &DbInfo) -> Result<Option<String>, Box<dyn Error>> {
+        let kv = db.prefix_get(&PrefixTypeCode::RouteInfo, &self.cluster_name)?;
+        let last = db.get_route_generation(&self.cluster_name)?;
+        if kv.value.len() == 0 {
+            self.generation = last + 1;
+            self.update_time = crate::timestamp();
+            return Ok(Some("init".to_string()));
+        }
+        let old: RouteInfo = serde_json::from_str(&kv.value)?;
+        let mut reason = vec![];
+        if old.write != self.write {
+            reason.push(format!("write {}:{} -> {}:{}", old.write.host, old.write.port, self.write.host, self.write.port));
+        }
+        for r in &old.read {
+            if !self.read.iter().any(|n| n.host == r.host && n.port == r.port) {
+                reason.push(format!("read remove {}:{}", r.host, r.port));
+            }
+        }
+        for r in &self.read {
+            if !old.read.iter().any(|n| n.host == r.host && n.port == r.port) {
+                reason.push(format!("read add {}:{}", r.host, r.port));
+            }
+        }
+        if reason.len() == 0 && old.read != self.read {
+            reason.push("read weight change".to_string());
+        }
+        if old.degraded != self.degraded {
+            reason.push(format!("degraded {} -> {}", old.degraded, self.degraded));
+        }
+        if old.read_policy != self.read_policy {
+            reason.push(format!("read policy {} -> {}", old.read_policy, self.read_policy));
+        }
+        if reason.len() == 0 {
+            return Ok(None);
+        }
+        self.generation = old.generation.max(last) + 1;
+        self.update_time = crate::timestamp();
+        Ok(Some(reason.join("; ")))
+    }
+
+    ///
+    /// 路由与generation在同一个batch中写入
+    fn save(&self, db: &DbInfo) -> Result<(), Box<dyn Error>> {
+        let cf_name = CfNameTypeCode::SystemData.get();
+        let route = KeyValue{ key: format!("{}:{}", PrefixTypeCode::RouteInfo.prefix(), &self.cluster_name), value: serde_json::to_string(self)? };
+        let generation = KeyValue{ key: format!("{}:{}", PrefixTypeCode::RouteGeneration.prefix(), &self.cluster_name), value: serde_json::to_string(&self.generation)? };
+        db.write_batch(&vec![(cf_name.clone(), route), (cf_name, generation)], &vec![])
+    }
+
     fn check_time_dif(&self, key: &String) -> Result<(), Box<dyn Error>>{
         let tmp_list = key.split("_");
         let tmp_list = tmp_list.collect::<Vec<&str>>();
@@ -126,6 +199,120 @@ impl RouteInfo {
     }
 
 }
+///
+/// 一次路由变化记录
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RouteHistory {
+    pub cluster_name: String,
+    pub generation: u64,
+    pub time: i64,
+    pub reason: String,
+    pub route: RouteInfo,
+}
+
+impl RouteHistory {
+    fn new(route: &RouteInfo, reason: String) -> RouteHistory {
+        RouteHistory{
+            cluster_name: route.cluster_name.clone(),
+            generation: route.generation,
+            time: route.update_time,
+            reason,
+            route: route.clone()
+        }
+    }
+
+    fn save(&self, db: &DbInfo) -> Result<(), Box<dyn Error>> {
+        let key = format!("{}_{}", &self.cluster_name, &self.generation);
+        db.prefix_put(&PrefixTypeCode::RouteHistory, &key, &self)?;
+        Ok(())
+    }
+}
+
+impl DbInfo {
+    ///
+    /// 获取集群路由变化历史， 按generation排序
+    ///
+    /// 集群最后使用的generation， 旧版本没有保存时取路由历史中最大的generation
+    pub fn get_route_generation(&self, cluster_name: &String) -> Result<u64, Box<dyn Error>> {
+        let kv = self.prefix_get(&PrefixTypeCode::RouteGeneration, cluster_name)?;
+        if kv.value.len() > 0 {
+            return Ok(serde_json::from_str(&kv.value)?);
+        }
+        Ok(self.get_route_history(cluster_name)?.last().map(|h| h.generation).unwrap_or(0))
+    }
+
+    pub fn get_route_history(&self, cluster_name: &String) -> Result<Vec<RouteHistory>, Box<dyn Error>> {
+        let prefix = format!("{}:{}_", PrefixTypeCode::RouteHistory.prefix(), cluster_name);
+        let mut rows = vec![];
+        let result = self.prefix_iterator(&prefix, &CfNameTypeCode::SystemData.get())?;
+        for row in result {
+            if !row.key.starts_with(&prefix){continue;}
+            if row.value.len() == 0 {continue;}
+            let value: RouteHistory = serde_json::from_str(&row.value)?;
+            if &value.cluster_name != cluster_name {continue;}
+            rows.push(value);
+        }
+        rows.sort_by(|a, b| a.generation.cmp(&b.generation));
+        Ok(rows)
+    }
+
+    ///
+    /// 删除超过保留天数的路由历史， 保留现有集群当前generation的记录， 已删除集群的历史同样过期
+    pub fn expired_route_history(&self) -> Result<(), Box<dyn Error>> {
+        let one_day_ms = (60 * 1000 * 60 * 24) as i64;
+        let days = crate::config::get().retention.route_history_days as i64;
+        let cur_time = crate::timestamp();
+        let cf_name = CfNameTypeCode::SystemData.get();
+        let current: HashMap<String, u64> = self.get_route_all()?.into_iter()
+            .map(|r| (r.value.cluster_name, r.value.generation)).collect();
+        let prefix = format!("{}:", PrefixTypeCode::RouteHistory.prefix());
+        for row in self.prefix_iterator(&prefix, &cf_name)? {
+            if !row.key.starts_with(&prefix) {continue;}
+            if row.value.len() == 0 {continue;}
+            let h: RouteHistory = serde_json::from_str(&row.value)?;
+            if current.get(&h.cluster_name) == Some(&h.generation) {continue;}
+            if cur_time - h.time > one_day_ms * days {
+                self.delete(&row.key, &cf_name)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+///
+/// slave在读路由中的状态
+///
+/// healthy_since为本次连续正常的开始时间， 不正常时为0
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NodeReadState {
+    pub host: String,
+    pub in_read: bool,
+    pub reason: String,         //不在读路由中的原因
+    pub healthy_since: i64,
+}
+
+///
+/// 集群所有slave的读路由状态， 有变化时保存
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReadRouteState {
+    pub cluster_name: String,
+    pub time: i64,
+    pub nodes: Vec<NodeReadState>,
+}
+
+impl DbInfo {
+    ///
+    /// 获取集群slave读路由状态
+    pub fn get_read_route_state(&self, cluster_name: &String) -> Result<ReadRouteState, Box<dyn Error>> {
+        let result = self.prefix_get(&PrefixTypeCode::ReadRouteState, cluster_name)?;
+        if result.value.len() > 0 {
+            let value: ReadRouteState = serde_json::from_str(&result.value)?;
+            return Ok(value);
+        }
+        Ok(ReadRouteState{ cluster_name: cluster_name.clone(), time: 0, nodes: vec![] })
+    }
+}
+
 ///
 /// 节点信息
 #[derive(Clone, Debug)]
@@ -150,13 +337,19 @@ impl NodeInfo {
 struct ClusterNodeInfo{
     cluster_name: String,
     slave_behind_setting: usize,    //slave 延迟配置
+    readmit_delay: usize,           //被剔除的slave重新加入的延迟阈值
+    min_healthy_time: i64,          //被剔除的slave重新加入前需持续正常的时间(毫秒)
+    read_policy: String,            //没有slave满足读路由条件时的策略
     node_list: Vec<NodeInfo>
 }
 impl ClusterNodeInfo {
-    fn new(ninfo: &NodeInfo, slave_behind: usize) -> ClusterNodeInfo {
+    fn new(ninfo: &NodeInfo, slave_behind: &SlaveBehindSetting, read_policy: String) -> ClusterNodeInfo {
         ClusterNodeInfo{
             cluster_name: ninfo.value.cluster_name.clone(),
-            slave_behind_setting: slave_behind,
+            slave_behind_setting: slave_behind.delay,
+            readmit_delay: slave_behind.get_readmit_delay(),
+            min_healthy_time: slave_behind.min_healthy_secs as i64 * 1000,
+            read_policy,
             node_list: vec![ninfo.clone()]
         }
     }
@@ -165,6 +358,9 @@ impl ClusterNodeInfo {
         ClusterNodeInfo{
             cluster_name: self.cluster_name.clone(),
             slave_behind_setting: self.slave_behind_setting.clone(),
+            readmit_delay: self.readmit_delay,
+            min_healthy_time: self.min_healthy_time,
+            read_policy: self.read_policy.clone(),
             node_list: self.node_list.clone(),
         }
     }
@@ -173,25 +369,66 @@ impl ClusterNodeInfo {
         self.node_list.push(ninfo.clone());
     }
 
-    fn route_check(&self, db: &web::Data<DbInfo>) -> Result<RouteInfo, Box<dyn Error>> {
+    fn route_check(&self, db: &DbInfo) -> Result<RouteInfo, Box<dyn Error>> {
         let mut route_info = RouteInfo::new(self.cluster_name.clone());
+        route_info.read_policy = self.read_policy.clone();
+        //只因延迟超过阈值被剔除的slave中延迟最小的一个
+        let mut least_lag: Option<(&NodeInfo, MysqlState)> = None;
+        let last_state = db.get_read_route_state(&self.cluster_name)?;
+        let mut read_state = vec![];
         // info!("{:?}", &route_info);
         for node in &self.node_list{
             let cur_state = node.value.get_state(db)?;
             if self.master_check(&node, &cur_state, db, &mut route_info)?{
                 continue;
             };
-            self.slave_check(&node, &cur_state, db, &mut route_info)?;
+            if self.slave_check(&node, &cur_state, db, &mut route_info, &last_state.nodes, &mut read_state)? {
+                let less = match &least_lag {
+                    Some((_, s)) => cur_state.seconds_behind < s.seconds_behind,
+                    None => true
+                };
+                if less {
+                    least_lag = Some((node, cur_state));
+                }
+            }
+        }
+        if !route_info.read.iter().any(|r| r.weight > 0) {
+            self.read_fallback(&mut route_info, least_lag, db)?;
+        }
+        if read_state != last_state.nodes {
+            let state = ReadRouteState{ cluster_name: self.cluster_name.clone(), time: crate::timestamp(), nodes: read_state };
+            db.prefix_put(&PrefixTypeCode::ReadRouteState, &self.cluster_name, &state)?;
         }
         Ok(route_info)
     }
 
+    ///
+    /// 没有slave满足读路由条件时按照集群策略处理
+    ///
+    /// master: master加入读路由
+    /// least_lag: 保留延迟最小的slave， 没有时读路由为空
+    /// degraded: 读路由为空
+    fn read_fallback(&self, route_info: &mut RouteInfo, least_lag: Option<(&NodeInfo, MysqlState)>, db: &DbInfo) -> Result<(), Box<dyn Error>> {
+        route_info.degraded = true;
+        if self.read_policy == READ_POLICY_MASTER {
+            if route_info.write.host.len() > 0 {
+                route_info.read.push(route_info.write.clone());
+            }
+        } else if self.read_policy == READ_POLICY_LEAST_LAG {
+            if let Some((node, state)) = least_lag {
+                let weight = self.read_weight(node, &state, db)?;
+                route_info.set_slave_info(node, weight);
+            }
+        }
+        Ok(())
+    }
+
     ///
     /// 对role为master的节点进行判断， 如果为online直接写入信息，如果宕机则需要检查宕机检查数据是否为实例宕机，如果为实例宕机则需要检查是否已经切换
     /// 因为在实例或者client宕机时则不会更新检查状态，所以宕机之前为master如果未恢复则会一直为master状态
     ///
     /// 这里首先判断是否为维护节点， 因为在手动重做时刚开始启动会没有slave线程，客户端会判断该节点为master， 这里不做判断就会把该节点放入路由中
-    fn master_check(&self, node: &NodeInfo, node_status: &MysqlState, db: &web::Data<DbInfo>, route_info: &mut RouteInfo) -> Result<bool, Box<dyn Error>> {
+    fn master_check(&self, node: &NodeInfo, node_status: &MysqlState, db: &DbInfo, route_info: &mut RouteInfo) -> Result<bool, Box<dyn Error>> {
         // info!("{:?}", node_status);
         if node.value.maintain{return Ok(false)}
         if node_status.role == "master".to_string() {
@@ -215,31 +452,101 @@ impl ClusterNodeInfo {
     /// 2、如果宕机则需要检查是实例宕机还是client宕机
     /// 3、如果为实例宕机直接剔除
     /// 4、如果client宕机将不做任何操作， 直接添加对应节点， 这里无法检测hebind值，因为如果client宕机将不会更新状态
-    fn slave_check(&self, node: &NodeInfo, node_status: &MysqlState, db: &web::Data<DbInfo>, route_info: &mut RouteInfo) -> Result<(), Box<dyn Error>> {
+    ///
+    /// 已被剔除的slave延迟需低于readmit_delay， 且持续正常min_healthy_secs后才重新加入
+    /// 每个slave的状态及剔除原因记录到read_state
+    ///
+    /// 只因延迟被剔除时返回true， 用于least_lag策略
+    fn slave_check(&self, node: &NodeInfo, node_status: &MysqlState, db: &DbInfo, route_info: &mut RouteInfo,
+                   last_state: &Vec<NodeReadState>, read_state: &mut Vec<NodeReadState>) -> Result<bool, Box<dyn Error>> {
         // info!("slave {:?}", node_status);
-        if node.value.maintain{return Ok(())}
-        if node_status.role == "slave".to_string() {
-            if node.value.online{
-                if !node_status.sql_thread {
-                    return Ok(());
-                }
-                if !node_status.io_thread {
-                    return Ok(())
-                }
-                if self.slave_behind_setting == 0{
-                    //为0表示不判断延迟
-                    route_info.set_slave_info(node);
-                }
-                else if node_status.seconds_behind <= self.slave_behind_setting {
-                    route_info.set_slave_info(node);
+        if !node.value.maintain && node_status.role != "slave".to_string() {
+            return Ok(false);
+        }
+        let last = last_state.iter().find(|s| s.host == node.key);
+        //没有历史状态时视为在读路由中， 不需要等待
+        let was_in = last.map(|s| s.in_read).unwrap_or(true);
+        let mut state = NodeReadState{
+            host: node.key.clone(),
+            in_read: false,
+            reason: "".to_string(),
+            healthy_since: last.map(|s| s.healthy_since).unwrap_or(0)
+        };
+        let mut lagged = false;
+        let mut weight = None;
+        let reason = if node.value.maintain {
+            Some("maintain")
+        } else if node.value.online {
+            let behind = if was_in { self.slave_behind_setting } else { self.readmit_delay };
+            if !node_status.sql_thread {
+                Some("sql_thread stopped")
+            } else if !node_status.io_thread {
+                Some("io_thread stopped")
+            } else if self.slave_behind_setting > 0 && node_status.seconds_behind > behind {
+                //为0表示不判断延迟
+                lagged = true;
+                if was_in { Some("seconds_behind above delay") } else { Some("seconds_behind above readmit_delay") }
+            } else {
+                weight = Some(self.read_weight(node, node_status, db)?);
+                None
+            }
+        } else if route_info.check_down_status(&node.key, db, "slave".to_string())? {
+            //client宕机无法获取最新状态， 只使用基础权重
+            weight = Some(db.get_read_weight_setting(&node.key)?.weight);
+            None
+        } else {
+            Some("mysql down")
+        };
+
+        match (reason, weight) {
+            (None, Some(weight)) => {
+                let now = crate::timestamp();
+                if state.healthy_since == 0 {
+                    state.healthy_since = now;
                 }
-            }else {
-                if route_info.check_down_status(&node.key, db, "slave".to_string())?{
-                    route_info.set_slave_info(node);
+                if !was_in && now - state.healthy_since < self.min_healthy_time {
+                    state.reason = "healthy time less than min_healthy_secs".to_string();
+                    lagged = true;
+                } else {
+                    state.in_read = true;
+                    route_info.set_slave_info(node, weight);
                 }
             }
+            (reason, _) => {
+                state.healthy_since = 0;
+                state.reason = reason.unwrap_or("").to_string();
+            }
         }
-        Ok(())
+        read_state.push(state);
+        Ok(lagged)
+    }
+
+    ///
+    /// 计算slave读权重
+    ///
+    /// 基础权重 * 延迟系数 * 负载系数， 延迟系数随延迟接近阈值线性降低， 最低为0.1
+    /// 负载系数为 1 / (1 + threads_running / 10)， 没有开启监控时为1
+    /// 两个系数相乘后按WEIGHT_STEPS分档， 只有跨档时权重才会变化
+    /// 基础权重为0时不分配读流量， 其余情况最低为1
+    fn read_weight(&self, node: &NodeInfo, node_status: &MysqlState, db: &DbInfo) -> Result<usize, Box<dyn Error>> {
+        let base = db.get_read_weight_setting(&node.key)?.weight;
+        if base == 0 {
+            return Ok(0);
+        }
+        let mut lag_factor = 1.0;
+        if self.slave_behind_setting > 0 {
+            lag_factor = 1.0 - node_status.seconds_behind as f64 / self.slave_behind_setting as f64;
+            if lag_factor < 0.1 {
+                lag_factor = 0.1;
+            }
+        }
+        let mut load_factor = 1.0;
+        if let Some(ms) = db.get_last_monitor(&node.key)? {
+            if crate::timestamp() - ms.time <= MONITOR_EXPIRED {
+                load_factor = 1.0 / (1.0 + ms.get("threads_running") as f64 / RUNNING_THREADS_FACTOR);
+            }
+        }
+        Ok(step_weight(base, lag_factor * load_factor))
     }
 
 }
@@ -253,7 +560,7 @@ struct AllNode {
 impl AllNode {
     ///
     /// 从db获取所有节点并通过cluster_name进行分类
-    fn new(db: &web::Data<DbInfo>) -> Result<AllNode, Box<dyn Error>> {
+    fn new(db: &DbInfo) -> Result<AllNode, Box<dyn Error>> {
         let all_node = db.iterator(&CfNameTypeCode::HaNodesInfo.get(), &"".to_string())?;
         let mut nodes_info: Vec<ClusterNodeInfo> = vec![];
         'all: for node in all_node {
@@ -270,17 +577,26 @@ impl AllNode {
                     continue 'all;
                 }
             }
-            let mut delay = 100;
+            let mut delay = SlaveBehindSetting::new(&ninfo.value.cluster_name);
             let delay_check = db.get_hehind_setting(&ninfo.value.cluster_name);
             match delay_check {
                 Ok(v) => {
-                    delay = v.delay;
+                    delay = v;
                 }
                 Err(e) => {
                     info!("check slave behind setting for cluster_name:{} , Error: {:?}", &ninfo.value.cluster_name,e.to_string());
                 }
             }
-            nodes_info.push(ClusterNodeInfo::new(&ninfo, delay));
+            let mut read_policy = crate::storage::opdb::READ_POLICY_DEGRADED.to_string();
+            match db.get_read_policy_setting(&ninfo.value.cluster_name) {
+                Ok(v) => {
+                    read_policy = v.policy;
+                }
+                Err(e) => {
+                    info!("check read policy setting for cluster_name:{} , Error: {:?}", &ninfo.value.cluster_name,e.to_string());
+                }
+            }
+            nodes_info.push(ClusterNodeInfo::new(&ninfo, &delay, read_policy));
         }
         Ok(AllNode{
             nodes: nodes_info
@@ -290,24 +606,37 @@ impl AllNode {
 
     ///
     /// 对cluster信息进行循环检查，并把对应route信息写入db
-    fn route_manager(&self, db: &web::Data<DbInfo>) {
+    fn route_manager(&self, db: &DbInfo, notify: &web::Data<RouteNotify>) {
         for cluster in &self.nodes {
-            self.run_check_state(cluster,db);
+            self.run_check_state(cluster, db, notify);
         }
     }
 
-    fn run_check_state(&self, cluster: &ClusterNodeInfo, db: &web::Data<DbInfo>){
+    fn run_check_state(&self, cluster: &ClusterNodeInfo, db: &DbInfo, notify: &web::Data<RouteNotify>){
         let check_state = cluster.route_check(db);
         match check_state{
-            Ok(rinfo) => {
+            Ok(mut rinfo) => {
                 for i in 0..10 {
                     if rinfo.write.host == "".to_string(){
                         thread::sleep(time::Duration::from_secs(1));
                         continue;
                     }
-                    if let Err(e) = db.prefix_put(&PrefixTypeCode::RouteInfo, &rinfo.cluster_name, &rinfo){
-                        info!("{:?}", e.to_string());
-                    };
+                    match rinfo.change_reason(db) {
+                        Ok(Some(reason)) => {
+                            if let Err(e) = rinfo.save(db){
+                                info!("{:?}", e.to_string());
+                                break;
+                            };
+                            if let Err(e) = RouteHistory::new(&rinfo, reason).save(db){
+                                info!("save route history failed: {}", e.to_string());
+                            }
+                            notify.notify(&rinfo.cluster_name);
+                        }
+                        Ok(None) => {}
+                        Err(e) => {
+                            info!("{:?}", e.to_string());
+                        }
+                    }
                     break;
                 }
             }
@@ -338,7 +667,7 @@ impl AllNode {
 }
 
 
-pub fn manager(db: web::Data<DbInfo>) {
+pub fn manager(db: web::Data<DbInfo>, notify: web::Data<RouteNotify>) {
     info!("router manager thread start success");
     let mut all_node = AllNode::new(&db).unwrap();
     let mut start_time = crate::timestamp();
@@ -349,7 +678,85 @@ pub fn manager(db: web::Data<DbInfo>) {
             //info!("node list: {:?}",all_node);
             start_time = crate::timestamp();
         }
-        all_node.route_manager(&db);
-        thread::sleep(time::Duration::from_secs(1));
+        all_node.route_manager(&db, &notify);
+        thread::sleep(time::Duration::from_millis(crate::config::get().interval.route_ms));
+    }
+}
+
+///
+/// 按分档后的系数计算权重
+fn step_weight(base: usize, factor: f64) -> usize {
+    let factor = (factor * WEIGHT_STEPS).ceil().max(1.0).min(WEIGHT_STEPS) / WEIGHT_STEPS;
+    let weight = (base as f64 * factor).round() as usize;
+    if weight < 1 {
+        return 1;
+    }
+    weight
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(write: &str) -> RouteInfo {
+        let mut route = RouteInfo::new("c1".to_string());
+        route.write = MysqlHostInfo{ host: write.to_string(), port: 3306, weight: 100 };
+        route
+    }
+
+    #[test]
+    fn generation_survives_route_deletion() {
+        let db = DbInfo::memory();
+        let mut r = route("10.0.0.1");
+        assert_eq!(r.change_reason(&db).unwrap(), Some("init".to_string()));
+        assert_eq!(r.generation, 1);
+        r.save(&db).unwrap();
+        assert_eq!(route("10.0.0.1").change_reason(&db).unwrap(), None);
+
+        let mut r = route("10.0.0.2");
+        assert!(r.change_reason(&db).unwrap().unwrap().starts_with("write"));
+        assert_eq!(r.generation, 2);
+        r.save(&db).unwrap();
+
+        let key = format!("{}:c1", PrefixTypeCode::RouteInfo.prefix());
+        db.delete(&key, &CfNameTypeCode::SystemData.get()).unwrap();
+        let mut r = route("10.0.0.2");
+        assert_eq!(r.change_reason(&db).unwrap(), Some("init".to_string()));
+        assert_eq!(r.generation, 3);
+    }
+
+    #[test]
+    fn expire_history_of_removed_cluster() {
+        let db = DbInfo::memory();
+        let history = |cluster: &str, generation: u64, time: i64| {
+            let mut r = route("10.0.0.1");
+            r.cluster_name = cluster.to_string();
+            r.generation = generation;
+            r.update_time = time;
+            RouteHistory::new(&r, "test".to_string()).save(&db).unwrap();
+            r
+        };
+        history("c1", 1, 0);
+        history("c1", 2, 0).save(&db).unwrap();
+        history("c1", 3, crate::timestamp());
+        //c2的路由已删除， 只剩历史
+        history("c2", 1, 0);
+        db.expired_route_history().unwrap();
+
+        let c1: Vec<u64> = db.get_route_history(&"c1".to_string()).unwrap().iter().map(|h| h.generation).collect();
+        assert_eq!(c1, vec![2, 3]);
+        assert_eq!(db.get_route_history(&"c2".to_string()).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn step_weight_ignores_small_changes() {
+        assert_eq!(step_weight(100, 1.0), 100);
+        assert_eq!(step_weight(100, 0.96), 100);
+        assert_eq!(step_weight(100, 0.76), 100);
+        assert_eq!(step_weight(100, 0.74), 75);
+        assert_eq!(step_weight(100, 0.51), 75);
+        assert_eq!(step_weight(100, 0.3), 50);
+        assert_eq!(step_weight(100, 0.1), 25);
+        assert_eq!(step_weight(1, 0.1), 1);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,137 @@
+/*
+@author: xiao cai niao
+@datetime: 2020/09/01
+*/
+
+//! 路由变化通知
+//!
+//! route_manager写入的路由与上一次不同时递增版本号并唤醒等待的请求，
+//! 应用通过/watchrouteinfo带上自己已知的版本号长轮询， 有变化立即返回
+//!
+//! 每个等待的请求持有一个oneshot， 超时由timer处理， 等待期间不占用线程
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use futures::{future, Future};
+use futures::sync::oneshot;
+use tokio_timer::Delay;
+
+///
+/// 一个等待中的请求， 关注的任意集群变化时通过tx返回当前版本号
+struct Waiter {
+    clusters: Vec<String>,
+    tx: oneshot::Sender<u64>,
+}
+
+struct NotifyState {
+    start: u64,                         //启动时的版本号
+    version: u64,                       //当前版本号
+    changed: HashMap<String, u64>,      //每个集群最后一次变化时的版本号
+    waiters: Vec<Waiter>,
+}
+
+pub struct RouteNotify {
+    state: Mutex<NotifyState>,
+}
+
+impl RouteNotify {
+    ///
+    /// 版本号以启动时间初始化， server重启后客户端持有的旧版本号依然小于新版本号
+    pub fn new() -> RouteNotify {
+        let start = crate::timestamp() as u64;
+        RouteNotify{
+            state: Mutex::new(NotifyState{ start, version: start, changed: HashMap::new(), waiters: vec![] }),
+        }
+    }
+
+    ///
+    /// 集群路由发生变化， 唤醒关注该集群的请求， 已超时断开的请求一并清理
+    pub fn notify(&self, cluster_name: &String) {
+        let mut state = self.state.lock().unwrap();
+        state.version += 1;
+        let version = state.version;
+        state.changed.insert(cluster_name.clone(), version);
+        let waiters = std::mem::replace(&mut state.waiters, vec![]);
+        for w in waiters {
+            if w.tx.is_canceled() {
+                continue;
+            }
+            if w.clusters.contains(cluster_name) {
+                let _ = w.tx.send(version);
+            } else {
+                state.waiters.push(w);
+            }
+        }
+    }
+
+    ///
+    /// 等待任意一个集群的路由在version之后发生变化， 返回(版本号, 是否有变化)
+    ///
+    /// version为0或者小于启动时的版本号直接返回， 超时返回开始等待时的版本号
+    pub fn wait(&self, clusters: &Vec<String>, version: u64, timeout: Duration)
+                -> Box<dyn Future<Item = (u64, bool), Error = ()> + Send> {
+        let mut state = self.state.lock().unwrap();
+        if version < state.start || Self::is_changed(&state, clusters, version) {
+            return Box::new(future::ok((state.version, true)));
+        }
+        let current = state.version;
+        let (tx, rx) = oneshot::channel();
+        state.waiters.retain(|w| !w.tx.is_canceled());
+        state.waiters.push(Waiter{ clusters: clusters.clone(), tx });
+        let changed = rx.map(|v| (v, true)).map_err(|_| ());
+        let expired = Delay::new(Instant::now() + timeout).map(move |_| (current, false)).map_err(|_| ());
+        Box::new(changed.select(expired).map(|(v, _)| v).map_err(|(e, _)| e))
+    }
+
+    fn is_changed(state: &NotifyState, clusters: &Vec<String>, version: u64) -> bool {
+        for cluster in clusters {
+            if let Some(v) = state.changed.get(cluster) {
+                if *v > version {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run<F: Future>(f: F) -> Result<F::Item, F::Error> {
+        actix_rt::System::new("test").block_on(f)
+    }
+
+    #[test]
+    fn wait_returns_on_old_version() {
+        let notify = RouteNotify::new();
+        let clusters = vec!["c1".to_string()];
+        assert_eq!(run(notify.wait(&clusters, 0, Duration::from_secs(10))), Ok((notify.state.lock().unwrap().version, true)));
+    }
+
+    #[test]
+    fn wait_woken_by_notify() {
+        let notify = RouteNotify::new();
+        let start = notify.state.lock().unwrap().version;
+        let clusters = vec!["c1".to_string()];
+        let fut = notify.wait(&clusters, start, Duration::from_secs(10));
+        notify.notify(&"c2".to_string());
+        assert_eq!(notify.state.lock().unwrap().waiters.len(), 1);
+        notify.notify(&"c1".to_string());
+        assert_eq!(notify.state.lock().unwrap().waiters.len(), 0);
+        assert_eq!(run(fut), Ok((start + 2, true)));
+    }
+
+    #[test]
+    fn wait_timeout() {
+        let notify = RouteNotify::new();
+        let start = notify.state.lock().unwrap().version;
+        let clusters = vec!["c1".to_string()];
+        let fut = notify.wait(&clusters, start, Duration::from_millis(50));
+        assert_eq!(run(fut), Ok((start, false)));
+        notify.notify(&"c1".to_string());
+        assert_eq!(notify.state.lock().unwrap().waiters.len(), 0);
+    }
+}
@@ -0,0 +1,189 @@
+/*
+@author: xiao cai niao
+@datetime: 2020/09/17
+*/
+
+//! 报警静默
+//!
+//! 静默按集群、节点及报警类型匹配， 为空的条件匹配所有， 三个条件不能同时为空，
+//! start_time在未来时为计划中的维护窗口， 到时间后生效
+//!
+//! 静默期间报警状态照常评估， 但不发送通知(包括恢复通知)， 静默结束后仍在firing的报警会补发一次，
+//! alterinterface中被静默的报警项置为0并标记silenced
+//!
+//! 每次创建、修改及提前结束都会写入审计记录， 保存操作人及操作后的静默内容
+
+use std::error::Error;
+use serde::{Serialize, Deserialize};
+use crate::storage::rocks::{DbInfo, PrefixTypeCode, CfNameTypeCode};
+use crate::storage::repo::Repo;
+
+///
+/// 一条静默规则
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Silence {
+    #[serde(default)]
+    pub id: String,                 //创建时生成
+    #[serde(default)]
+    pub cluster_name: String,       //为空时所有集群
+    #[serde(default)]
+    pub host: String,               //为空时集群内所有节点
+    #[serde(default)]
+    pub kinds: Vec<String>,         //报警类型， 与规则的kind相同， 为空时所有类型
+    #[serde(default)]
+    pub start_time: i64,            //为0时立即生效
+    pub end_time: i64,
+    #[serde(default)]
+    pub creator: String,
+    #[serde(default)]
+    pub comment: String,
+    #[serde(default)]
+    pub create_time: i64,
+    #[serde(default)]
+    pub update_time: i64,
+}
+
+impl Silence {
+    pub fn is_active(&self, now: i64) -> bool {
+        self.start_time <= now && now < self.end_time
+    }
+
+    ///
+    /// 是否静默某个集群、节点的某类报警
+    pub fn matches(&self, cluster_name: &String, host: &String, kind: &str, now: i64) -> bool {
+        self.is_active(now)
+            && (self.cluster_name.len() == 0 || &self.cluster_name == cluster_name)
+            && (self.host.len() == 0 || &self.host == host)
+            && (self.kinds.len() == 0 || self.kinds.iter().any(|k| k == kind))
+    }
+
+    fn check(&self) -> Result<(), Box<dyn Error>> {
+        if self.cluster_name.len() == 0 && self.host.len() == 0 && self.kinds.len() == 0 {
+            return Err("cluster_name, host and kinds can not all be empty".into());
+        }
+        if self.end_time <= self.start_time || self.end_time <= crate::timestamp() {
+            return Err("end_time must be later than start_time and now".into());
+        }
+        Ok(())
+    }
+}
+
+///
+/// 静默操作审计记录
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SilenceAudit {
+    pub action: String,             //create、update、expire
+    pub user: String,
+    pub silence: Silence,           //操作后的静默内容
+    pub time: i64,
+}
+
+impl SilenceAudit {
+    fn save(db: &DbInfo, action: &str, user: &String, silence: &Silence) -> Result<(), Box<dyn Error>> {
+        let audit = SilenceAudit{ action: action.to_string(), user: user.clone(), silence: silence.clone(), time: crate::timestamp() };
+        let key = format!("{:013}_{}_{}", audit.time, &silence.id, action);
+        db.prefix_put(&PrefixTypeCode::SilenceAudit, &key, &audit)
+    }
+}
+
+///
+/// 查找第一个匹配的静默
+pub fn find<'a>(silences: &'a Vec<Silence>, cluster_name: &String, host: &String, kind: &str, now: i64) -> Option<&'a Silence> {
+    silences.iter().find(|s| s.matches(cluster_name, host, kind, now))
+}
+
+///
+/// 创建或修改静默， id为空时创建， 修改时保留原创建人， 操作人记录在审计中
+pub fn save(db: &DbInfo, silence: &Silence, user: &String) -> Result<Silence, Box<dyn Error>> {
+    if user.len() == 0 {
+        return Err("user can not be empty".into());
+    }
+    let repo = Repo::<Silence>::new(db);
+    let now = crate::timestamp();
+    let mut value = silence.clone();
+    if value.start_time == 0 {
+        value.start_time = now;
+    }
+    value.update_time = now;
+    let action = if value.id.len() == 0 {
+        value.id = uuid::Uuid::new_v4().to_string();
+        value.creator = user.clone();
+        value.create_time = now;
+        "create"
+    } else {
+        let old = repo.get(&value.id)?.ok_or(format!("silence {} not found", &value.id))?;
+        value.creator = old.creator;
+        value.create_time = old.create_time;
+        "update"
+    };
+    value.check()?;
+    repo.put(&value.id, &value)?;
+    SilenceAudit::save(db, action, user, &value)?;
+    info!("{} silence {:?} by {}", action, &value, user);
+    Ok(value)
+}
+
+///
+/// 提前结束静默， 保留记录
+pub fn expire(db: &DbInfo, id: &String, user: &String) -> Result<(), Box<dyn Error>> {
+    if user.len() == 0 {
+        return Err("user can not be empty".into());
+    }
+    let repo = Repo::<Silence>::new(db);
+    let mut value = repo.get(id)?.ok_or(format!("silence {} not found", id))?;
+    let now = crate::timestamp();
+    if value.end_time > now {
+        value.end_time = now;
+    }
+    if value.start_time > value.end_time {
+        value.start_time = value.end_time;
+    }
+    value.update_time = now;
+    repo.put(id, &value)?;
+    SilenceAudit::save(db, "expire", user, &value)?;
+    info!("expire silence {} by {}", id, user);
+    Ok(())
+}
+
+impl DbInfo {
+    ///
+    /// 获取静默， all为false时只返回生效中及计划中的静默
+    pub fn get_silences(&self, all: bool) -> Result<Vec<Silence>, Box<dyn Error>> {
+        let now = crate::timestamp();
+        let mut silences: Vec<Silence> = Repo::<Silence>::new(self).all()?.into_iter()
+            .map(|r| r.value)
+            .filter(|s| all || s.end_time > now)
+            .collect();
+        silences.sort_by(|a, b| a.start_time.cmp(&b.start_time));
+        Ok(silences)
+    }
+
+    ///
+    /// 获取时间范围内的审计记录
+    pub fn get_silence_audit(&self, start: i64, stop: i64) -> Result<Vec<SilenceAudit>, Box<dyn Error>> {
+        let prefix = format!("{}:", PrefixTypeCode::SilenceAudit.prefix());
+        let start_key = format!("{}{:013}", &prefix, start);
+        let stop_key = format!("{}{:013}", &prefix, stop);
+        let mut audit = vec![];
+        for kv in self.storage.range(&CfNameTypeCode::SystemData.get(), &start_key, &stop_key)? {
+            if kv.value.len() == 0 {continue;}
+            let value: SilenceAudit = serde_json::from_str(&kv.value)?;
+            audit.push(value);
+        }
+        Ok(audit)
+    }
+
+    ///
+    /// 删除结束超过days天的静默及审计记录
+    pub fn expired_silence(&self, days: u64) -> Result<(), Box<dyn Error>> {
+        let expire = crate::timestamp() - days as i64 * 24 * 3600 * 1000;
+        let repo = Repo::<Silence>::new(self);
+        for s in repo.all()? {
+            if s.value.end_time < expire {
+                repo.delete(&s.key)?;
+            }
+        }
+        let prefix = format!("{}:", PrefixTypeCode::SilenceAudit.prefix());
+        self.storage.delete_range(&CfNameTypeCode::SystemData.get(), &prefix, &format!("{}{:013}", &prefix, expire))
+    }
+}
@@ -0,0 +1,216 @@
+/*
+@author: xiao cai niao
+@datetime: 2020/08/28
+*/
+
+//! 节点状态变化历史
+//!
+//! ha_manager每次检查时与上一次的状态对比， 有变化时追加一条记录，
+//! 用于故障复盘时查看节点或集群的时间线
+
+use std::error::Error;
+use serde::{Serialize, Deserialize};
+use crate::storage::rocks::{DbInfo, KeyValue, PrefixTypeCode, CfNameTypeCode};
+use crate::storage::opdb::HostInfoValue;
+use crate::ha::procotol::MysqlState;
+
+///
+/// 一条状态变化记录
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StateEvent {
+    pub host: String,
+    pub cluster_name: String,
+    pub event: String,          //online、role、io_thread、sql_thread、read_only、lag
+    pub old: String,
+    pub new: String,
+    pub time: i64,
+}
+
+impl StateEvent {
+    fn new(node: &HostInfoValue, event: &str, old: String, new: String) -> StateEvent {
+        StateEvent{
+            host: node.host.clone(),
+            cluster_name: node.cluster_name.clone(),
+            event: event.to_string(),
+            old,
+            new,
+            time: crate::timestamp()
+        }
+    }
+
+    ///
+    /// key格式为host_time_event， 同一次检查的多个变化不会互相覆盖
+    fn save(&self, db: &DbInfo) -> Result<(), Box<dyn Error>> {
+        let key = format!("{}_{}", event_key(&self.host, self.time), &self.event);
+        db.put(&KeyValue{ key, value: serde_json::to_string(&self)? }, &CfNameTypeCode::SystemData.get())?;
+        Ok(())
+    }
+}
+
+///
+/// 记录节点在线状态变化
+pub fn record_online(db: &DbInfo, node: &HostInfoValue, online: bool) {
+    let event = StateEvent::new(node, "online", (!online).to_string(), online.to_string());
+    if let Err(e) = event.save(db) {
+        info!("save state event failed: {}", e.to_string());
+    }
+}
+
+///
+/// 对比新旧状态， 记录角色、复制线程、read_only及延迟是否超过阈值的变化
+pub fn record_state(db: &DbInfo, node: &HostInfoValue, old: &MysqlState, new: &MysqlState) -> Result<(), Box<dyn Error>> {
+    let mut events = vec![];
+    if old.role != new.role {
+        events.push(StateEvent::new(node, "role", old.role.clone(), new.role.clone()));
+    }
+    if new.role == "slave".to_string() {
+        if old.io_thread != new.io_thread {
+            events.push(StateEvent::new(node, "io_thread", old.io_thread.to_string(), new.io_thread.to_string()));
+        }
+        if old.sql_thread != new.sql_thread {
+            events.push(StateEvent::new(node, "sql_thread", old.sql_thread.to_string(), new.sql_thread.to_string()));
+        }
+        let delay = db.get_hehind_setting(&node.cluster_name)?.delay;
+        if (old.seconds_behind > delay) != (new.seconds_behind > delay) {
+            events.push(StateEvent::new(node, "lag", old.seconds_behind.to_string(), new.seconds_behind.to_string()));
+        }
+    }
+    if old.read_only != new.read_only {
+        events.push(StateEvent::new(node, "read_only", old.read_only.to_string(), new.read_only.to_string()));
+    }
+    for event in &events {
+        event.save(db)?;
+    }
+    Ok(())
+}
+
+///
+/// 节点某一时间的key， 时间补齐为13位， 同一节点的记录按时间排序， 可以按范围查询及删除，
+/// 已保存记录的毫秒时间本身就是13位， 不需要迁移
+fn event_key(host: &str, time: i64) -> String {
+    format!("{}:{}_{:013}", PrefixTypeCode::NodeStateEvent.prefix(), host, time.max(0))
+}
+
+///
+/// 节点所有记录之后的key， '`'紧跟在'_'之后
+fn host_end(host: &str) -> String {
+    format!("{}:{}`", PrefixTypeCode::NodeStateEvent.prefix(), host)
+}
+
+impl DbInfo {
+    ///
+    /// 获取单个节点的状态变化记录， start/stop为毫秒时间戳， stop为0表示到当前
+    pub fn get_node_timeline(&self, host: &String, start: i64, stop: i64) -> Result<Vec<StateEvent>, Box<dyn Error>> {
+        let end = if stop > 0 { event_key(host, stop.saturating_add(1)) } else { host_end(host) };
+        let mut events = vec![];
+        for row in self.storage.range(&CfNameTypeCode::SystemData.get(), &event_key(host, start), &end)? {
+            if row.value.len() == 0 {continue;}
+            let value: StateEvent = serde_json::from_str(&row.value)?;
+            events.push(value);
+        }
+        Ok(events)
+    }
+
+    ///
+    /// 获取集群内所有节点的状态变化记录， 按时间排序
+    pub fn get_cluster_timeline(&self, cluster_name: &String, start: i64, stop: i64) -> Result<Vec<StateEvent>, Box<dyn Error>> {
+        let mut events = vec![];
+        let result = self.iterator(&CfNameTypeCode::HaNodesInfo.get(), &String::from(""))?;
+        for row in &result {
+            let node: HostInfoValue = serde_json::from_str(&row.value)?;
+            if &node.cluster_name != cluster_name {continue;}
+            events.extend(self.get_node_timeline(&node.host, start, stop)?);
+        }
+        events.sort_by(|a, b| a.time.cmp(&b.time));
+        Ok(events)
+    }
+
+    ///
+    /// 删除超过保留天数的状态变化记录
+    ///
+    /// 每个节点只读取第一条key得到host， 按范围删除后跳到下一个节点， 已删除的节点同样会过期
+    pub fn expired_state_event(&self) -> Result<(), Box<dyn Error>> {
+        let one_day_ms = (60 * 1000 * 60 * 24) as i64;
+        let days = crate::config::get().retention.state_event_days as i64;
+        let expired = crate::timestamp() - one_day_ms * days;
+        let cf_name = CfNameTypeCode::SystemData.get();
+        let prefix = format!("{}:", PrefixTypeCode::NodeStateEvent.prefix());
+        let end = format!("{};", PrefixTypeCode::NodeStateEvent.prefix());
+        let mut seek = prefix.clone();
+        loop {
+            let row = match self.storage.range_limit(&cf_name, &seek, &end, 1)?.pop() {
+                Some(row) => row,
+                None => break
+            };
+            let host = match row.key[prefix.len()..].split('_').next() {
+                Some(h) if h.len() > 0 => h.to_string(),
+                _ => return Err(format!("invalid state event key: {}", &row.key).into())
+            };
+            self.storage.delete_range(&cf_name, &event_key(&host, 0), &event_key(&host, expired))?;
+            seek = host_end(&host);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::repo::Repo;
+
+    fn node(host: &str) -> HostInfoValue {
+        HostInfoValue{ host: host.to_string(), dbport: 3306, rtype: "db".to_string(), cluster_name: "c1".to_string(),
+            online: true, insert_time: 0, update_time: 0, maintain: false }
+    }
+
+    fn save(db: &DbInfo, host: &str, event: &str, time: i64) {
+        let mut e = StateEvent::new(&node(host), event, "false".to_string(), "true".to_string());
+        e.time = time;
+        e.save(db).unwrap();
+    }
+
+    fn times(events: Vec<StateEvent>) -> Vec<(String, i64)> {
+        events.into_iter().map(|e| (e.host, e.time)).collect()
+    }
+
+    #[test]
+    fn timeline_by_time_range() {
+        let db = DbInfo::memory();
+        //时间位数不同时也按时间排序
+        save(&db, "127.0.0.1:9011", "online", 900);
+        save(&db, "127.0.0.1:9011", "io_thread", 1000);
+        save(&db, "127.0.0.1:9011", "sql_thread", 1000);
+        save(&db, "127.0.0.1:9011", "role", 2000);
+        save(&db, "127.0.0.1:90110", "online", 1500);
+        let host = "127.0.0.1:9011".to_string();
+        let all = db.get_node_timeline(&host, 0, 0).unwrap();
+        assert_eq!(all.iter().map(|e| e.time).collect::<Vec<i64>>(), vec![900, 1000, 1000, 2000]);
+        assert_eq!(db.get_node_timeline(&host, 1000, 1000).unwrap().len(), 2);
+        assert_eq!(db.get_node_timeline(&host, 1001, 0).unwrap().len(), 1);
+
+        let nodes = Repo::<HostInfoValue>::new(&db);
+        nodes.put("127.0.0.1:9011", &node("127.0.0.1:9011")).unwrap();
+        nodes.put("127.0.0.1:90110", &node("127.0.0.1:90110")).unwrap();
+        let cluster = db.get_cluster_timeline(&"c1".to_string(), 1000, 1500).unwrap();
+        assert_eq!(times(cluster), vec![("127.0.0.1:9011".to_string(), 1000), ("127.0.0.1:9011".to_string(), 1000),
+                                        ("127.0.0.1:90110".to_string(), 1500)]);
+    }
+
+    #[test]
+    fn expire_all_hosts() {
+        let db = DbInfo::memory();
+        let now = crate::timestamp();
+        let old = now - (crate::config::get().retention.state_event_days as i64 + 1) * 86400000;
+        for host in &["127.0.0.1:9011", "127.0.0.1:90110", "127.0.0.1:9012"] {
+            save(&db, host, "online", old);
+            save(&db, host, "role", now);
+        }
+        //已删除节点的记录同样过期
+        save(&db, "127.0.0.1:9013", "online", old);
+        db.expired_state_event().unwrap();
+        for host in &["127.0.0.1:9011", "127.0.0.1:90110", "127.0.0.1:9012"] {
+            assert_eq!(times(db.get_node_timeline(&host.to_string(), 0, 0).unwrap()), vec![(host.to_string(), now)]);
+        }
+        assert_eq!(db.get_node_timeline(&"127.0.0.1:9013".to_string(), 0, 0).unwrap().len(), 0);
+    }
+}
@@ -12,7 +12,11 @@ use std::error::Error;
 use crate::storage::opdb::NodeClusterList;
 use crate::ha::route_manager::RouteInfo;
 use serde::{Serialize, Deserialize};
-use crate::ha::procotol::{MyProtocol, MysqlMonitorStatus};
+use crate::ha::procotol::{MyProtocol, MysqlMonitorStatus, MonitorVariable, MONITOR_KINDS, MONITOR_SOURCES, default_monitor_variables};
+use std::collections::BTreeMap;
+use crate::storage::monitor_data;
+use crate::storage::repo::Repo;
+use crate::storage::opdb::HostInfoValue;
 
 ///
 ///
@@ -20,22 +24,23 @@ use crate::ha::procotol::{MyProtocol, MysqlMonitorStatus};
 ///
 impl DifferenceSql{
     ///
-    /// 过期删除超过7天的数据
-    fn expired(&self, db: &web::Data<DbInfo>) -> Result<(), Box<dyn Error>>{
-        if (crate::timestamp() - self.time) >= 604800000{
+    /// 过期删除超过retention.rollback_days的数据， 默认7天
+    fn expired(&self, db: &DbInfo) -> Result<(), Box<dyn Error>>{
+        let keep_ms = crate::config::get().retention.rollback_days as i64 * 86400000;
+        if (crate::timestamp() - self.time) >= keep_ms{
             self.delete(db)?;
         }
         Ok(())
     }
 
-    fn delete(&self, db: &web::Data<DbInfo>) -> Result<(), Box<dyn Error>>{
+    fn delete(&self, db: &DbInfo) -> Result<(), Box<dyn Error>>{
         let key = format!("{}:{}:{}_{}", PrefixTypeCode::RollBackSql.prefix(),&self.cluster, &self.host, &self.time);
         db.delete(&key, &CfNameTypeCode::SystemData.get())?;
         Ok(())
     }
 }
 /// 删除超过7天的差异sql
-fn expired_rollback(db: &web::Data<DbInfo>) {
+fn expired_rollback(db: &DbInfo) {
     //差异sql部分
     let result = db.get_rollback_sql(&String::from(""));
     match result {
@@ -59,7 +64,7 @@ fn expired_rollback(db: &web::Data<DbInfo>) {
 ///
 ///
 impl RouteInfo{
-    fn expired(&self, db: &web::Data<DbInfo>, cl_list: &NodeClusterList) -> Result<(), Box<dyn Error>>{
+    fn expired(&self, db: &DbInfo, cl_list: &NodeClusterList) -> Result<(), Box<dyn Error>>{
         for cluster_name in &cl_list.cluster_name_list{
             if cluster_name == &self.cluster_name{
                 return Ok(());
@@ -68,14 +73,14 @@ impl RouteInfo{
         return self.delete(db);
     }
 
-    fn delete(&self, db: &web::Data<DbInfo>) -> Result<(), Box<dyn Error>> {
+    fn delete(&self, db: &DbInfo) -> Result<(), Box<dyn Error>> {
         let key = format!("{}:{}", &PrefixTypeCode::RouteInfo.prefix(), &self.cluster_name);
         db.delete(&key, &CfNameTypeCode::SystemData.get())?;
         Ok(())
     }
 }
 /// 删除无效的路由信息
-fn expired_dirty_route_info(db: &web::Data<DbInfo>) {
+fn expired_dirty_route_info(db: &DbInfo) {
     let mut n = NodeClusterList::new();
     if let Err(e) = n.init(db){
         info!("{:?}", e.to_string());
@@ -99,54 +104,36 @@ fn expired_dirty_route_info(db: &web::Data<DbInfo>) {
 }
 
 ///
+/// 保存一次监控数据
+fn save_monitor(db: &DbInfo, host: &String, ms: &MysqlMonitorStatus) -> Result<(), Box<dyn Error>>{
+    db.put_monitor_data(host, ms)?;
+    db.prefix_put(&PrefixTypeCode::NodeMonitorLast, host, ms)?;
+    Ok(())
+}
+
 ///
+/// 计算两次监控之间的数据， 计数器为差值平均到秒， gauge为当前值
 ///
-///
-impl MysqlMonitorStatus{
-    fn save(&self, db: &web::Data<DbInfo>, host: &String, last_value: &mut MysqlMonitorStatus) -> Result<(), Box<dyn Error>>{
-        let prefix = PrefixTypeCode::NodeMonitorData;
-        let key = format!("{}_{}", host, &self.time);
-        let ms = self.calculation(last_value);
-        //info!("{:?}", &ms);
-        db.prefix_put(&prefix, &key, &ms)?;
-//        let wb = self.batch(db, host)?;
-//        db.db.write(wb)?;
-        Ok(())
-    }
-
-    ///
-    /// 计算两次监控之间数据差， 并计算平均到秒
-    fn calculation(&self, last_value: &mut MysqlMonitorStatus) -> MysqlMonitorStatus{
-        let time_dif = ((self.time - last_value.time) / 1000) as usize;
-        MysqlMonitorStatus{
-            com_insert: (self.com_insert - last_value.com_insert) / time_dif,
-            com_update: (self.com_update - last_value.com_update) / time_dif,
-            com_delete: (self.com_delete - last_value.com_delete) / time_dif,
-            com_select: (self.com_select - last_value.com_select) / time_dif,
-            questions: (self.questions - last_value.questions) / time_dif,
-            innodb_row_lock_current_waits: self.innodb_row_lock_current_waits,
-            innodb_row_lock_time: (self.innodb_row_lock_time - last_value.innodb_row_lock_time) / time_dif,
-            created_tmp_disk_tables: (self.created_tmp_disk_tables - last_value.created_tmp_disk_tables) / time_dif,
-            created_tmp_tables: (self.created_tmp_tables - last_value.created_tmp_tables) / time_dif,
-            innodb_buffer_pool_reads: (self.innodb_buffer_pool_reads - last_value.innodb_buffer_pool_reads) /time_dif,
-            innodb_buffer_pool_read_requests: (self.innodb_buffer_pool_read_requests - last_value.innodb_buffer_pool_read_requests) / time_dif,
-            handler_read_first: (self.handler_read_first - last_value.handler_read_first) / time_dif,
-            handler_read_key: (self.handler_read_key - last_value.handler_read_key) / time_dif,
-            handler_read_next: (self.handler_read_next - last_value.handler_read_next) / time_dif,
-            handler_read_prev: (self.handler_read_prev - last_value.handler_read_prev) / time_dif,
-            handler_read_rnd: (self.handler_read_rnd - last_value.handler_read_rnd) / time_dif,
-            handler_read_rnd_next: (self.handler_read_rnd_next - last_value.handler_read_rnd_next) / time_dif,
-            innodb_os_log_pending_fsyncs: self.innodb_os_log_pending_fsyncs,
-            innodb_os_log_pending_writes: self.innodb_os_log_pending_writes,
-            innodb_log_waits: self.innodb_log_waits,
-            threads_connected: self.threads_connected,
-            threads_running: self.threads_running,
-            bytes_sent: (self.bytes_sent - last_value.bytes_sent) / time_dif,
-            bytes_received: (self.bytes_received - last_value.bytes_received) / time_dif,
-            slow_queries: (self.slow_queries - last_value.slow_queries) / time_dif,
-            time: self.time
+/// 两次中缺少的变量及变小的计数器(mysql重启)跳过
+fn calculation(cur: &MysqlMonitorStatus, last: &MysqlMonitorStatus, variables: &Vec<MonitorVariable>) -> MysqlMonitorStatus{
+    let time_dif = ((cur.time - last.time) / 1000).max(1) as u64;
+    let mut values = BTreeMap::new();
+    for var in variables{
+        let v = match cur.values.get(&var.name) {
+            Some(v) => *v,
+            None => continue
+        };
+        if !var.is_counter(){
+            values.insert(var.name.clone(), v);
+            continue;
+        }
+        if let Some(l) = last.values.get(&var.name){
+            if v >= *l{
+                values.insert(var.name.clone(), (v - l) / time_dif);
+            }
         }
     }
+    MysqlMonitorStatus{ values, time: cur.time }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -154,71 +141,134 @@ pub struct MonitorSetting{
     pub host: String,
     pub monitor: bool,
     pub days: u8,   //保留天数, 默认7天
+    #[serde(default = "default_minute_days")]
+    pub minute_days: u16,   //分钟汇总数据保留天数, 默认30天
+    #[serde(default = "default_hour_days")]
+    pub hour_days: u16,     //小时汇总数据保留天数, 默认365天
 }
+
+fn default_minute_days() -> u16 { 30 }
+
+fn default_hour_days() -> u16 { 365 }
+
 impl MonitorSetting{
     pub fn new(host: &String) -> MonitorSetting {
         MonitorSetting{
             host: host.clone(),
             monitor: false,
-            days: 7
+            days: 7,
+            minute_days: default_minute_days(),
+            hour_days: default_hour_days()
         }
     }
 
-    pub fn save(&self, db: &web::Data<DbInfo>) -> Result<(), Box<dyn Error>>{
+    pub fn save(&self, db: &DbInfo) -> Result<(), Box<dyn Error>>{
         db.prefix_put(&PrefixTypeCode::NodeMonitorSeting, &self.host, &self)?;
         Ok(())
     }
 
-    pub fn delete(&self, db: &web::Data<DbInfo>) -> Result<(), Box<dyn Error>>{
+    pub fn delete(&self, db: &DbInfo) -> Result<(), Box<dyn Error>>{
         let key = format!("{}:{}", &PrefixTypeCode::NodeMonitorSeting.prefix(), &self.host);
         db.delete(&key, &CfNameTypeCode::SystemData.get())?;
         Ok(())
     }
 }
 
+///
+/// 监控变量配置， host不为空时为节点配置， 否则为集群配置
+///
+/// 节点配置优先于集群配置， 都没有时使用默认变量
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MonitorVariableSetting{
+    #[serde(default)]
+    pub cluster_name: String,
+    #[serde(default)]
+    pub host: String,
+    #[serde(default)]
+    pub variables: Vec<MonitorVariable>,
+}
+
+impl MonitorVariableSetting{
+    pub fn key(&self) -> String {
+        if self.host.len() > 0 {
+            return format!("host:{}", &self.host);
+        }
+        format!("cluster:{}", &self.cluster_name)
+    }
 
-impl MysqlMonitorStatus{
-    fn new() -> MysqlMonitorStatus{
-        MysqlMonitorStatus{
-            com_insert: 0,
-            com_update: 0,
-            com_delete: 0,
-            com_select: 0,
-            questions: 0,
-            innodb_row_lock_current_waits: 0,
-            innodb_row_lock_time: 0,
-            created_tmp_disk_tables: 0,
-            created_tmp_tables: 0,
-            innodb_buffer_pool_reads: 0,
-            innodb_buffer_pool_read_requests: 0,
-            handler_read_first: 0,
-            handler_read_key: 0,
-            handler_read_next: 0,
-            handler_read_prev: 0,
-            handler_read_rnd: 0,
-            handler_read_rnd_next: 0,
-            innodb_os_log_pending_fsyncs: 0,
-            innodb_os_log_pending_writes: 0,
-            innodb_log_waits: 0,
-            threads_connected: 0,
-            threads_running: 0,
-            bytes_sent: 0,
-            bytes_received: 0,
-            slow_queries: 0,
-            time: 0
+    ///
+    /// 检查配置， 变量名转为小写
+    pub fn check(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.cluster_name.len() == 0 && self.host.len() == 0 {
+            return Err("cluster_name and host can not both be empty".into());
+        }
+        if self.variables.len() == 0 {
+            return Err("variables can not be empty".into());
         }
+        let mut names = vec![];
+        for var in &mut self.variables {
+            var.name = var.name.trim().to_lowercase();
+            if var.name.len() == 0 || var.name == "time" {
+                return Err(format!("invalid variable name: {}", &var.name).into());
+            }
+            if names.contains(&var.name) {
+                return Err(format!("duplicate variable: {}", &var.name).into());
+            }
+            if !MONITOR_KINDS.contains(&var.kind.as_str()) {
+                return Err(format!("invalid kind of {}: {}, must be counter or gauge", &var.name, &var.kind).into());
+            }
+            if !MONITOR_SOURCES.contains(&var.source.as_str()) {
+                return Err(format!("invalid source of {}: {}, must be one of {}", &var.name, &var.source, MONITOR_SOURCES.join(", ")).into());
+            }
+            names.push(var.name.clone());
+        }
+        Ok(())
+    }
+
+    pub fn save(&self, db: &DbInfo) -> Result<(), Box<dyn Error>>{
+        Repo::<MonitorVariableSetting>::new(db).put(&self.key(), self)
+    }
+
+    pub fn delete(&self, db: &DbInfo) -> Result<(), Box<dyn Error>>{
+        Repo::<MonitorVariableSetting>::new(db).delete(&self.key())
+    }
+}
+
+impl DbInfo {
+    ///
+    /// 获取所有集群及节点的监控变量配置
+    pub fn get_monitor_variable_setting(&self) -> Result<Vec<MonitorVariableSetting>, Box<dyn Error>> {
+        Ok(Repo::<MonitorVariableSetting>::new(self).all()?.into_iter().map(|r| r.value).collect())
+    }
+
+    ///
+    /// 获取节点生效的监控变量
+    pub fn get_monitor_variables(&self, host: &String) -> Result<Vec<MonitorVariable>, Box<dyn Error>> {
+        let repo = Repo::<MonitorVariableSetting>::new(self);
+        if let Some(s) = repo.get(&format!("host:{}", host))? {
+            return Ok(s.variables);
+        }
+        if let Some(node) = Repo::<HostInfoValue>::new(self).get(host)? {
+            if let Some(s) = repo.get(&format!("cluster:{}", &node.cluster_name))? {
+                return Ok(s.variables);
+            }
+        }
+        Ok(default_monitor_variables())
     }
 }
 
+
 struct MonitorNodeSetInfo{
     setting: MonitorSetting,            //配置
+    variables: Vec<MonitorVariable>,    //采集的变量
     last_monitor_value: MysqlMonitorStatus  //上一次检查数据，用于计算差值
 }
 impl MonitorNodeSetInfo{
-    fn new(ms: &MonitorSetting) -> MonitorNodeSetInfo{
+    fn new(ms: &MonitorSetting, variables: Vec<MonitorVariable>) -> MonitorNodeSetInfo{
         MonitorNodeSetInfo{
             setting: ms.clone(),
-            last_monitor_value: MysqlMonitorStatus::new()
+            variables,
+            last_monitor_value: MysqlMonitorStatus::default()
         }
     }
 
@@ -226,31 +276,43 @@ impl MonitorNodeSetInfo{
         self.last_monitor_value = ms.clone();
     }
 
-    fn monitor_state(&mut self, db:&web::Data<DbInfo>) -> Result<(), Box<dyn Error>>{
-        let monitor_data = MyProtocol::get_monitor(&MyProtocol::GetMonitor, &self.setting.host)?;
+    fn monitor_state(&mut self, db:&DbInfo) -> Result<(), Box<dyn Error>>{
+        let monitor_data = MyProtocol::get_monitor(&MyProtocol::GetMonitor, &self.setting.host, &self.variables)?;
         //info!("{:?}", &monitor_data);
         if self.last_monitor_value.time != 0 {
-            monitor_data.save(db, &self.setting.host, &mut self.last_monitor_value)?;
+            let ms = calculation(&monitor_data, &self.last_monitor_value, &self.variables);
+            save_monitor(db, &self.setting.host, &ms)?;
         }
         self.last_monitor_value = monitor_data;
         Ok(())
     }
 }
 
-fn monitor(db: &web::Data<DbInfo>, setting: &mut Vec<MonitorNodeSetInfo>) {
+fn monitor(db: &DbInfo, setting: &mut Vec<MonitorNodeSetInfo>) {
     for rw in setting{
         if !rw.setting.monitor{continue;}
         if let Err(e) = rw.monitor_state(db){
             if rw.last_monitor_value.time != 0{
-                rw.last_monitor_value = MysqlMonitorStatus::new();
+                rw.last_monitor_value = MysqlMonitorStatus::default();
             }
             info!("get monitor data failed({}):{}", &rw.setting.host, e.to_string());
         }
     }
 }
+///
+/// 汇总已开启监控节点的分钟及小时数据
+fn rollup_monitor_data(db: &DbInfo, setting: &Vec<MonitorNodeSetInfo>) {
+    for rw in setting{
+        if !rw.setting.monitor{continue;}
+        if let Err(e) = monitor_data::rollup(db, &rw.setting){
+            info!("rollup monitor data failed({}):{}", &rw.setting.host, e.to_string());
+        }
+    }
+}
+
 ///
 /// 删除过期监控数据， 默认最多保留30天的数据
-fn expired_monitor_data(db: &web::Data<DbInfo>) {
+fn expired_monitor_data(db: &DbInfo) {
     let monitor_set = db.get_monitor_setting();
     match monitor_set {
         Ok(set) => {
@@ -272,6 +334,19 @@ fn expired(db: web::Data<DbInfo>){
     expired_rollback(&db);
     expired_dirty_route_info(&db);
     expired_monitor_data(&db);
+    if let Err(e) = db.expired_state_event(){
+        info!("clear outdated state event faild: {}", e.to_string());
+    }
+    if let Err(e) = db.expired_route_history(){
+        info!("clear outdated route history faild: {}", e.to_string());
+    }
+    let alert_history_days = crate::config::get().retention.alert_history_days;
+    if let Err(e) = db.expired_alert_history(alert_history_days){
+        info!("clear outdated alert history faild: {}", e.to_string());
+    }
+    if let Err(e) = db.expired_silence(alert_history_days){
+        info!("clear outdated silence faild: {}", e.to_string());
+    }
 }
 
 pub fn manager(db: web::Data<DbInfo>) {
@@ -279,13 +354,11 @@ pub fn manager(db: web::Data<DbInfo>) {
     let mut sche_start_time = crate::timestamp();
     let mut loop_start_time = crate::timestamp();
     let mut monitor_set = db.get_monitor_setting().unwrap();
-    let mut ms = vec![];
-    for rw in monitor_set{
-        ms.push(MonitorNodeSetInfo::new(&rw.value));
-    }
+    let mut ms = init_monitor_set(&db, &monitor_set, &vec![]);
     loop {
-        if crate::timestamp() - sche_start_time >= (3600000 * 24) {
-            //每24小时清理一次数据
+        let conf = crate::config::get();
+        if crate::timestamp() - sche_start_time >= 3600000 * conf.interval.purge_hours as i64 {
+            //默认每24小时清理一次数据
             let b = db.clone();
             thread::spawn(move ||{
                 expired(b);
@@ -296,22 +369,30 @@ pub fn manager(db: web::Data<DbInfo>) {
         if crate::timestamp() - loop_start_time >= 60000 {
             //每60秒重新获取一次配置信息
             monitor_set = db.get_monitor_setting().unwrap();
-            ms = init_monitor_set(&monitor_set, &ms);
+            ms = init_monitor_set(&db, &monitor_set, &ms);
             loop_start_time = crate::timestamp();
+            rollup_monitor_data(&db, &ms);
         }
 
         monitor(&db, &mut ms);
 
-        thread::sleep(time::Duration::from_secs(10));
+        thread::sleep(time::Duration::from_secs(conf.interval.monitor_secs));
     }
 }
 
 ///
-/// 初始化监控配置
-fn init_monitor_set(ms: &Vec<RowValue<MonitorSetting>>, mif: &Vec<MonitorNodeSetInfo>) -> Vec<MonitorNodeSetInfo>{
+/// 初始化监控配置及每个节点采集的变量
+fn init_monitor_set(db: &DbInfo, ms: &Vec<RowValue<MonitorSetting>>, mif: &Vec<MonitorNodeSetInfo>) -> Vec<MonitorNodeSetInfo>{
     let mut mm = vec![];
     for rw in ms{
-        let mut mi = MonitorNodeSetInfo::new(&rw.value);
+        let variables = match db.get_monitor_variables(&rw.value.host) {
+            Ok(v) => v,
+            Err(e) => {
+                info!("get monitor variables failed({}):{}", &rw.value.host, e.to_string());
+                default_monitor_variables()
+            }
+        };
+        let mut mi = MonitorNodeSetInfo::new(&rw.value, variables);
         for ii in mif{
             if ii.setting.host == rw.value.host{
                 mi.update_value(&ii.last_monitor_value);
@@ -0,0 +1,249 @@
+/*
+@author: xiao cai niao
+@datetime: 2020/09/10
+*/
+
+//! 集群vip管理
+//!
+//! 宕机切换及主动切换完成后通知旧master的client释放vip， 确认旧master已释放后新master的client绑定vip并发送免费arp，
+//! 之后查询两边的绑定状态进行确认， 每次漂移单独记录在VipLog中
+//!
+//! 旧master的client无法连接时无法确认vip已释放(mysql宕机但机器及vip仍然存在)， 这时不会绑定新master，
+//! 避免两个节点同时持有vip， 发送vip_unverified报警， 由运维人员隔离旧master后调用/takeovervip绑定
+
+use std::error::Error;
+use serde::{Serialize, Deserialize};
+use crate::storage::rocks::{DbInfo, PrefixTypeCode, CfNameTypeCode};
+use crate::storage::repo::Repo;
+use crate::ha::alert;
+use crate::storage::opdb::HostInfoValue;
+use crate::ha::procotol::{MyProtocol, VipInfo, VipState};
+
+///
+/// 集群vip配置
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VipSetting {
+    pub cluster_name: String,
+    pub vip: String,
+    pub interface: String,
+    pub netmask: u8,
+    pub enable: bool,
+}
+
+impl VipSetting {
+    pub fn save(&self, db: &DbInfo) -> Result<(), Box<dyn Error>> {
+        db.prefix_put(&PrefixTypeCode::VipSeting, &self.cluster_name, &self)?;
+        Ok(())
+    }
+
+    fn info(&self) -> VipInfo {
+        VipInfo{
+            vip: self.vip.clone(),
+            interface: self.interface.clone(),
+            netmask: self.netmask
+        }
+    }
+}
+
+impl DbInfo {
+    ///
+    /// 获取集群vip配置， 未配置返回None
+    pub fn get_vip_setting(&self, cluster_name: &String) -> Result<Option<VipSetting>, Box<dyn Error>> {
+        let result = self.prefix_get(&PrefixTypeCode::VipSeting, cluster_name)?;
+        if result.value.len() > 0 {
+            let value: VipSetting = serde_json::from_str(&result.value)?;
+            return Ok(Some(value));
+        }
+        Ok(None)
+    }
+}
+
+pub const VIP_FAILOVER: &str = "failover";
+pub const VIP_SWITCHOVER: &str = "switchover";
+pub const VIP_TAKEOVER: &str = "takeover";
+
+///
+/// vip漂移记录， 单独保存， 宕机切换时同时记录在HaChangeLog中
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VipLog {
+    #[serde(default)]
+    pub cluster_name: String,
+    #[serde(default)]
+    pub kind: String,           //failover、switchover、takeover(运维人员确认后绑定)
+    pub vip: String,
+    pub old_host: String,
+    pub new_host: String,
+    pub release: String,        //旧master释放结果， OK或错误信息， takeover时为操作人
+    pub acquire: String,        //新master绑定结果， OK或错误信息， 未确认释放时为skipped
+    pub verified: bool,         //新master已绑定且确认旧master未绑定
+    pub time: i64,
+}
+
+impl VipLog {
+    fn save(&self, db: &DbInfo) {
+        let key = format!("{}_{:013}", &self.cluster_name, self.time);
+        if let Err(e) = Repo::<VipLog>::new(db).put(&key, self) {
+            info!("save vip log {:?} failed: {}", self, e.to_string());
+        }
+    }
+
+    ///
+    /// 未确认时发送报警， 需要人工处理
+    fn finish(self, db: &DbInfo) -> VipLog {
+        if !self.verified {
+            alert::push_event(db, alert::VIP_UNVERIFIED, &self.cluster_name, &self.new_host,
+                              format!("vip {} is not verified on {}: release {}, acquire {}", &self.vip, &self.new_host, &self.release, &self.acquire));
+        }
+        self.save(db);
+        self
+    }
+}
+
+impl DbInfo {
+    ///
+    /// 获取集群的vip漂移记录， cluster_name为空时返回所有集群
+    pub fn get_vip_log(&self, cluster_name: &String) -> Result<Vec<VipLog>, Box<dyn Error>> {
+        let mut logs: Vec<VipLog> = Repo::<VipLog>::new(self).all()?.into_iter()
+            .map(|r| r.value)
+            .filter(|l| cluster_name.len() == 0 || &l.cluster_name == cluster_name)
+            .collect();
+        logs.sort_by(|a, b| a.time.cmp(&b.time));
+        Ok(logs)
+    }
+}
+
+fn result_string(r: Result<(), Box<dyn Error>>) -> String {
+    match r {
+        Ok(()) => "OK".to_string(),
+        Err(e) => e.to_string()
+    }
+}
+
+fn get_enabled_setting(db: &DbInfo, cluster_name: &String) -> Option<VipSetting> {
+    match db.get_vip_setting(cluster_name) {
+        Ok(Some(s)) => if s.enable { Some(s) } else { None },
+        Ok(None) => None,
+        Err(e) => {
+            info!("get vip setting for cluster {} failed: {}", cluster_name, e.to_string());
+            None
+        }
+    }
+}
+
+///
+/// 查询节点是否绑定了vip， 无法连接时为None
+fn bound(host: &String, info: &VipInfo) -> Option<bool> {
+    match MyProtocol::VipStatus.vip_status(host, info) {
+        Ok(s) => Some(s.bound),
+        Err(e) => {
+            info!("check vip status on {} failed: {}", host, e.to_string());
+            None
+        }
+    }
+}
+
+///
+/// 将vip从旧master漂移到新master， 集群未配置或未开启vip时返回None
+///
+/// 旧master释放失败且无法确认未绑定时不绑定新master， host均为client地址
+pub fn move_vip(db: &DbInfo, kind: &str, cluster_name: &String, old_host: &String, new_host: &String) -> Option<VipLog> {
+    let setting = get_enabled_setting(db, cluster_name)?;
+    let info = setting.info();
+    let mut log = VipLog{
+        cluster_name: cluster_name.clone(),
+        kind: kind.to_string(),
+        vip: setting.vip.clone(),
+        old_host: old_host.clone(),
+        new_host: new_host.clone(),
+        release: "".to_string(),
+        acquire: "".to_string(),
+        verified: false,
+        time: crate::timestamp()
+    };
+    info!("release vip {} from {}", &setting.vip, old_host);
+    let release = MyProtocol::VipRelease.send_myself_value_packet(old_host, &info);
+    let released = release.is_ok() || bound(old_host, &info) == Some(false);
+    log.release = result_string(release);
+    info!("release result: {}", &log.release);
+    if !released {
+        log.acquire = format!("skipped, vip is not confirmed released on {}, fence it and call /takeovervip", old_host);
+        info!("vip {} {}", &setting.vip, &log.acquire);
+        return Some(log.finish(db));
+    }
+
+    info!("acquire vip {} on {}", &setting.vip, new_host);
+    log.acquire = result_string(MyProtocol::VipAcquire.send_myself_value_packet(new_host, &info));
+    info!("acquire result: {}", &log.acquire);
+    let new_bound = bound(new_host, &info);
+    let old_bound = bound(old_host, &info);
+    log.verified = new_bound == Some(true) && old_bound == Some(false);
+    info!("vip {} verify result: {}, new master bound: {:?}, old master bound: {:?}", &setting.vip, log.verified, new_bound, old_bound);
+    Some(log.finish(db))
+}
+
+///
+/// 运维人员确认旧master已隔离(关机、断网或已手动删除vip)后在当前master上绑定vip
+pub fn takeover_vip(db: &DbInfo, cluster_name: &String, host: &String, user: &String) -> Result<VipLog, Box<dyn Error>> {
+    let setting = get_enabled_setting(db, cluster_name).ok_or(format!("cluster {} has no enabled vip", cluster_name))?;
+    let node = Repo::<HostInfoValue>::new(db).get(host)?.ok_or(format!("host {} not found", host))?;
+    if &node.cluster_name != cluster_name {
+        return Err(format!("host {} is not in cluster {}", host, cluster_name).into());
+    }
+    if node.get_role(db)? != "master" {
+        return Err(format!("host {} is not master", host).into());
+    }
+    let info = setting.info();
+    info!("{} takeover vip {} on {}", user, &setting.vip, host);
+    let mut log = VipLog{
+        cluster_name: cluster_name.clone(),
+        kind: VIP_TAKEOVER.to_string(),
+        vip: setting.vip.clone(),
+        old_host: "".to_string(),
+        new_host: host.clone(),
+        release: format!("confirmed by {}", user),
+        acquire: result_string(MyProtocol::VipAcquire.send_myself_value_packet(host, &info)),
+        verified: false,
+        time: crate::timestamp()
+    };
+    log.verified = bound(host, &info) == Some(true);
+    Ok(log.finish(db))
+}
+
+///
+/// vip当前绑定情况
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ClusterVipState {
+    pub cluster_name: String,
+    pub vip: String,
+    pub enable: bool,
+    pub bound_hosts: Vec<String>,       //已绑定vip的节点， 正常情况只有master
+    pub errors: Vec<String>,            //无法查询的节点
+}
+
+///
+/// 查询集群内所有在线节点的vip绑定情况
+pub fn get_cluster_vip_state(db: &DbInfo, cluster_name: &String) -> Result<ClusterVipState, Box<dyn Error>> {
+    let setting = db.get_vip_setting(cluster_name)?
+        .ok_or(format!("cluster {} has no vip setting", cluster_name))?;
+    let mut state = ClusterVipState{
+        cluster_name: cluster_name.clone(),
+        vip: setting.vip.clone(),
+        enable: setting.enable,
+        bound_hosts: vec![],
+        errors: vec![]
+    };
+    let info = setting.info();
+    let result = db.iterator(&CfNameTypeCode::HaNodesInfo.get(), &String::from(""))?;
+    for row in result {
+        let node: HostInfoValue = serde_json::from_str(&row.value)?;
+        if &node.cluster_name != cluster_name || node.rtype == "route" || !node.online {
+            continue;
+        }
+        match MyProtocol::VipStatus.vip_status(&row.key, &info) {
+            Ok(VipState{bound: true, ..}) => state.bound_hosts.push(row.key.clone()),
+            Ok(_) => {}
+            Err(e) => state.errors.push(format!("{}: {}", &row.key, e.to_string()))
+        }
+    }
+    Ok(state)
+}
@@ -6,6 +6,7 @@ pub mod webroute;
 pub mod storage;
 pub mod ha;
 pub mod readvalue;
+pub mod config;
 use std::time::{SystemTime, UNIX_EPOCH};
 use actix_web::{web, App, HttpServer};
 use actix_web::{guard, HttpResponse};
@@ -30,15 +31,44 @@ use log4rs::config::{Appender, Root};
 use actix_session::{CookieSession};
 //use actix_web::middleware::Logger;
 
-#[derive(Debug, StructOpt)]
+#[derive(Debug, Clone, StructOpt)]
 #[structopt(name = "example", about = "An example of StructOpt usage.")]
 pub struct Opt {
+    #[structopt(long = "config", help="toml格式的配置文件， 命令行参数优先")]
+    pub config: Option<String>,
+
     #[structopt(long = "port", help="监听端口")]
     pub port: Option<String>,
 
     #[structopt(long = "listen", help="监听地址，如:127.0.0.1")]
     pub listen: Option<String>,
 
+    #[structopt(long = "agentport", help="client主动注册及推送心跳的监听端口， 不设置则不启用")]
+    pub agentport: Option<String>,
+
+    #[structopt(long = "agenttoken", help="client注册及推送心跳时需要携带的token， 启用agentport时必须设置")]
+    pub agenttoken: Option<String>,
+
+    #[structopt(long = "dnsport", help="内置DNS监听端口(udp及tcp)， 不设置则不启用")]
+    pub dnsport: Option<String>,
+
+    #[structopt(long = "dnsdomain", help="内置DNS解析的域名后缀， 默认db.local")]
+    pub dnsdomain: Option<String>,
+
+    #[structopt(long = "backup", help="创建元数据备份后退出， server运行中请使用/createbackup接口")]
+    pub backup: bool,
+
+    #[structopt(long = "listbackup", help="列出所有元数据备份后退出")]
+    pub listbackup: bool,
+
+    #[structopt(long = "verifybackup", help="校验指定备份后退出")]
+    pub verifybackup: Option<String>,
+
+    #[structopt(long = "restore", help="启动前从指定备份恢复元数据")]
+    pub restore: Option<String>,
+
+    #[structopt(long = "memory", help="使用内存存储， 数据不落盘， 仅用于测试")]
+    pub memory: bool,
 
 }
 
@@ -46,41 +76,38 @@ pub struct Opt {
 pub struct Config {
     pub port: usize,
     pub listen: String,
+    pub agentport: Option<usize>,
+    pub dnsport: Option<usize>,
+    pub dnsdomain: String,
 }
 
 impl Config{
-    pub fn new(args: Opt) -> Result<Config, &'static str> {
-        let mut port = 8099;
-        let mut listen = String::from("127.0.0.1");
-
-
-        match args.port {
-            None => {
-            },
-            Some(t) => port = t.parse().unwrap(),
-        }
-
-        match args.listen {
-            None => {
-            },
-            Some(t) => listen = t,
+    ///
+    /// 命令行参数已在加载配置文件时合并
+    pub fn new(conf: &config::ServerConfig) -> Config {
+        Config{
+            port: conf.server.port,
+            listen: conf.server.listen.clone(),
+            agentport: conf.server.agentport,
+            dnsport: conf.server.dnsport,
+            dnsdomain: conf.server.dnsdomain.clone()
         }
-
-        Ok(Config{
-            port,
-            listen
-        })
     }
 }
 
 
 
-fn init_log() {
+///
+/// 初始化日志， 需要在加载配置之后、恢复备份及数据升级之前执行
+pub fn init_log() {
+    let server_conf = config::get();
+    let conf = &server_conf.log;
     let stdout = ConsoleAppender::builder().build();
+    let level: LevelFilter = conf.level.parse().unwrap_or(LevelFilter::Info);
 
     let requests = FileAppender::builder()
         .encoder(Box::new(PatternEncoder::new("{d} - {m}{n}")))
-        .build("log/requests.log")
+        .build(&conf.path)
         .unwrap();
 
     let config = log4rs::config::Config::builder()
@@ -91,7 +118,7 @@ fn init_log() {
             .appender("requests")
             .additive(false)
             .build("app::requests", LevelFilter::Info))
-        .build(Root::builder().appender("requests").build(LevelFilter::Info))
+        .build(Root::builder().appender("requests").build(level))
         .unwrap();
     log4rs::init_config(config).unwrap();
 }
@@ -115,14 +142,10 @@ pub fn rand_string() -> String {
 }
 
 pub fn start_web(db: DbInfo) {
-    let args = crate::Opt::from_args();
-    let conf = crate::Config::new(args).unwrap_or_else(|err|{
-        println!("Problem parsing arguments: {}", err);
-        std::process::exit(1);
-    });
+    let server_conf = config::get();
+    let conf = crate::Config::new(&server_conf);
     let listen_info = format!("{}:{}", conf.listen, conf.port);
 
-    init_log();
     info!("Start......");
     //let db = Arc::new(db);
     let rcdb = web::Data::new(db);
@@ -130,10 +153,14 @@ pub fn start_web(db: DbInfo) {
     //状态检查线程与宕机切换线程之间同步状态信息的channel
     let (state_tx, state_rx) = mpsc::channel();
 
+    //健康检查耗时等运行指标， /metrics输出
+    let metrics = web::Data::new(ha::metrics::ServerMetrics::new());
+
     //节点状态检查线程
     let a = rcdb.clone();
+    let m = metrics.clone();
     thread::spawn(move ||{
-        ha::ha_manager(a, state_tx);
+        ha::ha_manager(a, state_tx, m);
     });
 
     //宕机切换恢复管理线程
@@ -142,10 +169,14 @@ pub fn start_web(db: DbInfo) {
         ha::nodes_manager::manager(b, state_rx);
     });
 
+    //路由变化通知， route信息管理线程写入， web长轮询等待
+    let notify = web::Data::new(ha::route_notify::RouteNotify::new());
+
     //route信息管理线程
     let c = rcdb.clone();
+    let n = notify.clone();
     thread::spawn(move||{
-        ha::route_manager::manager(c);
+        ha::route_manager::manager(c, n);
     });
 
     //管理线程
@@ -154,15 +185,63 @@ pub fn start_web(db: DbInfo) {
         ha::sys_manager::manager(c);
     });
 
+    //读写分离代理管理线程
+    let e = rcdb.clone();
+    let proxy_listen = conf.listen.clone();
+    thread::spawn(move||{
+        ha::proxy::manager(e, proxy_listen);
+    });
+
+    //ProxySQL同步线程
+    let e = rcdb.clone();
+    thread::spawn(move||{
+        ha::proxysql::manager(e);
+    });
+
+    //路由导出线程
+    let e = rcdb.clone();
+    thread::spawn(move||{
+        ha::exporter::manager(e);
+    });
+
+    //定时备份线程
+    let e = rcdb.clone();
+    thread::spawn(move||{
+        storage::backup::manager(e);
+    });
+
+    //报警线程
+    let e = rcdb.clone();
+    thread::spawn(move||{
+        ha::alert::manager(e);
+    });
+
+    //SIGHUP重新加载配置线程
+    thread::spawn(move||{
+        config::watcher();
+    });
+
+    //client注册及心跳监听线程
+    if let Some(agentport) = conf.agentport {
+        let d = rcdb.clone();
+        let agent_listen = format!("{}:{}", conf.listen, agentport);
+        thread::spawn(move||{
+            ha::heartbeat::listener(d, agent_listen);
+        });
+    }
+
+    //内置DNS监听线程
+    if let Some(dnsport) = conf.dnsport {
+        let d = rcdb.clone();
+        let dns_listen = format!("{}:{}", conf.listen, dnsport);
+        let dns_domain = conf.dnsdomain.clone();
+        thread::spawn(move||{
+            ha::dns::listener(d, dns_listen, dns_domain);
+        });
+    }
+
     //web服务
-  // let mut builder =
-  //     SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
-  // builder
-  //     .set_private_key_file("key.pem", SslFiletype::PEM)
-  //     .unwrap();
-  // builder.set_certificate_chain_file("cert.pem").unwrap();
-
-    HttpServer::new(move|| {
+    let server = HttpServer::new(move|| {
         App::new()
 //            .wrap(Logger::default())
 //            .wrap(Logger::new("%a %s %{User-Agent}i"))
@@ -172,6 +251,8 @@ pub fn start_web(db: DbInfo) {
                     .secure(false),
             )
             .register_data(rcdb.clone())
+            .register_data(notify.clone())
+            .register_data(metrics.clone())
             .service(
                 web::resource("/index.html")
                     .name("foo") // <- set resource name, then it could be used in `url_for`
@@ -412,6 +493,63 @@ pub fn start_web(db: DbInfo) {
             .route("/", web::get().to(webroute::index))
             .route("/getuserinfo", web::post().to(webroute::route::get_user_info))
             .route("/get_cluster_metric", web::post().to(webroute::monitor_route::get_cluster_metric))
+            .route("/getpendingnodes", web::post().to(webroute::new_route::get_pending_nodes))
+            .route("/approvenode", web::post().to(webroute::new_route::approve_node))
+            .route("/rejectnode", web::post().to(webroute::new_route::reject_node))
+            .route("/probesetting", web::post().to(webroute::new_route::set_probe_setting))
+            .route("/getprobesetting", web::post().to(webroute::new_route::get_probe_setting))
+            .route("/getstatetimeline", web::post().to(webroute::new_route::get_state_timeline))
+            .route("/watchrouteinfo", web::post().to_async(webroute::route::watch_route_info))
+            .route("/readweightsetting", web::post().to(webroute::new_route::read_weight_setting))
+            .route("/getreadweightsetting", web::post().to(webroute::new_route::get_read_weight_setting))
+            .route("/getroutehistory", web::post().to(webroute::route::get_route_history))
+            .route("/proxysetting", web::post().to(webroute::new_route::proxy_setting))
+            .route("/getproxysetting", web::post().to(webroute::new_route::get_proxy_setting))
+            .route("/proxysqlsetting", web::post().to(webroute::new_route::proxysql_setting))
+            .route("/getproxysqlsetting", web::post().to(webroute::new_route::get_proxysql_setting))
+            .route("/getproxysqlstatus", web::post().to(webroute::new_route::get_proxysql_status))
+            .route("/exportersetting", web::post().to(webroute::new_route::exporter_setting))
+            .route("/getexportersetting", web::post().to(webroute::new_route::get_exporter_setting))
+            .route("/getexporterstatus", web::post().to(webroute::new_route::get_exporter_status))
+            .route("/renderexporter", web::post().to(webroute::new_route::render_exporter))
+            .route("/vipsetting", web::post().to(webroute::new_route::vip_setting))
+            .route("/getvipsetting", web::post().to(webroute::new_route::get_vip_setting))
+            .route("/getvipstatus", web::post().to(webroute::new_route::get_vip_status))
+            .route("/getviplog", web::post().to(webroute::new_route::get_vip_log))
+            .route("/takeovervip", web::post().to(webroute::new_route::takeover_vip))
+            .route("/readpolicysetting", web::post().to(webroute::new_route::read_policy_setting))
+            .route("/getreadpolicysetting", web::post().to(webroute::new_route::get_read_policy_setting))
+            .route("/getreadroutestate", web::post().to(webroute::new_route::get_read_route_state))
+            .route("/createbackup", web::post().to(webroute::new_route::create_backup))
+            .route("/getbackuplist", web::post().to(webroute::new_route::get_backup_list))
+            .route("/verifybackup", web::post().to(webroute::new_route::verify_backup))
+            .route("/deletebackup", web::post().to(webroute::new_route::delete_backup))
+            .route("/restorebackup", web::post().to(webroute::new_route::restore_backup))
+            .route("/backupsetting", web::post().to(webroute::new_route::backup_setting))
+            .route("/getbackupsetting", web::post().to(webroute::new_route::get_backup_setting))
+            .route("/configexport", web::post().to(webroute::new_route::config_export))
+            .route("/configdiff", web::post().to(webroute::new_route::config_diff))
+            .route("/configimport", web::post().to(webroute::new_route::config_import))
+            .route("/reloadconfig", web::post().to(webroute::new_route::reload_config))
+            .route("/getserverconfig", web::post().to(webroute::new_route::get_server_config))
+            .route("/metrics", web::get().to(webroute::new_route::metrics))
+            .route("/alertrule", web::post().to(webroute::new_route::alert_rule))
+            .route("/getalertrule", web::post().to(webroute::new_route::get_alert_rule))
+            .route("/deletealertrule", web::post().to(webroute::new_route::delete_alert_rule))
+            .route("/alertchannel", web::post().to(webroute::new_route::alert_channel))
+            .route("/getalertchannel", web::post().to(webroute::new_route::get_alert_channel))
+            .route("/deletealertchannel", web::post().to(webroute::new_route::delete_alert_channel))
+            .route("/testalertchannel", web::post().to(webroute::new_route::test_alert_channel))
+            .route("/getalerts", web::post().to(webroute::new_route::get_alerts))
+            .route("/getalerthistory", web::post().to(webroute::new_route::get_alert_history))
+            .route("/createsilence", web::post().to(webroute::new_route::create_silence))
+            .route("/expiresilence", web::post().to(webroute::new_route::expire_silence))
+            .route("/getsilences", web::post().to(webroute::new_route::get_silences))
+            .route("/getsilenceaudit", web::post().to(webroute::new_route::get_silence_audit))
+            .route("/monitorvariablesetting", web::post().to(webroute::new_route::set_monitor_variable_setting))
+            .route("/getmonitorvariablesetting", web::post().to(webroute::new_route::get_monitor_variable_setting))
+            .route("/deletemonitorvariablesetting", web::post().to(webroute::new_route::delete_monitor_variable_setting))
+            .route("/getmonitorvariables", web::post().to(webroute::new_route::get_monitor_variables))
             .route("/createuser", web::post().to(webroute::route::create_user))
             .route("/getlogdata", web::post().to(webroute::route::switchlog))
             .route("/{filename:.*}", web::get().to(webroute::index_static))
@@ -420,11 +558,20 @@ pub fn start_web(db: DbInfo) {
                     .guard(guard::Not(guard::Get()))
                     .to(|| HttpResponse::MethodNotAllowed()),
             )
-    })
-      // .bind_ssl(listen_info, builder)
-      // .unwrap()
-         .bind(listen_info)
-         .unwrap()
+    });
+    let server = if server_conf.tls.enable {
+        let mut builder =
+            SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
+        builder
+            .set_private_key_file(&server_conf.tls.key, SslFiletype::PEM)
+            .unwrap();
+        builder.set_certificate_chain_file(&server_conf.tls.cert).unwrap();
+        server.bind_ssl(listen_info, builder)
+    } else {
+        server.bind(listen_info)
+    };
+    server
+        .unwrap()
         .run()
         .unwrap();
 }
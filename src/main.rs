@@ -5,6 +5,8 @@
 
 use mymha;
 use mymha::storage::rocks::DbInfo;
+use mymha::storage::backup;
+use structopt::StructOpt;
 
 
 pub fn main() {
@@ -12,7 +14,53 @@ pub fn main() {
 //    db_info.init_db();
 
 
+    let args = mymha::Opt::from_args();
+    if let Err(e) = mymha::config::init(&args) {
+        println!("load config failed: {}", e.to_string());
+        std::process::exit(1);
+    }
+    mymha::init_log();
+    if args.listbackup {
+        match backup::list_backups() {
+            Ok(v) => {
+                for b in v {
+                    println!("{}\t{}\t{}\t{} bytes", b.name, b.kind, b.time, b.size);
+                }
+            }
+            Err(e) => println!("list backup failed: {}", e.to_string())
+        }
+        std::process::exit(0);
+    }
+    if let Some(name) = &args.verifybackup {
+        match backup::verify_backup(name) {
+            Ok(v) => println!("{}", serde_json::to_string_pretty(&v).unwrap()),
+            Err(e) => println!("verify backup failed: {}", e.to_string())
+        }
+        std::process::exit(0);
+    }
+    if args.memory {
+        let db = DbInfo::memory();
+        db.init_admin_user().unwrap();
+        mymha::start_web(db);
+        return;
+    }
+    if let Err(e) = backup::restore_at_startup(args.restore.clone()) {
+        println!("restore metadata failed: {}", e.to_string());
+        std::process::exit(1);
+    }
+
     let db = DbInfo::new();
+    if let Err(e) = mymha::storage::migrate::run(&db) {
+        println!("migrate metadata failed: {}", e.to_string());
+        std::process::exit(1);
+    }
+    if args.backup {
+        match backup::create_backup(&db, backup::BACKUP_MANUAL) {
+            Ok(v) => println!("create backup {} success, {} files, {} bytes", v.name, v.files.len(), v.size),
+            Err(e) => println!("create backup failed: {}", e.to_string())
+        }
+        std::process::exit(0);
+    }
     db.init_admin_user().unwrap();
     mymha::start_web(db);
 
@@ -7,6 +7,12 @@
 pub mod opdb;
 pub mod dbpool;
 pub mod rocks;
+pub mod backend;
+pub mod monitor_data;
+pub mod repo;
+pub mod migrate;
+pub mod backup;
+pub mod cluster_config;
 
 
 
@@ -0,0 +1,261 @@
+/*
+@author: xiao cai niao
+@datetime: 2020/09/14
+*/
+
+//! 存储后端
+//!
+//! DbInfo的所有读写都通过Storage完成， 默认为rocksdb， 也可以使用内存存储，
+//! 内存存储不落盘， 用于测试及配合agent_sim在单机上快速验证切换流程
+
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::sync::RwLock;
+use crate::storage::rocks::KeyValue;
+
+pub trait Storage: Send + Sync {
+    fn has_cf(&self, cf_name: &str) -> bool;
+
+    fn get(&self, cf_name: &str, key: &str) -> Result<Option<String>, Box<dyn Error>>;
+
+    fn put(&self, cf_name: &str, key: &str, value: &str) -> Result<(), Box<dyn Error>>;
+
+    fn delete(&self, cf_name: &str, key: &str) -> Result<(), Box<dyn Error>>;
+
+    ///
+    /// 从seek_to开始顺序遍历到列簇结尾， seek_to为空时从第一条开始
+    fn iterator(&self, cf_name: &str, seek_to: &str) -> Result<Vec<KeyValue>, Box<dyn Error>>;
+
+    ///
+    /// 前缀遍历， 返回结果可能包含不以prefix开头的key， 调用方需要自行过滤
+    fn prefix_iterator(&self, cf_name: &str, prefix: &str) -> Result<Vec<KeyValue>, Box<dyn Error>>;
+
+    ///
+    /// 获取[start, end)范围内的数据
+    fn range(&self, cf_name: &str, start: &str, end: &str) -> Result<Vec<KeyValue>, Box<dyn Error>> {
+        self.range_limit(cf_name, start, end, usize::MAX)
+    }
+
+    ///
+    /// 获取[start, end)范围内最多limit条数据， 用于分批遍历大量数据
+    fn range_limit(&self, cf_name: &str, start: &str, end: &str, limit: usize) -> Result<Vec<KeyValue>, Box<dyn Error>>;
+
+    ///
+    /// 删除[start, end)范围内的数据
+    fn delete_range(&self, cf_name: &str, start: &str, end: &str) -> Result<(), Box<dyn Error>>;
+
+    ///
+    /// rows为(列簇, 数据)， deletes为(列簇, key)， 全部成功或全部失败
+    fn write_batch(&self, rows: &Vec<(String, KeyValue)>, deletes: &Vec<(String, String)>) -> Result<(), Box<dyn Error>>;
+
+    ///
+    /// 创建一致性快照到path
+    fn checkpoint(&self, path: &str) -> Result<(), Box<dyn Error>>;
+
+    ///
+    /// 存储引擎的统计信息
+    fn stats(&self) -> Result<StorageStats, Box<dyn Error>>;
+}
+
+///
+/// 存储引擎统计信息
+#[derive(Debug, Default)]
+pub struct StorageStats {
+    pub properties: Vec<(String, String, u64)>,             //(列簇, 属性, 值)
+    pub tickers: Vec<(String, u64)>,                        //累计计数
+    pub histograms: Vec<(String, Vec<(f64, f64)>, u64, u64)>,   //(名称, [(分位, 值)], count, sum)
+}
+
+///
+/// 内存存储， 每个列簇为一个有序map， 遍历顺序与rocksdb一致
+pub struct MemoryStorage {
+    data: RwLock<HashMap<String, BTreeMap<String, String>>>,
+}
+
+impl MemoryStorage {
+    pub fn new(cf_names: &Vec<String>) -> MemoryStorage {
+        let data = cf_names.iter().map(|cf| (cf.clone(), BTreeMap::new())).collect();
+        MemoryStorage{ data: RwLock::new(data) }
+    }
+}
+
+fn no_cf(cf_name: &str) -> Box<dyn Error> {
+    format!("no cloumnfamily {}", cf_name).into()
+}
+
+fn lock_err<T>(_: T) -> Box<dyn Error> {
+    "memory storage lock poisoned".into()
+}
+
+impl Storage for MemoryStorage {
+    fn has_cf(&self, cf_name: &str) -> bool {
+        match self.data.read() {
+            Ok(data) => data.contains_key(cf_name),
+            Err(_) => false
+        }
+    }
+
+    fn get(&self, cf_name: &str, key: &str) -> Result<Option<String>, Box<dyn Error>> {
+        let data = self.data.read().map_err(lock_err)?;
+        let cf = data.get(cf_name).ok_or_else(|| no_cf(cf_name))?;
+        Ok(cf.get(key).cloned())
+    }
+
+    fn put(&self, cf_name: &str, key: &str, value: &str) -> Result<(), Box<dyn Error>> {
+        let mut data = self.data.write().map_err(lock_err)?;
+        let cf = data.get_mut(cf_name).ok_or_else(|| no_cf(cf_name))?;
+        cf.insert(key.to_string(), value.to_string());
+        Ok(())
+    }
+
+    fn delete(&self, cf_name: &str, key: &str) -> Result<(), Box<dyn Error>> {
+        let mut data = self.data.write().map_err(lock_err)?;
+        let cf = data.get_mut(cf_name).ok_or_else(|| no_cf(cf_name))?;
+        cf.remove(key);
+        Ok(())
+    }
+
+    fn iterator(&self, cf_name: &str, seek_to: &str) -> Result<Vec<KeyValue>, Box<dyn Error>> {
+        let data = self.data.read().map_err(lock_err)?;
+        let cf = data.get(cf_name).ok_or_else(|| no_cf(cf_name))?;
+        Ok(cf.range(seek_to.to_string()..).map(|(k, v)| KeyValue{ key: k.clone(), value: v.clone() }).collect())
+    }
+
+    fn prefix_iterator(&self, cf_name: &str, prefix: &str) -> Result<Vec<KeyValue>, Box<dyn Error>> {
+        let data = self.data.read().map_err(lock_err)?;
+        let cf = data.get(cf_name).ok_or_else(|| no_cf(cf_name))?;
+        Ok(cf.range(prefix.to_string()..)
+            .take_while(|(k, _)| k.starts_with(prefix))
+            .map(|(k, v)| KeyValue{ key: k.clone(), value: v.clone() })
+            .collect())
+    }
+
+    fn range_limit(&self, cf_name: &str, start: &str, end: &str, limit: usize) -> Result<Vec<KeyValue>, Box<dyn Error>> {
+        if start >= end {
+            return Ok(vec![]);
+        }
+        let data = self.data.read().map_err(lock_err)?;
+        let cf = data.get(cf_name).ok_or_else(|| no_cf(cf_name))?;
+        Ok(cf.range(start.to_string()..end.to_string()).take(limit).map(|(k, v)| KeyValue{ key: k.clone(), value: v.clone() }).collect())
+    }
+
+    fn delete_range(&self, cf_name: &str, start: &str, end: &str) -> Result<(), Box<dyn Error>> {
+        if start >= end {
+            return Ok(());
+        }
+        let mut data = self.data.write().map_err(lock_err)?;
+        let cf = data.get_mut(cf_name).ok_or_else(|| no_cf(cf_name))?;
+        let keys: Vec<String> = cf.range(start.to_string()..end.to_string()).map(|(k, _)| k.clone()).collect();
+        for key in keys {
+            cf.remove(&key);
+        }
+        Ok(())
+    }
+
+    fn write_batch(&self, rows: &Vec<(String, KeyValue)>, deletes: &Vec<(String, String)>) -> Result<(), Box<dyn Error>> {
+        let mut data = self.data.write().map_err(lock_err)?;
+        //先检查所有列簇， 保证不会只写入一部分
+        for cf_name in rows.iter().map(|r| &r.0).chain(deletes.iter().map(|d| &d.0)) {
+            if !data.contains_key(cf_name) {
+                return Err(no_cf(cf_name));
+            }
+        }
+        for (cf_name, kv) in rows {
+            if let Some(cf) = data.get_mut(cf_name) {
+                cf.insert(kv.key.clone(), kv.value.clone());
+            }
+        }
+        for (cf_name, key) in deletes {
+            if let Some(cf) = data.get_mut(cf_name) {
+                cf.remove(key);
+            }
+        }
+        Ok(())
+    }
+
+    fn checkpoint(&self, _path: &str) -> Result<(), Box<dyn Error>> {
+        Err("memory storage does not support backup".into())
+    }
+
+    fn stats(&self) -> Result<StorageStats, Box<dyn Error>> {
+        let data = self.data.read().map_err(lock_err)?;
+        let mut stats = StorageStats::default();
+        for (cf_name, cf) in data.iter() {
+            stats.properties.push((cf_name.clone(), "estimate-num-keys".to_string(), cf.len() as u64));
+        }
+        stats.properties.sort();
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::rocks::{DbInfo, KeyValue};
+
+    fn kv(key: &str, value: &str) -> KeyValue {
+        KeyValue{ key: key.to_string(), value: value.to_string() }
+    }
+
+    fn keys(rows: Vec<KeyValue>) -> Vec<String> {
+        rows.into_iter().map(|r| r.key).collect()
+    }
+
+    fn cf() -> String {
+        "System_data".to_string()
+    }
+
+    #[test]
+    fn put_get_delete() {
+        let db = DbInfo::memory();
+        db.put(&kv("a", "1"), &cf()).unwrap();
+        assert_eq!(db.get(&"a".to_string(), &cf()).unwrap().value, "1");
+        db.put(&kv("a", "2"), &cf()).unwrap();
+        assert_eq!(db.get(&"a".to_string(), &cf()).unwrap().value, "2");
+        db.delete(&"a".to_string(), &cf()).unwrap();
+        assert_eq!(db.get(&"a".to_string(), &cf()).unwrap().value, "");
+        assert!(db.put(&kv("a", "1"), &"no_cf".to_string()).is_err());
+        assert!(!db.storage.has_cf("no_cf"));
+    }
+
+    #[test]
+    fn prefix_and_iterator() {
+        let db = DbInfo::memory();
+        for k in &["a:1", "a:2", "ab:1", "b:1"] {
+            db.put(&kv(k, "v"), &cf()).unwrap();
+        }
+        assert_eq!(keys(db.prefix_iterator(&"a:".to_string(), &cf()).unwrap()), vec!["a:1", "a:2"]);
+        assert_eq!(keys(db.prefix_iterator(&"c".to_string(), &cf()).unwrap()), Vec::<String>::new());
+        assert_eq!(keys(db.iterator(&cf(), &"ab".to_string()).unwrap()), vec!["ab:1", "b:1"]);
+        assert_eq!(keys(db.iterator(&cf(), &"".to_string()).unwrap()).len(), 4);
+    }
+
+    #[test]
+    fn range_and_delete_range() {
+        let db = DbInfo::memory();
+        for i in 0..10 {
+            db.put(&kv(&format!("k{}", i), "v"), &cf()).unwrap();
+        }
+        assert_eq!(keys(db.storage.range(&cf(), "k2", "k5").unwrap()), vec!["k2", "k3", "k4"]);
+        assert_eq!(db.storage.range(&cf(), "k5", "k2").unwrap().len(), 0);
+        assert_eq!(keys(db.storage.range_limit(&cf(), "k2", "k9", 2).unwrap()), vec!["k2", "k3"]);
+        db.storage.delete_range(&cf(), "k2", "k5").unwrap();
+        assert_eq!(keys(db.storage.range(&cf(), "k0", "k9").unwrap()), vec!["k0", "k1", "k5", "k6", "k7", "k8"]);
+        db.storage.delete_range(&cf(), "k9", "k0").unwrap();
+        assert_eq!(db.iterator(&cf(), &"".to_string()).unwrap().len(), 7);
+    }
+
+    #[test]
+    fn write_batch_is_atomic() {
+        let db = DbInfo::memory();
+        db.put(&kv("old", "v"), &cf()).unwrap();
+        let rows = vec![(cf(), kv("new", "v")), ("Ha_change_log".to_string(), kv("log", "v"))];
+        db.write_batch(&rows, &vec![(cf(), "old".to_string())]).unwrap();
+        assert_eq!(keys(db.iterator(&cf(), &"".to_string()).unwrap()), vec!["new"]);
+        assert_eq!(db.get(&"log".to_string(), &"Ha_change_log".to_string()).unwrap().value, "v");
+
+        //任意一个列簇不存在时全部不写入
+        let rows = vec![(cf(), kv("other", "v")), ("no_cf".to_string(), kv("x", "v"))];
+        assert!(db.write_batch(&rows, &vec![(cf(), "new".to_string())]).is_err());
+        assert_eq!(keys(db.iterator(&cf(), &"".to_string()).unwrap()), vec!["new"]);
+    }
+}
@@ -0,0 +1,331 @@
+/*
+@author: xiao cai niao
+@datetime: 2020/09/12
+*/
+
+//! 元数据备份及恢复
+//!
+//! 备份使用rocksdb checkpoint， 与运行中的写入保持一致， 同一文件系统下为硬链接， 不会额外占用空间，
+//! 每个备份目录中的backup.json记录所有文件的大小及sha256， 用于校验
+//!
+//! 恢复只能在启动时进行: 先校验备份， 当前数据目录重命名保留， 再把备份复制为新的数据目录，
+//! 通过api发起的恢复会写入restore_pending文件， 下次启动时执行
+
+use std::error::Error;
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use std::{thread, time};
+use actix_web::web;
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+use rocksdb::{DB, Options};
+use crate::storage::rocks::{DbInfo, PrefixTypeCode};
+
+const MANIFEST: &str = "backup.json";
+const RESTORE_PENDING: &str = "restore_pending";
+
+pub const BACKUP_MANUAL: &str = "manual";
+pub const BACKUP_SCHEDULE: &str = "schedule";
+
+///
+/// 定时备份配置
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BackupSetting {
+    pub enable: bool,
+    pub interval: u64,          //备份间隔， 单位分钟
+    pub keep: usize,            //保留的定时备份个数， 手动备份不会自动删除
+}
+
+impl BackupSetting {
+    pub fn check(&self) -> Result<(), Box<dyn Error>> {
+        if self.interval == 0 || self.keep == 0 {
+            return Err("interval and keep must be greater than 0".into());
+        }
+        Ok(())
+    }
+
+    pub fn save(&self, db: &DbInfo) -> Result<(), Box<dyn Error>> {
+        self.check()?;
+        db.prefix_put(&PrefixTypeCode::BackupSeting, &"backup".to_string(), &self)?;
+        Ok(())
+    }
+}
+
+impl DbInfo {
+    ///
+    /// 获取定时备份配置， 未配置时不开启
+    pub fn get_backup_setting(&self) -> Result<BackupSetting, Box<dyn Error>> {
+        let result = self.prefix_get(&PrefixTypeCode::BackupSeting, &"backup".to_string())?;
+        if result.value.len() > 0 {
+            let value: BackupSetting = serde_json::from_str(&result.value)?;
+            return Ok(value);
+        }
+        Ok(BackupSetting{ enable: false, interval: 1440, keep: 7 })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BackupFile {
+    pub name: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+///
+/// 备份信息， 保存在备份目录的backup.json中
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BackupInfo {
+    pub name: String,
+    pub kind: String,           //manual、schedule
+    pub time: i64,
+    pub size: u64,
+    pub files: Vec<BackupFile>,
+}
+
+///
+/// 校验结果
+#[derive(Serialize, Deserialize, Debug)]
+pub struct VerifyResult {
+    pub name: String,
+    pub ok: bool,
+    pub errors: Vec<String>,
+    pub keys: Vec<(String, usize)>,     //每个列簇的key数量
+}
+
+///
+/// 数据目录及备份目录由配置文件指定
+fn data_dir() -> String {
+    crate::config::get().storage.data_dir.clone()
+}
+
+fn backup_dir() -> String {
+    crate::config::get().storage.backup_dir.clone()
+}
+
+fn backup_path(name: &str) -> Result<String, Box<dyn Error>> {
+    if name.len() == 0 || name.contains('/') || name.contains("..") {
+        return Err(format!("invalid backup name: {}", name).into());
+    }
+    Ok(format!("{}/{}", backup_dir(), name))
+}
+
+fn file_sha256(path: &Path) -> Result<String, Box<dyn Error>> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 65536];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.input(&buf[..n]);
+    }
+    Ok(hex::encode(hasher.result()))
+}
+
+///
+/// 创建备份， 可以在运行中执行
+pub fn create_backup(db: &DbInfo, kind: &str) -> Result<BackupInfo, Box<dyn Error>> {
+    fs::create_dir_all(backup_dir())?;
+    let time = crate::timestamp();
+    let name = format!("{}_{}", kind, time);
+    let path = backup_path(&name)?;
+    db.storage.checkpoint(&path)?;
+
+    let mut info = BackupInfo{ name: name.clone(), kind: kind.to_string(), time, size: 0, files: vec![] };
+    for entry in fs::read_dir(&path)? {
+        let entry = entry?;
+        let size = entry.metadata()?.len();
+        info.files.push(BackupFile{
+            name: entry.file_name().to_string_lossy().to_string(),
+            size,
+            sha256: file_sha256(&entry.path())?
+        });
+        info.size += size;
+    }
+    info.files.sort_by(|a, b| a.name.cmp(&b.name));
+    fs::write(format!("{}/{}", &path, MANIFEST), serde_json::to_string_pretty(&info)?)?;
+    info!("create backup {} success, {} files, {} bytes", &name, info.files.len(), info.size);
+    Ok(info)
+}
+
+fn read_manifest(name: &str) -> Result<BackupInfo, Box<dyn Error>> {
+    let path = format!("{}/{}", backup_path(name)?, MANIFEST);
+    let content = fs::read_to_string(&path)
+        .map_err(|e| format!("read {} failed: {}", &path, e.to_string()))?;
+    let info: BackupInfo = serde_json::from_str(&content)?;
+    Ok(info)
+}
+
+///
+/// 获取所有备份， 按时间倒序
+pub fn list_backups() -> Result<Vec<BackupInfo>, Box<dyn Error>> {
+    let mut backups = vec![];
+    let dir = backup_dir();
+    if !Path::new(&dir).exists() {
+        return Ok(backups);
+    }
+    for entry in fs::read_dir(&dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {continue;}
+        let name = entry.file_name().to_string_lossy().to_string();
+        //没有backup.json的目录为创建中途失败的备份
+        if let Ok(info) = read_manifest(&name) {
+            backups.push(info);
+        }
+    }
+    backups.sort_by(|a, b| b.time.cmp(&a.time));
+    Ok(backups)
+}
+
+///
+/// 校验备份文件完整性， 并以只读方式打开读取所有数据
+pub fn verify_backup(name: &str) -> Result<VerifyResult, Box<dyn Error>> {
+    let info = read_manifest(name)?;
+    let path = backup_path(name)?;
+    let mut result = VerifyResult{ name: name.to_string(), ok: false, errors: vec![], keys: vec![] };
+    for f in &info.files {
+        let file_path = Path::new(&path).join(&f.name);
+        match fs::metadata(&file_path) {
+            Ok(m) if m.len() != f.size => {
+                result.errors.push(format!("{} size is {}, expected {}", &f.name, m.len(), f.size));
+                continue;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                result.errors.push(format!("{}: {}", &f.name, e.to_string()));
+                continue;
+            }
+        }
+        match file_sha256(&file_path) {
+            Ok(ref sum) if sum == &f.sha256 => {}
+            Ok(_) => result.errors.push(format!("{} checksum mismatch", &f.name)),
+            Err(e) => result.errors.push(format!("{}: {}", &f.name, e.to_string()))
+        }
+    }
+    if result.errors.len() > 0 {
+        return Ok(result);
+    }
+
+    match count_keys(&path) {
+        Ok(keys) => result.keys = keys,
+        Err(e) => result.errors.push(format!("open backup failed: {}", e.to_string()))
+    }
+    result.ok = result.errors.len() == 0;
+    Ok(result)
+}
+
+///
+/// 只读打开备份并遍历所有列簇， 读取过程中会校验block的checksum
+fn count_keys(path: &String) -> Result<Vec<(String, usize)>, Box<dyn Error>> {
+    let opts = Options::default();
+    let cfs = DB::list_cf(&opts, path)?;
+    let db = DB::open_cf_for_read_only(&opts, path, &cfs, false)?;
+    let mut keys = vec![];
+    for cf_name in &cfs {
+        let mut count = 0;
+        if let Some(cf) = db.cf_handle(cf_name) {
+            let mut iter = db.raw_iterator_cf(cf);
+            iter.seek_to_first();
+            while iter.valid() {
+                count += 1;
+                iter.next();
+            }
+            iter.status()?;
+        }
+        keys.push((cf_name.clone(), count));
+    }
+    Ok(keys)
+}
+
+pub fn delete_backup(name: &str) -> Result<(), Box<dyn Error>> {
+    read_manifest(name)?;
+    fs::remove_dir_all(backup_path(name)?)?;
+    info!("delete backup {}", name);
+    Ok(())
+}
+
+///
+/// 标记下次启动时恢复该备份
+pub fn set_restore_pending(name: &str) -> Result<VerifyResult, Box<dyn Error>> {
+    let result = verify_backup(name)?;
+    if result.ok {
+        fs::write(format!("{}/{}", backup_dir(), RESTORE_PENDING), name)?;
+        info!("backup {} will be restored at next startup", name);
+    }
+    Ok(result)
+}
+
+///
+/// 启动时恢复， 参数为空时检查是否有通过api标记的恢复
+///
+/// 必须在打开rocksdb之前执行， 原数据目录重命名为<data_dir>.before_restore_<time>保留
+pub fn restore_at_startup(name: Option<String>) -> Result<(), Box<dyn Error>> {
+    let pending = format!("{}/{}", backup_dir(), RESTORE_PENDING);
+    let name = match name {
+        Some(n) => n,
+        None => match fs::read_to_string(&pending) {
+            Ok(n) => n.trim().to_string(),
+            Err(_) => return Ok(())
+        }
+    };
+    let result = verify_backup(&name)?;
+    if !result.ok {
+        return Err(format!("backup {} verify failed: {}", &name, result.errors.join("; ")).into());
+    }
+
+    //先复制到临时目录， 复制完成后再替换， 中途失败不影响原数据
+    let data_dir = data_dir();
+    let tmp = format!("{}.restore_tmp", &data_dir);
+    if Path::new(&tmp).exists() {
+        fs::remove_dir_all(&tmp)?;
+    }
+    fs::create_dir_all(&tmp)?;
+    let info = read_manifest(&name)?;
+    let path = backup_path(&name)?;
+    for f in &info.files {
+        fs::copy(Path::new(&path).join(&f.name), Path::new(&tmp).join(&f.name))?;
+    }
+    if Path::new(&data_dir).exists() {
+        let old = format!("{}.before_restore_{}", &data_dir, crate::timestamp());
+        fs::rename(&data_dir, &old)?;
+        info!("current data moved to {}", &old);
+    }
+    fs::rename(&tmp, &data_dir)?;
+    if Path::new(&pending).exists() {
+        fs::remove_file(&pending)?;
+    }
+    info!("restore backup {} success", &name);
+    Ok(())
+}
+
+///
+/// 定时备份线程， 每分钟检查一次
+pub fn manager(db: web::Data<DbInfo>) {
+    info!("backup thread start success");
+    loop {
+        if let Err(e) = check_schedule(&db) {
+            info!("scheduled backup failed: {}", e.to_string());
+        }
+        thread::sleep(time::Duration::from_secs(60));
+    }
+}
+
+fn check_schedule(db: &DbInfo) -> Result<(), Box<dyn Error>> {
+    let setting = db.get_backup_setting()?;
+    if !setting.enable {
+        return Ok(());
+    }
+    let scheduled: Vec<BackupInfo> = list_backups()?.into_iter().filter(|b| b.kind == BACKUP_SCHEDULE).collect();
+    let last = scheduled.first().map(|b| b.time).unwrap_or(0);
+    if crate::timestamp() - last < setting.interval as i64 * 60000 {
+        return Ok(());
+    }
+    create_backup(db, BACKUP_SCHEDULE)?;
+    //加上本次创建的备份， 超出保留个数的从最旧的开始删除
+    for old in scheduled.iter().skip(setting.keep.saturating_sub(1)) {
+        delete_backup(&old.name)?;
+    }
+    Ok(())
+}
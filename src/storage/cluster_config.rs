@@ -0,0 +1,456 @@
+/*
+@author: xiao cai niao
+@datetime: 2020/09/13
+*/
+
+//! 集群配置导出及导入
+//!
+//! 导出节点、集群名、slave延迟配置、监控配置及用户(不含密码)为一个json文档， 可以放到git中管理，
+//! 导入时与当前配置比较生成变更列表， 确认后在一个batch中写入， 全部成功或全部失败
+//!
+//! 导入是声明式的: 文档中不存在的节点、配置及用户会被删除， 节点只有处于维护模式时才允许删除
+
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use serde::{Serialize, Deserialize};
+use serde_json::{Value, json};
+use crate::storage::rocks::{DbInfo, KeyValue, CfNameTypeCode};
+use crate::storage::opdb::{HostInfoValue, UserInfo, SlaveBehindSetting};
+use crate::storage::repo::{Record, Repo, encode_key};
+use crate::ha::sys_manager::MonitorSetting;
+use crate::webroute::route::{EditMainTain, PostUserInfo};
+
+///
+/// 当前文档格式版本
+pub const CONFIG_VERSION: u32 = 1;
+
+pub const CHANGE_ADD: &str = "add";
+pub const CHANGE_UPDATE: &str = "update";
+pub const CHANGE_DELETE: &str = "delete";
+
+///
+/// 节点配置， 不包含在线状态等运行时信息
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NodeConfig {
+    pub host: String,
+    pub dbport: usize,
+    pub rtype: String,
+    pub cluster_name: String,
+    #[serde(default)]
+    pub maintain: bool,
+}
+
+impl NodeConfig {
+    fn from(node: &HostInfoValue) -> NodeConfig {
+        NodeConfig{
+            host: node.host.clone(),
+            dbport: node.dbport,
+            rtype: node.rtype.clone(),
+            cluster_name: node.cluster_name.clone(),
+            maintain: node.maintain
+        }
+    }
+}
+
+///
+/// 用户， 导出时不包含密码， 导入新用户或修改密码时填写
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UserConfig {
+    pub user_name: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub password: String,
+}
+
+///
+/// 完整的配置文档
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ClusterConfig {
+    pub version: u32,
+    pub clusters: Vec<String>,
+    pub nodes: Vec<NodeConfig>,
+    #[serde(default)]
+    pub slave_behind: Vec<SlaveBehindSetting>,
+    #[serde(default)]
+    pub monitor: Vec<MonitorSetting>,
+    pub users: Vec<UserConfig>,
+}
+
+///
+/// 一项变更， old、new为变更前后的值， 新增时old为null， 删除时new为null
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ConfigChange {
+    pub kind: String,           //node、slave_behind、monitor、user
+    pub key: String,
+    pub action: String,         //add、update、delete
+    pub old: Value,
+    pub new: Value,
+}
+
+///
+/// 变更列表及需要写入的数据
+struct ConfigPlan {
+    changes: Vec<ConfigChange>,
+    rows: Vec<(String, KeyValue)>,
+    deletes: Vec<(String, String)>,
+}
+
+impl ConfigPlan {
+    fn change(&mut self, kind: &str, key: &String, action: &str, old: Value, new: Value) {
+        self.changes.push(ConfigChange{ kind: kind.to_string(), key: key.clone(), action: action.to_string(), old, new });
+    }
+
+    fn put<T: Record>(&mut self, key: &str, value: &T) -> Result<(), Box<dyn Error>> {
+        self.rows.push((T::cf().get(), KeyValue{ key: encode_key::<T>(key), value: serde_json::to_string(value)? }));
+        Ok(())
+    }
+
+    fn delete<T: Record>(&mut self, key: &str) {
+        self.deletes.push((T::cf().get(), encode_key::<T>(key)));
+    }
+}
+
+///
+/// 导出当前配置
+pub fn export(db: &DbInfo) -> Result<ClusterConfig, Box<dyn Error>> {
+    let mut nodes: Vec<NodeConfig> = Repo::<HostInfoValue>::new(db).all()?.iter().map(|r| NodeConfig::from(&r.value)).collect();
+    nodes.sort_by(|a, b| a.host.cmp(&b.host));
+    let mut clusters: Vec<String> = nodes.iter().map(|n| n.cluster_name.clone()).collect::<HashSet<String>>().into_iter().collect();
+    clusters.sort();
+    let mut slave_behind: Vec<SlaveBehindSetting> = Repo::<SlaveBehindSetting>::new(db).all()?.into_iter().map(|r| r.value).collect();
+    slave_behind.sort_by(|a, b| a.cluster_name.cmp(&b.cluster_name));
+    let mut monitor: Vec<MonitorSetting> = Repo::<MonitorSetting>::new(db).all()?.into_iter().map(|r| r.value).collect();
+    monitor.sort_by(|a, b| a.host.cmp(&b.host));
+    let mut users: Vec<UserConfig> = Repo::<UserInfo>::new(db).all()?.into_iter()
+        .map(|r| UserConfig{ user_name: r.value.user_name, password: "".to_string() }).collect();
+    users.sort_by(|a, b| a.user_name.cmp(&b.user_name));
+    Ok(ClusterConfig{ version: CONFIG_VERSION, clusters, nodes, slave_behind, monitor, users })
+}
+
+///
+/// 与当前配置比较， 返回变更列表， 不做修改
+pub fn diff(db: &DbInfo, config: &ClusterConfig) -> Result<Vec<ConfigChange>, Box<dyn Error>> {
+    Ok(plan(db, config)?.changes)
+}
+
+///
+/// 导入配置， 所有变更在一个batch中写入， user为操作人
+pub fn import(db: &DbInfo, config: &ClusterConfig, user: &String) -> Result<Vec<ConfigChange>, Box<dyn Error>> {
+    let plan = plan(db, config)?;
+    if plan.changes.len() > 0 {
+        db.write_batch(&plan.rows, &plan.deletes)?;
+        info!("import cluster config by {} success, {} changes", user, plan.changes.len());
+    }
+    Ok(plan.changes)
+}
+
+fn check_unique<'a, I: Iterator<Item = &'a String>>(kind: &str, keys: I) -> Result<(), Box<dyn Error>> {
+    let mut set = HashSet::new();
+    for key in keys {
+        if !set.insert(key) {
+            return Err(format!("duplicate {}: {}", kind, key).into());
+        }
+    }
+    Ok(())
+}
+
+///
+/// 检查文档本身是否有效
+fn check(config: &ClusterConfig) -> Result<(), Box<dyn Error>> {
+    if config.version == 0 || config.version > CONFIG_VERSION {
+        return Err(format!("unsupported config version {}, current version is {}", config.version, CONFIG_VERSION).into());
+    }
+    check_unique("cluster", config.clusters.iter())?;
+    check_unique("node", config.nodes.iter().map(|n| &n.host))?;
+    check_unique("slave_behind", config.slave_behind.iter().map(|s| &s.cluster_name))?;
+    check_unique("monitor", config.monitor.iter().map(|m| &m.host))?;
+    check_unique("user", config.users.iter().map(|u| &u.user_name))?;
+    for node in &config.nodes {
+        if node.host.len() == 0 || (node.rtype != "db" && node.rtype != "route") {
+            return Err(format!("invalid node {}: host can not be empty and rtype must be db or route", &node.host).into());
+        }
+        if !config.clusters.contains(&node.cluster_name) {
+            return Err(format!("cluster {} of node {} is not in clusters", &node.cluster_name, &node.host).into());
+        }
+    }
+    for s in &config.slave_behind {
+        if !config.clusters.contains(&s.cluster_name) {
+            return Err(format!("slave_behind cluster {} is not in clusters", &s.cluster_name).into());
+        }
+        if s.readmit_delay > s.delay {
+            return Err(format!("slave_behind cluster {}: readmit_delay must be less than or equal to delay", &s.cluster_name).into());
+        }
+    }
+    for m in &config.monitor {
+        if !config.nodes.iter().any(|n| n.host == m.host) {
+            return Err(format!("monitor host {} is not in nodes", &m.host).into());
+        }
+    }
+    if config.users.len() == 0 {
+        return Err("users can not be empty".into());
+    }
+    Ok(())
+}
+
+fn plan(db: &DbInfo, config: &ClusterConfig) -> Result<ConfigPlan, Box<dyn Error>> {
+    check(config)?;
+    let mut plan = ConfigPlan{ changes: vec![], rows: vec![], deletes: vec![] };
+    plan_nodes(db, config, &mut plan)?;
+    plan_slave_behind(db, config, &mut plan)?;
+    plan_monitor(db, config, &mut plan)?;
+    plan_users(db, config, &mut plan)?;
+    Ok(plan)
+}
+
+fn plan_nodes(db: &DbInfo, config: &ClusterConfig, plan: &mut ConfigPlan) -> Result<(), Box<dyn Error>> {
+    let mut current: HashMap<String, HostInfoValue> = Repo::<HostInfoValue>::new(db).all()?.into_iter().map(|r| (r.key, r.value)).collect();
+    for node in &config.nodes {
+        let new = serde_json::to_value(node)?;
+        match current.remove(&node.host) {
+            Some(mut cur) => {
+                let old = serde_json::to_value(NodeConfig::from(&cur))?;
+                if old == new {continue;}
+                //与/setmaintain相同， 在线的master不能设置为维护模式
+                if node.maintain && !cur.maintain {
+                    let m = EditMainTain{ host: node.host.clone(), maintain: "true".to_string() };
+                    if !m.check_role(db)? {
+                        return Err(format!("the master node {} cannot be set to maintenance mode", &node.host).into());
+                    }
+                }
+                cur.dbport = node.dbport;
+                cur.rtype = node.rtype.clone();
+                cur.cluster_name = node.cluster_name.clone();
+                cur.maintain = node.maintain;
+                cur.update_time = crate::timestamp();
+                plan.put(&node.host, &cur)?;
+                plan.change("node", &node.host, CHANGE_UPDATE, old, new);
+            }
+            None => {
+                let value = HostInfoValue{
+                    host: node.host.clone(),
+                    dbport: node.dbport,
+                    rtype: node.rtype.clone(),
+                    cluster_name: node.cluster_name.clone(),
+                    online: false,
+                    insert_time: crate::timestamp(),
+                    update_time: crate::timestamp(),
+                    maintain: node.maintain
+                };
+                plan.put(&node.host, &value)?;
+                plan.change("node", &node.host, CHANGE_ADD, Value::Null, new);
+            }
+        }
+    }
+    //与/deletenode相同， 只能删除维护模式的节点， 同时删除状态信息
+    for (host, cur) in current {
+        if !cur.maintain {
+            return Err(format!("node {} is not in maintenance mode and can not be deleted", &host).into());
+        }
+        plan.delete::<HostInfoValue>(&host);
+        plan.deletes.push((CfNameTypeCode::NodesState.get(), host.clone()));
+        plan.change("node", &host, CHANGE_DELETE, serde_json::to_value(NodeConfig::from(&cur))?, Value::Null);
+    }
+    Ok(())
+}
+
+fn plan_slave_behind(db: &DbInfo, config: &ClusterConfig, plan: &mut ConfigPlan) -> Result<(), Box<dyn Error>> {
+    let mut current: HashMap<String, SlaveBehindSetting> = Repo::<SlaveBehindSetting>::new(db).all()?.into_iter().map(|r| (r.key, r.value)).collect();
+    for s in &config.slave_behind {
+        let new = serde_json::to_value(s)?;
+        match current.remove(&s.cluster_name) {
+            Some(cur) => {
+                let old = serde_json::to_value(&cur)?;
+                if old == new {continue;}
+                plan.put(&s.cluster_name, s)?;
+                plan.change("slave_behind", &s.cluster_name, CHANGE_UPDATE, old, new);
+            }
+            None => {
+                plan.put(&s.cluster_name, s)?;
+                plan.change("slave_behind", &s.cluster_name, CHANGE_ADD, Value::Null, new);
+            }
+        }
+    }
+    for (key, cur) in current {
+        plan.delete::<SlaveBehindSetting>(&key);
+        plan.change("slave_behind", &key, CHANGE_DELETE, serde_json::to_value(&cur)?, Value::Null);
+    }
+    Ok(())
+}
+
+fn plan_monitor(db: &DbInfo, config: &ClusterConfig, plan: &mut ConfigPlan) -> Result<(), Box<dyn Error>> {
+    let mut current: HashMap<String, MonitorSetting> = Repo::<MonitorSetting>::new(db).all()?.into_iter().map(|r| (r.key, r.value)).collect();
+    for m in &config.monitor {
+        let new = serde_json::to_value(m)?;
+        match current.remove(&m.host) {
+            Some(cur) => {
+                let old = serde_json::to_value(&cur)?;
+                if old == new {continue;}
+                plan.put(&m.host, m)?;
+                plan.change("monitor", &m.host, CHANGE_UPDATE, old, new);
+            }
+            None => {
+                plan.put(&m.host, m)?;
+                plan.change("monitor", &m.host, CHANGE_ADD, Value::Null, new);
+            }
+        }
+    }
+    for (key, cur) in current {
+        plan.delete::<MonitorSetting>(&key);
+        plan.change("monitor", &key, CHANGE_DELETE, serde_json::to_value(&cur)?, Value::Null);
+    }
+    Ok(())
+}
+
+///
+/// 变更列表中不显示密码
+fn user_value(user_name: &String, password_changed: bool) -> Value {
+    if password_changed {
+        return json!({"user_name": user_name, "password": "******"});
+    }
+    json!({"user_name": user_name})
+}
+
+fn plan_users(db: &DbInfo, config: &ClusterConfig, plan: &mut ConfigPlan) -> Result<(), Box<dyn Error>> {
+    let mut current: HashMap<String, UserInfo> = Repo::<UserInfo>::new(db).all()?.into_iter().map(|r| (r.key, r.value)).collect();
+    for u in &config.users {
+        match current.remove(&u.user_name) {
+            Some(mut cur) => {
+                if u.password.len() == 0 || u.password == cur.password {continue;}
+                cur.password = u.password.clone();
+                cur.update_time = crate::timestamp();
+                plan.put(&u.user_name, &cur)?;
+                plan.change("user", &u.user_name, CHANGE_UPDATE, user_value(&u.user_name, false), user_value(&u.user_name, true));
+            }
+            None => {
+                if u.password.len() == 0 {
+                    return Err(format!("new user {} must have a password", &u.user_name).into());
+                }
+                let user = UserInfo::new(&PostUserInfo{ user_name: u.user_name.clone(), password: u.password.clone() });
+                plan.put(&u.user_name, &user)?;
+                plan.change("user", &u.user_name, CHANGE_ADD, Value::Null, user_value(&u.user_name, true));
+            }
+        }
+    }
+    for (key, _) in current {
+        plan.delete::<UserInfo>(&key);
+        plan.change("user", &key, CHANGE_DELETE, user_value(&key, false), Value::Null);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(host: &str, cluster_name: &str, maintain: bool) -> HostInfoValue {
+        HostInfoValue{ host: host.to_string(), dbport: 3306, rtype: "db".to_string(), cluster_name: cluster_name.to_string(),
+            online: false, insert_time: 0, update_time: 0, maintain }
+    }
+
+    fn user(user_name: &str, password: &str) -> UserInfo {
+        UserInfo::new(&PostUserInfo{ user_name: user_name.to_string(), password: password.to_string() })
+    }
+
+    ///
+    /// 两个节点， 9012处于维护模式， 两个用户
+    fn seed() -> DbInfo {
+        let db = DbInfo::memory();
+        let nodes = Repo::<HostInfoValue>::new(&db);
+        nodes.put("127.0.0.1:9011", &node("127.0.0.1:9011", "c1", false)).unwrap();
+        nodes.put("127.0.0.1:9012", &node("127.0.0.1:9012", "c1", true)).unwrap();
+        let users = Repo::<UserInfo>::new(&db);
+        users.put("admin", &user("admin", "pass")).unwrap();
+        users.put("ops", &user("ops", "pass")).unwrap();
+        db
+    }
+
+    fn actions(changes: &Vec<ConfigChange>) -> Vec<(String, String, String)> {
+        let mut v: Vec<(String, String, String)> = changes.iter().map(|c| (c.kind.clone(), c.key.clone(), c.action.clone())).collect();
+        v.sort();
+        v
+    }
+
+    fn change(kind: &str, key: &str, action: &str) -> (String, String, String) {
+        (kind.to_string(), key.to_string(), action.to_string())
+    }
+
+    #[test]
+    fn export_then_diff_is_empty() {
+        let db = seed();
+        let config = export(&db).unwrap();
+        assert_eq!(config.clusters, vec!["c1".to_string()]);
+        assert_eq!(config.nodes.len(), 2);
+        assert!(config.users.iter().all(|u| u.password.len() == 0));
+        assert_eq!(diff(&db, &config).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn import_add_update_delete() {
+        let db = seed();
+        let mut config = export(&db).unwrap();
+        //修改9011端口， 删除维护模式的9012， 新增9013
+        config.nodes[0].dbport = 3307;
+        config.nodes.remove(1);
+        config.nodes.push(NodeConfig{ host: "127.0.0.1:9013".to_string(), dbport: 3306, rtype: "db".to_string(),
+            cluster_name: "c1".to_string(), maintain: false });
+        config.slave_behind.push(SlaveBehindSetting::new(&"c1".to_string()));
+        config.users.retain(|u| u.user_name == "admin");
+        config.users[0].password = "newpass".to_string();
+
+        let expected = vec![
+            change("node", "127.0.0.1:9011", CHANGE_UPDATE),
+            change("node", "127.0.0.1:9012", CHANGE_DELETE),
+            change("node", "127.0.0.1:9013", CHANGE_ADD),
+            change("slave_behind", "c1", CHANGE_ADD),
+            change("user", "admin", CHANGE_UPDATE),
+            change("user", "ops", CHANGE_DELETE),
+        ];
+        //diff不做修改
+        assert_eq!(actions(&diff(&db, &config).unwrap()), expected);
+        assert_eq!(Repo::<HostInfoValue>::new(&db).get("127.0.0.1:9011").unwrap().unwrap().dbport, 3306);
+
+        assert_eq!(actions(&import(&db, &config, &"admin".to_string()).unwrap()), expected);
+        let nodes = Repo::<HostInfoValue>::new(&db);
+        assert_eq!(nodes.get("127.0.0.1:9011").unwrap().unwrap().dbport, 3307);
+        assert!(nodes.get("127.0.0.1:9012").unwrap().is_none());
+        assert!(nodes.get("127.0.0.1:9013").unwrap().is_some());
+        assert!(Repo::<SlaveBehindSetting>::new(&db).get("c1").unwrap().is_some());
+        let users = Repo::<UserInfo>::new(&db);
+        assert_eq!(users.get("admin").unwrap().unwrap().password, "newpass");
+        assert!(users.get("ops").unwrap().is_none());
+        //再次导入没有变更
+        assert_eq!(diff(&db, &config).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn reject_delete_online_node_without_partial_write() {
+        let db = seed();
+        let mut config = export(&db).unwrap();
+        //删除不在维护模式的9011， 同时新增节点及修改密码
+        config.nodes.retain(|n| n.host != "127.0.0.1:9011");
+        config.nodes.push(NodeConfig{ host: "127.0.0.1:9013".to_string(), dbport: 3306, rtype: "db".to_string(),
+            cluster_name: "c1".to_string(), maintain: false });
+        config.users[0].password = "newpass".to_string();
+
+        assert!(diff(&db, &config).is_err());
+        assert!(import(&db, &config, &"admin".to_string()).is_err());
+        let nodes = Repo::<HostInfoValue>::new(&db);
+        assert!(nodes.get("127.0.0.1:9011").unwrap().is_some());
+        assert!(nodes.get("127.0.0.1:9013").unwrap().is_none());
+        assert_eq!(Repo::<UserInfo>::new(&db).get("admin").unwrap().unwrap().password, "pass");
+    }
+
+    #[test]
+    fn reject_invalid_document() {
+        let db = seed();
+        let mut config = export(&db).unwrap();
+        config.nodes[0].cluster_name = "c2".to_string();
+        assert!(import(&db, &config, &"admin".to_string()).is_err());
+        let mut config = export(&db).unwrap();
+        config.users.push(UserConfig{ user_name: "new".to_string(), password: "".to_string() });
+        assert!(import(&db, &config, &"admin".to_string()).is_err());
+        let mut config = export(&db).unwrap();
+        config.version = CONFIG_VERSION + 1;
+        assert!(diff(&db, &config).is_err());
+        assert_eq!(Repo::<UserInfo>::new(&db).all().unwrap().len(), 2);
+    }
+}
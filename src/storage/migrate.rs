@@ -0,0 +1,342 @@
+/*
+@author: xiao cai niao
+@datetime: 2020/09/11
+*/
+
+//! 启动时升级已保存的数据
+//!
+//! 每种记录类型的schema版本保存在System_data中， 启动时与Record::VERSION比较，
+//! 依次执行升级函数， 所有记录升级并校验能够反序列化后与新版本号在同一个batch中写入
+//!
+//! 保存的版本高于当前代码时拒绝启动， 避免旧版本server修改新格式的数据
+
+use std::error::Error;
+use serde_json::{Value, json};
+use crate::storage::rocks::{DbInfo, KeyValue, PrefixTypeCode, CfNameTypeCode};
+use crate::storage::repo::{Record, Repo};
+use crate::storage::opdb::{HostInfoValue, HaChangeLog, UserInfo, SlaveBehindSetting, ReadWeightSetting, ReadPolicySetting, HA_LOG_FAILOVER};
+use crate::ha::procotol::MysqlState;
+use crate::ha::nodes_manager::CheckState;
+use crate::ha::route_manager::RouteInfo;
+use crate::ha::sys_manager::{MonitorSetting, MonitorVariableSetting};
+use crate::ha::proxy::ProxySetting;
+use crate::ha::proxysql::ProxysqlSetting;
+use crate::ha::exporter::ExporterSetting;
+use crate::ha::vip::{VipSetting, VipLog};
+use crate::storage::backup::BackupSetting;
+use crate::storage::monitor_data;
+use crate::ha::procotol::MysqlMonitorStatus;
+use crate::ha::alert::AlertRule;
+use crate::ha::alert_channel::AlertChannel;
+use crate::ha::silence::Silence;
+
+///
+/// 将某一类型的记录从version - 1升级到version
+struct Migration {
+    name: &'static str,
+    version: u32,
+    upgrade: fn(&mut Value) -> Result<(), Box<dyn Error>>,
+}
+
+fn migrations() -> Vec<Migration> {
+    vec![
+        Migration{ name: HaChangeLog::NAME, version: 2, upgrade: ha_change_log_v2 },
+        Migration{ name: CheckState::NAME, version: 2, upgrade: check_state_v2 },
+        Migration{ name: RouteInfo::NAME, version: 2, upgrade: route_info_v2 },
+        Migration{ name: SlaveBehindSetting::NAME, version: 2, upgrade: slave_behind_setting_v2 },
+        Migration{ name: MonitorSetting::NAME, version: 2, upgrade: monitor_setting_v2 },
+    ]
+}
+
+///
+/// 字段不存在时设置默认值
+fn set_default(value: &mut Value, field: &str, default: Value) -> Result<(), Box<dyn Error>> {
+    match value.as_object_mut() {
+        Some(obj) => {
+            if !obj.contains_key(field) {
+                obj.insert(field.to_string(), default);
+            }
+            Ok(())
+        }
+        None => Err(format!("record is not an object: {}", value).into())
+    }
+}
+
+///
+/// 增加切换类型、vip漂移记录及切换开始结束时间， 旧版本只记录宕机切换，
+/// 时间设置为0不参与耗时统计
+fn ha_change_log_v2(value: &mut Value) -> Result<(), Box<dyn Error>> {
+    set_default(value, "kind", json!(HA_LOG_FAILOVER))?;
+    set_default(value, "vip", Value::Null)?;
+    set_default(value, "start_time", json!(0))?;
+    set_default(value, "finish_time", json!(0))
+}
+
+///
+/// 增加server直连检查结果
+fn check_state_v2(value: &mut Value) -> Result<(), Box<dyn Error>> {
+    set_default(value, "probe", json!(""))
+}
+
+///
+/// 增加读权重、generation及读路由降级策略
+fn route_info_v2(value: &mut Value) -> Result<(), Box<dyn Error>> {
+    set_default(value, "generation", json!(0))?;
+    set_default(value, "update_time", json!(0))?;
+    set_default(value, "read_policy", json!(""))?;
+    set_default(value, "degraded", json!(false))?;
+    if let Some(write) = value.get_mut("write") {
+        set_default(write, "weight", json!(100))?;
+    }
+    if let Some(Value::Array(read)) = value.get_mut("read") {
+        for r in read {
+            set_default(r, "weight", json!(100))?;
+        }
+    }
+    Ok(())
+}
+
+///
+/// 增加重新加入阈值及持续正常时间
+fn slave_behind_setting_v2(value: &mut Value) -> Result<(), Box<dyn Error>> {
+    set_default(value, "readmit_delay", json!(0))?;
+    set_default(value, "min_healthy_secs", json!(0))
+}
+
+///
+/// 增加分钟及小时汇总数据的保留天数
+fn monitor_setting_v2(value: &mut Value) -> Result<(), Box<dyn Error>> {
+    set_default(value, "minute_days", json!(30))?;
+    set_default(value, "hour_days", json!(365))
+}
+
+impl DbInfo {
+    ///
+    /// 获取记录类型保存的schema版本， 未保存返回None
+    pub fn get_schema_version(&self, name: &str) -> Result<Option<u32>, Box<dyn Error>> {
+        let result = self.prefix_get(&PrefixTypeCode::SchemaVersion, &name.to_string())?;
+        if result.value.len() > 0 {
+            let version: u32 = serde_json::from_str(&result.value)?;
+            return Ok(Some(version));
+        }
+        Ok(None)
+    }
+}
+
+///
+/// 升级所有记录类型
+pub fn run(db: &DbInfo) -> Result<(), Box<dyn Error>> {
+    let all = migrations();
+    migrate::<HostInfoValue>(db, &all)?;
+    migrate::<HaChangeLog>(db, &all)?;
+    migrate::<MysqlState>(db, &all)?;
+    migrate::<CheckState>(db, &all)?;
+    migrate::<RouteInfo>(db, &all)?;
+    migrate::<UserInfo>(db, &all)?;
+    migrate::<SlaveBehindSetting>(db, &all)?;
+    migrate::<MonitorSetting>(db, &all)?;
+    migrate::<ReadWeightSetting>(db, &all)?;
+    migrate::<ReadPolicySetting>(db, &all)?;
+    migrate::<ProxySetting>(db, &all)?;
+    migrate::<ProxysqlSetting>(db, &all)?;
+    migrate::<ExporterSetting>(db, &all)?;
+    migrate::<VipSetting>(db, &all)?;
+    migrate::<BackupSetting>(db, &all)?;
+    migrate::<AlertRule>(db, &all)?;
+    migrate::<AlertChannel>(db, &all)?;
+    migrate::<Silence>(db, &all)?;
+    migrate::<MonitorVariableSetting>(db, &all)?;
+    migrate::<VipLog>(db, &all)?;
+    migrate_monitor_data(db)?;
+    Ok(())
+}
+
+const MONITOR_DATA: &str = "monitor_data";
+const MONITOR_DATA_VERSION: u32 = 2;
+
+///
+/// 每批迁移的监控数据条数
+const MONITOR_DATA_BATCH: usize = 1000;
+
+///
+/// 监控数据从System_data中的"{host}_{time}"迁移到Monitor_data列簇
+///
+/// 按key顺序每次读取1000条， 在一个batch中写入新key并删除旧key， 不会一次加载所有数据，
+/// 中途失败重启后会继续迁移剩余的数据
+fn migrate_monitor_data(db: &DbInfo) -> Result<(), Box<dyn Error>> {
+    if let Some(v) = db.get_schema_version(MONITOR_DATA)? {
+        if v > MONITOR_DATA_VERSION {
+            return Err(format!("schema version of {} is {}, newer than {} supported by this server", MONITOR_DATA, v, MONITOR_DATA_VERSION).into());
+        }
+        if v == MONITOR_DATA_VERSION {
+            return Ok(());
+        }
+    }
+    let system_cf = CfNameTypeCode::SystemData.get();
+    let monitor_cf = CfNameTypeCode::MonitorData.get();
+    let prefix = format!("{}:", PrefixTypeCode::NodeMonitorData.prefix());
+    //':'之后的字符为';'， 作为前缀范围的结束
+    let end = format!("{};", PrefixTypeCode::NodeMonitorData.prefix());
+    db.check_cf(&system_cf)?;
+    let mut cursor = prefix.clone();
+    let mut total = 0;
+    loop {
+        let rows = db.storage.range_limit(&system_cf, &cursor, &end, MONITOR_DATA_BATCH)?;
+        let last = match rows.last() {
+            Some(row) => row.key.clone(),
+            None => break
+        };
+        let mut batch = vec![];
+        let mut deletes = vec![];
+        for row in &rows {
+            deletes.push((system_cf.clone(), row.key.clone()));
+            //旧key格式为{prefix}:{host}_{time}， 无法解析的直接删除
+            let host = match row.key[prefix.len()..].rsplitn(2, '_').nth(1) {
+                Some(h) => h.to_string(),
+                None => continue
+            };
+            let value: MysqlMonitorStatus = match serde_json::from_str(&row.value) {
+                Ok(v) => v,
+                Err(_) => continue
+            };
+            batch.push((monitor_cf.clone(), KeyValue{ key: monitor_data::encode_key(&host, value.time), value: row.value.clone() }));
+        }
+        db.write_batch(&batch, &deletes)?;
+        total += rows.len();
+        info!("migrate monitor data to {}: {} records", &monitor_cf, total);
+        cursor = format!("{}\0", last);
+    }
+    let key = format!("{}:{}", PrefixTypeCode::SchemaVersion.prefix(), MONITOR_DATA);
+    db.write_batch(&vec![(system_cf, KeyValue{ key, value: serde_json::to_string(&MONITOR_DATA_VERSION)? })], &vec![])
+}
+
+///
+/// 升级一种记录类型
+///
+/// 没有保存版本时， 已有数据视为版本1， 没有数据则直接记录当前版本
+fn migrate<T: Record>(db: &DbInfo, all: &Vec<Migration>) -> Result<(), Box<dyn Error>> {
+    let rows = Repo::<T>::new(db).raw()?;
+    let stored = match db.get_schema_version(T::NAME)? {
+        Some(v) => v,
+        None if rows.len() > 0 => 1,
+        None => T::VERSION
+    };
+    if stored > T::VERSION {
+        let err = format!("schema version of {} is {}, newer than {} supported by this server", T::NAME, stored, T::VERSION);
+        return Err(err.into());
+    }
+
+    let cf_name = T::cf().get();
+    let mut batch = vec![];
+    if stored < T::VERSION {
+        info!("migrate {} from version {} to {}, {} records", T::NAME, stored, T::VERSION, rows.len());
+        for row in rows {
+            if row.value.len() == 0 {continue;}
+            let mut value: Value = serde_json::from_str(&row.value)
+                .map_err(|e| format!("parse {} key {} failed: {}", T::NAME, &row.key, e.to_string()))?;
+            for version in stored + 1..=T::VERSION {
+                let m = all.iter().find(|m| m.name == T::NAME && m.version == version)
+                    .ok_or(format!("no migration for {} version {}", T::NAME, version))?;
+                (m.upgrade)(&mut value)
+                    .map_err(|e| format!("upgrade {} key {} failed: {}", T::NAME, &row.key, e.to_string()))?;
+            }
+            //升级后必须能被当前结构读取
+            let record: T = serde_json::from_value(value)
+                .map_err(|e| format!("check {} key {} failed: {}", T::NAME, &row.key, e.to_string()))?;
+            batch.push((cf_name.clone(), KeyValue{ key: row.key, value: serde_json::to_string(&record)? }));
+        }
+    } else if db.get_schema_version(T::NAME)?.is_some() {
+        return Ok(());
+    }
+    let key = format!("{}:{}", PrefixTypeCode::SchemaVersion.prefix(), T::NAME);
+    batch.push((CfNameTypeCode::SystemData.get(), KeyValue{ key, value: serde_json::to_string(&T::VERSION)? }));
+    db.write_batch(&batch, &vec![])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ///
+    /// 当前结构去掉新增字段， 模拟旧版本写入的数据
+    fn legacy_log(recovery_status: bool) -> String {
+        let mut log = HaChangeLog::new();
+        log.switch_status = true;
+        log.recovery_status = recovery_status;
+        let mut value = serde_json::to_value(&log).unwrap();
+        let obj = value.as_object_mut().unwrap();
+        for field in &["kind", "vip", "start_time", "finish_time"] {
+            obj.remove(*field);
+        }
+        value.to_string()
+    }
+
+    #[test]
+    fn fresh_database_records_current_version() {
+        let db = DbInfo::memory();
+        run(&db).unwrap();
+        assert_eq!(db.get_schema_version(HaChangeLog::NAME).unwrap(), Some(HaChangeLog::VERSION));
+        assert_eq!(db.get_schema_version(ProxySetting::NAME).unwrap(), Some(ProxySetting::VERSION));
+        assert_eq!(db.get_schema_version(MONITOR_DATA).unwrap(), Some(MONITOR_DATA_VERSION));
+        //再次执行不做任何修改
+        run(&db).unwrap();
+    }
+
+    #[test]
+    fn upgrade_legacy_records() {
+        let db = DbInfo::memory();
+        let cf_name = HaChangeLog::cf().get();
+        db.put(&KeyValue{ key: "127.0.0.1:9011_1".to_string(), value: legacy_log(true) }, &cf_name).unwrap();
+        db.put(&KeyValue{ key: "127.0.0.1:9011_2".to_string(), value: legacy_log(false) }, &cf_name).unwrap();
+        run(&db).unwrap();
+
+        let repo = Repo::<HaChangeLog>::new(&db);
+        for row in repo.all().unwrap() {
+            assert_eq!(row.value.kind, HA_LOG_FAILOVER);
+            assert!(row.value.vip.is_none());
+            assert_eq!(row.value.start_time, 0);
+            assert!(row.value.duration_secs().is_none());
+        }
+        let (key, log) = db.get_last_failover_log(&"127.0.0.1:9011".to_string()).unwrap().unwrap();
+        assert_eq!(key, "127.0.0.1:9011_2");
+        assert!(!log.recovery_status);
+
+        assert_eq!(db.get_schema_version(HaChangeLog::NAME).unwrap(), Some(HaChangeLog::VERSION));
+    }
+
+    #[test]
+    fn migrate_monitor_data_in_batches() {
+        let db = DbInfo::memory();
+        let host = "127.0.0.1:9011".to_string();
+        let count = MONITOR_DATA_BATCH * 2 + 500;
+        for i in 0..count {
+            let key = format!("{}_{}", &host, 1000 + i);
+            db.prefix_put(&PrefixTypeCode::NodeMonitorData, &key, &json!({"threads_running": i, "time": 1000 + i})).unwrap();
+        }
+        db.prefix_put(&PrefixTypeCode::NodeMonitorData, &"broken".to_string(), &json!({})).unwrap();
+        run(&db).unwrap();
+
+        let prefix = format!("{}:", PrefixTypeCode::NodeMonitorData.prefix());
+        let left = db.prefix_iterator(&prefix, &CfNameTypeCode::SystemData.get()).unwrap();
+        assert_eq!(left.iter().filter(|kv| kv.key.starts_with(&prefix)).count(), 0);
+        let data = db.get_monitor_data(&host, 0, 0).unwrap();
+        assert_eq!(data.len(), count);
+        assert_eq!(data[10].get("threads_running"), 10);
+        assert_eq!(db.get_schema_version(MONITOR_DATA).unwrap(), Some(MONITOR_DATA_VERSION));
+    }
+
+    #[test]
+    fn reject_newer_version() {
+        let db = DbInfo::memory();
+        db.prefix_put(&PrefixTypeCode::SchemaVersion, &HaChangeLog::NAME.to_string(), &(HaChangeLog::VERSION + 1)).unwrap();
+        assert!(run(&db).is_err());
+    }
+
+    #[test]
+    fn broken_record_stops_migration() {
+        let db = DbInfo::memory();
+        db.put(&KeyValue{ key: "127.0.0.1:9011_1".to_string(), value: "{}".to_string() }, &HaChangeLog::cf().get()).unwrap();
+        assert!(run(&db).is_err());
+        assert_eq!(db.get_schema_version(HaChangeLog::NAME).unwrap(), None);
+    }
+}
@@ -0,0 +1,353 @@
+/*
+@author: xiao cai niao
+@datetime: 2020/09/15
+*/
+
+//! 监控数据存储
+//!
+//! 监控数据保存在单独的Monitor_data列簇， key为16位host前缀加16位时间戳，
+//! host前缀为host的sha256前8字节， 时间戳为大端u64， 均为hex编码， 字典序即时间顺序，
+//! 查询时直接定位到start_time并在stop_time停止， 过期数据使用范围删除
+//!
+//! 原始数据每分钟汇总到Monitor_data_1m， 分钟数据每小时汇总到Monitor_data_1h，
+//! 汇总数据key格式相同， 时间为时间段的开始时间， 保存每个指标的最小、最大及平均值，
+//! 各精度单独设置保留天数， 长时间范围的查询使用汇总数据
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+use crate::storage::rocks::{DbInfo, CfNameTypeCode, KeyValue, PrefixTypeCode};
+use crate::ha::procotol::MysqlMonitorStatus;
+use crate::ha::sys_manager::MonitorSetting;
+
+const ONE_DAY_MS: i64 = 86400000;
+
+///
+/// 时间段结束后等待的时间， 避免汇总时还有数据未写入
+const ROLLUP_DELAY_MS: i64 = 20000;
+
+///
+/// 固定长度的host前缀
+pub fn host_key(host: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input(host.as_bytes());
+    hex::encode(&hasher.result()[..8])
+}
+
+///
+/// host前缀加大端时间戳， 负数时间按0处理
+pub fn encode_key(host: &str, time: i64) -> String {
+    format!("{}{:016x}", host_key(host), time.max(0) as u64)
+}
+
+impl DbInfo {
+    ///
+    /// 写入一条监控数据
+    pub fn put_monitor_data(&self, host: &String, value: &MysqlMonitorStatus) -> Result<(), Box<dyn Error>> {
+        let cf_name = CfNameTypeCode::MonitorData.get();
+        self.check_cf(&cf_name)?;
+        self.storage.put(&cf_name, &encode_key(host, value.time), &serde_json::to_string(value)?)
+    }
+
+    ///
+    /// 获取时间范围内的监控数据， 包含start和stop， stop为0时不限制结束时间
+    pub fn get_monitor_data(&self, host: &String, start: i64, stop: i64) -> Result<Vec<MysqlMonitorStatus>, Box<dyn Error>> {
+        let mut values = vec![];
+        for row in self.monitor_range(&Resolution::Raw, host, start, stop)? {
+            let value: MysqlMonitorStatus = serde_json::from_str(&row.value)?;
+            values.push(value);
+        }
+        Ok(values)
+    }
+
+    ///
+    /// 获取时间范围内的汇总数据， 时间为时间段的开始时间
+    pub fn get_monitor_rollup(&self, res: &Resolution, host: &String, start: i64, stop: i64) -> Result<Vec<MonitorRollup>, Box<dyn Error>> {
+        let mut values = vec![];
+        for row in self.monitor_range(res, host, start, stop)? {
+            let value: MonitorRollup = serde_json::from_str(&row.value)?;
+            values.push(value);
+        }
+        Ok(values)
+    }
+
+    fn monitor_range(&self, res: &Resolution, host: &String, start: i64, stop: i64) -> Result<Vec<KeyValue>, Box<dyn Error>> {
+        let cf_name = res.cf();
+        self.check_cf(&cf_name)?;
+        let end = if stop > 0 { encode_key(host, stop.saturating_add(1)) } else { format!("{}g", host_key(host)) };
+        self.storage.range(&cf_name, &encode_key(host, start), &end)
+    }
+
+    ///
+    /// 删除time之前的监控数据
+    pub fn delete_monitor_data(&self, host: &String, time: i64) -> Result<(), Box<dyn Error>> {
+        self.delete_monitor_before(&Resolution::Raw, host, time)
+    }
+
+    ///
+    /// 删除某一精度time之前的数据
+    pub fn delete_monitor_before(&self, res: &Resolution, host: &String, time: i64) -> Result<(), Box<dyn Error>> {
+        let cf_name = res.cf();
+        self.check_cf(&cf_name)?;
+        self.storage.delete_range(&cf_name, &encode_key(host, 0), &encode_key(host, time))
+    }
+
+    ///
+    /// 删除节点所有精度的过期数据
+    pub fn expired_monitor_host(&self, setting: &MonitorSetting) -> Result<(), Box<dyn Error>> {
+        let cur_time = crate::timestamp();
+        for res in &[Resolution::Raw, Resolution::Minute, Resolution::Hour] {
+            self.delete_monitor_before(res, &setting.host, cur_time - res.retention(setting))?;
+        }
+        Ok(())
+    }
+
+    fn get_rollup_state(&self, host: &String, res: &Resolution) -> Result<Option<i64>, Box<dyn Error>> {
+        let result = self.prefix_get(&PrefixTypeCode::MonitorRollupState, &rollup_state_key(host, res))?;
+        if result.value.len() > 0 {
+            let next: i64 = serde_json::from_str(&result.value)?;
+            return Ok(Some(next));
+        }
+        Ok(None)
+    }
+}
+
+fn rollup_state_key(host: &str, res: &Resolution) -> String {
+    format!("{}:{}", host, res.name())
+}
+
+///
+/// 监控数据精度
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Resolution {
+    Raw,
+    Minute,
+    Hour,
+}
+
+impl Resolution {
+    pub fn from_name(name: &str) -> Result<Resolution, Box<dyn Error>> {
+        match name {
+            "raw" => Ok(Resolution::Raw),
+            "1m" => Ok(Resolution::Minute),
+            "1h" => Ok(Resolution::Hour),
+            _ => Err(format!("invalid resolution: {}, must be raw, 1m or 1h", name).into())
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Resolution::Raw => "raw",
+            Resolution::Minute => "1m",
+            Resolution::Hour => "1h",
+        }
+    }
+
+    pub fn cf(&self) -> String {
+        match self {
+            Resolution::Raw => CfNameTypeCode::MonitorData.get(),
+            Resolution::Minute => CfNameTypeCode::MonitorData1m.get(),
+            Resolution::Hour => CfNameTypeCode::MonitorData1h.get(),
+        }
+    }
+
+    ///
+    /// 时间段长度， 毫秒， 原始数据为采集间隔
+    pub fn interval(&self) -> i64 {
+        match self {
+            Resolution::Raw => 10000,
+            Resolution::Minute => 60000,
+            Resolution::Hour => 3600000,
+        }
+    }
+
+    ///
+    /// 汇总的数据来源
+    fn source(&self) -> Option<Resolution> {
+        match self {
+            Resolution::Raw => None,
+            Resolution::Minute => Some(Resolution::Raw),
+            Resolution::Hour => Some(Resolution::Minute),
+        }
+    }
+
+    ///
+    /// 保留时长， 毫秒
+    pub fn retention(&self, setting: &MonitorSetting) -> i64 {
+        let days = match self {
+            Resolution::Raw => setting.days as i64,
+            Resolution::Minute => setting.minute_days as i64,
+            Resolution::Hour => setting.hour_days as i64,
+        };
+        days * ONE_DAY_MS
+    }
+
+    ///
+    /// 根据查询范围选择精度: 6小时以内使用原始数据， 7天以内使用分钟数据， 其余使用小时数据，
+    /// 开始时间超出该精度保留时长时使用更低的精度
+    pub fn auto(setting: &MonitorSetting, start: i64, stop: i64) -> Resolution {
+        let now = crate::timestamp();
+        let stop = if stop > 0 { stop } else { now };
+        let span = stop - start;
+        if span <= 6 * 3600000 && start >= now - Resolution::Raw.retention(setting) {
+            return Resolution::Raw;
+        }
+        if span <= 7 * ONE_DAY_MS && start >= now - Resolution::Minute.retention(setting) {
+            return Resolution::Minute;
+        }
+        Resolution::Hour
+    }
+}
+
+///
+/// 一个时间段的汇总数据， count为包含的原始数据条数
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MonitorRollup {
+    pub time: i64,
+    pub count: u64,
+    pub min: MysqlMonitorStatus,
+    pub max: MysqlMonitorStatus,
+    pub avg: MysqlMonitorStatus,
+}
+
+impl MonitorRollup {
+    ///
+    /// 原始数据视为只包含一条数据的汇总
+    fn from_sample(value: &MysqlMonitorStatus) -> MonitorRollup {
+        MonitorRollup{ time: value.time, count: 1, min: value.clone(), max: value.clone(), avg: value.clone() }
+    }
+
+    ///
+    /// 合并多个汇总数据， 平均值按count加权，
+    /// 采集变量调整后各条数据的指标可能不同， 每个指标只按包含它的数据计算
+    fn merge(time: i64, items: &[MonitorRollup]) -> Result<MonitorRollup, Box<dyn Error>> {
+        let mut count = 0;
+        let mut min: BTreeMap<String, u64> = BTreeMap::new();
+        let mut max: BTreeMap<String, u64> = BTreeMap::new();
+        let mut sum: BTreeMap<String, (u64, u64)> = BTreeMap::new();
+        for item in items {
+            count += item.count;
+            for (field, v) in &item.min.values {
+                let m = min.entry(field.clone()).or_insert(*v);
+                *m = (*m).min(*v);
+            }
+            for (field, v) in &item.max.values {
+                let m = max.entry(field.clone()).or_insert(*v);
+                *m = (*m).max(*v);
+            }
+            for (field, v) in &item.avg.values {
+                let s = sum.entry(field.clone()).or_insert((0, 0));
+                s.0 = s.0.saturating_add(v.saturating_mul(item.count));
+                s.1 += item.count;
+            }
+        }
+        if count == 0 {
+            return Err("no monitor data to merge".into());
+        }
+        let avg = sum.into_iter().map(|(field, (v, c))| (field, v / c.max(1))).collect();
+        Ok(MonitorRollup{
+            time,
+            count,
+            min: MysqlMonitorStatus{ values: min, time },
+            max: MysqlMonitorStatus{ values: max, time },
+            avg: MysqlMonitorStatus{ values: avg, time }
+        })
+    }
+}
+
+///
+/// 汇总节点的监控数据， 先汇总分钟数据再汇总小时数据
+pub fn rollup(db: &DbInfo, setting: &MonitorSetting) -> Result<(), Box<dyn Error>> {
+    rollup_resolution(db, setting, &Resolution::Minute)?;
+    rollup_resolution(db, setting, &Resolution::Hour)
+}
+
+///
+/// 汇总上次进度到当前所有已结束的时间段， 第一次执行时从来源数据的保留时长开始，
+/// 汇总数据与新的进度在同一个batch中写入
+fn rollup_resolution(db: &DbInfo, setting: &MonitorSetting, res: &Resolution) -> Result<(), Box<dyn Error>> {
+    let source = match res.source() {
+        Some(s) => s,
+        None => return Ok(())
+    };
+    let interval = res.interval();
+    let now = crate::timestamp();
+    let end = (now - ROLLUP_DELAY_MS) / interval * interval;
+    let start = match db.get_rollup_state(&setting.host, res)? {
+        Some(next) => next,
+        None => (now - source.retention(setting)).max(0) / interval * interval
+    };
+    if start >= end {
+        return Ok(());
+    }
+
+    let items = match source {
+        Resolution::Raw => db.get_monitor_data(&setting.host, start, end - 1)?.iter().map(MonitorRollup::from_sample).collect(),
+        _ => db.get_monitor_rollup(&source, &setting.host, start, end - 1)?
+    };
+    let mut buckets: BTreeMap<i64, Vec<MonitorRollup>> = BTreeMap::new();
+    for item in items {
+        buckets.entry(item.time / interval * interval).or_insert(vec![]).push(item);
+    }
+
+    let cf_name = res.cf();
+    let mut batch = vec![];
+    for (time, items) in &buckets {
+        let value = MonitorRollup::merge(*time, items)?;
+        batch.push((cf_name.clone(), KeyValue{ key: encode_key(&setting.host, *time), value: serde_json::to_string(&value)? }));
+    }
+    let key = format!("{}:{}", PrefixTypeCode::MonitorRollupState.prefix(), rollup_state_key(&setting.host, res));
+    batch.push((CfNameTypeCode::SystemData.get(), KeyValue{ key, value: serde_json::to_string(&end)? }));
+    db.write_batch(&batch, &vec![])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(time: i64, v: u64) -> MysqlMonitorStatus {
+        let mut values = BTreeMap::new();
+        values.insert("threads_running".to_string(), v);
+        MysqlMonitorStatus{ values, time }
+    }
+
+    #[test]
+    fn key_order_is_time_order() {
+        let host = "127.0.0.1:9011";
+        assert_eq!(host_key(host).len(), 16);
+        assert!(encode_key(host, 1) < encode_key(host, 2));
+        assert!(encode_key(host, 255) < encode_key(host, 256));
+        assert_eq!(encode_key(host, -1), encode_key(host, 0));
+    }
+
+    #[test]
+    fn merge_weighted() {
+        let a = MonitorRollup::merge(0, &[MonitorRollup::from_sample(&sample(0, 1)), MonitorRollup::from_sample(&sample(10, 3))]).unwrap();
+        assert_eq!((a.count, a.min.get("threads_running"), a.max.get("threads_running"), a.avg.get("threads_running")), (2, 1, 3, 2));
+        let b = MonitorRollup::from_sample(&sample(20, 8));
+        let c = MonitorRollup::merge(0, &[a, b]).unwrap();
+        assert_eq!((c.count, c.min.get("threads_running"), c.max.get("threads_running"), c.avg.get("threads_running")), (3, 1, 8, 4));
+        assert!(MonitorRollup::merge(0, &[]).is_err());
+    }
+
+    #[test]
+    fn rollup_minutes() {
+        let db = DbInfo::memory();
+        let host = "127.0.0.1:9011".to_string();
+        let setting = MonitorSetting::new(&host);
+        let t0 = (crate::timestamp() - 3600000) / 60000 * 60000;
+        for i in 0..12 {
+            db.put_monitor_data(&host, &sample(t0 + i * 10000, i as u64)).unwrap();
+        }
+        rollup(&db, &setting).unwrap();
+        //已汇总的时间段不会重复汇总
+        rollup(&db, &setting).unwrap();
+
+        let minutes = db.get_monitor_rollup(&Resolution::Minute, &host, t0, t0 + 119999).unwrap();
+        assert_eq!(minutes.len(), 2);
+        assert_eq!((minutes[0].time, minutes[0].count, minutes[0].avg.get("threads_running")), (t0, 6, 2));
+        assert_eq!((minutes[1].time, minutes[1].min.get("threads_running"), minutes[1].max.get("threads_running")), (t0 + 60000, 6, 11));
+        assert_eq!(db.get_monitor_rollup(&Resolution::Minute, &host, 0, 0).unwrap().len(), 2);
+    }
+}
@@ -5,15 +5,15 @@
 use actix_web::{web};
 use crate::webroute::route::{HostInfo, PostUserInfo, EditInfo, EditMainTain};
 use crate::storage::rocks::{DbInfo, KeyValue, CfNameTypeCode, PrefixTypeCode};
-use crate::ha::procotol::{DownNodeCheck, RecoveryInfo, ReplicationState, MysqlMonitorStatus};
+use crate::ha::procotol::{DownNodeCheck, RecoveryInfo, ReplicationState};
 use std::error::Error;
 use crate::ha::nodes_manager::{SlaveInfo};
 use serde::{Serialize, Deserialize};
 use crate::rand_string;
 use crate::ha::procotol::MysqlState;
 use crate::ha::sys_manager::MonitorSetting;
+use crate::ha::vip::VipLog;
 use crate::webroute::new_route::ResponseMonitorStatic;
-use std::str::from_utf8;
 
 
 ///
@@ -40,8 +40,17 @@ pub fn insert_mysql_host_info(data: web::Data<DbInfo>, info: &web::Json<HostInfo
     Ok(())
 }
 
+pub const HA_LOG_FAILOVER: &str = "failover";
+pub const HA_LOG_SWITCHOVER: &str = "switchover";
+
+fn default_ha_log_kind() -> String {
+    HA_LOG_FAILOVER.to_string()
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct HaChangeLog {
+    #[serde(default = "default_ha_log_kind")]
+    pub kind: String,                       //failover、switchover， 只有failover需要宕机恢复
     pub key: String,                        //格式 host_timestamp  host为宕机节点
     pub cluster_name: String,
     pub old_master_info:  DownNodeCheck,    //宕机节点信息
@@ -49,11 +58,18 @@ pub struct HaChangeLog {
     pub recovery_info: RecoveryInfo,        //宕机恢复同步所需的新master信息
     pub recovery_status: bool,              //是否已恢复
     pub switch_status: bool,                //切换状态
+    #[serde(default)]
+    pub vip: Option<VipLog>,                //vip漂移记录， 未配置vip时为空
+    #[serde(default)]
+    pub start_time: i64,                    //开始切换时间(毫秒)， 旧版本日志为0
+    #[serde(default)]
+    pub finish_time: i64,                   //切换结束时间(毫秒)， 旧版本日志为0
 }
 
 impl HaChangeLog {
     pub fn new() -> HaChangeLog {
         HaChangeLog{
+            kind: HA_LOG_FAILOVER.to_string(),
             key: "".to_string(),
             cluster_name: "".to_string(),
             old_master_info: DownNodeCheck { host: "".to_string(), dbport: 0 },
@@ -77,24 +93,62 @@ impl HaChangeLog {
                 read_position: 0
             },
             recovery_status: false,
-            switch_status: false
+            switch_status: false,
+            vip: None,
+            start_time: crate::timestamp(),
+            finish_time: 0
         }
     }
 
-    pub fn save(&self, db: &web::Data<DbInfo>) -> Result<(), Box<dyn Error>> {
-        let key = format!("{}_{}",self.key.clone(), crate::timestamp());
+    ///
+    /// 保存时记录结束时间， key为host_结束时间
+    pub fn save(&mut self, db: &DbInfo) -> Result<(), Box<dyn Error>> {
+        self.finish_time = crate::timestamp();
+        let key = format!("{}_{}",self.key.clone(), self.finish_time);
         let value = serde_json::to_string(self)?;
         let row = KeyValue{key, value};
         db.put(&row, &CfNameTypeCode::HaChangeLog.get())?;
         return Ok(());
     }
 
-    pub fn update(&mut self, db: &web::Data<DbInfo>, row_key: String) -> Result<(), Box<dyn Error>> {
+    pub fn update(&mut self, db: &DbInfo, row_key: String) -> Result<(), Box<dyn Error>> {
         let value = serde_json::to_string(self)?;
         let row = KeyValue{key: row_key, value};
         db.put(&row, &CfNameTypeCode::HaChangeLog.get())?;
         return Ok(());
     }
+
+    pub fn is_failover(&self) -> bool {
+        self.kind == HA_LOG_FAILOVER
+    }
+
+    ///
+    /// 切换耗时(秒)， 旧版本日志没有记录时间返回None
+    pub fn duration_secs(&self) -> Option<f64> {
+        if self.start_time <= 0 || self.finish_time < self.start_time {
+            return None;
+        }
+        Some((self.finish_time - self.start_time) as f64 / 1000.0)
+    }
+}
+
+impl DbInfo {
+    ///
+    /// 获取节点最后一条宕机切换日志， 返回(key, 日志)， 不包含主动切换
+    pub fn get_last_failover_log(&self, host: &String) -> Result<Option<(String, HaChangeLog)>, Box<dyn Error>> {
+        let prefix = format!("{}_", host);
+        let mut rows: Vec<KeyValue> = self.prefix_iterator(&prefix, &CfNameTypeCode::HaChangeLog.get())?.into_iter()
+            .filter(|r| r.key.starts_with(&prefix) && r.value.len() > 0)
+            .collect();
+        rows.sort_by(|a, b| b.key.cmp(&a.key));
+        for row in rows {
+            let value: HaChangeLog = serde_json::from_str(&row.value)?;
+            if value.is_failover() {
+                return Ok(Some((row.key, value)));
+            }
+        }
+        Ok(None)
+    }
 }
 
 ///
@@ -157,7 +211,7 @@ impl HostInfoValue {
 
     ///
     /// 写入db
-    pub fn save(&self, db: &web::Data<DbInfo>) -> Result<(), Box<dyn Error>> {
+    pub fn save(&self, db: &DbInfo) -> Result<(), Box<dyn Error>> {
         let value = serde_json::to_string(&self)?;
         let row = KeyValue{key: self.host.clone(), value};
         db.put(&row, &CfNameTypeCode::HaNodesInfo.get())?;
@@ -186,7 +240,7 @@ impl HostInfoValue {
 
     ///
     /// 获取当前节点在db中保存的状态信息
-    pub fn get_state(&self, db: &web::Data<DbInfo>) -> Result<MysqlState, Box<dyn Error>> {
+    pub fn get_state(&self, db: &DbInfo) -> Result<MysqlState, Box<dyn Error>> {
         let kv = db.get(&self.host, &CfNameTypeCode::NodesState.get())?;
         if kv.value.len() > 0 {
             let state: MysqlState = serde_json::from_str(&kv.value)?;
@@ -199,7 +253,7 @@ impl HostInfoValue {
         }
     }
 
-    pub fn get_role(&self, db: &web::Data<DbInfo>) -> Result<String, Box<dyn Error>> {
+    pub fn get_role(&self, db: &DbInfo) -> Result<String, Box<dyn Error>> {
         let state = self.get_state(db)?;
         Ok(state.role)
     }
@@ -220,7 +274,7 @@ impl NodeClusterList{
         NodeClusterList { cluster_name_list: vec![] }
     }
 
-    pub fn init(&mut self, db: &web::Data<DbInfo>) -> Result<(), Box<dyn Error>> {
+    pub fn init(&mut self, db: &DbInfo) -> Result<(), Box<dyn Error>> {
         let result = db.iterator(&CfNameTypeCode::HaNodesInfo.get(), &String::from(""))?;
         for row in &result{
             let value: HostInfoValue = serde_json::from_str(&row.value)?;
@@ -251,7 +305,7 @@ impl RouteClusterList{
         RouteClusterList{ cluster_name_list: vec![] }
     }
 
-    pub fn init(&mut self, db: &web::Data<DbInfo>) -> Result<(), Box<dyn Error>>{
+    pub fn init(&mut self, db: &DbInfo) -> Result<(), Box<dyn Error>>{
         let route_all = db.get_route_all()?;
         for route in &route_all {
             if !self.is_exists(&route.value.cluster_name){
@@ -349,7 +403,7 @@ impl ClusterNodeInfo{
         }
     }
 
-    pub fn init(&mut self, db: &web::Data<DbInfo>) -> Result<(), Box<dyn Error>>{
+    pub fn init(&mut self, db: &DbInfo) -> Result<(), Box<dyn Error>>{
         let result = db.iterator(&CfNameTypeCode::HaNodesInfo.get(), &String::from(""))?;
         for row in &result{
             let node: HostInfoValue = serde_json::from_str(&row.value)?;
@@ -366,54 +420,21 @@ impl ClusterNodeInfo{
     ///
     /// 统计所有节点监控信息， 用于首页展示
     ///
-    /// 倒叙迭代获取每个节点最后一条数据， 如果每个节点都已获取最后一条数据就退出迭代
-    pub fn static_monitor(&self, db: &web::Data<DbInfo>, rsm: &mut ResponseMonitorStatic) -> Result<(), Box<dyn Error>> {
-        let cf_name = CfNameTypeCode::SystemData.get();
-        let mut tmp: Vec<String> = vec![];
-        //首先检查是否开启监控
+    /// 使用每个节点最新一次的监控数据
+    pub fn static_monitor(&self, db: &DbInfo, rsm: &mut ResponseMonitorStatic) -> Result<(), Box<dyn Error>> {
         for node in &self.nodes_info{
+            //首先检查是否开启监控
             if !self.check_monitor_setting(db, &node.host){
-                tmp.push(node.host.clone());
+                continue;
             }
-        }
-
-        if let Some(cf) = db.db.cf_handle(&cf_name){
-            let mut iter = db.db.raw_iterator_cf(cf);
-            iter.seek_to_last();
-            iter.prev();
-            'all: while iter.valid() {
-                if tmp.len() == self.nodes_info.len(){
-                    break 'all;
-                }
-                if let Some(s) = iter.key(){
-                    let key: String = from_utf8(&s.to_vec())?.parse()?;
-                    if key.starts_with(&PrefixTypeCode::NodeMonitorData.prefix()){
-                        'b: for n in &self.nodes_info{
-                            if key.contains(n.host.as_str()){
-                                for t in &tmp{
-                                    if t == &n.host{
-                                        break 'b;
-                                    }
-                                }
-                                if let Some(v) = iter.value(){
-                                    let v: MysqlMonitorStatus = serde_json::from_slice(&v)?;
-                                    rsm.update(&v);
-                                    tmp.push(n.host.clone());
-                                }
-                                break 'b;
-                            }
-                        }
-                    }
-                }
-                //
-                //
-                iter.prev();
+            if let Some(v) = db.get_last_monitor(&node.host)? {
+                rsm.update(&v);
             }
         }
         Ok(())
     }
 
-    fn check_monitor_setting(&self, db: &web::Data<DbInfo>, host: &String) -> bool{
+    fn check_monitor_setting(&self, db: &DbInfo, host: &String) -> bool{
         let a = db.prefix_get(&PrefixTypeCode::NodeMonitorSeting, host);
         match a {
             Ok(v) => {
@@ -433,7 +454,7 @@ impl ClusterNodeInfo{
 ///
 ///
 impl MysqlState{
-    pub fn save(&self, db: &web::Data<DbInfo>, key: &String) -> Result<(), Box<dyn Error>> {
+    pub fn save(&self, db: &DbInfo, key: &String) -> Result<(), Box<dyn Error>> {
         let value = serde_json::to_string(&self)?;
         let a = KeyValue{key: key.clone(), value };
         db.put(&a, &CfNameTypeCode::NodesState.get())?;
@@ -445,22 +466,87 @@ impl MysqlState{
 ///
 ///
 /// slave behind 配置结构体
+///
+/// delay为剔除阈值， readmit_delay为重新加入阈值(0表示与delay相同)，
+/// min_healthy_secs为被剔除的slave恢复正常后需持续的秒数， 避免在阈值附近反复加入剔除
 #[derive(Serialize, Deserialize, Debug)]
 pub struct SlaveBehindSetting{
     pub cluster_name: String,
-    pub delay: usize
+    pub delay: usize,
+    #[serde(default)]
+    pub readmit_delay: usize,
+    #[serde(default)]
+    pub min_healthy_secs: usize,
 }
 
 impl SlaveBehindSetting{
     pub fn new(cluster_name: &String) -> SlaveBehindSetting {
-        SlaveBehindSetting{ cluster_name: cluster_name.clone(), delay: 100 }
+        SlaveBehindSetting{ cluster_name: cluster_name.clone(), delay: 100, readmit_delay: 0, min_healthy_secs: 0 }
+    }
+
+    ///
+    /// 重新加入阈值， 未配置或大于delay时使用delay
+    pub fn get_readmit_delay(&self) -> usize {
+        if self.readmit_delay == 0 || self.readmit_delay > self.delay {
+            return self.delay;
+        }
+        self.readmit_delay
     }
-    pub fn save(&self, db: &web::Data<DbInfo>) -> Result<(), Box<dyn Error>>{
+
+    pub fn save(&self, db: &DbInfo) -> Result<(), Box<dyn Error>>{
         db.prefix_put(&PrefixTypeCode::SlaveDelaySeting, &self.cluster_name, &self)?;
         Ok(())
     }
 }
 
+///
+/// slave读路由基础权重配置， 0表示不分配读流量
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReadWeightSetting{
+    pub host: String,
+    pub weight: usize
+}
+
+impl ReadWeightSetting{
+    pub fn new(host: &String) -> ReadWeightSetting {
+        ReadWeightSetting{ host: host.clone(), weight: 100 }
+    }
+    pub fn save(&self, db: &DbInfo) -> Result<(), Box<dyn Error>>{
+        db.prefix_put(&PrefixTypeCode::ReadWeightSeting, &self.host, &self)?;
+        Ok(())
+    }
+}
+
+///
+/// 没有slave满足读路由条件时的处理策略
+pub const READ_POLICY_MASTER: &str = "master";         //master加入读路由
+pub const READ_POLICY_LEAST_LAG: &str = "least_lag";   //保留延迟最小的slave
+pub const READ_POLICY_DEGRADED: &str = "degraded";     //读路由为空， 只标记degraded
+
+///
+/// 集群读路由降级策略配置， 未配置默认degraded
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReadPolicySetting{
+    pub cluster_name: String,
+    pub policy: String
+}
+impl ReadPolicySetting{
+    pub fn new(cluster_name: &String) -> ReadPolicySetting {
+        ReadPolicySetting{ cluster_name: cluster_name.clone(), policy: READ_POLICY_DEGRADED.to_string() }
+    }
+    pub fn check(&self) -> Result<(), Box<dyn Error>> {
+        match self.policy.as_str() {
+            READ_POLICY_MASTER | READ_POLICY_LEAST_LAG | READ_POLICY_DEGRADED => Ok(()),
+            _ => Err(format!("invalid read policy: {}, must be one of master, least_lag, degraded", &self.policy).into())
+        }
+    }
+    pub fn save(&self, db: &DbInfo) -> Result<(), Box<dyn Error>>{
+        self.check()?;
+        db.prefix_put(&PrefixTypeCode::ReadPolicySeting, &self.cluster_name, &self)?;
+        Ok(())
+    }
+}
+
 
 
 
@@ -0,0 +1,302 @@
+/*
+@author: xiao cai niao
+@datetime: 2020/09/11
+*/
+
+//! 类型化存储
+//!
+//! 每种记录类型通过Record指定所在列簇、System_data中的前缀及schema版本，
+//! key统一由encode_key生成， 与prefix_put/prefix_get的格式一致， 已有数据不需要迁移key
+//!
+//! 记录结构有修改时递增VERSION， 并在migrate中添加对应的升级函数
+
+use std::error::Error;
+use std::marker::PhantomData;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use crate::storage::rocks::{DbInfo, KeyValue, RowValue, CfNameTypeCode, PrefixTypeCode};
+use crate::storage::opdb::{HostInfoValue, HaChangeLog, UserInfo, SlaveBehindSetting, ReadWeightSetting, ReadPolicySetting};
+use crate::ha::procotol::MysqlState;
+use crate::ha::nodes_manager::CheckState;
+use crate::ha::route_manager::RouteInfo;
+use crate::ha::sys_manager::{MonitorSetting, MonitorVariableSetting};
+use crate::ha::proxy::ProxySetting;
+use crate::ha::proxysql::ProxysqlSetting;
+use crate::ha::exporter::ExporterSetting;
+use crate::ha::vip::{VipSetting, VipLog};
+use crate::storage::backup::BackupSetting;
+use crate::ha::alert::AlertRule;
+use crate::ha::alert_channel::AlertChannel;
+use crate::ha::silence::Silence;
+
+pub trait Record: Serialize + DeserializeOwned {
+    ///
+    /// 记录类型名称， 用于保存schema版本
+    const NAME: &'static str;
+
+    ///
+    /// 当前代码使用的schema版本
+    const VERSION: u32;
+
+    fn cf() -> CfNameTypeCode;
+
+    ///
+    /// System_data中记录的前缀， 其他列簇为None
+    fn prefix() -> Option<PrefixTypeCode> {
+        None
+    }
+}
+
+///
+/// 生成完整的key
+pub fn encode_key<T: Record>(key: &str) -> String {
+    match T::prefix() {
+        Some(p) => format!("{}:{}", p.prefix(), key),
+        None => key.to_string()
+    }
+}
+
+///
+/// 去掉完整key中的前缀， 不属于该类型时返回None
+pub fn decode_key<T: Record>(key: &str) -> Option<String> {
+    match T::prefix() {
+        Some(p) => {
+            let prefix = format!("{}:", p.prefix());
+            if key.starts_with(&prefix) {
+                return Some(key[prefix.len()..].to_string());
+            }
+            None
+        }
+        None => Some(key.to_string())
+    }
+}
+
+///
+/// 某一类型记录的读写
+pub struct Repo<'a, T: Record> {
+    db: &'a DbInfo,
+    record: PhantomData<T>,
+}
+
+impl<'a, T: Record> Repo<'a, T> {
+    pub fn new(db: &'a DbInfo) -> Repo<'a, T> {
+        Repo{ db, record: PhantomData }
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<T>, Box<dyn Error>> {
+        let kv = self.db.get(&encode_key::<T>(key), &T::cf().get())?;
+        if kv.value.len() == 0 {
+            return Ok(None);
+        }
+        let value: T = serde_json::from_str(&kv.value)?;
+        Ok(Some(value))
+    }
+
+    pub fn put(&self, key: &str, value: &T) -> Result<(), Box<dyn Error>> {
+        let kv = KeyValue{ key: encode_key::<T>(key), value: serde_json::to_string(value)? };
+        self.db.put(&kv, &T::cf().get())
+    }
+
+    pub fn delete(&self, key: &str) -> Result<(), Box<dyn Error>> {
+        self.db.delete(&encode_key::<T>(key), &T::cf().get())
+    }
+
+    ///
+    /// 获取该类型所有记录， key为去掉前缀后的key
+    pub fn all(&self) -> Result<Vec<RowValue<T>>, Box<dyn Error>> {
+        let mut rows = vec![];
+        for kv in self.raw()? {
+            if kv.value.len() == 0 {continue;}
+            if let Some(key) = decode_key::<T>(&kv.key) {
+                let value: T = serde_json::from_str(&kv.value)?;
+                rows.push(RowValue{ key, value });
+            }
+        }
+        Ok(rows)
+    }
+
+    ///
+    /// 获取该类型所有记录的原始数据， key为完整key
+    pub fn raw(&self) -> Result<Vec<KeyValue>, Box<dyn Error>> {
+        match T::prefix() {
+            Some(p) => {
+                let prefix = format!("{}:", p.prefix());
+                let result = self.db.prefix_iterator(&prefix, &T::cf().get())?;
+                Ok(result.into_iter().filter(|kv| kv.key.starts_with(&prefix)).collect())
+            }
+            None => self.db.iterator(&T::cf().get(), &String::from(""))
+        }
+    }
+}
+
+impl Record for HostInfoValue {
+    const NAME: &'static str = "host_info";
+    const VERSION: u32 = 1;
+    fn cf() -> CfNameTypeCode { CfNameTypeCode::HaNodesInfo }
+}
+
+impl Record for HaChangeLog {
+    const NAME: &'static str = "ha_change_log";
+    const VERSION: u32 = 2;
+    fn cf() -> CfNameTypeCode { CfNameTypeCode::HaChangeLog }
+}
+
+impl Record for MysqlState {
+    const NAME: &'static str = "nodes_state";
+    const VERSION: u32 = 1;
+    fn cf() -> CfNameTypeCode { CfNameTypeCode::NodesState }
+}
+
+impl Record for CheckState {
+    const NAME: &'static str = "check_state";
+    const VERSION: u32 = 2;
+    fn cf() -> CfNameTypeCode { CfNameTypeCode::CheckState }
+}
+
+impl Record for RouteInfo {
+    const NAME: &'static str = "route_info";
+    const VERSION: u32 = 2;
+    fn cf() -> CfNameTypeCode { CfNameTypeCode::SystemData }
+    fn prefix() -> Option<PrefixTypeCode> { Some(PrefixTypeCode::RouteInfo) }
+}
+
+impl Record for UserInfo {
+    const NAME: &'static str = "user_info";
+    const VERSION: u32 = 1;
+    fn cf() -> CfNameTypeCode { CfNameTypeCode::SystemData }
+    fn prefix() -> Option<PrefixTypeCode> { Some(PrefixTypeCode::UserInfo) }
+}
+
+impl Record for SlaveBehindSetting {
+    const NAME: &'static str = "slave_behind_setting";
+    const VERSION: u32 = 2;
+    fn cf() -> CfNameTypeCode { CfNameTypeCode::SystemData }
+    fn prefix() -> Option<PrefixTypeCode> { Some(PrefixTypeCode::SlaveDelaySeting) }
+}
+
+impl Record for MonitorSetting {
+    const NAME: &'static str = "monitor_setting";
+    const VERSION: u32 = 2;
+    fn cf() -> CfNameTypeCode { CfNameTypeCode::SystemData }
+    fn prefix() -> Option<PrefixTypeCode> { Some(PrefixTypeCode::NodeMonitorSeting) }
+}
+
+impl Record for ReadWeightSetting {
+    const NAME: &'static str = "read_weight_setting";
+    const VERSION: u32 = 1;
+    fn cf() -> CfNameTypeCode { CfNameTypeCode::SystemData }
+    fn prefix() -> Option<PrefixTypeCode> { Some(PrefixTypeCode::ReadWeightSeting) }
+}
+
+impl Record for ReadPolicySetting {
+    const NAME: &'static str = "read_policy_setting";
+    const VERSION: u32 = 1;
+    fn cf() -> CfNameTypeCode { CfNameTypeCode::SystemData }
+    fn prefix() -> Option<PrefixTypeCode> { Some(PrefixTypeCode::ReadPolicySeting) }
+}
+
+impl Record for ProxySetting {
+    const NAME: &'static str = "proxy_setting";
+    const VERSION: u32 = 1;
+    fn cf() -> CfNameTypeCode { CfNameTypeCode::SystemData }
+    fn prefix() -> Option<PrefixTypeCode> { Some(PrefixTypeCode::ProxySeting) }
+}
+
+impl Record for ProxysqlSetting {
+    const NAME: &'static str = "proxysql_setting";
+    const VERSION: u32 = 1;
+    fn cf() -> CfNameTypeCode { CfNameTypeCode::SystemData }
+    fn prefix() -> Option<PrefixTypeCode> { Some(PrefixTypeCode::ProxysqlSeting) }
+}
+
+impl Record for ExporterSetting {
+    const NAME: &'static str = "exporter_setting";
+    const VERSION: u32 = 1;
+    fn cf() -> CfNameTypeCode { CfNameTypeCode::SystemData }
+    fn prefix() -> Option<PrefixTypeCode> { Some(PrefixTypeCode::ExporterSeting) }
+}
+
+impl Record for VipSetting {
+    const NAME: &'static str = "vip_setting";
+    const VERSION: u32 = 1;
+    fn cf() -> CfNameTypeCode { CfNameTypeCode::SystemData }
+    fn prefix() -> Option<PrefixTypeCode> { Some(PrefixTypeCode::VipSeting) }
+}
+
+impl Record for BackupSetting {
+    const NAME: &'static str = "backup_setting";
+    const VERSION: u32 = 1;
+    fn cf() -> CfNameTypeCode { CfNameTypeCode::SystemData }
+    fn prefix() -> Option<PrefixTypeCode> { Some(PrefixTypeCode::BackupSeting) }
+}
+
+impl Record for AlertRule {
+    const NAME: &'static str = "alert_rule";
+    const VERSION: u32 = 1;
+    fn cf() -> CfNameTypeCode { CfNameTypeCode::SystemData }
+    fn prefix() -> Option<PrefixTypeCode> { Some(PrefixTypeCode::AlertRule) }
+}
+
+impl Record for AlertChannel {
+    const NAME: &'static str = "alert_channel";
+    const VERSION: u32 = 1;
+    fn cf() -> CfNameTypeCode { CfNameTypeCode::SystemData }
+    fn prefix() -> Option<PrefixTypeCode> { Some(PrefixTypeCode::AlertChannel) }
+}
+
+impl Record for Silence {
+    const NAME: &'static str = "silence";
+    const VERSION: u32 = 1;
+    fn cf() -> CfNameTypeCode { CfNameTypeCode::SystemData }
+    fn prefix() -> Option<PrefixTypeCode> { Some(PrefixTypeCode::Silence) }
+}
+
+impl Record for MonitorVariableSetting {
+    const NAME: &'static str = "monitor_variable_setting";
+    const VERSION: u32 = 1;
+    fn cf() -> CfNameTypeCode { CfNameTypeCode::SystemData }
+    fn prefix() -> Option<PrefixTypeCode> { Some(PrefixTypeCode::MonitorVariableSeting) }
+}
+
+impl Record for VipLog {
+    const NAME: &'static str = "vip_log";
+    const VERSION: u32 = 1;
+    fn cf() -> CfNameTypeCode { CfNameTypeCode::SystemData }
+    fn prefix() -> Option<PrefixTypeCode> { Some(PrefixTypeCode::VipLog) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setting(cluster_name: &str) -> ProxySetting {
+        ProxySetting{ cluster_name: cluster_name.to_string(), listen: "".to_string(), write_port: 6033, read_port: 6034, enable: true }
+    }
+
+    #[test]
+    fn key_prefix() {
+        let key = encode_key::<ProxySetting>("c1");
+        assert_eq!(key, format!("{}:c1", PrefixTypeCode::ProxySeting.prefix()));
+        assert_eq!(decode_key::<ProxySetting>(&key), Some("c1".to_string()));
+        assert_eq!(decode_key::<ProxySetting>("c1"), None);
+        assert_eq!(encode_key::<HaChangeLog>("127.0.0.1:9011_1"), "127.0.0.1:9011_1");
+    }
+
+    #[test]
+    fn put_get_all_delete() {
+        let db = DbInfo::memory();
+        let repo = Repo::<ProxySetting>::new(&db);
+        assert_eq!(repo.get("c1").unwrap(), None);
+        repo.put("c1", &setting("c1")).unwrap();
+        repo.put("c2", &setting("c2")).unwrap();
+        //其他类型的记录不会被读取
+        db.prefix_put(&PrefixTypeCode::VipSeting, &"c1".to_string(), &"x").unwrap();
+
+        assert_eq!(repo.get("c1").unwrap(), Some(setting("c1")));
+        let all = repo.all().unwrap();
+        assert_eq!(all.iter().map(|r| r.key.clone()).collect::<Vec<String>>(), vec!["c1", "c2"]);
+        assert_eq!(all[1].value, setting("c2"));
+        repo.delete("c1").unwrap();
+        assert_eq!(repo.all().unwrap().len(), 1);
+    }
+}
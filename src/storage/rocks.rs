@@ -3,16 +3,19 @@
 @datetime: 2019/11/18
 */
 
-use rocksdb::{DB, Options, DBCompactionStyle, MemtableFactory, BlockBasedOptions};
+use rocksdb::{DB, Options, DBCompactionStyle, MemtableFactory, BlockBasedOptions, WriteBatch, ColumnFamily, ReadOptions};
 use rocksdb::{BlockBasedIndexType, PlainTableFactoryOptions, DataBlockIndexType};
+use rocksdb::checkpoint::Checkpoint;
 use std::error::Error;
 use std::str::from_utf8;
 use serde::{Deserialize, Serialize};
-use crate::storage::opdb::{UserInfo, SlaveBehindSetting};
+use crate::storage::opdb::{UserInfo, SlaveBehindSetting, ReadWeightSetting, ReadPolicySetting};
+use crate::ha::procotol::MysqlMonitorStatus;
 use crate::webroute::route::PostUserInfo;
 use crate::ha::nodes_manager::DifferenceSql;
 use crate::ha::route_manager::RouteInfo;
 use crate::ha::sys_manager::MonitorSetting;
+use crate::storage::backend::{Storage, MemoryStorage, StorageStats};
 
 
 pub enum PrefixTypeCode {
@@ -21,7 +24,35 @@ pub enum PrefixTypeCode {
     UserInfo,               //用户信息
     SlaveDelaySeting,       //每个集群slave最大延迟时间配置， 用于路由剔除
     NodeMonitorSeting,      //每个节点打开监控的配置
-    NodeMonitorData,        //每个节点的监控数据
+    NodeMonitorData,        //每个节点的监控数据， 旧格式， 启动时迁移到Monitor_data列簇
+    PendingNode,            //client主动注册， 等待审批的节点
+    AgentHeartbeat,         //client主动推送的心跳数据
+    MysqlProbeSeting,       //server直连mysql检查的配置
+    NodeStateEvent,         //节点状态变化历史
+    ReadWeightSeting,       //slave读路由基础权重配置
+    NodeMonitorLast,        //每个节点最新一次的监控数据
+    RouteHistory,           //集群路由变化历史
+    ProxySeting,            //集群读写分离代理配置
+    ProxysqlSeting,         //集群ProxySQL同步配置
+    ProxysqlStatus,         //集群ProxySQL同步状态
+    ExporterSeting,         //路由导出配置
+    ExporterStatus,         //路由导出状态
+    VipSeting,              //集群vip配置
+    ReadPolicySeting,       //集群读路由降级策略配置
+    ReadRouteState,         //集群slave读路由状态及剔除原因
+    SchemaVersion,          //每种记录类型的schema版本
+    BackupSeting,           //定时备份配置
+    MonitorRollupState,     //监控数据汇总进度， 保存下一个待汇总的时间段
+    AlertRule,              //报警规则
+    AlertChannel,           //报警通知渠道
+    AlertState,             //每条规则各目标的报警状态
+    AlertEvent,             //切换产生的待发送报警事件
+    AlertHistory,           //报警通知发送记录
+    Silence,                //报警静默
+    SilenceAudit,           //报警静默操作审计记录
+    MonitorVariableSeting,  //集群或节点监控变量配置
+    VipLog,                 //vip漂移记录
+    RouteGeneration,        //每个集群最后使用的路由generation， 删除路由后保留
 }
 
 impl PrefixTypeCode {
@@ -46,6 +77,90 @@ impl PrefixTypeCode {
             PrefixTypeCode::NodeMonitorData => {
                 format!("{}{}", 0x06, &prefix)
             }
+            PrefixTypeCode::PendingNode => {
+                format!("{}{}", 0x07, &prefix)
+            }
+            PrefixTypeCode::AgentHeartbeat => {
+                format!("{}{}", 0x08, &prefix)
+            }
+            PrefixTypeCode::MysqlProbeSeting => {
+                format!("{}{}", 0x09, &prefix)
+            }
+            PrefixTypeCode::NodeStateEvent => {
+                format!("{}{}", 0x0a, &prefix)
+            }
+            PrefixTypeCode::ReadWeightSeting => {
+                format!("{}{}", 0x0b, &prefix)
+            }
+            PrefixTypeCode::NodeMonitorLast => {
+                format!("{}{}", 0x0c, &prefix)
+            }
+            PrefixTypeCode::RouteHistory => {
+                format!("{}{}", 0x0d, &prefix)
+            }
+            PrefixTypeCode::ProxySeting => {
+                format!("{}{}", 0x0e, &prefix)
+            }
+            PrefixTypeCode::ProxysqlSeting => {
+                format!("{}{}", 0x0f, &prefix)
+            }
+            PrefixTypeCode::ProxysqlStatus => {
+                format!("{}{}", 0x10, &prefix)
+            }
+            PrefixTypeCode::ExporterSeting => {
+                format!("{}{}", 0x11, &prefix)
+            }
+            PrefixTypeCode::ExporterStatus => {
+                format!("{}{}", 0x12, &prefix)
+            }
+            PrefixTypeCode::VipSeting => {
+                format!("{}{}", 0x13, &prefix)
+            }
+            PrefixTypeCode::ReadPolicySeting => {
+                format!("{}{}", 0x14, &prefix)
+            }
+            PrefixTypeCode::ReadRouteState => {
+                format!("{}{}", 0x15, &prefix)
+            }
+            PrefixTypeCode::SchemaVersion => {
+                format!("{}{}", 0x16, &prefix)
+            }
+            PrefixTypeCode::BackupSeting => {
+                format!("{}{}", 0x17, &prefix)
+            }
+            PrefixTypeCode::MonitorRollupState => {
+                format!("{}{}", 0x18, &prefix)
+            }
+            PrefixTypeCode::AlertRule => {
+                format!("{}{}", 0x19, &prefix)
+            }
+            PrefixTypeCode::AlertChannel => {
+                format!("{}{}", 0x1a, &prefix)
+            }
+            PrefixTypeCode::AlertState => {
+                format!("{}{}", 0x1b, &prefix)
+            }
+            PrefixTypeCode::AlertEvent => {
+                format!("{}{}", 0x1c, &prefix)
+            }
+            PrefixTypeCode::AlertHistory => {
+                format!("{}{}", 0x1d, &prefix)
+            }
+            PrefixTypeCode::Silence => {
+                format!("{}{}", 0x1e, &prefix)
+            }
+            PrefixTypeCode::SilenceAudit => {
+                format!("{}{}", 0x1f, &prefix)
+            }
+            PrefixTypeCode::MonitorVariableSeting => {
+                format!("{}{}", 0x20, &prefix)
+            }
+            PrefixTypeCode::VipLog => {
+                format!("{}{}", 0x21, &prefix)
+            }
+            PrefixTypeCode::RouteGeneration => {
+                format!("{}{}", 0x22, &prefix)
+            }
         }
     }
 }
@@ -57,6 +172,9 @@ pub enum CfNameTypeCode {
     NodesState,             //每个节点的状态数据
     SystemData,             //系统数据
     CheckState,             //存储宕机状态的节点信息
+    MonitorData,            //节点监控数据， key为host前缀加时间戳
+    MonitorData1m,          //按分钟汇总的监控数据
+    MonitorData1h,          //按小时汇总的监控数据
 }
 
 impl CfNameTypeCode {
@@ -67,7 +185,10 @@ impl CfNameTypeCode {
             CfNameTypeCode::HaChangeLog => String::from("Ha_change_log"),
             CfNameTypeCode::SystemData => String::from("System_data"),
             CfNameTypeCode::NodesState => String::from("Nodes_state"),
-            CfNameTypeCode::CheckState => String::from("Check_state")
+            CfNameTypeCode::CheckState => String::from("Check_state"),
+            CfNameTypeCode::MonitorData => String::from("Monitor_data"),
+            CfNameTypeCode::MonitorData1m => String::from("Monitor_data_1m"),
+            CfNameTypeCode::MonitorData1h => String::from("Monitor_data_1h")
         }
     }
 }
@@ -91,20 +212,14 @@ impl KeyValue{
 }
 
 pub struct DbInfo{
-    pub db: DB,
+    pub storage: Box<dyn Storage>,
 }
 impl DbInfo {
     pub fn new() -> DbInfo {
-        let cf_names: Vec<String> = vec![String::from("Ha_nodes_info"),
-                                         String::from("Rollback_sql_info"),
-                                         String::from("Ha_change_log"),
-                                         String::from("System_data"),
-                                         String::from("Nodes_state"),
-                                         String::from("Check_state")];
-        let db_state = init_db(&cf_names);
+        let db_state = init_db(&cf_names());
         match db_state {
             Ok(db) => {
-                DbInfo{db}
+                DbInfo{ storage: Box::new(RocksStorage{db: db.0, opts: db.1}) }
             }
             Err(e) => {
                 info!("{:?}",e.to_string());
@@ -113,95 +228,47 @@ impl DbInfo {
         }
     }
 
+    ///
+    /// 使用内存存储， 数据不落盘
+    pub fn memory() -> DbInfo {
+        DbInfo{ storage: Box::new(MemoryStorage::new(&cf_names())) }
+    }
+
     pub fn put(&self, kv: &KeyValue, cf_name: &String) -> Result<(), Box<dyn Error>> {
         self.check_cf(cf_name)?;
-        match self.db.cf_handle(cf_name) {
-            Some(cf) => {
-                self.db.put_cf(cf, &kv.key, &kv.value)?;
-            }
-            None => {}
-        }
+        self.storage.put(cf_name, &kv.key, &kv.value)
+    }
 
-        Ok(())
+    ///
+    /// 在一个batch中写入、删除多个列簇的数据， 全部成功或全部失败
+    ///
+    /// rows为(列簇, 数据)， deletes为(列簇, key)
+    pub fn write_batch(&self, rows: &Vec<(String, KeyValue)>, deletes: &Vec<(String, String)>) -> Result<(), Box<dyn Error>> {
+        self.storage.write_batch(rows, deletes)
     }
 
     pub fn get(&self, key: &String, cf_name: &String) -> Result<KeyValue, Box<dyn Error>> {
         self.check_cf(cf_name)?;
         let mut kv = KeyValue::new(key, &String::from(""));
-        match self.db.cf_handle(cf_name){
-            Some(cf) => {
-                let value = self.db.get_cf(cf, key)?;
-                match value {
-                    Some(v) => {
-                        let value = from_utf8(&v).unwrap();
-                        kv.value = value.parse().unwrap();
-                    }
-                    None => {}
-                }
-            }
-            None => {}
+        if let Some(v) = self.storage.get(cf_name, key)? {
+            kv.value = v;
         }
-
         return Ok(kv);
     }
 
     pub fn delete(&self, key: &String, cf_name: &String) -> Result<(), Box<dyn Error>> {
         self.check_cf(cf_name)?;
-        match self.db.cf_handle(cf_name){
-            Some(cf) => {
-                self.db.delete_cf(cf, key)?;
-            }
-            None => {}
-        }
-        Ok(())
+        self.storage.delete(cf_name, key)
     }
 
     pub fn iterator(&self, cf_name: &String, seek_to: &String) -> Result<Vec<KeyValue>, Box<dyn Error>> {
         self.check_cf(cf_name)?;
-        if let Some(cf) = self.db.cf_handle(cf_name){
-            let mut iter = self.db.raw_iterator_cf(cf);
-            if seek_to.len() > 0 {
-                iter.seek(seek_to);
-            }else {
-                iter.seek_to_first();
-            }
-            let mut values: Vec<KeyValue> = vec![];
-            while iter.valid() {
-                let mut key: String = String::from("");
-                let mut value: String = String::from("");
-                if let Some(v) = iter.key() {
-                    key = from_utf8(&v.to_vec())?.parse()?;
-                }
-
-                if let Some(v) = iter.value() {
-                    value = from_utf8(&v.to_vec())?.parse()?;
-                }
-
-                let kv = KeyValue{key, value};
-                values.push(kv);
-                iter.next();
-            }
-            return Ok(values);
-        }
-        let a = format!("no cloumnfamily {}", cf_name);
-        return  Err(a.into())
+        self.storage.iterator(cf_name, seek_to)
     }
 
     pub fn prefix_iterator(&self, prefix: &String, cf_name: &String) -> Result<Vec<KeyValue>, Box<dyn Error>> {
         self.check_cf(cf_name)?;
-        if let Some(cf) = self.db.cf_handle(cf_name) {
-            let iter = self.db.prefix_iterator_cf(cf,prefix);
-            let mut values: Vec<KeyValue> = vec![];
-            for (k, v) in iter {
-                let key: String = from_utf8(&k.to_vec())?.parse()?;
-                let value: String = from_utf8(&v.to_vec())?.parse()?;
-                let kv = KeyValue{key, value};
-                values.push(kv);
-            }
-            return Ok(values);
-        }
-        let a = format!("no cloumnfamily {}", cf_name);
-        return  Box::new(Err(a)).unwrap();
+        self.storage.prefix_iterator(cf_name, prefix)
     }
 
     pub fn prefix_put<T: Serialize>(&self, prefix_type: &PrefixTypeCode, key: &String, value: &T) -> Result<(), Box<dyn Error>> {
@@ -225,13 +292,11 @@ impl DbInfo {
     /// 检查列簇是否已存在
     ///
     pub fn check_cf(&self, cf_name: &String) -> Result<(), Box<dyn Error>> {
-        let cf = self.db.cf_handle(cf_name);
-        match cf {
-            Some(_v) => {return Ok(());},
-            None => {}
-        };
+        if self.storage.has_cf(cf_name) {
+            return Ok(());
+        }
         let a = format!("no cloumnfamily {}", cf_name);
-        return  Box::new(Err(a)).unwrap();
+        return Err(a.into());
 
     }
 
@@ -297,6 +362,39 @@ impl DbInfo {
         return Ok(SlaveBehindSetting::new(cluster_name))
     }
 
+    ///
+    /// 获取slave读权重配置， 如果未配置默认100
+    pub fn get_read_weight_setting(&self, host: &String) -> Result<ReadWeightSetting, Box<dyn Error>>{
+        let result = self.prefix_get(&PrefixTypeCode::ReadWeightSeting, host)?;
+        if result.value.len() > 0{
+            let v: ReadWeightSetting = serde_json::from_str(&result.value)?;
+            return Ok(v)
+        }
+        return Ok(ReadWeightSetting::new(host))
+    }
+
+    ///
+    /// 获取集群读路由降级策略， 如果未配置默认degraded
+    pub fn get_read_policy_setting(&self, cluster_name: &String) -> Result<ReadPolicySetting, Box<dyn Error>>{
+        let result = self.prefix_get(&PrefixTypeCode::ReadPolicySeting, cluster_name)?;
+        if result.value.len() > 0{
+            let v: ReadPolicySetting = serde_json::from_str(&result.value)?;
+            return Ok(v)
+        }
+        return Ok(ReadPolicySetting::new(cluster_name))
+    }
+
+    ///
+    /// 获取节点最新一次监控数据， 未开启监控返回None
+    pub fn get_last_monitor(&self, host: &String) -> Result<Option<MysqlMonitorStatus>, Box<dyn Error>>{
+        let result = self.prefix_get(&PrefixTypeCode::NodeMonitorLast, host)?;
+        if result.value.len() > 0{
+            let v: MysqlMonitorStatus = serde_json::from_str(&result.value)?;
+            return Ok(Some(v))
+        }
+        return Ok(None)
+    }
+
     ///
     /// 获取所有节点监控开关配置
     pub fn get_monitor_setting(&self) -> Result<Vec<RowValue<MonitorSetting>>, Box<dyn Error>>{
@@ -316,58 +414,232 @@ impl DbInfo {
     ///
     /// 删除过期监控数据
     pub fn expired_monitor_data(&self, monitor_set: &Vec<RowValue<MonitorSetting>>) -> Result<(), Box<dyn Error>>{
-        let one_day_ms = (60 * 1000 * 60 * 24) as i64; // 一天多少毫秒
-        let cur_time = crate::timestamp();
-        let cf_name = CfNameTypeCode::SystemData.get();
-        self.check_cf(&cf_name)?;
-        if let Some(cf) = self.db.cf_handle(&cf_name){
-            let mut iter = self.db.raw_iterator_cf(cf);
+        for mset in monitor_set{
+            self.expired_monitor_host(&mset.value)?;
+        }
+        Ok(())
+    }
+
+}
+
+fn cf_names() -> Vec<String> {
+    vec![String::from("Ha_nodes_info"),
+         String::from("Rollback_sql_info"),
+         String::from("Ha_change_log"),
+         String::from("System_data"),
+         String::from("Nodes_state"),
+         String::from("Check_state"),
+         String::from("Monitor_data"),
+         String::from("Monitor_data_1m"),
+         String::from("Monitor_data_1h")]
+}
+
+///
+/// rocksdb存储
+pub struct RocksStorage {
+    db: DB,
+    opts: Options,
+}
+
+///
+/// 每个列簇导出的属性
+const CF_PROPERTIES: [&str; 5] = [
+    "rocksdb.estimate-num-keys",
+    "rocksdb.total-sst-files-size",
+    "rocksdb.cur-size-all-mem-tables",
+    "rocksdb.estimate-pending-compaction-bytes",
+    "rocksdb.num-running-compactions",
+];
+
+impl RocksStorage {
+    fn cf(&self, cf_name: &str) -> Result<&ColumnFamily, Box<dyn Error>> {
+        match self.db.cf_handle(cf_name) {
+            Some(cf) => Ok(cf),
+            None => Err(format!("no cloumnfamily {}", cf_name).into())
+        }
+    }
+}
+
+impl Storage for RocksStorage {
+    fn has_cf(&self, cf_name: &str) -> bool {
+        self.db.cf_handle(cf_name).is_some()
+    }
+
+    fn get(&self, cf_name: &str, key: &str) -> Result<Option<String>, Box<dyn Error>> {
+        match self.db.get_cf(self.cf(cf_name)?, key)? {
+            Some(v) => Ok(Some(from_utf8(&v)?.to_string())),
+            None => Ok(None)
+        }
+    }
+
+    fn put(&self, cf_name: &str, key: &str, value: &str) -> Result<(), Box<dyn Error>> {
+        self.db.put_cf(self.cf(cf_name)?, key, value)?;
+        Ok(())
+    }
+
+    fn delete(&self, cf_name: &str, key: &str) -> Result<(), Box<dyn Error>> {
+        self.db.delete_cf(self.cf(cf_name)?, key)?;
+        Ok(())
+    }
+
+    fn iterator(&self, cf_name: &str, seek_to: &str) -> Result<Vec<KeyValue>, Box<dyn Error>> {
+        let mut iter = self.db.raw_iterator_cf(self.cf(cf_name)?);
+        if seek_to.len() > 0 {
+            iter.seek(seek_to);
+        }else {
             iter.seek_to_first();
-            while iter.valid() {
-                if let Some(v) = iter.key() {
-                    let key: String = from_utf8(&v.to_vec())?.parse()?;
-                    if key.starts_with(&PrefixTypeCode::NodeMonitorData.prefix()){
-                        //info!("{:?}", &key);
-                        for mset in monitor_set{
-                            if key.contains(mset.value.host.as_str()){
-                                let key_info = key.split("_");
-                                let key_info = key_info.collect::<Vec<&str>>();
-                                let time = key_info[1].to_string().parse::<i64>()?;
-                                if &(cur_time - time) > &(one_day_ms * mset.value.days as i64) {
-                                    self.delete(&key, &cf_name)?;
-                                }
-                            }
-                        }
-                    }
-                }
-                iter.next();
+        }
+        let mut values: Vec<KeyValue> = vec![];
+        while iter.valid() {
+            let mut key: String = String::from("");
+            let mut value: String = String::from("");
+            if let Some(v) = iter.key() {
+                key = from_utf8(&v.to_vec())?.parse()?;
+            }
+
+            if let Some(v) = iter.value() {
+                value = from_utf8(&v.to_vec())?.parse()?;
             }
+
+            let kv = KeyValue{key, value};
+            values.push(kv);
+            iter.next();
+        }
+        Ok(values)
+    }
+
+    fn prefix_iterator(&self, cf_name: &str, prefix: &str) -> Result<Vec<KeyValue>, Box<dyn Error>> {
+        let iter = self.db.prefix_iterator_cf(self.cf(cf_name)?, prefix);
+        let mut values: Vec<KeyValue> = vec![];
+        for (k, v) in iter {
+            let key: String = from_utf8(&k.to_vec())?.parse()?;
+            let value: String = from_utf8(&v.to_vec())?.parse()?;
+            let kv = KeyValue{key, value};
+            values.push(kv);
+        }
+        Ok(values)
+    }
+
+    fn range_limit(&self, cf_name: &str, start: &str, end: &str, limit: usize) -> Result<Vec<KeyValue>, Box<dyn Error>> {
+        let mut values: Vec<KeyValue> = vec![];
+        if start >= end {
+            return Ok(values);
+        }
+        //列簇设置了前缀提取， 范围查询需要total_order_seek
+        let mut opts = ReadOptions::default();
+        opts.set_total_order_seek(true);
+        opts.set_iterate_upper_bound(end);
+        let mut iter = self.db.raw_iterator_cf_opt(self.cf(cf_name)?, opts);
+        iter.seek(start);
+        while iter.valid() && values.len() < limit {
+            if let (Some(k), Some(v)) = (iter.key(), iter.value()) {
+                values.push(KeyValue{ key: from_utf8(k)?.to_string(), value: from_utf8(v)?.to_string() });
+            }
+            iter.next();
+        }
+        iter.status()?;
+        Ok(values)
+    }
+
+    fn delete_range(&self, cf_name: &str, start: &str, end: &str) -> Result<(), Box<dyn Error>> {
+        if start >= end {
             return Ok(());
         }
-        let a = format!("no cloumnfamily {}", cf_name);
-        return  Err(a.into())
+        self.db.delete_range_cf(self.cf(cf_name)?, start, end)?;
+        Ok(())
+    }
+
+    fn write_batch(&self, rows: &Vec<(String, KeyValue)>, deletes: &Vec<(String, String)>) -> Result<(), Box<dyn Error>> {
+        let mut batch = WriteBatch::default();
+        for (cf_name, kv) in rows {
+            batch.put_cf(self.cf(cf_name)?, &kv.key, &kv.value);
+        }
+        for (cf_name, key) in deletes {
+            batch.delete_cf(self.cf(cf_name)?, key);
+        }
+        self.db.write(batch)?;
+        Ok(())
     }
 
+    fn checkpoint(&self, path: &str) -> Result<(), Box<dyn Error>> {
+        Checkpoint::new(&self.db)?.create_checkpoint(path)?;
+        Ok(())
+    }
+
+    fn stats(&self) -> Result<StorageStats, Box<dyn Error>> {
+        let mut stats = StorageStats::default();
+        for cf_name in cf_names() {
+            let cf = self.cf(&cf_name)?;
+            for property in CF_PROPERTIES.iter() {
+                if let Some(v) = self.db.property_int_value_cf(cf, property)? {
+                    stats.properties.push((cf_name.clone(), property.trim_start_matches("rocksdb.").to_string(), v));
+                }
+            }
+        }
+        if let Some(s) = self.opts.get_statistics() {
+            parse_statistics(&s, &mut stats);
+        }
+        Ok(stats)
+    }
+}
+
+///
+/// 解析statistics输出， 每行为一个ticker:
+///     rocksdb.block.cache.miss COUNT : 10
+/// 或一个histogram:
+///     rocksdb.db.get.micros P50 : 1.5 P95 : 3.2 P99 : 8.0 P100 : 20.0 COUNT : 100 SUM : 300
+fn parse_statistics(s: &str, stats: &mut StorageStats) {
+    for line in s.lines() {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        if tokens.len() < 4 || !tokens[0].starts_with("rocksdb.") {continue;}
+        let mut quantiles = vec![];
+        let mut count = None;
+        let mut sum = 0;
+        for kv in tokens[1..].chunks(3) {
+            if kv.len() != 3 || kv[1] != ":" {continue;}
+            match kv[0] {
+                "COUNT" => count = kv[2].parse().ok(),
+                "SUM" => sum = kv[2].parse().unwrap_or(0),
+                k if k.starts_with('P') => {
+                    if let (Ok(p), Ok(v)) = (k[1..].parse::<f64>(), kv[2].parse::<f64>()) {
+                        quantiles.push((p / 100.0, v));
+                    }
+                }
+                _ => {}
+            }
+        }
+        let name = tokens[0].trim_start_matches("rocksdb.").to_string();
+        match count {
+            Some(c) if quantiles.len() > 0 => stats.histograms.push((name, quantiles, c, sum)),
+            Some(c) => stats.tickers.push((name, c)),
+            None => {}
+        }
+    }
 }
 
-fn init_db(cf_names: &Vec<String>) -> Result<DB, Box<dyn Error>> {
-    let cf_info = DB::list_cf(&Options::default(),"rocksdb");
+///
+/// 返回打开时使用的Options， 开启了statistics， 用于获取统计信息
+fn init_db(cf_names: &Vec<String>) -> Result<(DB, Options), Box<dyn Error>> {
+    let conf = crate::config::get();
+    let path = &conf.storage.data_dir;
+    let cf_info = DB::list_cf(&Options::default(), path);
     match cf_info {
         Ok(c) =>{
             let opts = set_opts();
-            let mut db = DB::open_cf(&opts,"rocksdb", &c)?;
+            let mut db = DB::open_cf(&opts, path, &c)?;
             check_cf_exist(cf_names, &c, &mut db);
-            return Ok(db);
+            return Ok((db, opts));
         }
         Err(e) => {
             assert_eq!(e.to_string().find("No such file"), Some(10));
             info!("{:?}",e.to_string());
             info!("Create db file.....");
-            let mut db = DB::open_default("rocksdb")?;
+            let opts = set_opts();
+            let mut db = DB::open(&opts, path)?;
             info!("OK");
             let cl_list = vec![String::from("default")];
             check_cf_exist(cf_names, &cl_list, &mut db);
-            return Ok(db);
+            return Ok((db, opts));
         }
     }
 }
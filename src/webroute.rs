@@ -45,6 +45,15 @@ pub fn index_file(session: Session) -> Result<NamedFile>{
     Ok(NamedFile::open("index.html")?)
 }
 
+///
+/// 已登录的用户， 没有session时为None
+pub fn login_user(session: &Session) -> Option<String> {
+    match session.get::<String>("username") {
+        Ok(Some(u)) => Some(u),
+        _ => None
+    }
+}
+
 pub fn session_check(session: Session) -> Result<bool, Box<dyn Error>> {
     if let Some(_session) = session.get::<String>("username")? {
         //info!("{:?}", _session);
@@ -4,14 +4,15 @@
 */
 use actix_web::web;
 use actix_web::HttpResponse;
-use crate::storage::rocks::{DbInfo, CfNameTypeCode, RowValue, PrefixTypeCode};
+use crate::storage::rocks::{DbInfo, CfNameTypeCode, RowValue};
 use crate::webroute::response::{ResponseState, response_value};
 use std::error::Error;
 use crate::ha::sys_manager::MonitorSetting;
 use crate::storage::opdb::HostInfoValue;
-use std::str::from_utf8;
-use crate::ha::procotol::MysqlMonitorStatus;
+use crate::ha::procotol::{MysqlMonitorStatus, default_monitor_variables};
 use serde::{Serialize, Deserialize};
+use crate::storage::monitor_data::Resolution;
+use crate::storage::repo::Repo;
 
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -58,34 +59,25 @@ pub struct ResponseClusterMetric{
 }
 impl ResponseClusterMetric{
     fn new() -> ResponseClusterMetric{
-        let metric_info = vec!["com_insert".to_string(),
-                               "com_update".to_string(),
-                               "com_delete".to_string(),
-                               "com_select".to_string(),
-                               "questions".to_string(),
-                               "innodb_row_lock_current_waits".to_string(),
-                               "innodb_row_lock_time".to_string(),
-                               "created_tmp_disk_tables".to_string(),
-                               "created_tmp_tables".to_string(),
-                               "innodb_buffer_pool_reads".to_string(),
-                               "innodb_buffer_pool_read_requests".to_string(),
-                               "handler_read_first".to_string(),
-                               "handler_read_key".to_string(),
-                               "handler_read_next".to_string(),
-                               "handler_read_prev".to_string(),
-                               "handler_read_rnd".to_string(),
-                               "handler_read_rnd_next".to_string(),
-                               "innodb_os_log_pending_fsyncs".to_string(),
-                               "innodb_os_log_pending_writes".to_string(),
-                               "innodb_log_waits".to_string(),
-                               "threads_connected".to_string(),
-                               "threads_running".to_string(),
-                               "bytes_sent".to_string(),
-                               "bytes_received".to_string(),
-                               "slow_queries".to_string()];
+        let metric_info = default_monitor_variables().into_iter().map(|v| v.name).collect();
         ResponseClusterMetric{ metric_info, nodes_info: vec![] }
     }
-    fn init(&mut self, db: &web::Data<DbInfo>, ms: &Vec<RowValue<MonitorSetting>>) -> Result<(), Box<dyn Error>>{
+
+    ///
+    /// 追加各监控节点额外配置的变量
+    fn init_metric(&mut self, db: &DbInfo) -> Result<(), Box<dyn Error>>{
+        for cl_info in &self.nodes_info{
+            for host in &cl_info.node_list{
+                for var in db.get_monitor_variables(host)?{
+                    if !self.metric_info.contains(&var.name){
+                        self.metric_info.push(var.name);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+    fn init(&mut self, db: &DbInfo, ms: &Vec<RowValue<MonitorSetting>>) -> Result<(), Box<dyn Error>>{
         let result = db.iterator(&CfNameTypeCode::HaNodesInfo.get(),&"".to_string())?;
         'all: for row in &result{
             if row.value.len() == 0 {continue;}
@@ -101,7 +93,7 @@ impl ResponseClusterMetric{
             cnm.init(ms,&node_info.host);
             self.nodes_info.push(cnm);
         }
-        Ok(())
+        self.init_metric(db)
     }
 }
 
@@ -121,64 +113,6 @@ pub fn get_cluster_metric(data: web::Data<DbInfo>) -> HttpResponse {
     }
 }
 
-impl MysqlMonitorStatus{
-    fn get_value(&self, metric: &String) -> usize{
-        if metric == "com_insert"{
-            return self.com_insert;
-        }else if metric == "com_update" {
-            return self.com_update;
-        }else if metric == "com_delete" {
-            return self.com_delete;
-        }else if metric == "com_select" {
-            return self.com_select;
-        }else if metric == "questions" {
-            return self.questions;
-        }else if metric == "innodb_row_lock_current_waits" {
-            return self.innodb_row_lock_current_waits;
-        }else if metric == "innodb_row_lock_time" {
-            return self.innodb_row_lock_time;
-        }else if metric == "created_tmp_disk_tables" {
-            return  self.created_tmp_disk_tables;
-        }else if metric == "created_tmp_tables"{
-            return self.created_tmp_tables;
-        }else if metric == "innodb_buffer_pool_reads" {
-            return self.innodb_buffer_pool_reads;
-        }else if metric == "innodb_buffer_pool_read_requests" {
-            return self.innodb_buffer_pool_read_requests;
-        }else if metric == "handler_read_first" {
-            return self.handler_read_first;
-        }else if metric == "handler_read_key" {
-            return self.handler_read_key;
-        }else if metric == "handler_read_next" {
-            return self.handler_read_next;
-        }else if metric == "handler_read_prev" {
-            return  self.handler_read_prev;
-        }else if metric == "handler_read_rnd" {
-            return self.handler_read_rnd;
-        }else if metric == "handler_read_rnd_next" {
-            return  self.handler_read_rnd_next;
-        }else if metric == "innodb_os_log_pending_fsyncs"{
-            return self.innodb_os_log_pending_fsyncs;
-        }else if metric == "innodb_os_log_pending_writes"{
-            return self.innodb_os_log_pending_writes;
-        }else if metric == "innodb_log_waits" {
-            return self.innodb_log_waits;
-        }else if metric == "threads_connected" {
-            return self.threads_connected;
-        }else if metric == "threads_running" {
-            return self.threads_running;
-        }else if metric == "bytes_sent"{
-            return self.bytes_sent;
-        }else if metric == "bytes_received" {
-            return self.bytes_received;
-        }else if metric == "slow_queries"{
-            return self.slow_queries;
-        }else {
-            return 0;
-        }
-    }
-}
-
 #[derive(Serialize, Deserialize, Clone)]
 pub struct MetricValue{
     metric: String,
@@ -192,6 +126,7 @@ impl MetricValue{
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct ResponseMonitorMetricValue{
+    resolution: String,     //raw、1m、1h
     monitor_value: Vec<MetricValue>
 }
 impl ResponseMonitorMetricValue{
@@ -201,12 +136,12 @@ impl ResponseMonitorMetricValue{
             let metricvalue = MetricValue::new(&metric);
             m_list.push(metricvalue)
         }
-        ResponseMonitorMetricValue{ monitor_value: m_list }
+        ResponseMonitorMetricValue{ resolution: Resolution::Raw.name().to_string(), monitor_value: m_list }
     }
 
     fn init(&mut self, value: &MysqlMonitorStatus) {
         for metricv in &mut self.monitor_value{
-            let mv = vec![value.time.clone() as usize, value.get_value(&metricv.metric)];
+            let mv = vec![value.time.clone() as usize, value.get(&metricv.metric) as usize];
             metricv.value.push(mv);
         }
     }
@@ -214,45 +149,46 @@ impl ResponseMonitorMetricValue{
 
 ///
 /// web端拉取监控数据的请求
+///
+/// resolution为raw、1m、1h， 为空时根据查询范围自动选择， 汇总数据返回平均值
 #[derive(Serialize, Deserialize, Clone)]
 pub struct PostMonitorMetricValue{
     host: String,
     metric: Vec<String>,
     start_time: i64,
-    stop_time: i64
+    stop_time: i64,
+    #[serde(default)]
+    resolution: String
 }
 impl PostMonitorMetricValue{
-    fn get_value(&self, db: &web::Data<DbInfo>) -> Result<ResponseMonitorMetricValue, Box<dyn Error>>{
-        let cf_name = CfNameTypeCode::SystemData.get();
+    fn get_value(&self, db: &DbInfo) -> Result<ResponseMonitorMetricValue, Box<dyn Error>>{
         let mut rmmv = ResponseMonitorMetricValue::new(&self.metric);
         if self.host == "".to_string(){
             return Ok(rmmv);
         }
-        let prefix = format!("{}:{}", PrefixTypeCode::NodeMonitorData.prefix(), &self.host);
-        if let Some(cf) = db.db.cf_handle(&cf_name) {
-            let iter = db.db.prefix_iterator_cf(cf,&prefix);
-            for (k, v) in iter {
-                let key: String = from_utf8(&k.to_vec())?.parse()?;
-                if !key.starts_with(&prefix){continue;}
-                let value: MysqlMonitorStatus = serde_json::from_slice(&v.to_vec())?;
-                //info!("{:?}", &value);
-                if !self.check_time(&value){continue;}
+        let res = self.resolution(db)?;
+        rmmv.resolution = res.name().to_string();
+        if res == Resolution::Raw {
+            for value in db.get_monitor_data(&self.host, self.start_time, self.stop_time)? {
                 rmmv.init(&value)
             }
-            return Ok(rmmv);
+        } else {
+            for value in db.get_monitor_rollup(&res, &self.host, self.start_time, self.stop_time)? {
+                rmmv.init(&value.avg)
+            }
         }
-        let a = format!("no cloumnfamily {}", cf_name);
-        return Err(a.into())
+        return Ok(rmmv);
     }
 
-    fn check_time(&self, value: &MysqlMonitorStatus) -> bool{
-        if value.time < self.start_time{
-            return false;
-        }
-        if value.time > self.stop_time{
-            return true;
+    fn resolution(&self, db: &DbInfo) -> Result<Resolution, Box<dyn Error>>{
+        if self.resolution.len() > 0 {
+            return Resolution::from_name(&self.resolution);
         }
-        return true;
+        let setting = match Repo::<MonitorSetting>::new(db).get(&self.host)? {
+            Some(s) => s,
+            None => MonitorSetting::new(&self.host)
+        };
+        Ok(Resolution::auto(&setting, self.start_time, self.stop_time))
     }
 }
 
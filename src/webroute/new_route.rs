@@ -5,14 +5,28 @@
 use serde::Serialize;
 use serde::Deserialize;
 use actix_web::{web, HttpResponse};
+use actix_session::Session;
 use crate::storage::rocks::{DbInfo, PrefixTypeCode, CfNameTypeCode, KeyValue};
-use crate::storage::opdb::{ClusterNodeInfo, NodeClusterList, RouteClusterList, SlaveBehindSetting, HostInfoValue};
-use crate::webroute::response::{response_value, ResponseState};
+use crate::storage::opdb::{ClusterNodeInfo, NodeClusterList, RouteClusterList, SlaveBehindSetting, HostInfoValue, ReadWeightSetting, ReadPolicySetting};
+use crate::webroute::response::{response_value, ResponseState, response_state};
+use crate::webroute::route::HostInfo;
 use crate::webroute::op_value::ClusterMonitorInfo;
-use crate::ha::sys_manager::MonitorSetting;
+use crate::ha::sys_manager::{MonitorSetting, MonitorVariableSetting};
+use crate::ha::mysql_probe::ProbeSetting;
+use crate::ha::proxy::ProxySetting;
+use crate::ha::proxysql::ProxysqlSetting;
+use crate::ha::exporter::ExporterSetting;
+use crate::ha::vip::VipSetting;
+use crate::storage::backup::{self, BackupSetting};
+use crate::storage::cluster_config::{self, ClusterConfig};
 use crate::ha::procotol::{MysqlMonitorStatus, MysqlState};
 use std::error::Error;
 use crate::ha::nodes_manager::CheckState;
+use crate::ha::metrics::ServerMetrics;
+use crate::ha::alert::{self, AlertRule};
+use crate::ha::alert_channel::AlertChannel;
+use crate::ha::silence::{self, Silence};
+use crate::storage::repo::Repo;
 
 pub fn get_cluster_list(data: web::Data<DbInfo>) -> HttpResponse {
     let mut respons_list = NodeClusterList::new();
@@ -58,6 +72,9 @@ pub fn get_cluster_monitor_status(data: web::Data<DbInfo>, info: web::Json<PostC
 ///
 /// 配置slave 延迟检查
 pub fn slave_delay_setting(data: web::Data<DbInfo>, info: web::Json<SlaveBehindSetting>) -> HttpResponse{
+    if info.readmit_delay > info.delay {
+        return ResponseState::error("readmit_delay must be less than or equal to delay".to_string());
+    }
     if let Err(e) = info.save(&data){
         return ResponseState::error(e.to_string());
     }
@@ -79,6 +96,36 @@ pub fn get_slave_delay_setting(data: web::Data<DbInfo>, info: web::Json<PostClus
 }
 
 
+///
+/// 配置没有slave满足读路由条件时的策略
+pub fn read_policy_setting(data: web::Data<DbInfo>, info: web::Json<ReadPolicySetting>) -> HttpResponse{
+    return response_state(info.save(&data));
+}
+
+pub fn get_read_policy_setting(data: web::Data<DbInfo>, info: web::Json<PostCluster>) -> HttpResponse{
+    match data.get_read_policy_setting(&info.cluster_name) {
+        Ok(v) =>{
+            return response_value(&v);
+        }
+        Err(e) => {
+            return ResponseState::error(e.to_string());
+        }
+    }
+}
+
+///
+/// 获取集群slave读路由状态及剔除原因
+pub fn get_read_route_state(data: web::Data<DbInfo>, info: web::Json<PostCluster>) -> HttpResponse{
+    match data.get_read_route_state(&info.cluster_name) {
+        Ok(v) =>{
+            return response_value(&v);
+        }
+        Err(e) => {
+            return ResponseState::error(e.to_string());
+        }
+    }
+}
+
 ///
 /// 获取节点监控配置
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -118,6 +165,49 @@ pub fn set_monitor_setting(data: web::Data<DbInfo>, info: web::Json<MonitorSetti
 }
 
 
+///
+/// 设置集群或节点采集的监控变量， host不为空时为节点配置
+pub fn set_monitor_variable_setting(data: web::Data<DbInfo>, info: web::Json<MonitorVariableSetting>) -> HttpResponse{
+    let mut setting = info.into_inner();
+    if let Err(e) = setting.check() {
+        return ResponseState::error(e.to_string());
+    }
+    return response_state(setting.save(&data));
+}
+
+///
+/// 获取所有集群及节点的监控变量配置
+pub fn get_monitor_variable_setting(data: web::Data<DbInfo>) -> HttpResponse{
+    match data.get_monitor_variable_setting() {
+        Ok(v) => {
+            return response_value(&v);
+        }
+        Err(e) => {
+            return ResponseState::error(e.to_string());
+        }
+    }
+}
+
+///
+/// 删除监控变量配置， 删除后使用上一级配置
+pub fn delete_monitor_variable_setting(data: web::Data<DbInfo>, info: web::Json<MonitorVariableSetting>) -> HttpResponse{
+    return response_state(info.delete(&data));
+}
+
+///
+/// 获取节点当前生效的监控变量
+pub fn get_monitor_variables(data: web::Data<DbInfo>, info: web::Json<PostMonitorHost>) -> HttpResponse{
+    match data.get_monitor_variables(&info.host) {
+        Ok(v) => {
+            return response_value(&v);
+        }
+        Err(e) => {
+            return ResponseState::error(e.to_string());
+        }
+    }
+}
+
+
 ///
 /// 用于统计集群内所有节点监控数据之和
 #[derive(Serialize, Deserialize, Clone)]
@@ -146,14 +236,14 @@ impl ResponseMonitorStatic{
     }
 
     pub fn update(&mut self, ms: &MysqlMonitorStatus) {
-        self.current_qps += ms.questions;
-        self.slow_queries += ms.slow_queries;
-        self.thread_running += ms.threads_running;
-        self.thread_connected += ms.threads_connected;
-        self.com_select += ms.com_select;
-        self.com_delete += ms.com_delete;
-        self.com_update += ms.com_update;
-        self.com_insert += ms.com_insert;
+        self.current_qps += ms.get("questions") as usize;
+        self.slow_queries += ms.get("slow_queries") as usize;
+        self.thread_running += ms.get("threads_running") as usize;
+        self.thread_connected += ms.get("threads_connected") as usize;
+        self.com_select += ms.get("com_select") as usize;
+        self.com_delete += ms.get("com_delete") as usize;
+        self.com_update += ms.get("com_update") as usize;
+        self.com_insert += ms.get("com_insert") as usize;
     }
 
     pub fn get_total_a(&self) -> ResponseMonitorA{
@@ -249,6 +339,8 @@ pub struct ResponseDownNodeInfo{
     pub io_thread: usize,
     pub db_down: usize,
     pub client_down: usize,
+    pub maintain: usize,         // 维护模式时所有报警项为0
+    pub silenced: usize,         // 有报警项被静默时为1
 }
 impl ResponseDownNodeInfo{
     fn new(info: &HostInfoValue) -> ResponseDownNodeInfo{
@@ -259,7 +351,29 @@ impl ResponseDownNodeInfo{
             sql_thread: 0,
             io_thread: 0,
             db_down: 0,
-            client_down: 0
+            client_down: 0,
+            maintain: if info.maintain { 1 } else { 0 },
+            silenced: 0
+        }
+    }
+
+    ///
+    /// 维护模式及被静默的报警项置为0， 对应的报警类型为node_down、agent_down、repl_stopped
+    fn mute(&mut self, silences: &Vec<Silence>, now: i64) {
+        if self.maintain == 1 {
+            self.sql_thread = 0;
+            self.io_thread = 0;
+            self.db_down = 0;
+            self.client_down = 0;
+            return;
+        }
+        let kinds: [(&str, &mut usize); 4] = [("node_down", &mut self.db_down), ("agent_down", &mut self.client_down),
+            ("repl_stopped", &mut self.sql_thread), ("repl_stopped", &mut self.io_thread)];
+        for (kind, flag) in kinds {
+            if *flag == 1 && silence::find(silences, &self.cluster_name, &self.host, kind, now).is_some() {
+                *flag = 0;
+                self.silenced = 1;
+            }
         }
     }
 
@@ -338,6 +452,11 @@ impl DbInfo{
         let result = self.iterator(&cf_name, &"".to_string())?;
         let mut response_alter = ResponseAlter::new(&result, &self)?;
         response_alter.init(&self)?;
+        let silences = self.get_silences(false)?;
+        let now = crate::timestamp();
+        for node in &mut response_alter.nodes_info {
+            node.mute(&silences, now);
+        }
         Ok(response_alter)
     }
 }
@@ -354,4 +473,663 @@ pub fn alter_interface(data: web::Data<DbInfo>, info: web::Json<PostAlter>) -> H
             return ResponseState::error(e.to_string());
         }
     }
-}
\ No newline at end of file
+}
+
+///
+/// 获取client主动注册等待审批的节点
+pub fn get_pending_nodes(data: web::Data<DbInfo>) -> HttpResponse {
+    match data.get_pending_nodes() {
+        Ok(v) => {
+            return response_value(&v);
+        }
+        Err(e) => {
+            return ResponseState::error(e.to_string());
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PostPendingNode {
+    pub host: String,
+}
+
+///
+/// 审批通过， 写入节点信息交由ha_manager管理
+pub fn approve_node(data: web::Data<DbInfo>, info: web::Json<PostPendingNode>) -> HttpResponse {
+    let node = match data.get_pending_node(&info.host) {
+        Ok(v) => v,
+        Err(e) => {
+            return ResponseState::error(e.to_string());
+        }
+    };
+    let host_info = web::Json(HostInfo{
+        host: node.host.clone(),
+        rtype: "db".to_string(),
+        dbport: node.dbport.clone(),
+        cluster_name: node.cluster_name.clone()
+    });
+    if let Err(e) = crate::storage::opdb::insert_mysql_host_info(data.clone(), &host_info) {
+        return ResponseState::error(e.to_string());
+    }
+    info!("approve agent {} for cluster {}", &node.host, &node.cluster_name);
+    return response_state(data.delete_pending_node(&info.host));
+}
+
+///
+/// 拒绝注册， 从待审批队列删除
+pub fn reject_node(data: web::Data<DbInfo>, info: web::Json<PostPendingNode>) -> HttpResponse {
+    return response_state(data.delete_pending_node(&info.host));
+}
+
+///
+/// 设置server直连mysql检查的监控账号
+pub fn set_probe_setting(data: web::Data<DbInfo>, info: web::Json<ProbeSetting>) -> HttpResponse {
+    let mut info = info.into_inner();
+    match data.get_probe_setting() {
+        Ok(old) => info.keep_secret(&old),
+        Err(e) => return ResponseState::error(e.to_string())
+    }
+    return response_state(info.save(&data));
+}
+
+///
+/// 获取直连检查配置， 不返回密码
+pub fn get_probe_setting(data: web::Data<DbInfo>) -> HttpResponse {
+    match data.get_probe_setting() {
+        Ok(mut v) => {
+            v.password = "".to_string();
+            return response_value(&v);
+        }
+        Err(e) => {
+            return ResponseState::error(e.to_string());
+        }
+    }
+}
+
+///
+/// 查询状态变化时间线， host不为空时查询单个节点， 否则查询整个集群
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PostTimeline {
+    #[serde(default)]
+    pub cluster_name: String,
+    #[serde(default)]
+    pub host: String,
+    #[serde(default)]
+    pub start: i64,
+    #[serde(default)]
+    pub stop: i64,
+}
+
+pub fn get_state_timeline(data: web::Data<DbInfo>, info: web::Json<PostTimeline>) -> HttpResponse {
+    let result = if info.host.len() > 0 {
+        data.get_node_timeline(&info.host, info.start, info.stop)
+    } else {
+        data.get_cluster_timeline(&info.cluster_name, info.start, info.stop)
+    };
+    match result {
+        Ok(v) => {
+            return response_value(&v);
+        }
+        Err(e) => {
+            return ResponseState::error(e.to_string());
+        }
+    }
+}
+
+///
+/// 设置slave读路由基础权重
+pub fn read_weight_setting(data: web::Data<DbInfo>, info: web::Json<ReadWeightSetting>) -> HttpResponse {
+    return response_state(info.save(&data));
+}
+
+pub fn get_read_weight_setting(data: web::Data<DbInfo>, info: web::Json<PostMonitorHost>) -> HttpResponse {
+    match data.get_read_weight_setting(&info.host) {
+        Ok(v) => {
+            return response_value(&v);
+        }
+        Err(e) => {
+            return ResponseState::error(e.to_string());
+        }
+    }
+}
+
+///
+/// 设置集群读写分离代理， enable为false时关闭
+pub fn proxy_setting(data: web::Data<DbInfo>, info: web::Json<ProxySetting>) -> HttpResponse {
+    if let Err(e) = info.check(&data) {
+        return ResponseState::error(e.to_string());
+    }
+    return response_state(info.save(&data));
+}
+
+pub fn get_proxy_setting(data: web::Data<DbInfo>) -> HttpResponse {
+    match data.get_proxy_setting() {
+        Ok(v) => {
+            let settings: Vec<ProxySetting> = v.into_iter().map(|r| r.value).collect();
+            return response_value(&settings);
+        }
+        Err(e) => {
+            return ResponseState::error(e.to_string());
+        }
+    }
+}
+
+///
+/// 设置集群ProxySQL同步， 保存后由同步线程立即同步一次
+pub fn proxysql_setting(data: web::Data<DbInfo>, info: web::Json<ProxysqlSetting>) -> HttpResponse {
+    if info.writer_hostgroup == info.reader_hostgroup {
+        return ResponseState::error("writer_hostgroup and reader_hostgroup must be different".to_string());
+    }
+    let mut info = info.into_inner();
+    match Repo::<ProxysqlSetting>::new(&data).get(&info.cluster_name) {
+        Ok(Some(old)) => info.keep_secret(&old),
+        Ok(None) => {}
+        Err(e) => return ResponseState::error(e.to_string())
+    }
+    return response_state(info.save(&data));
+}
+
+///
+/// 获取ProxySQL同步配置， 不返回密码
+pub fn get_proxysql_setting(data: web::Data<DbInfo>) -> HttpResponse {
+    match data.get_proxysql_setting() {
+        Ok(v) => {
+            let settings: Vec<ProxysqlSetting> = v.into_iter().map(|mut r| {
+                r.value.password = "".to_string();
+                r.value
+            }).collect();
+            return response_value(&settings);
+        }
+        Err(e) => {
+            return ResponseState::error(e.to_string());
+        }
+    }
+}
+
+pub fn get_proxysql_status(data: web::Data<DbInfo>) -> HttpResponse {
+    let result = data.get_proxysql_setting().and_then(|settings| {
+        let mut status = vec![];
+        for s in settings {
+            status.push(data.get_proxysql_status(&s.key)?);
+        }
+        Ok(status)
+    });
+    match result {
+        Ok(v) => {
+            return response_value(&v);
+        }
+        Err(e) => {
+            return ResponseState::error(e.to_string());
+        }
+    }
+}
+
+///
+/// 设置路由导出， enable为false时停止导出， 已生成的文件不会删除，
+/// target只能是输出目录中的文件名， reload只能选择配置文件中的命令名称
+pub fn exporter_setting(data: web::Data<DbInfo>, info: web::Json<ExporterSetting>) -> HttpResponse {
+    if let Err(e) = info.check() {
+        return ResponseState::error(e.to_string());
+    }
+    return response_state(info.save(&data));
+}
+
+pub fn get_exporter_setting(data: web::Data<DbInfo>) -> HttpResponse {
+    match data.get_exporter_setting() {
+        Ok(v) => {
+            let settings: Vec<ExporterSetting> = v.into_iter().map(|r| r.value).collect();
+            return response_value(&settings);
+        }
+        Err(e) => {
+            return ResponseState::error(e.to_string());
+        }
+    }
+}
+
+pub fn get_exporter_status(data: web::Data<DbInfo>) -> HttpResponse {
+    let result = data.get_exporter_setting().and_then(|settings| {
+        let mut status = vec![];
+        for s in settings {
+            status.push(data.get_exporter_status(&s.key)?);
+        }
+        Ok(status)
+    });
+    match result {
+        Ok(v) => {
+            return response_value(&v);
+        }
+        Err(e) => {
+            return ResponseState::error(e.to_string());
+        }
+    }
+}
+
+///
+/// 使用当前路由预览模板渲染结果， 不写入文件
+pub fn render_exporter(data: web::Data<DbInfo>, info: web::Json<ExporterSetting>) -> HttpResponse {
+    let result = crate::ha::exporter::get_routes(&data, &info)
+        .and_then(|routes| crate::ha::exporter::render(&info, &routes));
+    match result {
+        Ok(v) => {
+            return response_value(&v);
+        }
+        Err(e) => {
+            return ResponseState::error(e.to_string());
+        }
+    }
+}
+
+///
+/// 设置集群vip， 切换时由client释放及绑定
+pub fn vip_setting(data: web::Data<DbInfo>, info: web::Json<VipSetting>) -> HttpResponse {
+    if info.vip.parse::<std::net::Ipv4Addr>().is_err() {
+        return ResponseState::error(format!("invalid vip: {}", &info.vip));
+    }
+    if info.interface.len() == 0 || info.netmask == 0 || info.netmask > 32 {
+        return ResponseState::error("interface can not be empty and netmask must be between 1 and 32".to_string());
+    }
+    return response_state(info.save(&data));
+}
+
+pub fn get_vip_setting(data: web::Data<DbInfo>, info: web::Json<PostCluster>) -> HttpResponse {
+    match data.get_vip_setting(&info.cluster_name) {
+        Ok(v) => {
+            return response_value(&v);
+        }
+        Err(e) => {
+            return ResponseState::error(e.to_string());
+        }
+    }
+}
+
+///
+/// 查询集群所有在线节点的vip绑定情况
+pub fn get_vip_status(data: web::Data<DbInfo>, info: web::Json<PostCluster>) -> HttpResponse {
+    match crate::ha::vip::get_cluster_vip_state(&data, &info.cluster_name) {
+        Ok(v) => {
+            return response_value(&v);
+        }
+        Err(e) => {
+            return ResponseState::error(e.to_string());
+        }
+    }
+}
+
+///
+/// 集群vip漂移记录， cluster_name为空时返回所有集群
+pub fn get_vip_log(data: web::Data<DbInfo>, info: web::Json<PostCluster>) -> HttpResponse {
+    match data.get_vip_log(&info.cluster_name) {
+        Ok(v) => {
+            return response_value(&v);
+        }
+        Err(e) => {
+            return ResponseState::error(e.to_string());
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PostTakeoverVip {
+    pub cluster_name: String,
+    pub host: String,
+}
+
+///
+/// 旧master无法确认释放vip时， 由运维人员隔离旧master后在当前master上绑定vip， 需要登录
+pub fn takeover_vip(data: web::Data<DbInfo>, info: web::Json<PostTakeoverVip>, session: Session) -> HttpResponse {
+    let user = match crate::webroute::login_user(&session) {
+        Some(u) => u,
+        None => return ResponseState::no_session()
+    };
+    match crate::ha::vip::takeover_vip(&data, &info.cluster_name, &info.host, &user) {
+        Ok(v) => {
+            return response_value(&v);
+        }
+        Err(e) => {
+            return ResponseState::error(e.to_string());
+        }
+    }
+}
+
+///
+/// 立即创建一个元数据备份
+pub fn create_backup(data: web::Data<DbInfo>) -> HttpResponse {
+    match backup::create_backup(&data, backup::BACKUP_MANUAL) {
+        Ok(v) => {
+            return response_value(&v);
+        }
+        Err(e) => {
+            return ResponseState::error(e.to_string());
+        }
+    }
+}
+
+pub fn get_backup_list() -> HttpResponse {
+    match backup::list_backups() {
+        Ok(v) => {
+            return response_value(&v);
+        }
+        Err(e) => {
+            return ResponseState::error(e.to_string());
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PostBackup {
+    pub name: String,
+}
+
+pub fn verify_backup(info: web::Json<PostBackup>) -> HttpResponse {
+    match backup::verify_backup(&info.name) {
+        Ok(v) => {
+            return response_value(&v);
+        }
+        Err(e) => {
+            return ResponseState::error(e.to_string());
+        }
+    }
+}
+
+pub fn delete_backup(info: web::Json<PostBackup>, session: Session) -> HttpResponse {
+    let user = match crate::webroute::login_user(&session) {
+        Some(u) => u,
+        None => return ResponseState::no_session()
+    };
+    info!("user {} delete backup {}", &user, &info.name);
+    return response_state(backup::delete_backup(&info.name));
+}
+
+///
+/// 校验通过后标记恢复， 需要重启server生效
+pub fn restore_backup(info: web::Json<PostBackup>, session: Session) -> HttpResponse {
+    let user = match crate::webroute::login_user(&session) {
+        Some(u) => u,
+        None => return ResponseState::no_session()
+    };
+    info!("user {} mark backup {} to restore", &user, &info.name);
+    match backup::set_restore_pending(&info.name) {
+        Ok(v) => {
+            if !v.ok {
+                return ResponseState::error(format!("backup {} verify failed: {}", &info.name, v.errors.join("; ")));
+            }
+            return ResponseState::ok();
+        }
+        Err(e) => {
+            return ResponseState::error(e.to_string());
+        }
+    }
+}
+
+pub fn backup_setting(data: web::Data<DbInfo>, info: web::Json<BackupSetting>) -> HttpResponse {
+    return response_state(info.save(&data));
+}
+
+pub fn get_backup_setting(data: web::Data<DbInfo>) -> HttpResponse {
+    match data.get_backup_setting() {
+        Ok(v) => {
+            return response_value(&v);
+        }
+        Err(e) => {
+            return ResponseState::error(e.to_string());
+        }
+    }
+}
+
+///
+/// 导出集群配置， 不包含密码
+pub fn config_export(data: web::Data<DbInfo>) -> HttpResponse {
+    match cluster_config::export(&data) {
+        Ok(v) => {
+            return response_value(&v);
+        }
+        Err(e) => {
+            return ResponseState::error(e.to_string());
+        }
+    }
+}
+
+///
+/// 返回导入该配置将产生的变更， 不做修改
+pub fn config_diff(data: web::Data<DbInfo>, info: web::Json<ClusterConfig>) -> HttpResponse {
+    match cluster_config::diff(&data, &info) {
+        Ok(v) => {
+            return response_value(&v);
+        }
+        Err(e) => {
+            return ResponseState::error(e.to_string());
+        }
+    }
+}
+
+///
+/// 导入集群配置， 会删除文档中没有的节点及用户， 需要登录
+pub fn config_import(data: web::Data<DbInfo>, info: web::Json<ClusterConfig>, session: Session) -> HttpResponse {
+    let user = match crate::webroute::login_user(&session) {
+        Some(u) => u,
+        None => return ResponseState::no_session()
+    };
+    match cluster_config::import(&data, &info, &user) {
+        Ok(v) => {
+            return response_value(&v);
+        }
+        Err(e) => {
+            return ResponseState::error(e.to_string());
+        }
+    }
+}
+
+///
+/// 重新加载配置文件， 与SIGHUP相同
+pub fn reload_config() -> HttpResponse {
+    match crate::config::reload() {
+        Ok(v) => {
+            return response_value(&v);
+        }
+        Err(e) => {
+            return ResponseState::error(e.to_string());
+        }
+    }
+}
+
+///
+/// 当前生效的配置， 需要登录， 不返回agent_token
+pub fn get_server_config(session: Session) -> HttpResponse {
+    if crate::webroute::login_user(&session).is_none() {
+        return ResponseState::no_session();
+    }
+    response_value(&*crate::config::get())
+}
+
+///
+/// Prometheus text格式指标
+pub fn metrics(data: web::Data<DbInfo>, metrics: web::Data<ServerMetrics>) -> HttpResponse {
+    match crate::ha::metrics::render(&data, &metrics) {
+        Ok(v) => {
+            return HttpResponse::Ok()
+                .content_type("text/plain; version=0.0.4")
+                .body(v);
+        }
+        Err(e) => {
+            return HttpResponse::InternalServerError().body(e.to_string());
+        }
+    }
+}
+
+///
+/// 设置报警规则， enable为false时停止评估并清除该规则的报警状态
+pub fn alert_rule(data: web::Data<DbInfo>, info: web::Json<AlertRule>) -> HttpResponse {
+    if let Err(e) = info.check() {
+        return ResponseState::error(e.to_string());
+    }
+    return response_state(info.save(&data));
+}
+
+pub fn get_alert_rule(data: web::Data<DbInfo>) -> HttpResponse {
+    match data.get_alert_rule() {
+        Ok(v) => {
+            let rules: Vec<AlertRule> = v.into_iter().map(|r| r.value).collect();
+            return response_value(&rules);
+        }
+        Err(e) => {
+            return ResponseState::error(e.to_string());
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PostName {
+    pub name: String,
+}
+
+pub fn delete_alert_rule(data: web::Data<DbInfo>, info: web::Json<PostName>) -> HttpResponse {
+    return response_state(data.delete_alert_rule(&info.name));
+}
+
+///
+/// 设置报警通知渠道
+pub fn alert_channel(data: web::Data<DbInfo>, info: web::Json<AlertChannel>) -> HttpResponse {
+    let mut info = info.into_inner();
+    match Repo::<AlertChannel>::new(&data).get(&info.name) {
+        Ok(Some(old)) => info.keep_secret(&old),
+        Ok(None) => {}
+        Err(e) => return ResponseState::error(e.to_string())
+    }
+    if let Err(e) = info.check() {
+        return ResponseState::error(e.to_string());
+    }
+    return response_state(info.save(&data));
+}
+
+///
+/// 获取通知渠道， 不返回密码及加签密钥
+pub fn get_alert_channel(data: web::Data<DbInfo>) -> HttpResponse {
+    match data.get_alert_channel() {
+        Ok(v) => {
+            let channels: Vec<AlertChannel> = v.into_iter().map(|r| {
+                let mut c = r.value;
+                c.password = "".to_string();
+                c.secret = "".to_string();
+                c
+            }).collect();
+            return response_value(&channels);
+        }
+        Err(e) => {
+            return ResponseState::error(e.to_string());
+        }
+    }
+}
+
+pub fn delete_alert_channel(data: web::Data<DbInfo>, info: web::Json<PostName>) -> HttpResponse {
+    return response_state(Repo::<AlertChannel>::new(&data).delete(&info.name));
+}
+
+///
+/// 向已保存的通知渠道发送一条测试通知
+pub fn test_alert_channel(data: web::Data<DbInfo>, info: web::Json<PostName>) -> HttpResponse {
+    let result = Repo::<AlertChannel>::new(&data).get(&info.name).and_then(|c| {
+        match c {
+            Some(c) => alert::test_channel(&c),
+            None => Err(format!("channel {} not found", &info.name).into())
+        }
+    });
+    return response_state(result);
+}
+
+///
+/// 当前pending及firing的报警
+pub fn get_alerts(data: web::Data<DbInfo>) -> HttpResponse {
+    match data.get_alert_state() {
+        Ok(v) => {
+            return response_value(&v);
+        }
+        Err(e) => {
+            return ResponseState::error(e.to_string());
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PostAlertHistory {
+    #[serde(default)]
+    pub start: i64,
+    #[serde(default)]
+    pub stop: i64,      //为0时到当前时间
+}
+
+///
+/// 报警通知发送记录
+pub fn get_alert_history(data: web::Data<DbInfo>, info: web::Json<PostAlertHistory>) -> HttpResponse {
+    let stop = if info.stop == 0 { crate::timestamp() + 1 } else { info.stop };
+    match data.get_alert_history(info.start, stop) {
+        Ok(v) => {
+            return response_value(&v);
+        }
+        Err(e) => {
+            return ResponseState::error(e.to_string());
+        }
+    }
+}
+
+///
+/// 创建或修改报警静默， id为空时创建， 需要登录， 操作人为登录用户
+pub fn create_silence(data: web::Data<DbInfo>, info: web::Json<Silence>, session: Session) -> HttpResponse {
+    let user = match crate::webroute::login_user(&session) {
+        Some(u) => u,
+        None => return ResponseState::no_session()
+    };
+    match silence::save(&data, &info, &user) {
+        Ok(v) => {
+            return response_value(&v);
+        }
+        Err(e) => {
+            return ResponseState::error(e.to_string());
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PostSilence {
+    pub id: String,
+}
+
+///
+/// 提前结束静默， 需要登录
+pub fn expire_silence(data: web::Data<DbInfo>, info: web::Json<PostSilence>, session: Session) -> HttpResponse {
+    let user = match crate::webroute::login_user(&session) {
+        Some(u) => u,
+        None => return ResponseState::no_session()
+    };
+    return response_state(silence::expire(&data, &info.id, &user));
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PostSilences {
+    #[serde(default)]
+    pub all: bool,      //为true时包含已结束的静默
+}
+
+pub fn get_silences(data: web::Data<DbInfo>, info: web::Json<PostSilences>) -> HttpResponse {
+    match data.get_silences(info.all) {
+        Ok(v) => {
+            return response_value(&v);
+        }
+        Err(e) => {
+            return ResponseState::error(e.to_string());
+        }
+    }
+}
+
+///
+/// 静默操作审计记录
+pub fn get_silence_audit(data: web::Data<DbInfo>, info: web::Json<PostAlertHistory>) -> HttpResponse {
+    let stop = if info.stop == 0 { crate::timestamp() + 1 } else { info.stop };
+    match data.get_silence_audit(info.start, stop) {
+        Ok(v) => {
+            return response_value(&v);
+        }
+        Err(e) => {
+            return ResponseState::error(e.to_string());
+        }
+    }
+}
@@ -4,7 +4,6 @@
 */
 
 
-use actix_web::web;
 use crate::storage::rocks::{DbInfo, CfNameTypeCode};
 use std::error::Error;
 use crate::storage::opdb::{ClusterNodeInfo, HaChangeLog};
@@ -49,7 +48,7 @@ impl ClusterInfo{
         }
     }
 
-    fn init(&mut self, db: &web::Data<DbInfo>, cl_info: &ClusterNodeInfo) -> Result<(), Box<dyn Error>>{
+    fn init(&mut self, db: &DbInfo, cl_info: &ClusterNodeInfo) -> Result<(), Box<dyn Error>>{
         self.check_cluster_state(cl_info);
         self.check_difference_data(db, cl_info)?;
         Ok(())
@@ -68,7 +67,7 @@ impl ClusterInfo{
         }
     }
 
-    fn check_difference_data(&mut self, db: &web::Data<DbInfo>, cl_info: &ClusterNodeInfo) -> Result<(), Box<dyn Error>>{
+    fn check_difference_data(&mut self, db: &DbInfo, cl_info: &ClusterNodeInfo) -> Result<(), Box<dyn Error>>{
         let result = db.get_rollback_sql(&cl_info.cluster_name)?;
         for row in &result{
             if row.value.status == 0{
@@ -106,12 +105,12 @@ impl ClusterSwitchInfo{
             last_switch_state: true
         }
     }
-    fn init(&mut self, db: &web::Data<DbInfo>, cl_info: &ClusterNodeInfo) -> Result<(), Box<dyn Error>>{
+    fn init(&mut self, db: &DbInfo, cl_info: &ClusterNodeInfo) -> Result<(), Box<dyn Error>>{
         let result = db.prefix_iterator(&String::from(""), &CfNameTypeCode::HaChangeLog.get())?;
         for row in result{
             if row.value.len() == 0 {continue;}
             let value: HaChangeLog = serde_json::from_str(&row.value)?;
-            if value.cluster_name == cl_info.cluster_name {
+            if value.cluster_name == cl_info.cluster_name && value.is_failover() {
                 self.switch_total += 1;
                 let tmp_list = row.key.split("_");
                 let tmp_list = tmp_list.collect::<Vec<&str>>();
@@ -140,7 +139,7 @@ impl ClusterMonitorInfo{
         }
     }
 
-    pub fn init(&mut self, db: &web::Data<DbInfo>, cluster_name: &String) -> Result<(), Box<dyn Error>> {
+    pub fn init(&mut self, db: &DbInfo, cluster_name: &String) -> Result<(), Box<dyn Error>> {
         let mut cl_info = ClusterNodeInfo::new(cluster_name);
         cl_info.init(db)?;
         self.init_monitor_status(&cl_info);
@@ -153,12 +152,12 @@ impl ClusterMonitorInfo{
         self.monitor_status.update_failover_count(cl_info);
     }
 
-    fn init_cluster_info(&mut self, db: &web::Data<DbInfo>, cl_info: &ClusterNodeInfo) -> Result<(), Box<dyn Error>> {
+    fn init_cluster_info(&mut self, db: &DbInfo, cl_info: &ClusterNodeInfo) -> Result<(), Box<dyn Error>> {
         self.cluster_info.init(db, cl_info)?;
         Ok(())
     }
 
-    fn init_switch_log_info(&mut self, db: &web::Data<DbInfo>, cl_info: &ClusterNodeInfo) -> Result<(), Box<dyn Error>>{
+    fn init_switch_log_info(&mut self, db: &DbInfo, cl_info: &ClusterNodeInfo) -> Result<(), Box<dyn Error>>{
         self.switch_info.init(db, cl_info)?;
         Ok(())
     }
@@ -3,7 +3,8 @@
 @datetime: 2019/12/27
 */
 use serde::{Serialize, Deserialize};
-use actix_web::HttpResponse;
+use actix_web::{HttpResponse, HttpRequest};
+use actix_web::http::header;
 use std::error::Error;
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -60,4 +61,21 @@ pub struct ResponseValue<F: Serialize>{
 pub fn response_value<F: Serialize>(value: &F) -> HttpResponse {
     HttpResponse::Ok()
         .json(ResponseValue{status:3, value})
-}
\ No newline at end of file
+}
+
+///
+/// 带ETag的返回， 请求头If-None-Match与etag一致时返回304
+pub fn response_value_etag<F: Serialize>(req: &HttpRequest, etag: &String, value: &F) -> HttpResponse {
+    if let Some(v) = req.headers().get(header::IF_NONE_MATCH) {
+        if let Ok(v) = v.to_str() {
+            if v.split(",").any(|t| t.trim() == etag || t.trim() == "*") {
+                return HttpResponse::NotModified()
+                    .header(header::ETAG, etag.as_str())
+                    .finish();
+            }
+        }
+    }
+    HttpResponse::Ok()
+        .header(header::ETAG, etag.as_str())
+        .json(ResponseValue{status:3, value})
+}
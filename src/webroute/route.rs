@@ -2,7 +2,7 @@
 @author: xiao cai niao
 @datetime: 2019/11/5
 */
-use actix_web::{web, HttpResponse};
+use actix_web::{web, HttpResponse, HttpRequest};
 use actix_session::{ Session};
 use serde::{Deserialize, Serialize};
 use crate::storage;
@@ -11,10 +11,13 @@ use crate::ha::procotol::{MysqlState, CommandSql, MyProtocol};
 use std::error::Error;
 use crate::ha::nodes_manager::{SwitchForNodes, DifferenceSql, SqlRelation};
 use crate::storage::opdb::{HaChangeLog, UserInfo, HostInfoValue};
-use crate::ha::route_manager::RouteInfo;
-use crate::webroute::response::{response_state, response_value, ResponseState};
+use crate::ha::route_manager::{RouteInfo, RouteHistory};
+use crate::webroute::response::{response_state, response_value, ResponseState, response_value_etag};
 use crate::webroute::new_route::PostCluster;
 use crate::ha::sys_manager::MonitorSetting;
+use crate::ha::route_notify::RouteNotify;
+use futures::{future, Future};
+use std::time::Duration;
 
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -67,7 +70,7 @@ pub struct EditMainTain{
 }
 
 impl EditMainTain{
-    pub fn check_role(&self, data: &web::Data<DbInfo>) -> Result<bool, Box<dyn Error>>{
+    pub fn check_role(&self, data: &DbInfo) -> Result<bool, Box<dyn Error>>{
         let status =  data.get(&self.host, &CfNameTypeCode::NodesState.get())?;
         if status.value.len() > 0{
             let cur_status: MysqlState = serde_json::from_str(&status.value).unwrap();
@@ -81,7 +84,7 @@ impl EditMainTain{
         return Ok(true);
     }
 
-    pub fn check_state(&self, data: &web::Data<DbInfo>) -> Result<bool, Box<dyn Error>>{
+    pub fn check_state(&self, data: &DbInfo) -> Result<bool, Box<dyn Error>>{
         let result = data.iterator(&CfNameTypeCode::HaNodesInfo.get(), &self.host)?;
         for row in &result {
             let node: HostInfoValue = serde_json::from_str(&row.value)?;
@@ -139,7 +142,7 @@ pub struct DeleteNode {
 }
 
 impl DeleteNode {
-    pub fn exec(&self, data: &web::Data<DbInfo>) -> HttpResponse {
+    pub fn exec(&self, data: &DbInfo) -> HttpResponse {
         let cf_name = String::from("Ha_nodes_info");
         let cur_value = data.get(&self.host, &cf_name);
         match cur_value {
@@ -208,7 +211,7 @@ impl SwitchLog {
         SwitchLog{log_data: vec![] }
     }
 
-    fn get_all(&mut self, db: &web::Data<DbInfo>) -> Result<(), Box<dyn Error>> {
+    fn get_all(&mut self, db: &DbInfo) -> Result<(), Box<dyn Error>> {
         let result = db.iterator(&CfNameTypeCode::HaChangeLog.get(), &String::from(""));
 //        let host = String::from("10.0.1.112");
 //        let result = db.prefix_iterator(&host, &CfNameTypeCode::HaChangeLog.get());
@@ -246,6 +249,15 @@ pub struct ResponseRouteInfo {
     pub route: Vec<RouteInfo>
 }
 
+impl ResponseRouteInfo {
+    ///
+    /// 由各集群路由的generation组成， 任意集群路由变化etag都会变化
+    pub fn etag(&self) -> String {
+        let tags = self.route.iter().map(|r| format!("{}:{}", r.cluster_name, r.generation)).collect::<Vec<String>>();
+        format!("\"{}\"", tags.join(";"))
+    }
+}
+
 ///
 /// 获取集群对应路由关系的请求包
 #[derive(Serialize, Deserialize)]
@@ -255,7 +267,7 @@ pub struct GetRouteInfo {
 }
 
 impl GetRouteInfo {
-    pub fn getall(&self, db: &web::Data<DbInfo>) -> Result<ResponseRouteInfo, Box<dyn Error>> {
+    pub fn getall(&self, db: &DbInfo) -> Result<ResponseRouteInfo, Box<dyn Error>> {
         let mut res_route = ResponseRouteInfo{route: vec![]};
         let result = db.prefix_iterator(&PrefixTypeCode::RouteInfo.prefix(), &CfNameTypeCode::SystemData.get())?;
         for kv in result {
@@ -270,7 +282,7 @@ impl GetRouteInfo {
         Ok(res_route)
     }
 
-    pub fn get(&self, db: &web::Data<DbInfo>) -> Result<ResponseRouteInfo, Box<dyn Error>> {
+    pub fn get(&self, db: &DbInfo) -> Result<ResponseRouteInfo, Box<dyn Error>> {
 //        self.check_user_info(db)?;
         db.check_user_info(&self.hook_id)?;
         let mut res_route = ResponseRouteInfo{route: vec![]};
@@ -282,7 +294,7 @@ impl GetRouteInfo {
         }
         Ok(res_route)
     }
-//    pub fn check_user_info(&self, db: &web::Data<DbInfo>) -> Result<(), Box<dyn Error>> {
+//    pub fn check_user_info(&self, db: &DbInfo) -> Result<(), Box<dyn Error>> {
 //        let result = db.prefix_iterator(&PrefixTypeCode::UserInfo.prefix(), &CfNameTypeCode::SystemData.get())?;
 //        for kv in result{
 //            let user_info: UserInfo = serde_json::from_str(&kv.value)?;
@@ -296,23 +308,112 @@ impl GetRouteInfo {
 }
 
 ///
-/// 获取mysql路由信息
-pub fn get_route_info(db: web::Data<DbInfo>, info: web::Json<GetRouteInfo>) -> HttpResponse {
+/// 获取mysql路由信息， 支持If-None-Match
+pub fn get_route_info(req: HttpRequest, db: web::Data<DbInfo>, info: web::Json<GetRouteInfo>) -> HttpResponse {
     //let info = GetRouteInfo{hook_id: info.hook_id.clone(), clusters: info.clusters.clone()};
     let v = info.get(&db);
     match v {
         Ok(rinfo) => {
-            return response_value(&rinfo);
+            return response_value_etag(&req, &rinfo.etag(), &rinfo);
+        }
+        Err(e) => {
+            return ResponseState::error(e.to_string());
+        }
+    }
+
+}
+
+///
+/// 查询路由变化历史， time不为0时返回该时间点生效的路由
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PostRouteHistory {
+    pub cluster_name: String,
+    #[serde(default)]
+    pub start: i64,
+    #[serde(default)]
+    pub stop: i64,
+    #[serde(default)]
+    pub time: i64,
+}
+
+impl PostRouteHistory {
+    fn get(&self, db: &DbInfo) -> Result<Vec<RouteHistory>, Box<dyn Error>> {
+        let history = db.get_route_history(&self.cluster_name)?;
+        if self.time > 0 {
+            let at = history.into_iter().filter(|h| h.time <= self.time).last();
+            return Ok(at.into_iter().collect());
+        }
+        Ok(history.into_iter()
+            .filter(|h| h.time >= self.start && (self.stop == 0 || h.time <= self.stop))
+            .collect())
+    }
+}
+
+pub fn get_route_history(db: web::Data<DbInfo>, info: web::Json<PostRouteHistory>) -> HttpResponse {
+    match info.get(&db) {
+        Ok(v) => {
+            return response_value(&v);
         }
         Err(e) => {
             return ResponseState::error(e.to_string());
         }
     }
+}
+
+///
+/// 长轮询等待路由变化， version为上一次返回的版本号， 第一次请求传0
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WatchRouteInfo {
+    pub hook_id: String,
+    pub clusters: Vec<String>,
+    pub version: u64,
+    #[serde(default)]
+    pub timeout: u64,           //最长等待秒数， 默认30秒， 最大60秒
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ResponseWatchRoute {
+    pub version: u64,
+    pub changed: bool,
+    pub route: Vec<RouteInfo>
+}
+
+impl WatchRouteInfo {
+    fn timeout(&self) -> Duration {
+        let timeout = match self.timeout {
+            0 => 30,
+            t if t > 60 => 60,
+            t => t
+        };
+        Duration::from_secs(timeout)
+    }
 
+    fn response(&self, db: &DbInfo, version: u64, changed: bool) -> Result<ResponseWatchRoute, Box<dyn Error>> {
+        let get = GetRouteInfo{ hook_id: self.hook_id.clone(), clusters: self.clusters.clone() };
+        let res_route = get.get(db)?;
+        Ok(ResponseWatchRoute{ version, changed, route: res_route.route })
+    }
+}
+
+///
+/// 等待路由变化的future， 等待期间不占用web worker及线程池
+pub fn watch_route_info(db: web::Data<DbInfo>, notify: web::Data<RouteNotify>, info: web::Json<WatchRouteInfo>)
+                        -> Box<dyn Future<Item = HttpResponse, Error = actix_web::Error>> {
+    if let Err(e) = db.check_user_info(&info.hook_id) {
+        return Box::new(future::ok(ResponseState::error(e.to_string())));
+    }
+    Box::new(notify.wait(&info.clusters, info.version, info.timeout())
+        .then(move |res| match res {
+            Ok((version, changed)) => match info.response(&db, version, changed) {
+                Ok(v) => Ok(response_value(&v)),
+                Err(e) => Ok(ResponseState::error(e.to_string()))
+            },
+            Err(_) => Ok(ResponseState::error("route notify is gone".to_string()))
+        }))
 }
 
 impl PostCluster{
-    fn get_route_info(&self, db: &web::Data<DbInfo>) -> Result<ResponseRouteInfo, Box<dyn Error>>{
+    fn get_route_info(&self, db: &DbInfo) -> Result<ResponseRouteInfo, Box<dyn Error>>{
         let mut res_route = ResponseRouteInfo{route: vec![]};
         let kv = db.prefix_get(&PrefixTypeCode::RouteInfo, &self.cluster_name)?;
         if kv.value.len() > 0 {
@@ -326,11 +427,11 @@ impl PostCluster{
 ///
 ///
 /// web端拉取对应集群的路由信息
-pub fn web_get_route_info(db: web::Data<DbInfo>, info: web::Json<PostCluster>) -> HttpResponse {
+pub fn web_get_route_info(req: HttpRequest, db: web::Data<DbInfo>, info: web::Json<PostCluster>) -> HttpResponse {
     let v = info.get_route_info(&db);
     match v {
         Ok(rinfo) => {
-            return response_value(&rinfo);
+            return response_value_etag(&req, &rinfo.etag(), &rinfo);
         }
         Err(e) => {
             return ResponseState::error(e.to_string());
@@ -511,7 +612,7 @@ impl ResponseAllSql {
         ResponseAllSql{total: 0, sql_info: vec![] }
     }
 
-    fn init_sql_info(&mut self, db: &web::Data<DbInfo>, info: &GetSql) -> Result<(), Box<dyn Error>> {
+    fn init_sql_info(&mut self, db: &DbInfo, info: &GetSql) -> Result<(), Box<dyn Error>> {
         let mut sql_vec = vec![];
         let mut total = 0 as usize;
 
@@ -637,7 +738,7 @@ impl ExtractAll{
         self.info.push(extra);
     }
 
-    fn execute(&mut self, db: &web::Data<DbInfo>) -> Result<(), Box<dyn Error>> {
+    fn execute(&mut self, db: &DbInfo) -> Result<(), Box<dyn Error>> {
         for extrac_info in &self.info{
             let master_host = self.get_master_info(&extrac_info.cluster_name, db)?;
             self.push_sql(&master_host, &extrac_info.sqls)?;
@@ -648,7 +749,7 @@ impl ExtractAll{
     }
 
 
-    fn get_master_info(&self, cluster_name: &String, db: &web::Data<DbInfo>) -> Result<String, Box<dyn Error>>{
+    fn get_master_info(&self, cluster_name: &String, db: &DbInfo) -> Result<String, Box<dyn Error>>{
         let route_result = db.prefix_get(&PrefixTypeCode::RouteInfo, cluster_name)?;
         let route_info: RouteInfo = serde_json::from_str(&route_result.value).unwrap();
         let result = db.iterator(&CfNameTypeCode::HaNodesInfo.get(), &String::from(""))?;
@@ -670,7 +771,7 @@ impl ExtractAll{
         return MyProtocol::Command.push_sql(master_host, &command_sql);
     }
 
-    fn alter_sql_info_from_db(&self, db: &web::Data<DbInfo>, mark_info: &Vec<MarkSqlInfo>) -> Result<(), Box<dyn Error>>{
+    fn alter_sql_info_from_db(&self, db: &DbInfo, mark_info: &Vec<MarkSqlInfo>) -> Result<(), Box<dyn Error>>{
         for mark in mark_info{
             mark.set_mark(db)?
         }
@@ -702,7 +803,7 @@ pub struct MarkSqlInfo{
     pub number: u64
 }
 impl MarkSqlInfo{
-    fn set_mark(&self, db: &web::Data<DbInfo>) -> Result<(), Box<dyn Error>>{
+    fn set_mark(&self, db: &DbInfo) -> Result<(), Box<dyn Error>>{
         let key = format!("{}:{}_{}", &self.cluster_name, &self.host, &self.time);
         let result = db.prefix_get(&PrefixTypeCode::RollBackSql, &key)?;
         let mut value: DifferenceSql = serde_json::from_str(&result.value).unwrap();